@@ -1,6 +1,7 @@
+use fuse::dir::DirSnapshot;
 use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
-    Session,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    RequestContext, Session,
 };
 use libc::ENOENT;
 use std::env;
@@ -48,7 +49,7 @@ const HELLO_TXT_ATTR: FileAttr = FileAttr {
 struct HelloFS;
 
 impl Filesystem for HelloFS {
-    fn lookup(&mut self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, _req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEntry) {
         if parent == 1 && name.to_str() == Some("hello.txt") {
             reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
         } else {
@@ -56,7 +57,7 @@ impl Filesystem for HelloFS {
         }
     }
 
-    fn getattr(&mut self, ino: u64, reply: ReplyAttr) {
+    fn getattr(&mut self, _req: &RequestContext, ino: u64, reply: ReplyAttr) {
         match ino {
             1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
             2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
@@ -66,6 +67,7 @@ impl Filesystem for HelloFS {
 
     fn read(
         &mut self,
+        _req: &RequestContext,
         ino: u64,
         _fh: u64,
         offset: i64,
@@ -81,27 +83,21 @@ impl Filesystem for HelloFS {
 
     fn readdir(
         &mut self,
+        _req: &RequestContext,
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        reply: ReplyDirectory,
     ) {
         if ino != 1 {
             reply.error(ENOENT);
             return;
         }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::RegularFile, "hello.txt"),
-        ];
-
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
-        }
-        reply.ok();
+        // The directory never changes, so it can be listed from a new snapshot every time
+        let mut dir = DirSnapshot::new(1, 1);
+        dir.add(2, FileType::RegularFile, "hello.txt");
+        dir.reply(offset, reply);
     }
 }
 
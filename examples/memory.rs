@@ -1,10 +1,10 @@
 use std::env;
 use std::ffi::OsStr;
+use std::time::Duration;
 
 use fuse::Session;
 use log::info;
 use tokio::signal;
-use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() {
@@ -21,20 +21,13 @@ async fn main() {
     let filesystem = fuse::memory::new(file_system_size);
     let mut session = Session::new(filesystem, mountpoint.as_ref(), &options).unwrap();
 
-    let (tx, rx) = mpsc::channel(1);
-
-    let ctrl_c = tokio::spawn(async move {
+    let shutdown = async {
         signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
         info!("Received Ctrl+C, initiating shutdown...");
-        tx.send(()).await.unwrap();
-    });
-
-    let background_task = tokio::spawn(async move {
-        session.run_with_signal(rx).await.unwrap();
-    });
-
-    let (ctrl_c_result, _) = futures::join!(ctrl_c, background_task);
-    if let Err(e) = ctrl_c_result {
-        eprintln!("Error occurred in ctrl_c task: {:?}", e);
-    }
+    };
+    let summary = session
+        .run_until(shutdown, Duration::from_secs(5))
+        .await
+        .unwrap();
+    info!("Shut down: {:?}", summary);
 }
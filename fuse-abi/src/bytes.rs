@@ -0,0 +1,178 @@
+//! Safe conversion of ABI structs from and to bytes.
+//!
+//! Every ABI struct implements `FromBytes` and `AsBytes`. The `abi_struct!` macro that defines
+//! them checks at compile time that all fields are plain integers (or arrays and structs of
+//! them) and that the struct has no padding bytes, so any byte pattern is a valid value and
+//! every byte of a value is initialized. With that, structs can be parsed from and encoded to
+//! byte buffers without unsafe code.
+
+use std::{mem, slice};
+
+/// Types that can be created from any byte pattern of the right size.
+///
+/// # Safety
+///
+/// Implementors must not have padding bytes or invalid bit patterns, i.e. they must consist of
+/// plain integers (or arrays and `#[repr(C)]` structs of them) only.
+pub unsafe trait FromBytes: Sized {
+    /// Reference a value at the beginning of the given bytes. Returns `None` if there are too
+    /// few bytes or if they aren't properly aligned for `Self`.
+    fn ref_from_prefix(bytes: &[u8]) -> Option<&Self> {
+        Self::slice_from_prefix(bytes, 1).map(|s| &s[0])
+    }
+
+    /// Reference `count` values at the beginning of the given bytes. Returns `None` if there
+    /// are too few bytes or if they aren't properly aligned for `Self`.
+    fn slice_from_prefix(bytes: &[u8], count: usize) -> Option<&[Self]> {
+        let len = mem::size_of::<Self>().checked_mul(count)?;
+        if bytes.len() < len || bytes.as_ptr() as usize % mem::align_of::<Self>() != 0 {
+            return None;
+        }
+        // SAFETY: the bytes are long enough and aligned, and any byte pattern is a valid `Self`
+        Some(unsafe { slice::from_raw_parts(bytes.as_ptr() as *const Self, count) })
+    }
+
+    /// Copy a value from the beginning of the given bytes, which don't need to be aligned.
+    /// Returns `None` if there are too few bytes.
+    fn read_from_prefix(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < mem::size_of::<Self>() {
+            return None;
+        }
+        // SAFETY: the bytes are long enough and any byte pattern is a valid `Self`
+        Some(unsafe { (bytes.as_ptr() as *const Self).read_unaligned() })
+    }
+
+    /// Create a value with all bytes set to zero
+    fn new_zeroed() -> Self {
+        // SAFETY: any byte pattern, including all zeros, is a valid `Self`
+        unsafe { mem::zeroed() }
+    }
+}
+
+/// Types that can be viewed as bytes.
+///
+/// # Safety
+///
+/// Implementors must not have padding bytes (which are uninitialized), i.e. they must consist
+/// of plain integers (or arrays and `#[repr(C)]` structs of them) only.
+pub unsafe trait AsBytes {
+    /// The in-memory representation of the value
+    fn as_bytes(&self) -> &[u8] {
+        let len = mem::size_of_val(self);
+        // SAFETY: the value has no padding, so all `len` bytes are initialized
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, len) }
+    }
+}
+
+macro_rules! impl_plain {
+    ($($ty:ty),*) => {$(
+        unsafe impl FromBytes for $ty {}
+        unsafe impl AsBytes for $ty {}
+    )*};
+}
+
+impl_plain!(u8, u16, u32, u64, i8, i16, i32, i64, ());
+
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
+unsafe impl<T: AsBytes, const N: usize> AsBytes for [T; N] {}
+unsafe impl<T: AsBytes> AsBytes for [T] {}
+
+/// Size of a field type, which must be plain data (used by `abi_struct!`)
+// Only called in anonymous consts, which the dead code lint doesn't look into
+#[allow(dead_code)]
+pub(crate) const fn plain_size<T: FromBytes + AsBytes>() -> usize {
+    mem::size_of::<T>()
+}
+
+/// Define `#[repr(C)]` ABI structs that implement `FromBytes` and `AsBytes`. Fails to compile
+/// if a field isn't plain data or if the struct has padding (which needs to be an explicit
+/// field instead).
+macro_rules! abi_struct {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $( $(#[$fattr:meta])* $fvis:vis $field:ident: $fty:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        #[derive(Debug)]
+        pub struct $name {
+            $( $(#[$fattr])* $fvis $field: $fty ),*
+        }
+
+        $(#[$attr])*
+        const _: () = {
+            unsafe impl $crate::FromBytes for $name {}
+            unsafe impl $crate::AsBytes for $name {}
+
+            let mut size = 0;
+            $(
+                $(#[$fattr])*
+                {
+                    size += $crate::bytes::plain_size::<$fty>();
+                }
+            )*
+            assert!(
+                size == ::std::mem::size_of::<$name>(),
+                concat!("padding in ABI struct ", stringify!($name)),
+            );
+        };
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsBytes, FromBytes};
+    use crate::{FuseInHeader, FuseOutHeader};
+
+    #[test]
+    fn plain() {
+        assert!(().as_bytes().is_empty());
+        assert_eq!(
+            [0x12u8, 0x34, 0x56, 0x78].as_bytes(),
+            [0x12, 0x34, 0x56, 0x78]
+        );
+        assert_eq!(0x1234u16.as_bytes(), 0x1234u16.to_ne_bytes());
+        assert_eq!([0x1234u16, 0x5678][..].as_bytes().len(), 4);
+        assert_eq!(u32::read_from_prefix(&[0, 0, 0]), None);
+        assert_eq!(u32::new_zeroed(), 0);
+    }
+
+    #[test]
+    fn structs() {
+        let header = FuseOutHeader {
+            len: 0x10,
+            error: -2,
+            unique: 0xdeadbeef,
+        };
+        let bytes = header.as_bytes();
+        assert_eq!(
+            bytes,
+            [
+                0x10, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00,
+                0x00, 0x00,
+            ]
+        );
+        let header = FuseOutHeader::read_from_prefix(bytes).unwrap();
+        assert_eq!(
+            (header.len, header.error, header.unique),
+            (0x10, -2, 0xdeadbeef)
+        );
+        assert!(FuseOutHeader::read_from_prefix(&bytes[1..]).is_none());
+        let header = FuseInHeader::new_zeroed();
+        assert_eq!(header.as_bytes(), [0; 40]);
+    }
+
+    #[test]
+    fn alignment() {
+        let data = [0u64; 4];
+        let bytes = data.as_bytes();
+        assert!(FuseOutHeader::ref_from_prefix(bytes).is_some());
+        assert!(FuseOutHeader::ref_from_prefix(&bytes[4..]).is_none());
+        assert!(FuseOutHeader::ref_from_prefix(&bytes[24..]).is_none());
+        assert_eq!(u64::slice_from_prefix(bytes, 4).map(|s| s.len()), Some(4));
+        assert!(u64::slice_from_prefix(bytes, 5).is_none());
+        assert!(u64::slice_from_prefix(bytes, usize::MAX).is_none());
+    }
+}
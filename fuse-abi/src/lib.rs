@@ -17,12 +17,20 @@
 //! - supports ABI 7.26 since FUSE 3.0.0
 //!
 //! Items without a version annotation are valid with ABI 7.8 and later
+//!
+//! All ABI structs implement `FromBytes` and `AsBytes` for converting them from and to bytes
+//! without unsafe code.
 
 #![warn(missing_debug_implementations, rust_2018_idioms)]
 #![allow(missing_docs)]
 
 use std::convert::TryFrom;
 
+#[macro_use]
+mod bytes;
+
+pub use bytes::{AsBytes, FromBytes};
+
 pub const FUSE_KERNEL_VERSION: u32 = 7;
 
 #[cfg(not(feature = "abi-7-9"))]
@@ -47,62 +55,80 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 16;
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 17;
 #[cfg(all(feature = "abi-7-18", not(feature = "abi-7-19")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 18;
-#[cfg(feature = "abi-7-19")]
+#[cfg(all(feature = "abi-7-19", not(feature = "abi-7-20")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+#[cfg(all(feature = "abi-7-20", not(feature = "abi-7-21")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 20;
+#[cfg(all(feature = "abi-7-21", not(feature = "abi-7-22")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 21;
+#[cfg(all(feature = "abi-7-22", not(feature = "abi-7-23")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 22;
+#[cfg(all(feature = "abi-7-23", not(feature = "abi-7-24")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 23;
+#[cfg(all(feature = "abi-7-24", not(feature = "abi-7-25")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 24;
+#[cfg(all(feature = "abi-7-25", not(feature = "abi-7-26")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 25;
+#[cfg(all(feature = "abi-7-26", not(feature = "abi-7-27")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 26;
+#[cfg(all(feature = "abi-7-27", not(feature = "abi-7-28")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 27;
+#[cfg(feature = "abi-7-28")]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 28;
 
 pub const FUSE_ROOT_ID: u64 = 1;
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseAttr {
-    pub ino: u64,
-    pub size: u64,
-    pub blocks: u64,
-    pub atime: u64,
-    pub mtime: u64,
-    pub ctime: u64,
-    #[cfg(target_os = "macos")]
-    pub crtime: u64,
-    pub atimensec: u32,
-    pub mtimensec: u32,
-    pub ctimensec: u32,
-    #[cfg(target_os = "macos")]
-    pub crtimensec: u32,
-    pub mode: u32,
-    pub nlink: u32,
-    pub uid: u32,
-    pub gid: u32,
-    pub rdev: u32,
-    #[cfg(target_os = "macos")]
-    pub flags: u32, // see chflags(2)
-    #[cfg(feature = "abi-7-9")]
-    pub blksize: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseAttr {
+        pub ino: u64,
+        pub size: u64,
+        pub blocks: u64,
+        pub atime: u64,
+        pub mtime: u64,
+        pub ctime: u64,
+        #[cfg(target_os = "macos")]
+        pub crtime: u64,
+        pub atimensec: u32,
+        pub mtimensec: u32,
+        pub ctimensec: u32,
+        #[cfg(target_os = "macos")]
+        pub crtimensec: u32,
+        pub mode: u32,
+        pub nlink: u32,
+        pub uid: u32,
+        pub gid: u32,
+        pub rdev: u32,
+        #[cfg(target_os = "macos")]
+        pub flags: u32, // see chflags(2)
+        #[cfg(feature = "abi-7-9")]
+        pub blksize: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseKstatfs {
-    pub blocks: u64,  // Total blocks (in units of frsize)
-    pub bfree: u64,   // Free blocks
-    pub bavail: u64,  // Free blocks for unprivileged users
-    pub files: u64,   // Total inodes
-    pub ffree: u64,   // Free inodes
-    pub bsize: u32,   // Filesystem block size
-    pub namelen: u32, // Maximum filename length
-    pub frsize: u32,  // Fundamental file system block size
-    pub padding: u32,
-    pub spare: [u32; 6],
+abi_struct! {
+    pub struct FuseKstatfs {
+        pub blocks: u64,  // Total blocks (in units of frsize)
+        pub bfree: u64,   // Free blocks
+        pub bavail: u64,  // Free blocks for unprivileged users
+        pub files: u64,   // Total inodes
+        pub ffree: u64,   // Free inodes
+        pub bsize: u32,   // Filesystem block size
+        pub namelen: u32, // Maximum filename length
+        pub frsize: u32,  // Fundamental file system block size
+        pub padding: u32,
+        pub spare: [u32; 6],
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseFileLock {
-    pub start: u64,
-    pub end: u64,
-    pub typ: u32,
-    pub pid: u32,
+abi_struct! {
+    pub struct FuseFileLock {
+        pub start: u64,
+        pub end: u64,
+        pub typ: u32,
+        pub pid: u32,
+    }
 }
 
 pub mod consts {
@@ -120,6 +146,8 @@ pub mod consts {
     pub const FATTR_MTIME_NOW: u32 = 1 << 8;
     #[cfg(feature = "abi-7-9")]
     pub const FATTR_LOCKOWNER: u32 = 1 << 9;
+    #[cfg(feature = "abi-7-23")]
+    pub const FATTR_CTIME: u32 = 1 << 10;
 
     #[cfg(target_os = "macos")]
     pub const FATTR_CRTIME: u32 = 1 << 28;
@@ -135,6 +163,8 @@ pub mod consts {
     pub const FOPEN_KEEP_CACHE: u32 = 1 << 1; // don't invalidate the data cache on open
     #[cfg(feature = "abi-7-10")]
     pub const FOPEN_NONSEEKABLE: u32 = 1 << 2; // the file is not seekable
+    #[cfg(feature = "abi-7-28")]
+    pub const FOPEN_CACHE_DIR: u32 = 1 << 3; // allow caching this directory
 
     #[cfg(target_os = "macos")]
     pub const FOPEN_PURGE_ATTR: u32 = 1 << 30;
@@ -166,6 +196,30 @@ pub mod consts {
     pub const FUSE_FLOCK_LOCKS: u32 = 1 << 10; // remote locking for BSD style file locks
     #[cfg(feature = "abi-7-18")]
     pub const FUSE_HAS_IOCTL_DIR: u32 = 1 << 11; // kernel supports ioctl on directories
+    #[cfg(feature = "abi-7-20")]
+    pub const FUSE_AUTO_INVAL_DATA: u32 = 1 << 12; // automatically invalidate cached pages
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_DO_READDIRPLUS: u32 = 1 << 13; // do READDIRPLUS (READDIR+LOOKUP in one)
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_READDIRPLUS_AUTO: u32 = 1 << 14; // adaptive readdirplus
+    #[cfg(feature = "abi-7-22")]
+    pub const FUSE_ASYNC_DIO: u32 = 1 << 15; // asynchronous direct I/O submission
+    #[cfg(feature = "abi-7-23")]
+    pub const FUSE_WRITEBACK_CACHE: u32 = 1 << 16; // use writeback cache for buffered writes
+    #[cfg(feature = "abi-7-23")]
+    pub const FUSE_NO_OPEN_SUPPORT: u32 = 1 << 17; // kernel supports zero-message opens
+    #[cfg(feature = "abi-7-25")]
+    pub const FUSE_PARALLEL_DIROPS: u32 = 1 << 18; // allow parallel lookups and readdir
+    #[cfg(feature = "abi-7-26")]
+    pub const FUSE_HANDLE_KILLPRIV: u32 = 1 << 19; // filesystem handles killing suid/sgid/cap on write/chown/trunc
+    #[cfg(feature = "abi-7-26")]
+    pub const FUSE_POSIX_ACL: u32 = 1 << 20; // filesystem supports posix acls
+    #[cfg(feature = "abi-7-27")]
+    pub const FUSE_ABORT_ERROR: u32 = 1 << 21; // reading the device after abort returns ECONNABORTED
+    #[cfg(feature = "abi-7-28")]
+    pub const FUSE_MAX_PAGES: u32 = 1 << 22; // init_out.max_pages contains the max number of req pages
+    #[cfg(feature = "abi-7-28")]
+    pub const FUSE_CACHE_SYMLINKS: u32 = 1 << 23; // cache READLINK responses
 
     #[cfg(target_os = "macos")]
     pub const FUSE_ALLOCATE: u32 = 1 << 27;
@@ -225,6 +279,10 @@ pub mod consts {
 
     // The read buffer is required to be at least 8k, but may be much larger
     pub const FUSE_MIN_READ_BUFFER: usize = 8192;
+
+    // Size of the init reply for kernels before ABI 7.23
+    #[cfg(feature = "abi-7-23")]
+    pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
 }
 
 /// Invalid opcode error.
@@ -408,523 +466,535 @@ impl TryFrom<u32> for fuse_notify_code {
     }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseEntryOut {
-    pub nodeid: u64,
-    pub generation: u64,
-    pub entry_valid: u64,
-    pub attr_valid: u64,
-    pub entry_valid_nsec: u32,
-    pub attr_valid_nsec: u32,
-    pub attr: FuseAttr,
+abi_struct! {
+    pub struct FuseEntryOut {
+        pub nodeid: u64,
+        pub generation: u64,
+        pub entry_valid: u64,
+        pub attr_valid: u64,
+        pub entry_valid_nsec: u32,
+        pub attr_valid_nsec: u32,
+        pub attr: FuseAttr,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseForgetIn {
-    pub nlookup: u64,
+abi_struct! {
+    pub struct FuseForgetIn {
+        pub nlookup: u64,
+    }
 }
 
-#[cfg(feature = "abi-7-16")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_forget_one {
-    pub nodeid: u64,
-    pub nlookup: u64,
+abi_struct! {
+    #[cfg(feature = "abi-7-16")]
+    pub struct fuse_forget_one {
+        pub nodeid: u64,
+        pub nlookup: u64,
+    }
 }
 
-#[cfg(feature = "abi-7-16")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_batch_forget_in {
-    pub count: u32,
-    pub dummy: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-16")]
+    pub struct fuse_batch_forget_in {
+        pub count: u32,
+        pub dummy: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-9")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_getattr_in {
-    pub getattr_flags: u32,
-    pub dummy: u32,
-    pub fh: u64,
+abi_struct! {
+    #[cfg(feature = "abi-7-9")]
+    pub struct fuse_getattr_in {
+        pub getattr_flags: u32,
+        pub dummy: u32,
+        pub fh: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseAttrOut {
-    pub attr_valid: u64,
-    pub attr_valid_nsec: u32,
-    pub dummy: u32,
-    pub attr: FuseAttr,
+abi_struct! {
+    pub struct FuseAttrOut {
+        pub attr_valid: u64,
+        pub attr_valid_nsec: u32,
+        pub dummy: u32,
+        pub attr: FuseAttr,
+    }
 }
 
-#[cfg(target_os = "macos")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_getxtimes_out {
-    pub bkuptime: u64,
-    pub crtime: u64,
-    pub bkuptimensec: u32,
-    pub crtimensec: u32,
+abi_struct! {
+    #[cfg(target_os = "macos")]
+    pub struct fuse_getxtimes_out {
+        pub bkuptime: u64,
+        pub crtime: u64,
+        pub bkuptimensec: u32,
+        pub crtimensec: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseMknodIn {
-    pub mode: u32,
-    pub rdev: u32,
-    #[cfg(feature = "abi-7-12")]
-    pub umask: u32,
-    #[cfg(feature = "abi-7-12")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseMknodIn {
+        pub mode: u32,
+        pub rdev: u32,
+        #[cfg(feature = "abi-7-12")]
+        pub umask: u32,
+        #[cfg(feature = "abi-7-12")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseMkdirIn {
-    pub mode: u32,
-    #[cfg(not(feature = "abi-7-12"))]
-    pub padding: u32,
-    #[cfg(feature = "abi-7-12")]
-    pub umask: u32,
+abi_struct! {
+    pub struct FuseMkdirIn {
+        pub mode: u32,
+        #[cfg(not(feature = "abi-7-12"))]
+        pub padding: u32,
+        #[cfg(feature = "abi-7-12")]
+        pub umask: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseRenameIn {
-    pub newdir: u64,
+abi_struct! {
+    pub struct FuseRenameIn {
+        pub newdir: u64,
+    }
 }
 
-#[cfg(target_os = "macos")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_exchange_in {
-    pub olddir: u64,
-    pub newdir: u64,
-    pub options: u64,
+abi_struct! {
+    #[cfg(target_os = "macos")]
+    pub struct fuse_exchange_in {
+        pub olddir: u64,
+        pub newdir: u64,
+        pub options: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseLinkIn {
-    pub oldnodeid: u64,
+abi_struct! {
+    pub struct FuseLinkIn {
+        pub oldnodeid: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseSetattrIn {
-    pub valid: u32,
-    pub padding: u32,
-    pub fh: u64,
-    pub size: u64,
-    #[cfg(not(feature = "abi-7-9"))]
-    pub unused1: u64,
-    #[cfg(feature = "abi-7-9")]
-    pub lock_owner: u64,
-    pub atime: u64,
-    pub mtime: u64,
-    pub unused2: u64,
-    pub atimensec: u32,
-    pub mtimensec: u32,
-    pub unused3: u32,
-    pub mode: u32,
-    pub unused4: u32,
-    pub uid: u32,
-    pub gid: u32,
-    pub unused5: u32,
-    #[cfg(target_os = "macos")]
-    pub bkuptime: u64,
-    #[cfg(target_os = "macos")]
-    pub chgtime: u64,
-    #[cfg(target_os = "macos")]
-    pub crtime: u64,
-    #[cfg(target_os = "macos")]
-    pub bkuptimensec: u32,
-    #[cfg(target_os = "macos")]
-    pub chgtimensec: u32,
-    #[cfg(target_os = "macos")]
-    pub crtimensec: u32,
-    #[cfg(target_os = "macos")]
-    pub flags: u32, // see chflags(2)
+abi_struct! {
+    pub struct FuseSetattrIn {
+        pub valid: u32,
+        pub padding: u32,
+        pub fh: u64,
+        pub size: u64,
+        #[cfg(not(feature = "abi-7-9"))]
+        pub unused1: u64,
+        #[cfg(feature = "abi-7-9")]
+        pub lock_owner: u64,
+        pub atime: u64,
+        pub mtime: u64,
+        pub unused2: u64,
+        pub atimensec: u32,
+        pub mtimensec: u32,
+        pub unused3: u32,
+        pub mode: u32,
+        pub unused4: u32,
+        pub uid: u32,
+        pub gid: u32,
+        pub unused5: u32,
+        #[cfg(target_os = "macos")]
+        pub bkuptime: u64,
+        #[cfg(target_os = "macos")]
+        pub chgtime: u64,
+        #[cfg(target_os = "macos")]
+        pub crtime: u64,
+        #[cfg(target_os = "macos")]
+        pub bkuptimensec: u32,
+        #[cfg(target_os = "macos")]
+        pub chgtimensec: u32,
+        #[cfg(target_os = "macos")]
+        pub crtimensec: u32,
+        #[cfg(target_os = "macos")]
+        pub flags: u32, // see chflags(2)
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseOpenIn {
-    pub flags: u32,
-    pub unused: u32,
+abi_struct! {
+    pub struct FuseOpenIn {
+        pub flags: u32,
+        pub unused: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseCreateIn {
-    pub flags: u32,
-    pub mode: u32,
-    #[cfg(feature = "abi-7-12")]
-    pub umask: u32,
-    #[cfg(feature = "abi-7-12")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseCreateIn {
+        pub flags: u32,
+        pub mode: u32,
+        #[cfg(feature = "abi-7-12")]
+        pub umask: u32,
+        #[cfg(feature = "abi-7-12")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseOpenOut {
-    pub fh: u64,
-    pub open_flags: u32,
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseOpenOut {
+        pub fh: u64,
+        pub open_flags: u32,
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseReleaseIn {
-    pub fh: u64,
-    pub flags: u32,
-    pub release_flags: u32,
-    pub lock_owner: u64,
+abi_struct! {
+    pub struct FuseReleaseIn {
+        pub fh: u64,
+        pub flags: u32,
+        pub release_flags: u32,
+        pub lock_owner: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseFlushIn {
-    pub fh: u64,
-    pub unused: u32,
-    pub padding: u32,
-    pub lock_owner: u64,
+abi_struct! {
+    pub struct FuseFlushIn {
+        pub fh: u64,
+        pub unused: u32,
+        pub padding: u32,
+        pub lock_owner: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseReadIn {
-    pub fh: u64,
-    pub offset: u64,
-    pub size: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub read_flags: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub lock_owner: u64,
-    #[cfg(feature = "abi-7-9")]
-    pub flags: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseReadIn {
+        pub fh: u64,
+        pub offset: u64,
+        pub size: u32,
+        #[cfg(not(feature = "abi-7-9"))]
+        pub padding: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub read_flags: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub lock_owner: u64,
+        #[cfg(feature = "abi-7-9")]
+        pub flags: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseWriteIn {
-    pub fh: u64,
-    pub offset: u64,
-    pub size: u32,
-    pub write_flags: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub lock_owner: u64,
-    #[cfg(feature = "abi-7-9")]
-    pub flags: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseWriteIn {
+        pub fh: u64,
+        pub offset: u64,
+        pub size: u32,
+        pub write_flags: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub lock_owner: u64,
+        #[cfg(feature = "abi-7-9")]
+        pub flags: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseWriteOut {
-    pub size: u32,
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseWriteOut {
+        pub size: u32,
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseStatfsOut {
-    pub st: FuseKstatfs,
+abi_struct! {
+    pub struct FuseStatfsOut {
+        pub st: FuseKstatfs,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseFsyncIn {
-    pub fh: u64,
-    pub fsync_flags: u32,
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseFsyncIn {
+        pub fh: u64,
+        pub fsync_flags: u32,
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseSetxattrIn {
-    pub size: u32,
-    pub flags: u32,
-    #[cfg(target_os = "macos")]
-    pub position: u32,
-    #[cfg(target_os = "macos")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseSetxattrIn {
+        pub size: u32,
+        pub flags: u32,
+        #[cfg(target_os = "macos")]
+        pub position: u32,
+        #[cfg(target_os = "macos")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseGetxattrIn {
-    pub size: u32,
-    pub padding: u32,
-    #[cfg(target_os = "macos")]
-    pub position: u32,
-    #[cfg(target_os = "macos")]
-    pub padding2: u32,
+abi_struct! {
+    pub struct FuseGetxattrIn {
+        pub size: u32,
+        pub padding: u32,
+        #[cfg(target_os = "macos")]
+        pub position: u32,
+        #[cfg(target_os = "macos")]
+        pub padding2: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseGetxattrOut {
-    pub size: u32,
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseGetxattrOut {
+        pub size: u32,
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseLkIn {
-    pub fh: u64,
-    pub owner: u64,
-    pub lk: FuseFileLock,
-    #[cfg(feature = "abi-7-9")]
-    pub lk_flags: u32,
-    #[cfg(feature = "abi-7-9")]
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseLkIn {
+        pub fh: u64,
+        pub owner: u64,
+        pub lk: FuseFileLock,
+        #[cfg(feature = "abi-7-9")]
+        pub lk_flags: u32,
+        #[cfg(feature = "abi-7-9")]
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseLkOut {
-    pub lk: FuseFileLock,
+abi_struct! {
+    pub struct FuseLkOut {
+        pub lk: FuseFileLock,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseAccessIn {
-    pub mask: u32,
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseAccessIn {
+        pub mask: u32,
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseInitIn {
-    pub major: u32,
-    pub minor: u32,
-    pub max_readahead: u32,
-    pub flags: u32,
+abi_struct! {
+    pub struct FuseInitIn {
+        pub major: u32,
+        pub minor: u32,
+        pub max_readahead: u32,
+        pub flags: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseInitOut {
-    pub major: u32,
-    pub minor: u32,
-    pub max_readahead: u32,
-    pub flags: u32,
-    #[cfg(not(feature = "abi-7-13"))]
-    pub unused: u32,
-    #[cfg(feature = "abi-7-13")]
-    pub max_background: u16,
-    #[cfg(feature = "abi-7-13")]
-    pub congestion_threshold: u16,
-    pub max_write: u32,
-}
-
-#[cfg(feature = "abi-7-12")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct cuse_init_in {
-    pub major: u32,
-    pub minor: u32,
-    pub unused: u32,
-    pub flags: u32,
+abi_struct! {
+    pub struct FuseInitOut {
+        pub major: u32,
+        pub minor: u32,
+        pub max_readahead: u32,
+        pub flags: u32,
+        #[cfg(not(feature = "abi-7-13"))]
+        pub unused: u32,
+        #[cfg(feature = "abi-7-13")]
+        pub max_background: u16,
+        #[cfg(feature = "abi-7-13")]
+        pub congestion_threshold: u16,
+        pub max_write: u32,
+        #[cfg(feature = "abi-7-23")]
+        pub time_gran: u32,
+        #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
+        pub unused: [u32; 9],
+        #[cfg(feature = "abi-7-28")]
+        pub max_pages: u16,
+        #[cfg(feature = "abi-7-28")]
+        pub padding: u16,
+        #[cfg(feature = "abi-7-28")]
+        pub unused: [u32; 8],
+    }
 }
 
-#[cfg(feature = "abi-7-12")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct cuse_init_out {
-    pub major: u32,
-    pub minor: u32,
-    pub unused: u32,
-    pub flags: u32,
-    pub max_read: u32,
-    pub max_write: u32,
-    pub dev_major: u32, // chardev major
-    pub dev_minor: u32, // chardev minor
-    pub spare: [u32; 10],
+abi_struct! {
+    #[cfg(feature = "abi-7-12")]
+    pub struct cuse_init_in {
+        pub major: u32,
+        pub minor: u32,
+        pub unused: u32,
+        pub flags: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseInterruptIn {
-    pub unique: u64,
+abi_struct! {
+    #[cfg(feature = "abi-7-12")]
+    pub struct cuse_init_out {
+        pub major: u32,
+        pub minor: u32,
+        pub unused: u32,
+        pub flags: u32,
+        pub max_read: u32,
+        pub max_write: u32,
+        pub dev_major: u32, // chardev major
+        pub dev_minor: u32, // chardev minor
+        pub spare: [u32; 10],
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseBmapIn {
-    pub block: u64,
-    pub blocksize: u32,
-    pub padding: u32,
+abi_struct! {
+    pub struct FuseInterruptIn {
+        pub unique: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseBmapOut {
-    pub block: u64,
+abi_struct! {
+    pub struct FuseBmapIn {
+        pub block: u64,
+        pub blocksize: u32,
+        pub padding: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-11")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_ioctl_in {
-    pub fh: u64,
-    pub flags: u32,
-    pub cmd: u32,
-    pub arg: u64,
-    pub in_size: u32,
-    pub out_size: u32,
+abi_struct! {
+    pub struct FuseBmapOut {
+        pub block: u64,
+    }
 }
 
-#[cfg(feature = "abi-7-16")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_ioctl_iovec {
-    pub base: u64,
-    pub len: u64,
+abi_struct! {
+    #[cfg(feature = "abi-7-11")]
+    pub struct fuse_ioctl_in {
+        pub fh: u64,
+        pub flags: u32,
+        pub cmd: u32,
+        pub arg: u64,
+        pub in_size: u32,
+        pub out_size: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-11")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_ioctl_out {
-    pub result: i32,
-    pub flags: u32,
-    pub in_iovs: u32,
-    pub out_iovs: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-16")]
+    pub struct fuse_ioctl_iovec {
+        pub base: u64,
+        pub len: u64,
+    }
 }
 
-#[cfg(feature = "abi-7-11")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_poll_in {
-    pub fh: u64,
-    pub kh: u64,
-    pub flags: u32,
-    pub padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-11")]
+    pub struct fuse_ioctl_out {
+        pub result: i32,
+        pub flags: u32,
+        pub in_iovs: u32,
+        pub out_iovs: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-11")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_poll_out {
-    pub revents: u32,
-    pub padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-11")]
+    pub struct fuse_poll_in {
+        pub fh: u64,
+        pub kh: u64,
+        pub flags: u32,
+        pub padding: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-11")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_poll_wakeup_out {
-    pub kh: u64,
+abi_struct! {
+    #[cfg(feature = "abi-7-11")]
+    pub struct fuse_poll_out {
+        pub revents: u32,
+        pub padding: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-19")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_fallocate_in {
-    fh: u64,
-    offset: u64,
-    length: u64,
-    mode: u32,
-    padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-11")]
+    pub struct fuse_notify_poll_wakeup_out {
+        pub kh: u64,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseInHeader {
-    pub len: u32,
-    pub opcode: u32,
-    pub unique: u64,
-    pub nodeid: u64,
-    pub uid: u32,
-    pub gid: u32,
-    pub pid: u32,
-    pub padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-19")]
+    pub struct fuse_fallocate_in {
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+        padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseOutHeader {
-    pub len: u32,
-    pub error: i32,
-    pub unique: u64,
+abi_struct! {
+    pub struct FuseInHeader {
+        pub len: u32,
+        pub opcode: u32,
+        pub unique: u64,
+        pub nodeid: u64,
+        pub uid: u32,
+        pub gid: u32,
+        pub pid: u32,
+        pub padding: u32,
+    }
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FuseDirent {
-    pub ino: u64,
-    pub off: u64,
-    pub namelen: u32,
-    pub typ: u32,
-    // followed by name of namelen bytes
+abi_struct! {
+    pub struct FuseOutHeader {
+        pub len: u32,
+        pub error: i32,
+        pub unique: u64,
+    }
 }
 
-#[cfg(feature = "abi-7-12")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_inval_inode_out {
-    pub ino: u64,
-    pub off: i64,
-    pub len: i64,
+abi_struct! {
+    pub struct FuseDirent {
+        pub ino: u64,
+        pub off: u64,
+        pub namelen: u32,
+        pub typ: u32,
+        // followed by name of namelen bytes
+    }
 }
 
-#[cfg(feature = "abi-7-12")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_inval_entry_out {
-    pub parent: u64,
-    pub namelen: u32,
-    pub padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-12")]
+    pub struct fuse_notify_inval_inode_out {
+        pub ino: u64,
+        pub off: i64,
+        pub len: i64,
+    }
 }
 
-#[cfg(feature = "abi-7-18")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_delete_out {
-    parent: u64,
-    child: u64,
-    namelen: u32,
-    padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-12")]
+    pub struct fuse_notify_inval_entry_out {
+        pub parent: u64,
+        pub namelen: u32,
+        pub padding: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-15")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_store_out {
-    pub nodeid: u64,
-    pub offset: u64,
-    pub size: u32,
-    pub padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-18")]
+    pub struct fuse_notify_delete_out {
+        parent: u64,
+        child: u64,
+        namelen: u32,
+        padding: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-15")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_retrieve_out {
-    pub notify_unique: u64,
-    pub nodeid: u64,
-    pub offset: u64,
-    pub size: u32,
-    pub padding: u32,
+abi_struct! {
+    #[cfg(feature = "abi-7-15")]
+    pub struct fuse_notify_store_out {
+        pub nodeid: u64,
+        pub offset: u64,
+        pub size: u32,
+        pub padding: u32,
+    }
 }
 
-#[cfg(feature = "abi-7-15")]
-#[repr(C)]
-#[derive(Debug)]
-pub struct fuse_notify_retrieve_in {
-    // matches the size of fuse_write_in
-    pub dummy1: u64,
-    pub offset: u64,
-    pub size: u32,
-    pub dummy2: u32,
-    pub dummy3: u64,
-    pub dummy4: u64,
+abi_struct! {
+    #[cfg(feature = "abi-7-15")]
+    pub struct fuse_notify_retrieve_out {
+        pub notify_unique: u64,
+        pub nodeid: u64,
+        pub offset: u64,
+        pub size: u32,
+        pub padding: u32,
+    }
+}
+
+abi_struct! {
+    #[cfg(feature = "abi-7-15")]
+    pub struct fuse_notify_retrieve_in {
+        // matches the size of fuse_write_in
+        pub dummy1: u64,
+        pub offset: u64,
+        pub size: u32,
+        pub dummy2: u32,
+        pub dummy3: u64,
+        pub dummy4: u64,
+    }
 }
@@ -0,0 +1,21 @@
+//! Dispatch sequences of requests with arbitrary arguments to a `MemoryFS`. Every request must
+//! be replied (with an error if it's malformed) and the filesystem must not panic.
+
+#![no_main]
+
+use fuse::memory::MemoryFS;
+use fuse::testing::Driver;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut driver = Driver::new(MemoryFS::new(1 << 20));
+    driver.init().unwrap();
+    // Requests are separated by a 3 byte prefix of opcode, inode and argument length
+    let mut data = data;
+    while let [opcode, nodeid, len, rest @ ..] = data {
+        let (args, rest) = rest.split_at((*len as usize).min(rest.len()));
+        driver.send((*opcode).into(), (*nodeid).into(), &[args]);
+        driver.poll();
+        data = rest;
+    }
+});
@@ -0,0 +1,19 @@
+//! Parse requests of every opcode with arbitrary arguments. Unlike the `request` target, the
+//! header is always valid, so the fuzzer gets to every `Operation::parse` arm quickly.
+
+#![no_main]
+
+use fuse::ll::Request;
+use fuse::testing::packet;
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&opcode, args)) = data.split_first() else {
+        return;
+    };
+    let packet = packet(opcode.into(), 1, 1, &[args]);
+    if let Ok(request) = Request::try_from(&packet[..]) {
+        let _ = request.to_string();
+    }
+});
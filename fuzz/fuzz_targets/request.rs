@@ -0,0 +1,17 @@
+//! Parse arbitrary data as a request, like it's received from the kernel driver
+
+#![no_main]
+
+use fuse::ll::Request;
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    // The fuzzer's data is allocated like the session's receive buffers. Unaligned requests
+    // must be rejected without reading any arguments.
+    for data in [data, data.get(1..).unwrap_or_default()] {
+        if let Ok(request) = Request::try_from(data) {
+            let _ = request.to_string();
+        }
+    }
+});
@@ -0,0 +1,481 @@
+//! Typed operation arguments
+//!
+//! Arguments of filesystem operations that consist of several values or of flags are passed
+//! as the types in this module instead of long lists of raw integers.
+
+use fuse_abi::consts::*;
+#[cfg(feature = "abi-7-16")]
+use fuse_abi::fuse_forget_one;
+use fuse_abi::{FuseLkIn, FuseSetattrIn};
+use libc::c_int;
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Define a newtype for a set of bit flags
+macro_rules! flags {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident: $ty:ty {
+            $(
+                $(#[$fmeta:meta])*
+                const $flag:ident = $value:expr;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+        pub struct $name($ty);
+
+        impl $name {
+            $(
+                $(#[$fmeta])*
+                pub const $flag: $name = $name($value as $ty);
+            )*
+
+            /// Returns an empty set of flags
+            pub const fn empty() -> $name {
+                $name(0)
+            }
+
+            /// Create flags from the given bits (unknown bits are kept)
+            pub const fn from_bits_retain(bits: $ty) -> $name {
+                $name(bits)
+            }
+
+            /// Returns the raw bits
+            pub const fn bits(self) -> $ty {
+                self.0
+            }
+
+            /// Returns true if no flag is set
+            pub const fn is_empty(self) -> bool {
+                self.0 == 0
+            }
+
+            /// Returns true if all of the given flags are set
+            pub const fn contains(self, other: $name) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            /// Returns true if any of the given flags is set
+            pub const fn intersects(self, other: $name) -> bool {
+                self.0 & other.0 != 0
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = $name;
+
+            fn bitor(self, other: $name) -> $name {
+                $name(self.0 | other.0)
+            }
+        }
+
+        impl BitOrAssign for $name {
+            fn bitor_assign(&mut self, other: $name) {
+                self.0 |= other.0;
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = $name;
+
+            fn bitand(self, other: $name) -> $name {
+                $name(self.0 & other.0)
+            }
+        }
+
+        impl fmt::Debug for $name {
+            #[allow(unused_doc_comments, unused_mut)]
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mut rest = self.0;
+                let mut list = f.debug_tuple(stringify!($name));
+                $(
+                    $(#[$fmeta])*
+                    {
+                        let value = $value as $ty;
+                        if value != 0 && rest & value == value {
+                            list.field(&format_args!(stringify!($flag)));
+                            rest &= !value;
+                        }
+                    }
+                )*
+                if rest != 0 {
+                    list.field(&format_args!("{:#x}", rest));
+                }
+                list.finish()
+            }
+        }
+    };
+}
+
+flags! {
+    /// Attributes to set in a setattr operation (FATTR_*)
+    pub struct AttrValid: u32 {
+        /// Set file mode
+        const MODE = FATTR_MODE;
+        /// Set owner
+        const UID = FATTR_UID;
+        /// Set group
+        const GID = FATTR_GID;
+        /// Set size (truncate)
+        const SIZE = FATTR_SIZE;
+        /// Set access time
+        const ATIME = FATTR_ATIME;
+        /// Set modification time
+        const MTIME = FATTR_MTIME;
+        /// File handle is valid
+        const FH = FATTR_FH;
+        /// Set access time to the current time
+        #[cfg(feature = "abi-7-9")]
+        const ATIME_NOW = FATTR_ATIME_NOW;
+        /// Set modification time to the current time
+        #[cfg(feature = "abi-7-9")]
+        const MTIME_NOW = FATTR_MTIME_NOW;
+        /// Lock owner is valid
+        #[cfg(feature = "abi-7-9")]
+        const LOCKOWNER = FATTR_LOCKOWNER;
+        /// Set change time (sent by the kernel since ABI 7.23)
+        const CTIME = 1 << 10;
+        /// Clear the suid and sgid bits (sent by the kernel since ABI 7.33)
+        const KILL_SUIDGID = 1 << 11;
+        /// Set creation time (macOS only)
+        #[cfg(target_os = "macos")]
+        const CRTIME = FATTR_CRTIME;
+        /// Set change time (macOS only)
+        #[cfg(target_os = "macos")]
+        const CHGTIME = FATTR_CHGTIME;
+        /// Set backup time (macOS only)
+        #[cfg(target_os = "macos")]
+        const BKUPTIME = FATTR_BKUPTIME;
+        /// Set flags (macOS only, see chflags(2))
+        #[cfg(target_os = "macos")]
+        const FLAGS = FATTR_FLAGS;
+    }
+}
+
+flags! {
+    /// Flags of an opened file that are sent back to the kernel (FOPEN_*)
+    pub struct FopenFlags: u32 {
+        /// Bypass the page cache for this open file
+        const DIRECT_IO = FOPEN_DIRECT_IO;
+        /// Don't invalidate the data cache on open
+        const KEEP_CACHE = FOPEN_KEEP_CACHE;
+        /// The file is not seekable
+        #[cfg(feature = "abi-7-10")]
+        const NONSEEKABLE = FOPEN_NONSEEKABLE;
+        /// Purge attribute cache (macOS only)
+        #[cfg(target_os = "macos")]
+        const PURGE_ATTR = FOPEN_PURGE_ATTR;
+        /// Purge unified buffer cache (macOS only)
+        #[cfg(target_os = "macos")]
+        const PURGE_UBC = FOPEN_PURGE_UBC;
+    }
+}
+
+flags! {
+    /// Flags of a setxattr operation
+    pub struct XattrFlags: u32 {
+        /// Fail if the attribute already exists
+        const CREATE = libc::XATTR_CREATE;
+        /// Fail if the attribute doesn't exist yet
+        const REPLACE = libc::XATTR_REPLACE;
+    }
+}
+
+flags! {
+    /// Flags of a release operation (FUSE_RELEASE_*)
+    pub struct ReleaseFlags: u32 {
+        /// Flush the file before releasing it (remove the POSIX locks of the lock owner)
+        const FLUSH = FUSE_RELEASE_FLUSH;
+        /// Remove the BSD flock(2) locks of the lock owner (sent by the kernel since ABI 7.17)
+        const FLOCK_UNLOCK = 1 << 1;
+    }
+}
+
+flags! {
+    /// Flags a file is opened with (O_*, see open(2))
+    pub struct OpenFlags: u32 {
+        /// Open for writing only
+        const WRONLY = libc::O_WRONLY;
+        /// Open for reading and writing
+        const RDWR = libc::O_RDWR;
+        /// Append to the end of file on each write
+        const APPEND = libc::O_APPEND;
+        /// Create the file if it doesn't exist (create operations)
+        const CREAT = libc::O_CREAT;
+        /// Truncate the file to length 0
+        const TRUNC = libc::O_TRUNC;
+        /// Fail if the file already exists (create operations)
+        const EXCL = libc::O_EXCL;
+        /// Non-blocking mode
+        const NONBLOCK = libc::O_NONBLOCK;
+        /// Synchronous writes
+        const SYNC = libc::O_SYNC;
+        /// Don't update the access time
+        #[cfg(target_os = "linux")]
+        const NOATIME = libc::O_NOATIME;
+        /// Bypass the page cache
+        #[cfg(target_os = "linux")]
+        const DIRECT = libc::O_DIRECT;
+    }
+}
+
+impl OpenFlags {
+    /// Returns the access mode (O_RDONLY, O_WRONLY or O_RDWR)
+    pub fn access_mode(self) -> c_int {
+        self.0 as c_int & libc::O_ACCMODE
+    }
+
+    /// Returns true if the file is opened for reading
+    pub fn is_readable(self) -> bool {
+        self.access_mode() != libc::O_WRONLY
+    }
+
+    /// Returns true if the file is opened for writing
+    pub fn is_writable(self) -> bool {
+        self.access_mode() != libc::O_RDONLY
+    }
+}
+
+/// Type of a POSIX file lock
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct LockType(u32);
+
+impl LockType {
+    /// Shared (read) lock
+    pub const READ: LockType = LockType(libc::F_RDLCK as u32);
+    /// Exclusive (write) lock
+    pub const WRITE: LockType = LockType(libc::F_WRLCK as u32);
+    /// No lock (unlock or no conflicting lock)
+    pub const UNLOCK: LockType = LockType(libc::F_UNLCK as u32);
+
+    /// Create a lock type from the given raw value
+    pub const fn from_raw(typ: u32) -> LockType {
+        LockType(typ)
+    }
+
+    /// Returns the raw value
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for LockType {
+    fn default() -> LockType {
+        LockType::UNLOCK
+    }
+}
+
+/// Attributes to change in a setattr operation. Only attributes that are given should be
+/// changed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SetAttr {
+    /// Flags of the attributes that are given (includes flags that don't carry a value)
+    pub valid: AttrValid,
+    /// File mode (type and permissions)
+    pub mode: Option<u32>,
+    /// Owner
+    pub uid: Option<u32>,
+    /// Group
+    pub gid: Option<u32>,
+    /// Size (truncate or extend)
+    pub size: Option<u64>,
+    /// Time of last access
+    pub atime: Option<SystemTime>,
+    /// Time of last modification
+    pub mtime: Option<SystemTime>,
+    /// Time of last change
+    pub ctime: Option<SystemTime>,
+    /// File handle if the file is open (e.g. ftruncate)
+    pub fh: Option<u64>,
+    /// Lock owner of the process that changes the attributes
+    pub lock_owner: Option<u64>,
+    /// Time of creation (macOS only)
+    pub crtime: Option<SystemTime>,
+    /// Time of last change (macOS only)
+    pub chgtime: Option<SystemTime>,
+    /// Time of last backup (macOS only)
+    pub bkuptime: Option<SystemTime>,
+    /// Flags (macOS only, see chflags(2))
+    pub flags: Option<u32>,
+}
+
+impl SetAttr {
+    /// Returns true if the suid and sgid bits should be cleared
+    pub fn kill_suidgid(&self) -> bool {
+        self.valid.contains(AttrValid::KILL_SUIDGID)
+    }
+}
+
+/// Returns the given value if the given flag is set
+fn valid<T>(valid: AttrValid, flag: AttrValid, value: T) -> Option<T> {
+    match valid.contains(flag) {
+        true => Some(value),
+        false => None,
+    }
+}
+
+/// Returns the time of the given seconds and nanoseconds since the epoch. Times that can't be
+/// represented (only sent by a misbehaving kernel) are clamped to the epoch.
+fn system_time(secs: u64, nsecs: u32) -> SystemTime {
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(secs))
+        .and_then(|time| time.checked_add(Duration::from_nanos(nsecs.into())))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+impl From<&FuseSetattrIn> for SetAttr {
+    fn from(arg: &FuseSetattrIn) -> SetAttr {
+        let flags = AttrValid::from_bits_retain(arg.valid);
+        #[cfg(feature = "abi-7-9")]
+        let lock_owner = valid(flags, AttrValid::LOCKOWNER, arg.lock_owner);
+        #[cfg(not(feature = "abi-7-9"))]
+        let lock_owner = None;
+        SetAttr {
+            valid: flags,
+            mode: valid(flags, AttrValid::MODE, arg.mode),
+            uid: valid(flags, AttrValid::UID, arg.uid),
+            gid: valid(flags, AttrValid::GID, arg.gid),
+            size: valid(flags, AttrValid::SIZE, arg.size),
+            atime: valid(flags, AttrValid::ATIME, ())
+                .map(|()| system_time(arg.atime, arg.atimensec)),
+            mtime: valid(flags, AttrValid::MTIME, ())
+                .map(|()| system_time(arg.mtime, arg.mtimensec)),
+            // The ctime is passed in fields that were unused before ABI 7.23
+            ctime: valid(flags, AttrValid::CTIME, ())
+                .map(|()| system_time(arg.unused2, arg.unused3)),
+            fh: valid(flags, AttrValid::FH, arg.fh),
+            lock_owner,
+            #[cfg(target_os = "macos")]
+            crtime: valid(flags, AttrValid::CRTIME, ())
+                .map(|()| system_time(arg.crtime, arg.crtimensec)),
+            #[cfg(target_os = "macos")]
+            chgtime: valid(flags, AttrValid::CHGTIME, ())
+                .map(|()| system_time(arg.chgtime, arg.chgtimensec)),
+            #[cfg(target_os = "macos")]
+            bkuptime: valid(flags, AttrValid::BKUPTIME, ())
+                .map(|()| system_time(arg.bkuptime, arg.bkuptimensec)),
+            #[cfg(target_os = "macos")]
+            flags: valid(flags, AttrValid::FLAGS, arg.flags),
+            #[cfg(not(target_os = "macos"))]
+            crtime: None,
+            #[cfg(not(target_os = "macos"))]
+            chgtime: None,
+            #[cfg(not(target_os = "macos"))]
+            bkuptime: None,
+            #[cfg(not(target_os = "macos"))]
+            flags: None,
+        }
+    }
+}
+
+/// A POSIX file lock (or BSD flock) request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LockRequest {
+    /// File handle of the open file
+    pub fh: u64,
+    /// Owner of the lock
+    pub lock_owner: u64,
+    /// Start of the range to lock
+    pub start: u64,
+    /// End of the range to lock (inclusive)
+    pub end: u64,
+    /// Type of lock
+    pub typ: LockType,
+    /// Pid of the process that requests the lock
+    pub pid: u32,
+    /// True if this is a BSD flock(2) lock instead of a POSIX lock
+    pub flock: bool,
+}
+
+impl From<&FuseLkIn> for LockRequest {
+    fn from(arg: &FuseLkIn) -> LockRequest {
+        #[cfg(feature = "abi-7-9")]
+        let flock = arg.lk_flags & FUSE_LK_FLOCK != 0;
+        #[cfg(not(feature = "abi-7-9"))]
+        let flock = false;
+        LockRequest {
+            fh: arg.fh,
+            lock_owner: arg.owner,
+            start: arg.lk.start,
+            end: arg.lk.end,
+            typ: LockType::from_raw(arg.lk.typ),
+            pid: arg.lk.pid,
+            flock,
+        }
+    }
+}
+
+/// Number of lookups of an inode to forget (entry of a batch forget operation)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Forget {
+    /// Inode number
+    pub ino: u64,
+    /// Number of lookups to forget
+    pub nlookup: u64,
+}
+
+#[cfg(feature = "abi-7-16")]
+impl From<&fuse_forget_one> for Forget {
+    fn from(arg: &fuse_forget_one) -> Forget {
+        Forget {
+            ino: arg.nodeid,
+            nlookup: arg.nlookup,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AttrValid, LockType, OpenFlags, SetAttr};
+    use fuse_abi::{FromBytes, FuseSetattrIn};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn flags() {
+        let flags = AttrValid::MODE | AttrValid::SIZE;
+        assert!(flags.contains(AttrValid::MODE));
+        assert!(!flags.contains(AttrValid::MODE | AttrValid::UID));
+        assert!(flags.intersects(AttrValid::MODE | AttrValid::UID));
+        assert_eq!(flags.bits(), 0b1001);
+        assert_eq!(
+            format!("{:?}", AttrValid::from_bits_retain(0x4001)),
+            "AttrValid(MODE, 0x4000)"
+        );
+        assert!(AttrValid::empty().is_empty());
+    }
+
+    #[test]
+    fn open_flags() {
+        let flags = OpenFlags::from_bits_retain((libc::O_RDWR | libc::O_TRUNC) as u32);
+        assert_eq!(flags.access_mode(), libc::O_RDWR);
+        assert!(flags.is_readable() && flags.is_writable());
+        assert!(flags.contains(OpenFlags::TRUNC));
+        let flags = OpenFlags::from_bits_retain(libc::O_RDONLY as u32);
+        assert!(flags.is_readable() && !flags.is_writable());
+        assert_eq!(LockType::from_raw(libc::F_WRLCK as u32), LockType::WRITE);
+    }
+
+    #[test]
+    fn setattr() {
+        let mut arg = FuseSetattrIn::new_zeroed();
+        arg.valid = (AttrValid::MODE | AttrValid::MTIME | AttrValid::CTIME).bits();
+        arg.mode = 0o100644;
+        arg.uid = 1000;
+        arg.mtime = 10;
+        arg.mtimensec = 20;
+        arg.unused2 = 30;
+        let attr = SetAttr::from(&arg);
+        assert_eq!(attr.mode, Some(0o100644));
+        assert_eq!(attr.uid, None);
+        assert_eq!(attr.mtime, Some(UNIX_EPOCH + Duration::new(10, 20)));
+        assert_eq!(attr.ctime, Some(UNIX_EPOCH + Duration::from_secs(30)));
+        assert!(!attr.kill_suidgid());
+        // Times out of range don't panic
+        arg.mtime = u64::MAX;
+        arg.mtimensec = u32::MAX;
+        assert_eq!(SetAttr::from(&arg).mtime, Some(UNIX_EPOCH));
+    }
+}
@@ -0,0 +1,54 @@
+//! Pretty-print a trace of FUSE requests and replies recorded with `fuse::trace::Recorder`
+
+use fuse::trace::{FrameKind, TraceReader};
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{env, process};
+
+fn main() {
+    let path = match env::args_os().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: fuse-trace <trace file>");
+            process::exit(2);
+        }
+    };
+    let reader = match TraceReader::open(&path) {
+        Ok(reader) => reader,
+        Err(err) => {
+            eprintln!("Failed to open trace: {}", err);
+            process::exit(1);
+        }
+    };
+    // Times are shown relative to the first frame. Requests are kept until they're replied,
+    // since reply data can only be decoded with the request.
+    let mut start = None;
+    let mut requests = HashMap::new();
+    for frame in reader {
+        match frame {
+            Ok(frame) => {
+                let start = *start.get_or_insert(frame.time);
+                let time = frame.time.duration_since(start).unwrap_or(Duration::ZERO);
+                let request = match frame.kind {
+                    FrameKind::Request => None,
+                    FrameKind::Reply => frame.unique().and_then(|unique| requests.remove(&unique)),
+                };
+                match request {
+                    Some(request) => println!(
+                        "{:>12.6} {}",
+                        time.as_secs_f64(),
+                        frame.describe_reply(&request)
+                    ),
+                    None => println!("{:>12.6} {}", time.as_secs_f64(), frame),
+                }
+                if let (FrameKind::Request, Some(unique)) = (frame.kind, frame.unique()) {
+                    requests.insert(unique, frame);
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to read trace: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
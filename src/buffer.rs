@@ -0,0 +1,344 @@
+//! Request buffers
+//!
+//! Buffers for receiving requests from the kernel driver are taken from a pool and go back to it
+//! when they're not used anymore, so that they can be reused without allocating. Received buffers
+//! are shared for dispatching, reusing the allocations of previously shared buffers as well.
+//! Buffers are sized to fit the largest request the kernel may send, which depends on the max
+//! write size reported at init. The data of write requests is handed to the filesystem as
+//! `WriteData`, which keeps its buffer alive and allows keeping the data without copying it.
+//! If requests are received with splice, the data of write requests is left in a pipe instead
+//! of the buffer, and can be spliced to a file without copying it through userspace at all.
+
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::{fmt, io, mem};
+
+#[cfg(target_os = "linux")]
+use crate::splice::SplicedData;
+
+/// Max number of unused buffers kept by a pool
+const MAX_FREE_BUFFERS: usize = 16;
+
+/// Shared state of a buffer pool
+#[derive(Debug)]
+struct Pool {
+    /// Size of buffers handed out by the pool
+    size: AtomicUsize,
+    /// Unused buffers
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Unused allocations for sharing buffers (each holding an empty buffer)
+    shared: Mutex<Vec<Arc<Buffer>>>,
+}
+
+impl Pool {
+    /// Put the given data of a buffer back into the pool, unless there are enough unused
+    /// buffers already
+    fn put(&self, mut data: Vec<u8>) {
+        // Buffers of a previous size are released
+        if data.capacity() == self.size.load(Ordering::Relaxed) {
+            let mut free = self.free.lock().unwrap();
+            if free.len() < MAX_FREE_BUFFERS {
+                data.clear();
+                free.push(data);
+            }
+        }
+    }
+}
+
+/// A pool of recycled buffers for receiving requests
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    pool: Arc<Pool>,
+}
+
+impl BufferPool {
+    /// Create a new pool of buffers with the given size
+    pub fn new(size: usize) -> BufferPool {
+        BufferPool {
+            pool: Arc::new(Pool {
+                size: AtomicUsize::new(size),
+                free: Mutex::new(Vec::new()),
+                shared: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Returns the size of buffers handed out by the pool
+    pub fn size(&self) -> usize {
+        self.pool.size.load(Ordering::Relaxed)
+    }
+
+    /// Change the size of buffers handed out by the pool. Unused buffers of the previous
+    /// size are released.
+    pub fn set_size(&self, size: usize) {
+        self.pool.size.store(size, Ordering::Relaxed);
+        self.pool.free.lock().unwrap().clear();
+    }
+
+    /// Take an empty buffer from the pool (or allocate a new one if there's no unused buffer)
+    pub fn get(&self) -> Buffer {
+        let size = self.size();
+        let data = self.pool.free.lock().unwrap().pop();
+        Buffer {
+            data: data.unwrap_or_else(|| Vec::with_capacity(size)),
+            pool: Arc::downgrade(&self.pool),
+            #[cfg(target_os = "linux")]
+            spliced: None,
+        }
+    }
+
+    /// Share a buffer (e.g. for dispatching the request received in it). Reuses the
+    /// allocation of a previously shared buffer that was recycled, if there is one.
+    pub fn share(&self, buffer: Buffer) -> Arc<Buffer> {
+        let shared = self.pool.shared.lock().unwrap().pop();
+        match shared {
+            Some(mut shared) => {
+                *Arc::get_mut(&mut shared).unwrap() = buffer;
+                shared
+            }
+            None => Arc::new(buffer),
+        }
+    }
+
+    /// Recycle a shared buffer when done with it. If it's not used anymore (e.g. by write data
+    /// kept by the filesystem), the buffer goes back to the pool and its allocation is kept
+    /// for sharing another buffer. Otherwise it goes back when the last user drops it.
+    pub fn recycle(&self, mut shared: Arc<Buffer>) {
+        if let Some(buffer) = Arc::get_mut(&mut shared) {
+            self.pool.put(mem::take(&mut buffer.data));
+            #[cfg(target_os = "linux")]
+            {
+                buffer.spliced = None;
+            }
+            let mut unused = self.pool.shared.lock().unwrap();
+            if unused.len() < MAX_FREE_BUFFERS {
+                unused.push(shared);
+            }
+        }
+    }
+}
+
+/// A buffer taken from a pool. Goes back to the pool when dropped.
+#[derive(Debug)]
+pub struct Buffer {
+    data: Vec<u8>,
+    pool: Weak<Pool>,
+    /// Data of the request that was left in a pipe instead of the buffer
+    #[cfg(target_os = "linux")]
+    spliced: Option<Arc<SplicedData>>,
+}
+
+impl Buffer {
+    /// Returns the data of the request that was left in a pipe (see `Channel::receive_spliced`)
+    #[cfg(target_os = "linux")]
+    pub fn spliced(&self) -> Option<&Arc<SplicedData>> {
+        self.spliced.as_ref()
+    }
+
+    /// Set the data of the request that was left in a pipe
+    #[cfg(target_os = "linux")]
+    pub fn set_spliced(&mut self, spliced: Option<SplicedData>) {
+        self.spliced = spliced.map(Arc::new);
+    }
+
+    /// Returns the size of the data of the request that was left in a pipe
+    pub fn spliced_len(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        if let Some(spliced) = &self.spliced {
+            return spliced.len();
+        }
+        0
+    }
+}
+
+impl Deref for Buffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.data
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            pool.put(mem::take(&mut self.data));
+        }
+    }
+}
+
+/// Data of a write request. Derefs to the written bytes and can be kept by the filesystem
+/// after the write operation returned without copying the data. The request buffer it refers
+/// to is reused for further requests only after the data was dropped. If the data was left in
+/// a pipe (see `Session::set_splice`), it's read from the pipe when the bytes are accessed for
+/// the first time, unless it's only written to files with `write_to`.
+#[derive(Clone)]
+pub struct WriteData {
+    data: Data,
+}
+
+/// Location of write data
+#[derive(Clone)]
+enum Data {
+    /// Part of a request buffer
+    Buffer {
+        buffer: Arc<Buffer>,
+        offset: usize,
+        len: usize,
+    },
+    /// Left in a pipe
+    #[cfg(target_os = "linux")]
+    Spliced(Arc<SplicedData>),
+}
+
+impl WriteData {
+    /// Create write data for the given slice, which must be part of the given buffer
+    pub(crate) fn new(buffer: &Arc<Buffer>, data: &[u8]) -> WriteData {
+        let offset = (data.as_ptr() as usize).wrapping_sub(buffer.as_ptr() as usize);
+        assert!(offset <= buffer.len() && data.len() <= buffer.len() - offset);
+        WriteData {
+            data: Data::Buffer {
+                buffer: Arc::clone(buffer),
+                offset,
+                len: data.len(),
+            },
+        }
+    }
+
+    /// Create write data for data that was left in a pipe
+    #[cfg(target_os = "linux")]
+    pub(crate) fn spliced(spliced: &Arc<SplicedData>) -> WriteData {
+        WriteData {
+            data: Data::Spliced(Arc::clone(spliced)),
+        }
+    }
+
+    /// Returns the size of the data (without reading data that was left in a pipe)
+    pub fn len(&self) -> usize {
+        match &self.data {
+            Data::Buffer { len, .. } => *len,
+            #[cfg(target_os = "linux")]
+            Data::Spliced(spliced) => spliced.len(),
+        }
+    }
+
+    /// Returns true if there's no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the data to the given file at the given offset. Data that was left in a pipe is
+    /// spliced to the file without copying it through userspace if possible.
+    pub fn write_to(&self, file: BorrowedFd<'_>, offset: u64) -> io::Result<()> {
+        match &self.data {
+            Data::Buffer { .. } => write_all_at(file, self, offset),
+            #[cfg(target_os = "linux")]
+            Data::Spliced(spliced) => spliced.write_to(file, offset),
+        }
+    }
+}
+
+impl Deref for WriteData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            Data::Buffer {
+                buffer,
+                offset,
+                len,
+            } => &buffer[*offset..*offset + *len],
+            #[cfg(target_os = "linux")]
+            Data::Spliced(spliced) => spliced.bytes(),
+        }
+    }
+}
+
+impl AsRef<[u8]> for WriteData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for WriteData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WriteData({} bytes)", self.len())
+    }
+}
+
+/// Write all given data to the given file at the given offset
+pub(crate) fn write_all_at(file: BorrowedFd<'_>, data: &[u8], offset: u64) -> io::Result<()> {
+    // The file is borrowed, so it must not be closed
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(file.as_raw_fd()) });
+    file.write_all_at(data, offset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BufferPool, WriteData};
+    use std::sync::Arc;
+
+    #[test]
+    fn recycle_buffers() {
+        let pool = BufferPool::new(64);
+        let mut buffer = pool.get();
+        assert_eq!(buffer.capacity(), 64);
+        buffer.extend_from_slice(b"foo");
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+        // The same buffer is handed out again (empty)
+        let buffer = pool.get();
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert!(buffer.is_empty());
+        // Buffers of a previous size aren't reused
+        pool.set_size(128);
+        drop(buffer);
+        assert_eq!(pool.get().capacity(), 128);
+    }
+
+    #[test]
+    fn share_buffers() {
+        let pool = BufferPool::new(64);
+        let buffer = pool.share(pool.get());
+        let ptr = Arc::as_ptr(&buffer);
+        let data = buffer.as_ptr();
+        pool.recycle(buffer);
+        // The allocation of the shared buffer and the buffer itself are reused
+        let buffer = pool.share(pool.get());
+        assert_eq!(Arc::as_ptr(&buffer), ptr);
+        assert_eq!(buffer.as_ptr(), data);
+        // Buffers that are still in use aren't recycled
+        let write = WriteData::new(&buffer, &buffer[..]);
+        pool.recycle(buffer);
+        assert_ne!(Arc::as_ptr(&pool.share(pool.get())), ptr);
+        drop(write);
+        assert_eq!(pool.get().as_ptr(), data);
+    }
+
+    #[test]
+    fn write_data() {
+        let pool = BufferPool::new(64);
+        let mut buffer = pool.get();
+        buffer.extend_from_slice(b"headerdata");
+        let ptr = buffer.as_ptr();
+        let buffer = Arc::new(buffer);
+        let data = WriteData::new(&buffer, &buffer[6..]);
+        drop(buffer);
+        // The buffer is in use as long as the write data is alive
+        assert_ne!(pool.get().as_ptr(), ptr);
+        assert_eq!(&*data, b"data");
+        drop(data);
+        assert_eq!(pool.get().as_ptr(), ptr);
+    }
+}
@@ -4,13 +4,22 @@
 
 use fuse_sys::{fuse_mount_compat25, FuseArgs};
 use libc::{self, c_int, c_void, size_t};
-use log::{debug, error};
+use log::{debug, error, warn};
 use std::ffi::{CStr, CString, OsStr};
-use std::io;
 use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::BorrowedFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::{io, ptr};
 
+use crate::buffer::Buffer;
+#[cfg(target_os = "linux")]
+use crate::ll;
 use crate::reply::ReplySender;
+#[cfg(target_os = "linux")]
+use crate::splice::{self, Pipe, SplicedData};
+use crate::trace::Recorder;
 
 /// Helper function to provide options as a fuse_args struct
 /// (which contains an argc count and an argv pointer)
@@ -25,11 +34,89 @@ fn with_fuse_args<T, F: FnOnce(&FuseArgs) -> T>(options: &[&OsStr], f: F) -> T {
     })
 }
 
+/// Returns the file descriptor number if the given mountpoint uses the `/dev/fd/N` convention
+/// (introduced by libfuse 3.3) to refer to an already opened FUSE device.
+pub fn parse_dev_fd(mountpoint: &Path) -> Option<RawFd> {
+    let fd = mountpoint.to_str()?.strip_prefix("/dev/fd/")?;
+    if fd.is_empty() || !fd.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    fd.parse().ok()
+}
+
+/// Direction bits of ioctls that read data, shifted into place. Most architectures use the
+/// generic ioctl encoding (asm-generic/ioctl.h), but mips, powerpc and sparc have 3 direction
+/// bits at a lower position.
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )
+))]
+const IOC_READ: libc::c_ulong = 2 << 29;
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))
+))]
+const IOC_READ: libc::c_ulong = 2 << 30;
+
+/// Ioctl to clone a FUSE device file descriptor, _IOR(229, 0, uint32_t) (Linux 4.2 and later).
+/// A cloned file descriptor is attached to the same FUSE connection, but has its own queue of
+/// requests that are being processed, so replies must be sent on the fd a request was read from.
+#[cfg(target_os = "linux")]
+const FUSE_DEV_IOC_CLONE: libc::c_ulong =
+    IOC_READ | (std::mem::size_of::<u32>() as libc::c_ulong) << 16 | 229 << 8;
+
+/// Open a new FUSE device file descriptor that is attached to the same connection as the given one
+#[cfg(target_os = "linux")]
+fn clone_fd(fd: c_int) -> io::Result<c_int> {
+    let dev = CString::new("/dev/fuse")?;
+    let clonefd = unsafe { libc::open(dev.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+    if clonefd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut masterfd = fd as u32;
+    if unsafe { libc::ioctl(clonefd, FUSE_DEV_IOC_CLONE as _, &mut masterfd) } < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(clonefd) };
+        return Err(err);
+    }
+    Ok(clonefd)
+}
+
+/// Cloning FUSE device file descriptors is only supported on Linux
+#[cfg(not(target_os = "linux"))]
+fn clone_fd(_fd: c_int) -> io::Result<c_int> {
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
 /// A raw communication channel to the FUSE kernel driver
 #[derive(Debug)]
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
+    /// True if the channel mounted the filesystem itself (and needs to unmount it when dropped)
+    mounted: bool,
+    /// True if requests may be received and reply data may be sent with splice
+    #[cfg(target_os = "linux")]
+    splice: bool,
+    /// Pipe for receiving requests with splice (created when needed)
+    #[cfg(target_os = "linux")]
+    pipe: Option<Pipe>,
+    /// Recorder for requests and replies (if enabled)
+    recorder: Option<Recorder>,
 }
 
 impl Channel {
@@ -45,18 +132,126 @@ impl Channel {
             if fd < 0 {
                 Err(io::Error::last_os_error())
             } else {
-                Ok(Channel { mountpoint, fd })
+                Ok(Channel {
+                    mountpoint,
+                    fd,
+                    mounted: true,
+                    #[cfg(target_os = "linux")]
+                    splice: false,
+                    #[cfg(target_os = "linux")]
+                    pipe: None,
+                    recorder: None,
+                })
             }
         })
     }
 
+    /// Create a new communication channel from a FUSE device file descriptor that is referred
+    /// to by a mountpoint of the form `/dev/fd/N`. This is how privileged helpers pass an
+    /// already mounted FUSE device to an unprivileged process.
+    pub fn from_dev_fd(fd: RawFd) -> io::Result<Channel> {
+        // Make sure we got passed a valid file descriptor before taking ownership of it
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { Channel::from_raw_fd(fd) })
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint(&self) -> &Path {
         &self.mountpoint
     }
 
+    /// Create another channel to the same mounted filesystem. On Linux, the new channel gets
+    /// its own file descriptor with its own request queue (FUSE_DEV_IOC_CLONE), so that multiple
+    /// threads don't contend on a single queue. If cloning is not possible (other platforms, old
+    /// kernels or no access to /dev/fuse), the file descriptor is duplicated instead, which
+    /// shares the queue of this channel. The new channel never unmounts the filesystem.
+    pub fn try_clone(&self) -> io::Result<Channel> {
+        let fd = match clone_fd(self.fd) {
+            Ok(fd) => fd,
+            Err(err) => {
+                warn!(
+                    "Failed to clone FUSE device, sharing request queue: {}",
+                    err
+                );
+                let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                fd
+            }
+        };
+        Ok(Channel {
+            mountpoint: self.mountpoint.clone(),
+            fd,
+            mounted: false,
+            #[cfg(target_os = "linux")]
+            splice: self.splice(),
+            #[cfg(target_os = "linux")]
+            pipe: None,
+            recorder: self.recorder.clone(),
+        })
+    }
+
+    /// Enable or disable receiving write data (see `receive_spliced`) and sending reply data
+    /// from files with splice(2).
+    #[cfg(target_os = "linux")]
+    pub fn set_splice(&mut self, splice: bool) {
+        self.splice = splice;
+        self.pipe = None;
+    }
+
+    /// Returns true if splice is enabled for this channel
+    #[cfg(target_os = "linux")]
+    pub fn splice(&self) -> bool {
+        self.splice
+    }
+
+    /// Set a recorder that records all requests received and replies sent on this channel (and
+    /// on channels cloned from it afterwards), or disable recording.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Returns true if the mount point was mounted by this channel and is still mounted
+    pub fn is_mounted(&self) -> bool {
+        self.mounted
+    }
+
+    /// Unmount the mount point if it was mounted by this channel. If the filesystem is busy,
+    /// it's unmounted lazily (see `lazy_unmount`). Returns true if a lazy unmount was needed.
+    pub fn unmount(&mut self) -> io::Result<bool> {
+        if !self.mounted {
+            return Ok(false);
+        }
+        debug!("umount {}", self.mountpoint.display());
+        let lazy = match unmount(&self.mountpoint) {
+            Ok(()) => false,
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                warn!("{} is busy, unmounting lazily", self.mountpoint.display());
+                lazy_unmount(&self.mountpoint)?;
+                true
+            }
+            Err(err) => return Err(err),
+        };
+        self.mounted = false;
+        Ok(lazy)
+    }
+
     /// Receives data up to the capacity of the given buffer without blocking.
-    pub async fn async_receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    pub async fn async_receive(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        self.nonblocking(|ch| ch.receive(buffer))
+    }
+
+    /// Receives a request without blocking, leaving the data of a write request in a pipe
+    /// (see `receive_spliced`).
+    pub(crate) async fn async_receive_spliced(&mut self, buffer: &mut Buffer) -> io::Result<()> {
+        self.nonblocking(|ch| ch.receive_spliced(buffer))
+    }
+
+    /// Run the given receive operation with the file descriptor in non-blocking mode
+    fn nonblocking<T>(&mut self, f: impl FnOnce(&mut Channel) -> io::Result<T>) -> io::Result<T> {
         // Set the file descriptor to non-blocking mode
         let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
         if flags < 0 {
@@ -68,28 +263,81 @@ impl Channel {
         }
 
         // Perform the read operation
-        let rc = unsafe {
-            libc::read(
-                self.fd,
-                buffer.as_ptr() as *mut c_void,
-                buffer.capacity() as size_t,
-            )
-        };
+        let res = f(self);
 
         // Restore the original flags
         unsafe { libc::fcntl(self.fd, libc::F_SETFL, flags) };
 
-        if rc < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            unsafe {
-                buffer.set_len(rc as usize);
+        res
+    }
+
+    /// Receives data up to the capacity of the given buffer (can block).
+    pub fn receive(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        self.read(buffer)?;
+        if let Some(recorder) = &self.recorder {
+            recorder.request(buffer);
+        }
+        Ok(())
+    }
+
+    /// Receives a request like `receive`, but if splice is enabled, the data of a write request
+    /// is left in the pipe it was received in instead of being read into the buffer (see
+    /// `Buffer::spliced`). This allows the filesystem to splice it to a file without copying
+    /// it through userspace. Requests are read normally while they're recorded, since the
+    /// recorder needs them completely.
+    pub(crate) fn receive_spliced(&mut self, buffer: &mut Buffer) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.splice && self.recorder.is_none() {
+            let mut pipe = match self.pipe.take() {
+                Some(pipe) => pipe,
+                None => Pipe::new()?,
+            };
+            // Splice only works if the pipe can take a whole request
+            if pipe.reserve(buffer.capacity()) {
+                match self.splice_request(pipe, buffer) {
+                    Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                        warn!("Failed to splice FUSE request, disabling splice: {}", err);
+                        self.splice = false;
+                    }
+                    res => return res,
+                }
             }
-            Ok(())
         }
+        self.receive(buffer)
+    }
+
+    /// Receive a request through the given pipe. The data of a write request is left in the
+    /// pipe, otherwise the pipe is kept for receiving further requests.
+    #[cfg(target_os = "linux")]
+    fn splice_request(&mut self, pipe: Pipe, buffer: &mut Buffer) -> io::Result<()> {
+        buffer.clear();
+        let len = match pipe.splice_from(self.fd, None, buffer.capacity()) {
+            Ok(0) => return Err(io::Error::from_raw_os_error(libc::ENODEV)),
+            Ok(len) => len,
+            Err(err) => {
+                self.pipe = Some(pipe);
+                return Err(err);
+            }
+        };
+        // Read the header and the arguments of write requests, then decide what to leave
+        let header_len = std::mem::size_of::<fuse_abi::FuseInHeader>();
+        let write_len = header_len + std::mem::size_of::<fuse_abi::FuseWriteIn>();
+        pipe.read_exact(buffer, header_len.min(len))?;
+        let write = ll::peek_header(buffer)
+            .is_some_and(|header| header.opcode == fuse_abi::fuse_opcode::FUSE_WRITE as u32);
+        if write && len > write_len {
+            pipe.read_exact(buffer, write_len - header_len)?;
+            buffer.set_spliced(Some(SplicedData::new(pipe, len - write_len)));
+        } else {
+            let rest = len - buffer.len();
+            pipe.read_exact(buffer, rest)?;
+            self.pipe = Some(pipe);
+        }
+        Ok(())
     }
 
-    pub fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+    /// Reads a request into the given buffer
+    fn read(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
         let rc = unsafe {
             libc::read(
                 self.fd,
@@ -97,13 +345,17 @@ impl Channel {
                 buffer.capacity() as size_t,
             )
         };
-        if rc < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            unsafe {
-                buffer.set_len(rc as usize);
+        match rc {
+            rc if rc < 0 => Err(io::Error::last_os_error()),
+            // The kernel driver never sends empty requests, end of file means that the device
+            // was closed (like the other end of a socket used in its place)
+            0 => Err(io::Error::from_raw_os_error(libc::ENODEV)),
+            rc => {
+                unsafe {
+                    buffer.set_len(rc as usize);
+                }
+                Ok(())
             }
-            Ok(())
         }
     }
 
@@ -115,7 +367,36 @@ impl Channel {
         // a sender by using the same fd and use it in other threads. Only
         // the channel closes the fd when dropped. If any sender is used after
         // dropping the channel, it'll return an EBADF error.
-        ChannelSender { fd: self.fd }
+        ChannelSender {
+            fd: self.fd,
+            #[cfg(target_os = "linux")]
+            splice: self.splice(),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+impl FromRawFd for Channel {
+    /// Adopt an already opened (and mounted) FUSE device file descriptor. The channel takes
+    /// ownership of the file descriptor and closes it when dropped, but never mounts or
+    /// unmounts anything since the filesystem is mounted by whoever opened the descriptor.
+    unsafe fn from_raw_fd(fd: RawFd) -> Channel {
+        Channel {
+            mountpoint: PathBuf::from(format!("/dev/fd/{}", fd)),
+            fd,
+            mounted: false,
+            #[cfg(target_os = "linux")]
+            splice: false,
+            #[cfg(target_os = "linux")]
+            pipe: None,
+            recorder: None,
+        }
+    }
+}
+
+impl AsRawFd for Channel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
     }
 }
 
@@ -124,31 +405,51 @@ impl Drop for Channel {
         // TODO: send ioctl FUSEDEVIOCSETDAEMONDEAD on macOS before closing the fd
         // Close the communication channel to the kernel driver
         // (closing it before unnmount prevents sync unmount deadlock)
-        debug!("umount {}", self.mountpoint.display());
         unsafe {
             libc::close(self.fd);
         }
-        // Unmount this channel's mount point
-        let _ = unmount(&self.mountpoint);
+        // Unmount this channel's mount point if we mounted it
+        if self.mounted {
+            debug!("umount {}", self.mountpoint.display());
+            let _ = unmount(&self.mountpoint);
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Max number of slices a reply can be sent in (replies consist of a header and up to two
+/// parts of data)
+const MAX_IOVECS: usize = 4;
+
+#[derive(Clone, Debug)]
 pub struct ChannelSender {
     fd: c_int,
+    /// True if reply data may be spliced from files
+    #[cfg(target_os = "linux")]
+    splice: bool,
+    /// Recorder for sent replies (if enabled)
+    recorder: Option<Recorder>,
 }
 
 impl ChannelSender {
-    /// Send all data in the slice of slice of bytes in a single write (can block).
+    /// Send all data in the slice of slice of bytes in a single write (can block). The data
+    /// may consist of up to `MAX_IOVECS` slices.
     pub fn send(&self, buffer: &[&[u8]]) -> io::Result<()> {
-        let iovecs: Vec<_> = buffer
-            .iter()
-            .map(|d| libc::iovec {
-                iov_base: d.as_ptr() as *mut c_void,
-                iov_len: d.len() as size_t,
-            })
-            .collect();
-        let rc = unsafe { libc::writev(self.fd, iovecs.as_ptr(), iovecs.len() as c_int) };
+        if buffer.len() > MAX_IOVECS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many slices for a single write",
+            ));
+        }
+        // The iovecs are kept on the stack to not allocate for every reply
+        let mut iovecs = [libc::iovec {
+            iov_base: ptr::null_mut(),
+            iov_len: 0,
+        }; MAX_IOVECS];
+        for (iovec, d) in iovecs.iter_mut().zip(buffer) {
+            iovec.iov_base = d.as_ptr() as *mut c_void;
+            iovec.iov_len = d.len() as size_t;
+        }
+        let rc = unsafe { libc::writev(self.fd, iovecs.as_ptr(), buffer.len() as c_int) };
         if rc < 0 {
             Err(io::Error::last_os_error())
         } else {
@@ -159,14 +460,43 @@ impl ChannelSender {
 
 impl ReplySender for ChannelSender {
     fn send(&self, data: &[&[u8]]) {
+        if let Some(recorder) = &self.recorder {
+            recorder.reply(data);
+        }
         if let Err(err) = ChannelSender::send(self, data) {
             error!("Failed to send FUSE reply: {}", err);
         }
     }
+
+    #[cfg(target_os = "linux")]
+    fn send_fd(
+        &self,
+        data: &[&[u8]],
+        fd: BorrowedFd<'_>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<()> {
+        // Spliced data never passes through userspace, so it can't be recorded
+        if !self.splice || self.recorder.is_some() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        splice::send(self.fd, data, fd, offset, len)
+    }
 }
 
 /// Unmount an arbitrary mount point
 pub fn unmount(mountpoint: &Path) -> io::Result<()> {
+    unmount_with(mountpoint, false)
+}
+
+/// Lazily unmount an arbitrary mount point. The mount point is detached immediately and cleaned
+/// up as soon as it's not busy anymore. Systems that don't support lazy unmounts unmount
+/// forcibly instead.
+pub fn lazy_unmount(mountpoint: &Path) -> io::Result<()> {
+    unmount_with(mountpoint, true)
+}
+
+fn unmount_with(mountpoint: &Path, lazy: bool) -> io::Result<()> {
     // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
     // it attempts to call realpath, which in turn calls into the filesystem. So
     // if the filesystem returns an error, the unmount does not take place, with
@@ -182,8 +512,9 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
         target_os = "netbsd"
     ))]
     #[inline]
-    fn libc_umount(mnt: &CStr) -> c_int {
-        unsafe { libc::unmount(mnt.as_ptr(), 0) }
+    fn libc_umount(mnt: &CStr, lazy: bool) -> c_int {
+        let flags = if lazy { libc::MNT_FORCE } else { 0 };
+        unsafe { libc::unmount(mnt.as_ptr(), flags) }
     }
 
     #[cfg(not(any(
@@ -194,14 +525,16 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
         target_os = "netbsd"
     )))]
     #[inline]
-    fn libc_umount(mnt: &CStr) -> c_int {
+    fn libc_umount(mnt: &CStr, lazy: bool) -> c_int {
         use fuse_sys::fuse_unmount_compat22;
         use std::io::ErrorKind::PermissionDenied;
 
-        let rc = unsafe { libc::umount(mnt.as_ptr()) };
+        let flags = if lazy { libc::MNT_DETACH } else { 0 };
+        let rc = unsafe { libc::umount2(mnt.as_ptr(), flags) };
         if rc < 0 && io::Error::last_os_error().kind() == PermissionDenied {
             // Linux always returns EPERM for non-root users.  We have to let the
             // library go through the setuid-root "fusermount -u" to unmount.
+            // (which always unmounts lazily)
             unsafe {
                 fuse_unmount_compat22(mnt.as_ptr());
             }
@@ -212,7 +545,7 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
     }
 
     let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
-    let rc = libc_umount(&mnt);
+    let rc = libc_umount(&mnt, lazy);
     if rc < 0 {
         Err(io::Error::last_os_error())
     } else {
@@ -222,8 +555,76 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
 
 #[cfg(test)]
 mod test {
-    use super::with_fuse_args;
+    use super::{parse_dev_fd, with_fuse_args, Channel};
     use std::ffi::{CStr, OsStr};
+    use std::os::unix::io::FromRawFd;
+    use std::path::Path;
+
+    #[test]
+    fn cloned_channel() {
+        // A socket pair preserves message boundaries like the FUSE device does
+        let mut fds = [0; 2];
+        let rc =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(rc, 0);
+        let ch = unsafe { Channel::from_raw_fd(fds[0]) };
+        let mut peer = unsafe { Channel::from_raw_fd(fds[1]) };
+        // Not a FUSE device, so cloning falls back to sharing the file descriptor
+        let clone = ch.try_clone().unwrap();
+        assert_ne!(clone.fd, ch.fd);
+        drop(ch);
+        clone.sender().send(&[b"foo", b"bar"]).unwrap();
+        let mut buffer = Vec::with_capacity(16);
+        peer.receive(&mut buffer).unwrap();
+        assert_eq!(buffer, b"foobar");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn spliced_channel() {
+        use crate::reply::ReplySender;
+        use std::os::unix::io::AsFd;
+
+        // Use a pipe in place of the FUSE device to send to
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut rx = unsafe { Channel::from_raw_fd(fds[0]) };
+        let mut tx = unsafe { Channel::from_raw_fd(fds[1]) };
+        tx.set_splice(true);
+        assert!(tx.try_clone().unwrap().splice());
+
+        let path = std::env::temp_dir().join(format!("fuse-splice-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let sender = tx.sender();
+        ReplySender::send_fd(&sender, &[b"foo", b"bar"], file.as_fd(), 2, 5).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buffer = Vec::with_capacity(64);
+        rx.receive(&mut buffer).unwrap();
+        assert_eq!(buffer, b"foobar23456");
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn clone_ioctl() {
+        // Value of FUSE_DEV_IOC_CLONE in linux/fuse.h as compiled for these architectures
+        assert_eq!(super::FUSE_DEV_IOC_CLONE, 0x8004_e500);
+    }
+
+    #[test]
+    fn dev_fd_mountpoint() {
+        assert_eq!(parse_dev_fd(Path::new("/dev/fd/3")), Some(3));
+        assert_eq!(parse_dev_fd(Path::new("/dev/fd/42")), Some(42));
+        assert_eq!(parse_dev_fd(Path::new("/dev/fd/")), None);
+        assert_eq!(parse_dev_fd(Path::new("/dev/fd/+3")), None);
+        assert_eq!(parse_dev_fd(Path::new("/dev/fd/3/x")), None);
+        assert_eq!(parse_dev_fd(Path::new("/mnt/fd/3")), None);
+    }
+
     #[test]
     fn fuse_args() {
         with_fuse_args(&[OsStr::new("foo"), OsStr::new("bar")], |args| {
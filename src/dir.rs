@@ -0,0 +1,123 @@
+//! Directory snapshots
+//!
+//! Offsets of directory entries are passed back by the kernel to continue reading a directory.
+//! If they are indices into the current directory contents, entries are skipped or listed twice
+//! when the directory changes between readdir calls. A `DirSnapshot` taken at opendir (and kept
+//! in the state of the directory handle) serves all readdir calls of a directory stream from the
+//! same list of entries, so offsets stay valid.
+
+use std::ffi::{OsStr, OsString};
+
+use crate::{FileType, ReplyDirectory};
+
+/// Entry of a directory snapshot
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Entry {
+    ino: u64,
+    kind: FileType,
+    name: OsString,
+}
+
+/// Snapshot of the entries of a directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirSnapshot {
+    entries: Vec<Entry>,
+}
+
+impl DirSnapshot {
+    /// Create a snapshot of the directory with the given inode and parent inode. It starts with
+    /// the `.` and `..` entries. For the root directory, the parent is the root itself.
+    pub fn new(ino: u64, parent: u64) -> DirSnapshot {
+        let mut snapshot = DirSnapshot::empty();
+        snapshot.add(ino, FileType::Directory, ".");
+        snapshot.add(parent, FileType::Directory, "..");
+        snapshot
+    }
+
+    /// Create an empty snapshot without `.` and `..` entries
+    pub fn empty() -> DirSnapshot {
+        DirSnapshot {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add an entry to the snapshot
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, kind: FileType, name: T) {
+        self.entries.push(Entry {
+            ino,
+            kind,
+            name: name.as_ref().to_os_string(),
+        });
+    }
+
+    /// Returns the number of entries (including `.` and `..`)
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the snapshot has no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns an iterator over the entries (inode, kind and name)
+    pub fn iter(&self) -> impl Iterator<Item = (u64, FileType, &OsStr)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.ino, entry.kind, entry.name.as_os_str()))
+    }
+
+    /// Reply to a readdir request with the entries following the given offset. As many entries
+    /// as fit into the reply are sent, the kernel asks again with the offset of the last entry
+    /// it got.
+    pub fn reply(&self, offset: i64, reply: ReplyDirectory) {
+        reply.fill(offset, self.iter());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DirSnapshot;
+    use crate::reply::ReplyDirectory;
+    use crate::testing::{dir_entries, recv_reply};
+    use crate::FileType;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn dot_entries() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut dir = DirSnapshot::new(2, 1);
+        dir.add(3, FileType::RegularFile, "a");
+        assert_eq!(dir.len(), 3);
+        let entries: Vec<_> = dir.iter().map(|(ino, _, _)| ino).collect();
+        assert_eq!(entries, [2, 1, 3]);
+        dir.reply(0, ReplyDirectory::new(1, tx, 4096));
+        let names: Vec<_> = dir_entries(&recv_reply(&rx))
+            .into_iter()
+            .map(|e| e.1)
+            .collect();
+        assert_eq!(names, [".", "..", "a"]);
+    }
+
+    #[test]
+    fn continue_at_offset() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut dir = DirSnapshot::new(1, 1);
+        for i in 0..10 {
+            dir.add(i + 2, FileType::RegularFile, format!("file{}", i));
+        }
+        // Each entry needs 32 bytes, so only 3 entries fit
+        dir.reply(0, ReplyDirectory::new(1, tx.clone(), 100));
+        let entries = dir_entries(&recv_reply(&rx));
+        assert_eq!(entries.len(), 3);
+        // Continue at the offset of the last entry (like the kernel does)
+        let offset = entries[2].0;
+        dir.reply(offset, ReplyDirectory::new(2, tx.clone(), 4096));
+        let entries = dir_entries(&recv_reply(&rx));
+        assert_eq!(entries.len(), 9);
+        assert_eq!(entries[0], (4, "file1".to_string()));
+        // Reading past the end returns no entries
+        dir.reply(12, ReplyDirectory::new(3, tx, 4096));
+        assert!(dir_entries(&recv_reply(&rx)).is_empty());
+    }
+}
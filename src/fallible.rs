@@ -0,0 +1,1236 @@
+//! Result-returning filesystem interface
+//!
+//! `FallibleFilesystem` is an alternative to the `Filesystem` trait. Instead of getting a reply
+//! object that must be used exactly once, its methods return the result of an operation or an
+//! `Errno`. Returning early with an error (e.g. using the `?` operator) is fine and can't leave
+//! a request unanswered. Wrap a filesystem in `Fallible` to run it in a session.
+
+use libc::c_int;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::time::Duration;
+#[cfg(target_os = "macos")]
+use std::time::SystemTime;
+use std::{error, fmt, io};
+
+use crate::dir::DirSnapshot;
+use crate::handle::HandleTable;
+#[cfg(target_os = "macos")]
+use crate::ReplyXTimes;
+use crate::{
+    FileAttr, FileType, Filesystem, FopenFlags, LockRequest, LockType, OpenFlags, ReleaseFlags,
+    ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, RequestContext, SetAttr, Statfs,
+    XattrFlags,
+};
+
+/// Error number that an operation failed with
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct Errno(c_int);
+
+impl Errno {
+    /// Operation not permitted
+    pub const EPERM: Errno = Errno(libc::EPERM);
+    /// No such file or directory
+    pub const ENOENT: Errno = Errno(libc::ENOENT);
+    /// I/O error
+    pub const EIO: Errno = Errno(libc::EIO);
+    /// Bad file descriptor
+    pub const EBADF: Errno = Errno(libc::EBADF);
+    /// Permission denied
+    pub const EACCES: Errno = Errno(libc::EACCES);
+    /// Device or resource busy
+    pub const EBUSY: Errno = Errno(libc::EBUSY);
+    /// File exists
+    pub const EEXIST: Errno = Errno(libc::EEXIST);
+    /// Not a directory
+    pub const ENOTDIR: Errno = Errno(libc::ENOTDIR);
+    /// Is a directory
+    pub const EISDIR: Errno = Errno(libc::EISDIR);
+    /// Invalid argument
+    pub const EINVAL: Errno = Errno(libc::EINVAL);
+    /// No space left on device
+    pub const ENOSPC: Errno = Errno(libc::ENOSPC);
+    /// Result too large
+    pub const ERANGE: Errno = Errno(libc::ERANGE);
+    /// Function not implemented
+    pub const ENOSYS: Errno = Errno(libc::ENOSYS);
+    /// Directory not empty
+    pub const ENOTEMPTY: Errno = Errno(libc::ENOTEMPTY);
+
+    /// Create an error from the given error number. Values that aren't valid error numbers
+    /// (like 0, which would be sent as success) are turned into EIO.
+    pub fn new(code: c_int) -> Errno {
+        // Like the kernel driver, only accept errno values (excluding internal ones)
+        match code {
+            1..=511 => Errno(code),
+            _ => Errno::EIO,
+        }
+    }
+
+    /// Returns the error number
+    pub fn code(self) -> c_int {
+        self.0
+    }
+}
+
+impl From<c_int> for Errno {
+    fn from(code: c_int) -> Errno {
+        Errno::new(code)
+    }
+}
+
+impl From<Errno> for c_int {
+    fn from(err: Errno) -> c_int {
+        err.0
+    }
+}
+
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Errno {
+        match err.raw_os_error() {
+            Some(code) => Errno::new(code),
+            None => Errno::from(err.kind()),
+        }
+    }
+}
+
+impl From<io::ErrorKind> for Errno {
+    fn from(kind: io::ErrorKind) -> Errno {
+        match kind {
+            io::ErrorKind::NotFound => Errno::ENOENT,
+            io::ErrorKind::PermissionDenied => Errno::EACCES,
+            io::ErrorKind::AlreadyExists => Errno::EEXIST,
+            io::ErrorKind::InvalidInput => Errno::EINVAL,
+            io::ErrorKind::Unsupported => Errno::ENOSYS,
+            _ => Errno::EIO,
+        }
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(err: Errno) -> io::Error {
+        io::Error::from_raw_os_error(err.0)
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Errno({}: {})",
+            self.0,
+            io::Error::from_raw_os_error(self.0)
+        )
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        io::Error::from_raw_os_error(self.0).fmt(f)
+    }
+}
+
+impl error::Error for Errno {}
+
+/// Directory entry with attributes (result of lookup and creating operations)
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// Attributes of the entry
+    pub attr: FileAttr,
+    /// Time the kernel may cache the entry and attributes
+    pub ttl: Duration,
+    /// Generation number of the inode
+    pub generation: u64,
+}
+
+/// File attributes (result of getattr and setattr operations)
+#[derive(Clone, Copy, Debug)]
+pub struct Attr {
+    /// File attributes
+    pub attr: FileAttr,
+    /// Time the kernel may cache the attributes
+    pub ttl: Duration,
+}
+
+/// Opened file or directory (result of open and opendir operations)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Open {
+    /// File handle that is passed to further operations on the opened file
+    pub fh: u64,
+    /// Open flags that are sent back to the kernel
+    pub flags: FopenFlags,
+}
+
+/// Created and opened file (result of create operations)
+#[derive(Clone, Copy, Debug)]
+pub struct Created {
+    /// Entry of the created file
+    pub entry: Entry,
+    /// The opened file
+    pub open: Open,
+}
+
+/// Entry of a directory listing (as sent in replies to readdir requests)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Offset of the next entry (passed to readdir to continue after this entry)
+    pub offset: i64,
+    /// Kind of file
+    pub kind: FileType,
+    /// Name of the entry
+    pub name: OsString,
+}
+
+/// Conflicting file lock (result of getlk operations)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Lock {
+    /// Start of the locked range
+    pub start: u64,
+    /// End of the locked range
+    pub end: u64,
+    /// Type of lock (`LockType::UNLOCK` if there's no conflicting lock)
+    pub typ: LockType,
+    /// Pid of the process holding the lock
+    pub pid: u32,
+}
+
+/// Result-returning filesystem trait.
+///
+/// Same as `Filesystem`, but methods return their result instead of sending it with a reply
+/// object. See `Filesystem` for documentation of the operations. Reasonable default
+/// implementations are provided, most of them fail with ENOSYS. Use `Fallible` to run a
+/// filesystem implementing this trait in a session.
+pub trait FallibleFilesystem {
+    /// Initialize filesystem.
+    fn init(&mut self, _req: &RequestContext) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    fn destroy(&mut self) {}
+
+    /// Look up a directory entry by name and get its attributes.
+    fn lookup(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Forget about an inode.
+    fn forget(&mut self, _req: &RequestContext, _ino: u64, _nlookup: u64) {}
+
+    /// Get file attributes.
+    fn getattr(&mut self, _req: &RequestContext, _ino: u64) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set file attributes.
+    fn setattr(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _attr: &SetAttr,
+    ) -> Result<Attr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read symbolic link.
+    fn readlink(&mut self, _req: &RequestContext, _ino: u64) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create file node.
+    fn mknod(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _rdev: u32,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a directory.
+    fn mkdir(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a file.
+    fn unlink(&mut self, _req: &RequestContext, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a directory.
+    fn rmdir(&mut self, _req: &RequestContext, _parent: u64, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a symbolic link.
+    fn symlink(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _link: &Path,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file.
+    fn rename(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a hard link.
+    fn link(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+    ) -> Result<Entry, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a file.
+    fn open(&mut self, _req: &RequestContext, _ino: u64, _flags: OpenFlags) -> Result<Open, Errno> {
+        Ok(Open::default())
+    }
+
+    /// Read data. Returns up to size bytes (less only at the end of file, unless direct I/O
+    /// is used).
+    fn read(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _size: u32,
+    ) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Write data. Returns the number of bytes written.
+    fn write(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _flags: u32,
+    ) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Flush method.
+    fn flush(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open file.
+    fn release(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _release_flags: ReleaseFlags,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize file contents.
+    fn fsync(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a directory.
+    fn opendir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _flags: OpenFlags,
+    ) -> Result<Open, Errno> {
+        Ok(Open::default())
+    }
+
+    /// Read directory. Called at opendir with the handle it returned. Returns all entries of
+    /// the directory (see `DirSnapshot::new` for a listing with `.` and `..`), the adapter
+    /// keeps them until releasedir and takes care of sending them in chunks.
+    fn readdir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+    ) -> Result<DirSnapshot, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open directory.
+    fn releasedir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file system statistics.
+    fn statfs(&mut self, _req: &RequestContext, _ino: u64) -> Result<Statfs, Errno> {
+        Ok(Statfs {
+            bsize: 512,
+            namelen: 255,
+            ..Statfs::default()
+        })
+    }
+
+    /// Set an extended attribute.
+    #[allow(clippy::too_many_arguments)]
+    fn setxattr(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: XattrFlags,
+        _position: u32,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get the value of an extended attribute. Whether the value fits the caller's buffer is
+    /// checked by the adapter.
+    fn getxattr(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _name: &OsStr,
+    ) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// List the names of extended attributes. Whether the list fits the caller's buffer is
+    /// checked by the adapter.
+    fn listxattr(&mut self, _req: &RequestContext, _ino: u64) -> Result<Vec<OsString>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _name: &OsStr,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    fn access(&mut self, _req: &RequestContext, _ino: u64, _mask: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open a file.
+    fn create(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _flags: OpenFlags,
+    ) -> Result<Created, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _lock: &LockRequest,
+    ) -> Result<Lock, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _lock: &LockRequest,
+        _sleep: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Map block index within file to block index within device.
+    fn bmap(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _blocksize: u32,
+        _idx: u64,
+    ) -> Result<u64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// macOS only: Rename the volume.
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, _req: &RequestContext, _name: &OsStr) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// macOS only (undocumented)
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _options: u64,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// macOS only: Query extended times (bkuptime and crtime).
+    #[cfg(target_os = "macos")]
+    fn getxtimes(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+    ) -> Result<(SystemTime, SystemTime), Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+/// Open directory
+#[derive(Debug)]
+pub(crate) struct OpenDir {
+    /// Handle returned by the filesystem's opendir
+    pub(crate) fh: u64,
+    /// Entries listed at opendir
+    pub(crate) entries: DirSnapshot,
+}
+
+/// Adapter that runs a `FallibleFilesystem` as a `Filesystem` by sending the results of its
+/// operations as replies
+#[derive(Debug, Default)]
+pub struct Fallible<FS> {
+    filesystem: FS,
+    dirs: HandleTable<OpenDir>,
+}
+
+impl<FS: FallibleFilesystem> Fallible<FS> {
+    /// Create a new adapter for the given filesystem
+    pub fn new(filesystem: FS) -> Fallible<FS> {
+        Fallible {
+            filesystem,
+            dirs: HandleTable::new(),
+        }
+    }
+
+    /// Returns a reference to the wrapped filesystem
+    pub fn filesystem(&self) -> &FS {
+        &self.filesystem
+    }
+
+    /// Returns a mutable reference to the wrapped filesystem
+    pub fn filesystem_mut(&mut self) -> &mut FS {
+        &mut self.filesystem
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.filesystem
+    }
+}
+
+/// Send the given entry or error
+pub(crate) fn reply_entry(res: Result<Entry, Errno>, reply: ReplyEntry) {
+    match res {
+        Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
+        Err(err) => reply.error(err.code()),
+    }
+}
+
+/// Send the given attributes or error
+pub(crate) fn reply_attr(res: Result<Attr, Errno>, reply: ReplyAttr) {
+    match res {
+        Ok(attr) => reply.attr(&attr.ttl, &attr.attr),
+        Err(err) => reply.error(err.code()),
+    }
+}
+
+/// Send an empty reply or the given error
+pub(crate) fn reply_empty(res: Result<(), Errno>, reply: ReplyEmpty) {
+    match res {
+        Ok(()) => reply.ok(),
+        Err(err) => reply.error(err.code()),
+    }
+}
+
+/// Send the given opened file or error
+pub(crate) fn reply_open(res: Result<Open, Errno>, reply: ReplyOpen) {
+    match res {
+        Ok(open) => reply.opened(open.fh, open.flags),
+        Err(err) => reply.error(err.code()),
+    }
+}
+
+/// Send the given data or error
+pub(crate) fn reply_data(res: Result<Vec<u8>, Errno>, reply: ReplyData) {
+    match res {
+        Ok(data) => reply.data(&data),
+        Err(err) => reply.error(err.code()),
+    }
+}
+
+/// Send the given extended attribute data or error. If size is zero, only the size of the
+/// data is sent. If the data doesn't fit into size bytes, ERANGE is sent.
+pub(crate) fn reply_xattr(res: Result<Vec<u8>, Errno>, size: u32, reply: ReplyXattr) {
+    match res {
+        Ok(data) => reply.value(size, &data),
+        Err(err) => reply.error(err.code()),
+    }
+}
+
+impl<FS: FallibleFilesystem> Filesystem for Fallible<FS> {
+    fn init(&mut self, req: &RequestContext) -> Result<(), c_int> {
+        self.filesystem.init(req).map_err(Errno::code)
+    }
+
+    fn destroy(&mut self) {
+        self.filesystem.destroy();
+    }
+
+    fn lookup(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        reply_entry(self.filesystem.lookup(req, parent, name), reply);
+    }
+
+    fn forget(&mut self, req: &RequestContext, ino: u64, nlookup: u64) {
+        self.filesystem.forget(req, ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &RequestContext, ino: u64, reply: ReplyAttr) {
+        reply_attr(self.filesystem.getattr(req, ino), reply);
+    }
+
+    fn setattr(&mut self, req: &RequestContext, ino: u64, attr: &SetAttr, reply: ReplyAttr) {
+        reply_attr(self.filesystem.setattr(req, ino, attr), reply);
+    }
+
+    fn readlink(&mut self, req: &RequestContext, ino: u64, reply: ReplyData) {
+        reply_data(self.filesystem.readlink(req, ino), reply);
+    }
+
+    fn mknod(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        reply_entry(self.filesystem.mknod(req, parent, name, mode, rdev), reply);
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        reply: ReplyEntry,
+    ) {
+        reply_entry(self.filesystem.mkdir(req, parent, name, mode), reply);
+    }
+
+    fn unlink(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        reply_empty(self.filesystem.unlink(req, parent, name), reply);
+    }
+
+    fn rmdir(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        reply_empty(self.filesystem.rmdir(req, parent, name), reply);
+    }
+
+    fn symlink(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        reply_entry(self.filesystem.symlink(req, parent, name, link), reply);
+    }
+
+    fn rename(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(
+            self.filesystem
+                .rename(req, parent, name, newparent, newname),
+            reply,
+        );
+    }
+
+    fn link(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        reply_entry(self.filesystem.link(req, ino, newparent, newname), reply);
+    }
+
+    fn open(&mut self, req: &RequestContext, ino: u64, flags: OpenFlags, reply: ReplyOpen) {
+        reply_open(self.filesystem.open(req, ino, flags), reply);
+    }
+
+    fn read(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        reply_data(self.filesystem.read(req, ino, fh, offset, size), reply);
+    }
+
+    fn write(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match self.filesystem.write(req, ino, fh, offset, data, flags) {
+            Ok(size) => reply.written(size),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(self.filesystem.flush(req, ino, fh, lock_owner), reply);
+    }
+
+    fn release(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        release_flags: ReleaseFlags,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(
+            self.filesystem
+                .release(req, ino, fh, flags, lock_owner, release_flags),
+            reply,
+        );
+    }
+
+    fn fsync(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(self.filesystem.fsync(req, ino, fh, datasync), reply);
+    }
+
+    fn opendir(&mut self, req: &RequestContext, ino: u64, flags: OpenFlags, reply: ReplyOpen) {
+        let open = match self.filesystem.opendir(req, ino, flags) {
+            Ok(open) => open,
+            Err(err) => return reply.error(err.code()),
+        };
+        // List the directory right away, so that readdir offsets are indices into a listing
+        // that doesn't change
+        match self.filesystem.readdir(req, ino, open.fh) {
+            Ok(entries) => {
+                let dir = OpenDir {
+                    fh: open.fh,
+                    entries,
+                };
+                self.dirs.reply_opened(dir, open.flags, reply);
+            }
+            Err(err) => {
+                let _ = self.filesystem.releasedir(req, ino, open.fh, flags.bits());
+                reply.error(err.code());
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        match self.dirs.get(fh) {
+            Ok(dir) => dir.entries.reply(offset, reply),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let res = self
+            .dirs
+            .remove(fh)
+            .and_then(|dir| self.filesystem.releasedir(req, ino, dir.fh, flags));
+        reply_empty(res, reply);
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let res = self
+            .dirs
+            .get(fh)
+            .and_then(|dir| self.filesystem.fsyncdir(req, ino, dir.fh, datasync));
+        reply_empty(res, reply);
+    }
+
+    fn statfs(&mut self, req: &RequestContext, ino: u64, reply: ReplyStatfs) {
+        match self.filesystem.statfs(req, ino) {
+            Ok(stats) => reply.statfs(&stats),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: XattrFlags,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(
+            self.filesystem
+                .setxattr(req, ino, name, value, flags, position),
+            reply,
+        );
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        reply_xattr(self.filesystem.getxattr(req, ino, name), size, reply);
+    }
+
+    fn listxattr(&mut self, req: &RequestContext, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.filesystem.listxattr(req, ino) {
+            Ok(names) => reply.list(size, names),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn removexattr(&mut self, req: &RequestContext, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        reply_empty(self.filesystem.removexattr(req, ino, name), reply);
+    }
+
+    fn access(&mut self, req: &RequestContext, ino: u64, mask: u32, reply: ReplyEmpty) {
+        reply_empty(self.filesystem.access(req, ino, mask), reply);
+    }
+
+    fn create(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: OpenFlags,
+        reply: ReplyCreate,
+    ) {
+        match self.filesystem.create(req, parent, name, mode, flags) {
+            Ok(created) => {
+                let Created { entry, open } = created;
+                reply.created(
+                    &entry.ttl,
+                    &entry.attr,
+                    entry.generation,
+                    open.fh,
+                    open.flags,
+                );
+            }
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn getlk(&mut self, req: &RequestContext, ino: u64, lock: &LockRequest, reply: ReplyLock) {
+        match self.filesystem.getlk(req, ino, lock) {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        lock: &LockRequest,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply_empty(self.filesystem.setlk(req, ino, lock, sleep), reply);
+    }
+
+    fn bmap(&mut self, req: &RequestContext, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        match self.filesystem.bmap(req, ino, blocksize, idx) {
+            Ok(block) => reply.bmap(block),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, req: &RequestContext, name: &OsStr, reply: ReplyEmpty) {
+        reply_empty(self.filesystem.setvolname(req, name), reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        options: u64,
+        reply: ReplyEmpty,
+    ) {
+        let res = self
+            .filesystem
+            .exchange(req, parent, name, newparent, newname, options);
+        reply_empty(res, reply);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, req: &RequestContext, ino: u64, reply: ReplyXTimes) {
+        match self.filesystem.getxtimes(req, ino) {
+            Ok((bkuptime, crtime)) => reply.xtimes(bkuptime, crtime),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Attr, Created, Entry, Errno, Fallible, FallibleFilesystem, Open};
+    use crate::dir::DirSnapshot;
+    use crate::reply::ReplyDirectory;
+    use crate::testing::{dir_entries, recv_reply};
+    use crate::{FileAttr, FileType, Filesystem, OpenFlags, Reply, RequestContext};
+    use std::ffi::OsStr;
+    use std::io;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+
+    /// Returns the error and length of data of the next reply
+    fn parse_reply(rx: &Receiver<Vec<u8>>) -> (i32, usize) {
+        let reply = recv_reply(rx);
+        (reply.error, reply.data.len())
+    }
+
+    /// Filesystem with a flat set of files in the root directory
+    #[derive(Default)]
+    struct TestFS {
+        files: Vec<&'static str>,
+        released: Vec<u64>,
+    }
+
+    impl FallibleFilesystem for TestFS {
+        fn lookup(
+            &mut self,
+            _req: &RequestContext,
+            _parent: u64,
+            name: &OsStr,
+        ) -> Result<Entry, Errno> {
+            match self.files.iter().position(|file| name == *file) {
+                Some(i) => Ok(Entry {
+                    attr: FileAttr {
+                        ino: i as u64 + 2,
+                        ..FileAttr::default()
+                    },
+                    ttl: Duration::from_secs(1),
+                    generation: 1,
+                }),
+                None => Err(Errno::ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, _req: &RequestContext, ino: u64) -> Result<Attr, Errno> {
+            match ino {
+                1 => Ok(Attr {
+                    attr: FileAttr::default(),
+                    ttl: Duration::from_secs(1),
+                }),
+                _ => Err(Errno::ENOENT),
+            }
+        }
+
+        fn create(
+            &mut self,
+            _req: &RequestContext,
+            _parent: u64,
+            _name: &OsStr,
+            _mode: u32,
+            _flags: OpenFlags,
+        ) -> Result<Created, Errno> {
+            Err(Errno::new(0))
+        }
+
+        fn getxattr(
+            &mut self,
+            _req: &RequestContext,
+            _ino: u64,
+            _name: &OsStr,
+        ) -> Result<Vec<u8>, Errno> {
+            Ok(b"value".to_vec())
+        }
+
+        fn opendir(
+            &mut self,
+            _req: &RequestContext,
+            _ino: u64,
+            _flags: OpenFlags,
+        ) -> Result<Open, Errno> {
+            Ok(Open {
+                fh: 42,
+                ..Open::default()
+            })
+        }
+
+        fn readdir(
+            &mut self,
+            _req: &RequestContext,
+            ino: u64,
+            _fh: u64,
+        ) -> Result<DirSnapshot, Errno> {
+            if ino != 1 {
+                return Err(Errno::ENOTDIR);
+            }
+            let mut dir = DirSnapshot::new(1, 1);
+            for (i, file) in self.files.iter().enumerate() {
+                dir.add(i as u64 + 2, FileType::RegularFile, file);
+            }
+            Ok(dir)
+        }
+
+        fn releasedir(
+            &mut self,
+            _req: &RequestContext,
+            _ino: u64,
+            fh: u64,
+            _flags: u32,
+        ) -> Result<(), Errno> {
+            self.released.push(fh);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn errno_conversion() {
+        let err: Errno = io::Error::from_raw_os_error(libc::ENOTDIR).into();
+        assert_eq!(err, Errno::ENOTDIR);
+        assert_eq!(
+            Errno::from(io::Error::from(io::ErrorKind::NotFound)),
+            Errno::ENOENT
+        );
+        assert_eq!(Errno::from(io::Error::other("foo")), Errno::EIO);
+        let err: io::Error = Errno::EEXIST.into();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(Errno::new(libc::EACCES).code(), libc::EACCES);
+        // Error numbers that would be sent as success or rejected by the kernel become EIO
+        assert_eq!(Errno::new(0), Errno::EIO);
+        assert_eq!(Errno::from(-libc::ENOENT), Errno::EIO);
+        assert_eq!(Errno::new(512), Errno::EIO);
+        assert_eq!(Errno::from(io::Error::from_raw_os_error(0)), Errno::EIO);
+    }
+
+    #[test]
+    fn fallible_replies() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let req = RequestContext::new(1, 0, 0, 0);
+        let mut fs = Fallible::new(TestFS::default());
+        fs.getattr(&req, 1, Reply::new(1, tx.clone()));
+        assert_eq!(parse_reply(&rx).0, 0);
+        fs.getattr(&req, 2, Reply::new(2, tx.clone()));
+        assert_eq!(parse_reply(&rx), (libc::ENOENT, 0));
+        // Not implemented
+        fs.readlink(&req, 1, Reply::new(3, tx.clone()));
+        assert_eq!(parse_reply(&rx), (libc::ENOSYS, 0));
+        // Extended attributes are checked against the given size
+        fs.getxattr(&req, 1, OsStr::new("foo"), 0, Reply::new(4, tx.clone()));
+        assert_eq!(parse_reply(&rx), (0, 8));
+        fs.getxattr(&req, 1, OsStr::new("foo"), 3, Reply::new(5, tx.clone()));
+        assert_eq!(parse_reply(&rx), (libc::ERANGE, 0));
+        fs.getxattr(&req, 1, OsStr::new("foo"), 5, Reply::new(6, tx.clone()));
+        assert_eq!(parse_reply(&rx), (0, 5));
+        // An invalid error number isn't sent as success
+        fs.create(
+            &req,
+            1,
+            OsStr::new("a"),
+            0o644,
+            OpenFlags::empty(),
+            Reply::new(7, tx),
+        );
+        assert_eq!(parse_reply(&rx), (libc::EIO, 0));
+    }
+
+    #[test]
+    fn lookup_entry() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let req = RequestContext::new(1, 0, 0, 0);
+        let mut fs = Fallible::new(TestFS {
+            files: vec!["a", "b"],
+            ..TestFS::default()
+        });
+        fs.lookup(&req, 1, OsStr::new("b"), Reply::new(1, tx.clone()));
+        let reply = recv_reply(&rx);
+        assert_eq!(reply.error, 0);
+        // The entry starts with the inode and generation numbers
+        assert_eq!(u64::from_ne_bytes(reply.data[0..8].try_into().unwrap()), 3);
+        assert_eq!(u64::from_ne_bytes(reply.data[8..16].try_into().unwrap()), 1);
+        fs.lookup(&req, 1, OsStr::new("c"), Reply::new(2, tx));
+        assert_eq!(parse_reply(&rx), (libc::ENOENT, 0));
+    }
+
+    #[test]
+    fn readdir_snapshot() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let req = RequestContext::new(1, 0, 0, 0);
+        let mut fs = Fallible::new(TestFS {
+            files: vec!["a", "b", "c"],
+            ..TestFS::default()
+        });
+        fs.opendir(&req, 1, OpenFlags::empty(), Reply::new(1, tx.clone()));
+        let reply = recv_reply(&rx);
+        assert_eq!(reply.error, 0);
+        let fh = u64::from_ne_bytes(reply.data[0..8].try_into().unwrap());
+        // Only the dot entries fit (32 bytes each)
+        fs.readdir(&req, 1, fh, 0, ReplyDirectory::new(2, tx.clone(), 64));
+        let entries = dir_entries(&recv_reply(&rx));
+        assert_eq!(entries.len(), 2);
+        // Removing a file doesn't shift the offsets of the following entries
+        fs.filesystem_mut().files.remove(0);
+        fs.readdir(&req, 1, fh, 2, ReplyDirectory::new(3, tx.clone(), 4096));
+        let names: Vec<_> = dir_entries(&recv_reply(&rx))
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        // The filesystem gets its own handle back on release
+        fs.releasedir(&req, 1, fh, 0, Reply::new(4, tx.clone()));
+        assert_eq!(parse_reply(&rx), (0, 0));
+        assert_eq!(fs.filesystem().released, [42]);
+        fs.readdir(&req, 1, fh, 0, ReplyDirectory::new(5, tx, 4096));
+        assert_eq!(parse_reply(&rx), (libc::EBADF, 0));
+    }
+
+    #[test]
+    fn opendir_fails_if_readdir_fails() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let req = RequestContext::new(1, 0, 0, 0);
+        let mut fs = Fallible::new(TestFS::default());
+        fs.opendir(&req, 2, OpenFlags::empty(), Reply::new(1, tx));
+        assert_eq!(parse_reply(&rx), (libc::ENOTDIR, 0));
+        // The directory opened by the filesystem is released again
+        assert_eq!(fs.filesystem().released, [42]);
+    }
+}
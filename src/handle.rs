@@ -0,0 +1,151 @@
+//! File handle table
+//!
+//! `HandleTable` maps the file handles (`fh`) that are passed to the kernel on open, opendir and
+//! create to state of the filesystem. Handle numbers are never reused, so that a request with a
+//! handle that was already released is detected instead of accessing another file's state.
+
+use log::warn;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::fallible::Errno;
+use crate::{FopenFlags, ReplyEmpty, ReplyOpen};
+
+/// Thread-safe table of open file handles with state of type T
+#[derive(Debug)]
+pub struct HandleTable<T> {
+    handles: Mutex<HashMap<u64, Arc<T>>>,
+    next_fh: AtomicU64,
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> HandleTable<T> {
+        HandleTable::new()
+    }
+}
+
+impl<T> HandleTable<T> {
+    /// Create a new, empty handle table
+    pub fn new() -> HandleTable<T> {
+        HandleTable {
+            handles: Mutex::new(HashMap::new()),
+            // Handle 0 is never used, it's what stateless filesystems pass
+            next_fh: AtomicU64::new(1),
+        }
+    }
+
+    /// Returns the number of open handles
+    pub fn len(&self) -> usize {
+        self.handles.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no open handles
+    pub fn is_empty(&self) -> bool {
+        self.handles.lock().unwrap().is_empty()
+    }
+
+    /// Add a handle with the given state. Returns the new handle number.
+    pub fn insert(&self, state: T) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, Arc::new(state));
+        fh
+    }
+
+    /// Returns the state of a handle. Fails with EBADF if the handle is unknown or was already
+    /// released.
+    pub fn get(&self, fh: u64) -> Result<Arc<T>, Errno> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(state) => Ok(Arc::clone(state)),
+            None => Err(self.bad_handle(fh)),
+        }
+    }
+
+    /// Remove a handle and return its state. Fails with EBADF if the handle is unknown or was
+    /// already released.
+    pub fn remove(&self, fh: u64) -> Result<Arc<T>, Errno> {
+        match self.handles.lock().unwrap().remove(&fh) {
+            Some(state) => Ok(state),
+            None => Err(self.bad_handle(fh)),
+        }
+    }
+
+    /// Add a handle with the given state and reply to an open or opendir request with it
+    pub fn reply_opened(&self, state: T, flags: FopenFlags, reply: ReplyOpen) {
+        reply.opened(self.insert(state), flags);
+    }
+
+    /// Remove a handle and reply to a release or releasedir request
+    pub fn release(&self, fh: u64, reply: ReplyEmpty) {
+        match self.remove(fh) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    /// Log access with an unknown handle and return the error to reply with
+    fn bad_handle(&self, fh: u64) -> Errno {
+        if fh != 0 && fh < self.next_fh.load(Ordering::Relaxed) {
+            warn!("Use of released file handle {}", fh);
+        } else {
+            warn!("Use of unknown file handle {}", fh);
+        }
+        Errno::EBADF
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HandleTable;
+    use crate::fallible::Errno;
+    use crate::testing::recv_reply;
+    use crate::Reply;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn insert_and_release() {
+        let table = HandleTable::new();
+        let fh = table.insert("a");
+        assert_ne!(fh, 0);
+        assert_eq!(*table.get(fh).unwrap(), "a");
+        assert_eq!(*table.remove(fh).unwrap(), "a");
+        // Use after release is detected and the number isn't reused
+        assert_eq!(table.get(fh), Err(Errno::EBADF));
+        assert_eq!(table.remove(fh), Err(Errno::EBADF));
+        assert_ne!(table.insert("b"), fh);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn release_reply() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let table = HandleTable::new();
+        let fh = table.insert(());
+        table.release(fh, Reply::new(1, tx.clone()));
+        assert_eq!(recv_reply(&rx).error, 0);
+        table.release(fh, Reply::new(2, tx));
+        assert_eq!(recv_reply(&rx).error, libc::EBADF);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let table = Arc::new(HandleTable::new());
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let table = Arc::clone(&table);
+                thread::spawn(move || (0..100).map(|_| table.insert(i)).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut handles: Vec<u64> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        handles.sort_unstable();
+        handles.dedup();
+        assert_eq!(handles.len(), 400);
+        assert_eq!(table.len(), 400);
+    }
+}
@@ -0,0 +1,326 @@
+//! Inode table
+//!
+//! `InodeTable` helps filesystems implementing the inode-based `Filesystem` trait to manage
+//! inode numbers and the lookup counts the kernel holds on them. Inode numbers of freed inodes
+//! are reused with a new generation number. Entries are replied through the table, so that
+//! every lookup reference the kernel gets is counted and released again on `forget`.
+
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::{FileAttr, FopenFlags, Forget, ReplyCreate, ReplyEntry, FUSE_ROOT_ID};
+
+/// Inode in the table
+#[derive(Debug)]
+struct Inode<T> {
+    generation: u64,
+    nlookup: u64,
+    data: T,
+}
+
+/// Report of inodes whose lookup counts don't add up
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InodeReport {
+    /// Inodes that are still referenced by the kernel (inode number and lookup count)
+    pub leaked: Vec<(u64, u64)>,
+    /// Inodes that were forgotten more often than looked up, or forgotten while unknown
+    /// (inode number and number of excess forgets). Only recorded in debug mode.
+    pub over_forgotten: Vec<(u64, u64)>,
+}
+
+impl InodeReport {
+    /// Returns true if nothing was reported
+    pub fn is_empty(&self) -> bool {
+        self.leaked.is_empty() && self.over_forgotten.is_empty()
+    }
+}
+
+impl fmt::Display for InodeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} leaked inodes {:?}, {} over-forgotten inodes {:?}",
+            self.leaked.len(),
+            self.leaked,
+            self.over_forgotten.len(),
+            self.over_forgotten
+        )
+    }
+}
+
+/// Table of inodes with lookup counts. Stores data of type T for every inode.
+#[derive(Debug)]
+pub struct InodeTable<T> {
+    inodes: HashMap<u64, Inode<T>>,
+    free: Vec<(u64, u64)>,
+    next_ino: u64,
+    debug: bool,
+    over_forgotten: Vec<(u64, u64)>,
+}
+
+impl<T> InodeTable<T> {
+    /// Create a new inode table with the given data of the root directory. The root inode is
+    /// never freed.
+    pub fn new(root: T) -> InodeTable<T> {
+        let root = Inode {
+            generation: 0,
+            nlookup: 0,
+            data: root,
+        };
+        InodeTable {
+            inodes: HashMap::from([(FUSE_ROOT_ID, root)]),
+            free: Vec::new(),
+            next_ino: FUSE_ROOT_ID + 1,
+            debug: false,
+            over_forgotten: Vec::new(),
+        }
+    }
+
+    /// Enable or disable debug mode. In debug mode, forgets that don't match a lookup are
+    /// logged and recorded for the report.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Returns the number of inodes in the table (including the root)
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    /// Returns true if the table only contains the root inode
+    pub fn is_empty(&self) -> bool {
+        self.inodes.len() <= 1
+    }
+
+    /// Add an inode with the given data. Returns the allocated inode and generation number.
+    /// The new inode has no lookup references yet.
+    pub fn insert(&mut self, data: T) -> (u64, u64) {
+        let (ino, generation) = self.free.pop().unwrap_or_else(|| {
+            let ino = self.next_ino;
+            self.next_ino += 1;
+            (ino, 0)
+        });
+        let inode = Inode {
+            generation,
+            nlookup: 0,
+            data,
+        };
+        self.inodes.insert(ino, inode);
+        (ino, generation)
+    }
+
+    /// Remove an inode and free its number for reuse. Should be called when the filesystem
+    /// doesn't need the inode anymore and the kernel doesn't reference it (`forget` returned
+    /// true or the lookup count is 0). The root inode can't be removed.
+    pub fn remove(&mut self, ino: u64) -> Option<T> {
+        if ino == FUSE_ROOT_ID {
+            return None;
+        }
+        let inode = self.inodes.remove(&ino)?;
+        if self.debug && inode.nlookup > 0 {
+            warn!(
+                "Removed inode {} that is still referenced ({} lookups)",
+                ino, inode.nlookup
+            );
+        }
+        // Reused numbers get a new generation, so the kernel can tell them apart
+        self.free.push((ino, inode.generation + 1));
+        Some(inode.data)
+    }
+
+    /// Returns the data of an inode
+    pub fn get(&self, ino: u64) -> Option<&T> {
+        self.inodes.get(&ino).map(|inode| &inode.data)
+    }
+
+    /// Returns the data of an inode for modification
+    pub fn get_mut(&mut self, ino: u64) -> Option<&mut T> {
+        self.inodes.get_mut(&ino).map(|inode| &mut inode.data)
+    }
+
+    /// Returns the generation number of an inode
+    pub fn generation(&self, ino: u64) -> Option<u64> {
+        self.inodes.get(&ino).map(|inode| inode.generation)
+    }
+
+    /// Returns the number of lookup references the kernel holds on an inode
+    pub fn lookup_count(&self, ino: u64) -> u64 {
+        self.inodes.get(&ino).map_or(0, |inode| inode.nlookup)
+    }
+
+    /// Count a lookup reference to an inode. Returns its generation number, or `None` if the
+    /// inode doesn't exist. Prefer `reply_entry` and `reply_created`, which count the
+    /// reference when sending the reply.
+    pub fn lookup(&mut self, ino: u64) -> Option<u64> {
+        let inode = self.inodes.get_mut(&ino)?;
+        inode.nlookup += 1;
+        Some(inode.generation)
+    }
+
+    /// Forget lookup references to an inode. Returns true if the kernel doesn't reference the
+    /// inode anymore, so it can be removed if the filesystem doesn't need it either (e.g.
+    /// because it was unlinked).
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> bool {
+        let Some(inode) = self.inodes.get_mut(&ino) else {
+            self.over_forget(ino, nlookup);
+            return false;
+        };
+        let excess = nlookup.saturating_sub(inode.nlookup);
+        inode.nlookup -= nlookup - excess;
+        let unreferenced = inode.nlookup == 0 && ino != FUSE_ROOT_ID;
+        if excess > 0 {
+            self.over_forget(ino, excess);
+        }
+        unreferenced
+    }
+
+    /// Forget lookup references to multiple inodes. Returns the inodes that aren't referenced
+    /// by the kernel anymore.
+    pub fn batch_forget(&mut self, nodes: &[Forget]) -> Vec<u64> {
+        nodes
+            .iter()
+            .filter(|node| self.forget(node.ino, node.nlookup))
+            .map(|node| node.ino)
+            .collect()
+    }
+
+    /// Record a forget that doesn't match a lookup
+    fn over_forget(&mut self, ino: u64, excess: u64) {
+        if self.debug {
+            warn!(
+                "Inode {} forgotten {} times more than looked up",
+                ino, excess
+            );
+            self.over_forgotten.push((ino, excess));
+        }
+    }
+
+    /// Reply with an entry for the given attributes and count the lookup reference. The
+    /// inode number in the attributes must be in the table.
+    pub fn reply_entry(&mut self, reply: ReplyEntry, ttl: &Duration, attr: &FileAttr) {
+        match self.lookup(attr.ino) {
+            Some(generation) => reply.entry(ttl, attr, generation),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    /// Reply with a created file for the given attributes and count the lookup reference. The
+    /// inode number in the attributes must be in the table.
+    pub fn reply_created(
+        &mut self,
+        reply: ReplyCreate,
+        ttl: &Duration,
+        attr: &FileAttr,
+        fh: u64,
+        flags: FopenFlags,
+    ) {
+        match self.lookup(attr.ino) {
+            Some(generation) => reply.created(ttl, attr, generation, fh, flags),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    /// Returns a report of inodes that are still referenced by the kernel and (in debug mode)
+    /// inodes that were forgotten too often. Meant to be called on `destroy`; note that the
+    /// kernel doesn't always forget all inodes before unmounting. In debug mode, a non-empty
+    /// report is also logged.
+    pub fn report(&self) -> InodeReport {
+        let mut leaked: Vec<(u64, u64)> = self
+            .inodes
+            .iter()
+            .filter(|(&ino, inode)| ino != FUSE_ROOT_ID && inode.nlookup > 0)
+            .map(|(&ino, inode)| (ino, inode.nlookup))
+            .collect();
+        leaked.sort_unstable();
+        let report = InodeReport {
+            leaked,
+            over_forgotten: self.over_forgotten.clone(),
+        };
+        if self.debug && !report.is_empty() {
+            warn!("Inode table: {}", report);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InodeReport, InodeTable};
+    use crate::testing::recv_reply;
+    use crate::{FileAttr, Forget, Reply, FUSE_ROOT_ID};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn allocate_and_reuse() {
+        let mut table = InodeTable::new("root");
+        let (ino, generation) = table.insert("a");
+        assert_eq!((ino, generation), (FUSE_ROOT_ID + 1, 0));
+        assert_eq!(table.get(ino), Some(&"a"));
+        assert_eq!(table.remove(ino), Some("a"));
+        assert_eq!(table.get(ino), None);
+        // The number is reused with the next generation
+        assert_eq!(table.insert("b"), (ino, 1));
+        assert_eq!(table.insert("c"), (ino + 1, 0));
+        assert_eq!(table.remove(FUSE_ROOT_ID), None);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn lookup_and_forget() {
+        let mut table = InodeTable::new(());
+        let (ino, _) = table.insert(());
+        assert_eq!(table.lookup(ino), Some(0));
+        assert_eq!(table.lookup(ino), Some(0));
+        assert!(!table.forget(ino, 1));
+        assert_eq!(table.report().leaked, [(ino, 1)]);
+        assert_eq!(table.batch_forget(&[Forget { ino, nlookup: 1 }]), vec![ino]);
+        assert_eq!(table.report(), InodeReport::default());
+    }
+
+    #[test]
+    fn over_forget() {
+        let mut table = InodeTable::new(());
+        table.set_debug(true);
+        let (ino, _) = table.insert(());
+        table.lookup(ino);
+        assert!(table.forget(ino, 3));
+        assert!(!table.forget(42, 1));
+        let report = table.report();
+        assert!(report.leaked.is_empty());
+        assert_eq!(report.over_forgotten, [(ino, 2), (42, 1)]);
+    }
+
+    #[test]
+    fn reply_entry() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut table = InodeTable::new(());
+        let (ino, _) = table.insert(());
+        table.remove(ino);
+        let (ino, generation) = table.insert(());
+        let attr = FileAttr {
+            ino,
+            ..FileAttr::default()
+        };
+        table.reply_entry(Reply::new(1, tx.clone()), &Duration::from_secs(1), &attr);
+        let reply = recv_reply(&rx);
+        assert_eq!(
+            u64::from_ne_bytes(reply.data[0..8].try_into().unwrap()),
+            ino
+        );
+        assert_eq!(
+            u64::from_ne_bytes(reply.data[8..16].try_into().unwrap()),
+            generation
+        );
+        assert_eq!(table.lookup_count(ino), 1);
+        // Unknown inodes are not replied
+        let attr = FileAttr {
+            ino: 42,
+            ..FileAttr::default()
+        };
+        table.reply_entry(Reply::new(2, tx), &Duration::from_secs(1), &attr);
+        assert_eq!(recv_reply(&rx).error, libc::ENOENT);
+    }
+}
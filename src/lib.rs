@@ -9,22 +9,43 @@ use std::ffi::OsStr;
 use std::path::Path;
 use std::time::SystemTime;
 
+pub use args::{
+    AttrValid, FopenFlags, Forget, LockRequest, LockType, OpenFlags, ReleaseFlags, SetAttr,
+    XattrFlags,
+};
+pub use buffer::WriteData;
+pub use fallible::{Errno, Fallible, FallibleFilesystem};
 pub use fuse_abi::{consts, FUSE_ROOT_ID};
+pub use path::{PathAdapter, PathFilesystem};
+pub use raw::{RawHandler, RawRequest};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::{
-    Reply, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    RawReply, Reply, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
 };
-pub use request::Request;
-pub use session::Session;
+pub use request::{Request, RequestContext};
+pub use session::{RawSession, Session, ShutdownSummary};
 
+mod args;
+mod buffer;
 mod channel;
-mod ll;
+pub mod dir;
+pub mod fallible;
+pub mod handle;
+pub mod inode;
+pub mod ll;
+pub mod lock;
 pub mod memory;
+pub mod path;
+mod raw;
 mod reply;
 mod request;
 mod session;
+#[cfg(target_os = "linux")]
+mod splice;
+pub mod testing;
+pub mod trace;
 
 /// File types
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -99,16 +120,38 @@ impl Default for FileAttr {
     }
 }
 
+/// Filesystem statistics
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Statfs {
+    /// Total number of blocks (in units of frsize)
+    pub blocks: u64,
+    /// Number of free blocks
+    pub bfree: u64,
+    /// Number of free blocks available to unprivileged users
+    pub bavail: u64,
+    /// Total number of inodes
+    pub files: u64,
+    /// Number of free inodes
+    pub ffree: u64,
+    /// Preferred block size
+    pub bsize: u32,
+    /// Maximum length of filenames
+    pub namelen: u32,
+    /// Fundamental block size
+    pub frsize: u32,
+}
+
 /// Filesystem trait.
 ///
 /// This trait must be implemented to provide a userspace filesystem via FUSE.
 /// These methods correspond to fuse_lowlevel_ops in libfuse. Reasonable default
 /// implementations are provided here to get a mountable filesystem that does
-/// nothing.
+/// nothing. Every method (except destroy) gets the context of the request, which
+/// tells about the process that caused it.
 pub trait Filesystem {
     /// Initialize filesystem.
     /// Called before any other filesystem method.
-    fn init(&mut self) -> Result<(), c_int> {
+    fn init(&mut self, _req: &RequestContext) -> Result<(), c_int> {
         Ok(())
     }
 
@@ -117,7 +160,7 @@ pub trait Filesystem {
     fn destroy(&mut self) {}
 
     /// Look up a directory entry by name and get its attributes.
-    fn lookup(&mut self, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, _req: &RequestContext, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
 
@@ -128,68 +171,97 @@ pub trait Filesystem {
     /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
     /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
     /// inodes will receive a forget message.
-    fn forget(&mut self, _ino: u64, _nlookup: u64) {}
+    fn forget(&mut self, _req: &RequestContext, _ino: u64, _nlookup: u64) {}
+
+    /// Forget about multiple inodes.
+    /// Sent instead of single forget messages since ABI 7.16. By default, `forget` is called
+    /// for each of the inodes.
+    fn batch_forget(&mut self, req: &RequestContext, nodes: &[Forget]) {
+        for node in nodes {
+            self.forget(req, node.ino, node.nlookup);
+        }
+    }
+
+    /// Interrupt a request.
+    /// Sent by the kernel if the process that issued the request with the given unique id got
+    /// a signal. The interrupted request should be answered as soon as possible, with EINTR if
+    /// it was not completed (e.g. a setlk call waiting for a lock). Requests that complete
+    /// quickly may ignore interrupts. Returning an error replies to the interrupt: ENOSYS if
+    /// interrupts aren't supported (the kernel won't send any more), or EAGAIN if the request
+    /// isn't known yet, so the kernel sends the interrupt again later. Requests dispatched on
+    /// several threads (see `Session::run_multithreaded`) can be overtaken by their interrupt.
+    fn interrupt(&mut self, _req: &RequestContext, _unique: u64) -> Result<(), c_int> {
+        Err(ENOSYS)
+    }
 
     /// Get file attributes.
-    fn getattr(&mut self, _ino: u64, reply: ReplyAttr) {
+    fn getattr(&mut self, _req: &RequestContext, _ino: u64, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
     /// Set file attributes.
-    #[allow(clippy::too_many_arguments)]
-    fn setattr(
-        &mut self,
-        _ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<SystemTime>,
-        _mtime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
+    /// Only the attributes that are given in `attr` should be changed.
+    fn setattr(&mut self, _req: &RequestContext, _ino: u64, _attr: &SetAttr, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
     /// Read symbolic link.
-    fn readlink(&mut self, _ino: u64, reply: ReplyData) {
+    fn readlink(&mut self, _req: &RequestContext, _ino: u64, reply: ReplyData) {
         reply.error(ENOSYS);
     }
 
     /// Create file node.
     /// Create a regular file, character device, block device, fifo or socket node.
-    fn mknod(&mut self, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+    fn mknod(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// Create a directory.
-    fn mkdir(&mut self, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
+    fn mkdir(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        reply: ReplyEntry,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// Remove a file.
-    fn unlink(&mut self, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, _req: &RequestContext, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// Remove a directory.
-    fn rmdir(&mut self, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, _req: &RequestContext, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// Create a symbolic link.
-    fn symlink(&mut self, _parent: u64, _name: &OsStr, _link: &Path, reply: ReplyEntry) {
+    fn symlink(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _link: &Path,
+        reply: ReplyEntry,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// Rename a file.
     fn rename(
         &mut self,
+        _req: &RequestContext,
         _parent: u64,
         _name: &OsStr,
         _newparent: u64,
@@ -200,7 +272,14 @@ pub trait Filesystem {
     }
 
     /// Create a hard link.
-    fn link(&mut self, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
+    fn link(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
         reply.error(ENOSYS);
     }
 
@@ -212,8 +291,8 @@ pub trait Filesystem {
     /// anything in fh. There are also some flags (direct_io, keep_cache) which the
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
-    fn open(&mut self, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+    fn open(&mut self, _req: &RequestContext, _ino: u64, _flags: OpenFlags, reply: ReplyOpen) {
+        reply.opened(0, FopenFlags::empty());
     }
 
     /// Read data.
@@ -223,7 +302,15 @@ pub trait Filesystem {
     /// return value of the read system call will reflect the return value of this
     /// operation. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value.
-    fn read(&mut self, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
+    fn read(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _size: u32,
+        reply: ReplyData,
+    ) {
         reply.error(ENOSYS);
     }
 
@@ -236,6 +323,7 @@ pub trait Filesystem {
     #[allow(clippy::too_many_arguments)]
     fn write(
         &mut self,
+        _req: &RequestContext,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -246,6 +334,26 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Write data with an owned buffer.
+    /// Called instead of write with the same arguments, but the data is passed as an owned,
+    /// reference-counted buffer that the filesystem can keep after returning (e.g. to write
+    /// it back later) without copying it. If splice is enabled, the data can be written to a
+    /// file without copying it through userspace at all (see `WriteData::write_to`). The
+    /// default implementation calls write.
+    #[allow(clippy::too_many_arguments)]
+    fn write_buf(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: WriteData,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.write(req, ino, fh, offset, &data, flags, reply);
+    }
+
     /// Flush method.
     /// This is called on each close() of the opened file. Since file descriptors can
     /// be duplicated (dup, dup2, fork), for one open call there may be many flush
@@ -256,7 +364,14 @@ pub trait Filesystem {
     /// is not forced to flush pending writes. One reason to flush data, is if the
     /// filesystem wants to return write errors. If the filesystem supports file locking
     /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
-    fn flush(&mut self, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
         reply.error(ENOSYS);
     }
 
@@ -267,15 +382,17 @@ pub trait Filesystem {
     /// error, but error values are not returned to close() or munmap() which triggered
     /// the release. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same flags as for
-    /// open.
+    /// open. release_flags tell whether the POSIX locks (FLUSH) or flock locks (FLOCK_UNLOCK)
+    /// of lock_owner should be removed.
     #[allow(clippy::too_many_arguments)]
     fn release(
         &mut self,
+        _req: &RequestContext,
         _ino: u64,
         _fh: u64,
         _flags: u32,
         _lock_owner: u64,
-        _flush: bool,
+        _release_flags: ReleaseFlags,
         reply: ReplyEmpty,
     ) {
         reply.ok();
@@ -284,7 +401,14 @@ pub trait Filesystem {
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    fn fsync(&mut self, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+    fn fsync(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
         reply.error(ENOSYS);
     }
 
@@ -295,8 +419,8 @@ pub trait Filesystem {
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
-    fn opendir(&mut self, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        reply.opened(0, 0);
+    fn opendir(&mut self, _req: &RequestContext, _ino: u64, _flags: OpenFlags, reply: ReplyOpen) {
+        reply.opened(0, FopenFlags::empty());
     }
 
     /// Read directory.
@@ -304,7 +428,14 @@ pub trait Filesystem {
     /// requested size. Send an empty buffer on end of stream. fh will contain the
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
-    fn readdir(&mut self, _ino: u64, _fh: u64, _offset: i64, reply: ReplyDirectory) {
+    fn readdir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectory,
+    ) {
         reply.error(ENOSYS);
     }
 
@@ -312,7 +443,14 @@ pub trait Filesystem {
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
     /// opendir method didn't set any value.
-    fn releasedir(&mut self, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
+    fn releasedir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
         reply.ok();
     }
 
@@ -320,23 +458,35 @@ pub trait Filesystem {
     /// If the datasync parameter is set, then only the directory contents should
     /// be flushed, not the meta data. fh will contain the value set by the opendir
     /// method, or will be undefined if the opendir method didn't set any value.
-    fn fsyncdir(&mut self, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+    fn fsyncdir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// Get file system statistics.
-    fn statfs(&mut self, _ino: u64, reply: ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+    fn statfs(&mut self, _req: &RequestContext, _ino: u64, reply: ReplyStatfs) {
+        reply.statfs(&Statfs {
+            bsize: 512,
+            namelen: 255,
+            ..Statfs::default()
+        });
     }
 
     /// Set an extended attribute.
     #[allow(clippy::too_many_arguments)]
     fn setxattr(
         &mut self,
+        _req: &RequestContext,
         _ino: u64,
         _name: &OsStr,
         _value: &[u8],
-        _flags: u32,
+        _flags: XattrFlags,
         _position: u32,
         reply: ReplyEmpty,
     ) {
@@ -346,21 +496,28 @@ pub trait Filesystem {
     /// Get an extended attribute.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
-    /// `reply.error(ERANGE)` if it doesn't.
-    fn getxattr(&mut self, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+    /// `reply.error(ERANGE)` if it doesn't. `reply.value()` applies these rules.
+    fn getxattr(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _name: &OsStr,
+        _size: u32,
+        reply: ReplyXattr,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// List extended attribute names.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
-    /// `reply.error(ERANGE)` if it doesn't.
-    fn listxattr(&mut self, _ino: u64, _size: u32, reply: ReplyXattr) {
+    /// `reply.error(ERANGE)` if it doesn't. `reply.list()` applies these rules.
+    fn listxattr(&mut self, _req: &RequestContext, _ino: u64, _size: u32, reply: ReplyXattr) {
         reply.error(ENOSYS);
     }
 
     /// Remove an extended attribute.
-    fn removexattr(&mut self, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+    fn removexattr(&mut self, _req: &RequestContext, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -368,7 +525,7 @@ pub trait Filesystem {
     /// This will be called for the access() system call. If the 'default_permissions'
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    fn access(&mut self, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+    fn access(&mut self, _req: &RequestContext, _ino: u64, _mask: u32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -382,23 +539,20 @@ pub trait Filesystem {
     /// structure in <fuse_common.h> for more details. If this method is not
     /// implemented or under Linux kernel versions earlier than 2.6.15, the mknod()
     /// and open() methods will be called instead.
-    fn create(&mut self, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
+    fn create(
+        &mut self,
+        _req: &RequestContext,
+        _parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _flags: OpenFlags,
+        reply: ReplyCreate,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// Test for a POSIX file lock.
-    #[allow(clippy::too_many_arguments)]
-    fn getlk(
-        &mut self,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: u32,
-        _pid: u32,
-        reply: ReplyLock,
-    ) {
+    fn getlk(&mut self, _req: &RequestContext, _ino: u64, _lock: &LockRequest, reply: ReplyLock) {
         reply.error(ENOSYS);
     }
 
@@ -409,16 +563,11 @@ pub trait Filesystem {
     /// used to fill in this field in getlk(). Note: if the locking methods are not
     /// implemented, the kernel will still allow file locking to work locally.
     /// Hence these are only interesting for network filesystems and similar.
-    #[allow(clippy::too_many_arguments)]
     fn setlk(
         &mut self,
+        _req: &RequestContext,
         _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: u32,
-        _pid: u32,
+        _lock: &LockRequest,
         _sleep: bool,
         reply: ReplyEmpty,
     ) {
@@ -428,14 +577,21 @@ pub trait Filesystem {
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
-    fn bmap(&mut self, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
+    fn bmap(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        _blocksize: u32,
+        _idx: u64,
+        reply: ReplyBmap,
+    ) {
         reply.error(ENOSYS);
     }
 
     /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
-    fn setvolname(&mut self, _name: &OsStr, reply: ReplyEmpty) {
+    fn setvolname(&mut self, _req: &RequestContext, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -443,7 +599,7 @@ pub trait Filesystem {
     #[cfg(target_os = "macos")]
     fn exchange(
         &mut self,
-
+        _req: &RequestContext,
         _parent: u64,
         _name: &OsStr,
         _newparent: u64,
@@ -457,7 +613,7 @@ pub trait Filesystem {
     /// macOS only: Query extended times (bkuptime and crtime). Set fuse_init_out.flags
     /// during init to FUSE_XTIMES to enable
     #[cfg(target_os = "macos")]
-    fn getxtimes(&mut self, _ino: u64, reply: ReplyXTimes) {
+    fn getxtimes(&mut self, _req: &RequestContext, _ino: u64, reply: ReplyXTimes) {
         reply.error(ENOSYS);
     }
 }
@@ -3,10 +3,10 @@
 //! Helper to decompose a slice of binary data (incoming FUSE request) into multiple data
 //! structures (request arguments).
 
+use fuse_abi::FromBytes;
 use std::ffi::OsStr;
 use std::mem;
 use std::os::unix::ffi::OsStrExt;
-use std::ptr::NonNull;
 
 /// An iterator that can be used to fetch typed arguments from a byte slice.
 pub struct ArgumentIterator<'a> {
@@ -42,25 +42,24 @@ impl<'a> ArgumentIterator<'a> {
         Some(bytes)
     }
 
-    /// Fetch a typed argument. Returns `None` if there's not enough data left. This function is
-    /// unsafe because there is no guarantee that the data actually contains the type T.
-    pub unsafe fn fetch<T>(&mut self) -> Option<&'a T> {
-        let size = mem::size_of::<T>();
-        if size == 0 {
-            return Some(NonNull::dangling().as_ref());
-        }
+    /// Fetch a typed argument. Returns `None` if there's not enough data left or if the data
+    /// isn't aligned for the type T.
+    pub fn fetch<T: FromBytes>(&mut self) -> Option<&'a T> {
+        let bytes = self.fetch_bytes(mem::size_of::<T>())?;
+        T::ref_from_prefix(bytes)
+    }
 
-        let bytes = self.fetch_bytes(size)?;
-        if bytes.as_ptr().align_offset(mem::align_of::<T>()) != 0 {
-            return None;
-        }
-        Some(&*(bytes.as_ptr() as *const T))
+    /// Fetch a slice of the given number of typed arguments. Returns `None` if there's not enough
+    /// data left or if the data isn't aligned for the type T.
+    #[cfg(feature = "abi-7-16")]
+    pub fn fetch_slice<T: FromBytes>(&mut self, count: usize) -> Option<&'a [T]> {
+        let bytes = self.fetch_bytes(mem::size_of::<T>().checked_mul(count)?)?;
+        T::slice_from_prefix(bytes, count)
     }
 
     /// Fetch a (zero-terminated) string (can be non-utf8). Returns `None` if there's not enough
-    /// data left or no zero-termination could be found. This function is unsafe because there is
-    /// no guarantee that the data actually contains a string.
-    pub unsafe fn fetch_str(&mut self) -> Option<&'a OsStr> {
+    /// data left or no zero-termination could be found.
+    pub fn fetch_str(&mut self) -> Option<&'a OsStr> {
         let pos = self.data.iter().position(|&c| c == 0)?;
         let (bytes_with_zero, remaining) = self.data.split_at(pos + 1);
         self.data = remaining;
@@ -73,7 +72,12 @@ impl<'a> ArgumentIterator<'a> {
 mod tests {
     use super::*;
 
-    const TEST_DATA: [u8; 10] = [0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, 0x62, 0x61];
+    /// Test data needs to be aligned to be fetched as typed arguments.
+    #[repr(C, align(8))]
+    struct AlignedData<T>(T);
+
+    const TEST_DATA: AlignedData<[u8; 10]> =
+        AlignedData([0x66, 0x6f, 0x6f, 0x00, 0x62, 0x61, 0x72, 0x00, 0x62, 0x61]);
 
     #[repr(C)]
     struct TestArgument {
@@ -82,17 +86,19 @@ mod tests {
         p3: u16,
     }
 
+    unsafe impl FromBytes for TestArgument {}
+
     #[test]
     fn all_data() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
-        unsafe { it.fetch_str().unwrap() };
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        it.fetch_str().unwrap();
         let arg = it.fetch_all();
         assert_eq!(arg, [0x62, 0x61, 0x72, 0x00, 0x62, 0x61]);
     }
 
     #[test]
     fn bytes_data() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let arg = it.fetch_bytes(5).unwrap();
         assert_eq!(arg, [0x66, 0x6f, 0x6f, 0x00, 0x62]);
         let arg = it.fetch_bytes(2).unwrap();
@@ -102,36 +108,58 @@ mod tests {
 
     #[test]
     fn generic_argument() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg: &TestArgument = unsafe { it.fetch().unwrap() };
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        let arg: &TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
         assert_eq!(arg.p3, 0x006f);
-        let arg: &TestArgument = unsafe { it.fetch().unwrap() };
+        let arg: &TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x62);
         assert_eq!(arg.p2, 0x61);
         assert_eq!(arg.p3, 0x0072);
         assert_eq!(it.len(), 2);
     }
 
+    #[test]
+    fn unaligned_argument() {
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        let _arg = it.fetch_bytes(1).unwrap();
+        let arg: Option<&TestArgument> = it.fetch();
+        assert!(arg.is_none());
+        assert_eq!(it.len(), 5);
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn slice_argument() {
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        let args: &[TestArgument] = it.fetch_slice(2).unwrap();
+        assert_eq!(args.len(), 2);
+        assert_eq!(args[0].p1, 0x66);
+        assert_eq!(args[1].p3, 0x0072);
+        assert_eq!(it.len(), 2);
+        let args: Option<&[TestArgument]> = it.fetch_slice(1);
+        assert!(args.is_none());
+    }
+
     #[test]
     fn string_argument() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg = unsafe { it.fetch_str().unwrap() };
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "foo");
-        let arg = unsafe { it.fetch_str().unwrap() };
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "bar");
         assert_eq!(it.len(), 2);
     }
 
     #[test]
     fn mixed_arguments() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
-        let arg: &TestArgument = unsafe { it.fetch().unwrap() };
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
+        let arg: &TestArgument = it.fetch().unwrap();
         assert_eq!(arg.p1, 0x66);
         assert_eq!(arg.p2, 0x6f);
         assert_eq!(arg.p3, 0x006f);
-        let arg = unsafe { it.fetch_str().unwrap() };
+        let arg = it.fetch_str().unwrap();
         assert_eq!(arg, "bar");
         let arg = it.fetch_all();
         assert_eq!(arg, [0x62, 0x61]);
@@ -139,12 +167,12 @@ mod tests {
 
     #[test]
     fn out_of_data() {
-        let mut it = ArgumentIterator::new(&TEST_DATA);
+        let mut it = ArgumentIterator::new(&TEST_DATA.0);
         let _arg = it.fetch_bytes(8).unwrap();
-        let arg: Option<&TestArgument> = unsafe { it.fetch() };
+        let arg: Option<&TestArgument> = it.fetch();
         assert!(arg.is_none());
         assert_eq!(it.len(), 2);
-        let arg = unsafe { it.fetch_str() };
+        let arg = it.fetch_str();
         assert!(arg.is_none());
         assert_eq!(it.len(), 2);
     }
@@ -1,6 +1,14 @@
 //! Low-level kernel communication.
+//!
+//! Requests from the kernel driver are parsed into an `ll::Request` with the operation and its
+//! arguments, replies are encoded from an `ll::Response`. They're used by sessions internally,
+//! but can also be handled directly by a `RawHandler`.
 
 mod argument;
 
 mod request;
-pub use request::{Operation, Request};
+pub use request::{accepts_reply, expects_reply, peek_header, Operation, Request, RequestError};
+
+mod response;
+pub(crate) use response::out_header;
+pub use response::{Response, ResponseError};
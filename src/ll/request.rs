@@ -15,12 +15,12 @@ use super::argument::ArgumentIterator;
 pub enum RequestError {
     /// Not enough data for parsing header (short read).
     ShortReadHeader(usize),
-    /// Kernel requested an unknown operation.
-    UnknownOperation(u32),
     /// Not enough data for arguments (short read).
     ShortRead(usize, usize),
     /// Insufficient argument data.
     InsufficientData,
+    /// Request data isn't aligned for parsing in place.
+    Unaligned,
 }
 
 impl fmt::Display for RequestError {
@@ -32,11 +32,11 @@ impl fmt::Display for RequestError {
                 len,
                 mem::size_of::<FuseInHeader>()
             ),
-            RequestError::UnknownOperation(opcode) => write!(f, "Unknown FUSE opcode ({})", opcode),
             RequestError::ShortRead(len, total) => {
                 write!(f, "Short read of FUSE request ({} < {})", len, total)
             }
             RequestError::InsufficientData => write!(f, "Insufficient argument data"),
+            RequestError::Unaligned => write!(f, "Unaligned FUSE request data"),
         }
     }
 }
@@ -171,11 +171,11 @@ pub enum Operation<'a> {
     // NotifyReply {
     //     data: &'a [u8],
     // },
-    // TODO: FUSE_BATCH_FORGET since ABI 7.16
-    // BatchForget {
-    //     arg: &'a fuse_forget_in,
-    //     nodes: &'a [fuse_forget_one],
-    // },
+    #[cfg(feature = "abi-7-16")]
+    BatchForget {
+        arg: &'a fuse_batch_forget_in,
+        nodes: &'a [fuse_forget_one],
+    },
     // TODO: FUSE_FALLOCATE since ABI 7.19
     // FAllocate {
     //     arg: &'a fuse_fallocate_in,
@@ -196,6 +196,12 @@ pub enum Operation<'a> {
     // CuseInit {
     //     arg: &'a fuse_init_in,
     // },
+    /// Operation with an opcode that is unknown or not supported by the parser (e.g. a newer
+    /// or experimental one), with its unparsed argument data
+    Unknown {
+        opcode: u32,
+        data: &'a [u8],
+    },
 }
 
 impl fmt::Display for Operation<'_> {
@@ -237,6 +243,8 @@ impl fmt::Display for Operation<'_> {
             Operation::Interrupt { arg } => write!(f, "INTERRUPT unique {}", arg.unique),
             Operation::BMap { arg } => write!(f, "BMAP blocksize {}, ids {}", arg.blocksize, arg.block),
             Operation::Destroy => write!(f, "DESTROY"),
+            #[cfg(feature = "abi-7-16")]
+            Operation::BatchForget { arg, .. } => write!(f, "BATCH_FORGET count {}", arg.count),
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName { name } => write!(f, "SETVOLNAME name {:?}", name),
@@ -244,103 +252,147 @@ impl fmt::Display for Operation<'_> {
             Operation::GetXTimes => write!(f, "GETXTIMES"),
             #[cfg(target_os = "macos")]
             Operation::Exchange { arg, oldname, newname } => write!(f, "EXCHANGE olddir {:#018x}, oldname {:?}, newdir {:#018x}, newname {:?}, options {:#x}", arg.olddir, oldname, arg.newdir, newname, arg.options),
+            Operation::Unknown { opcode, data } => write!(f, "UNKNOWN opcode {}, {} bytes", opcode, data.len()),
         }
     }
 }
 
 impl<'a> Operation<'a> {
-    fn parse(opcode: &fuse_opcode, data: &mut ArgumentIterator<'a>) -> Option<Self> {
-        unsafe {
-            Some(match opcode {
-                fuse_opcode::FUSE_LOOKUP => Operation::Lookup {
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_FORGET => Operation::Forget { arg: data.fetch()? },
-                fuse_opcode::FUSE_GETATTR => Operation::GetAttr,
-                fuse_opcode::FUSE_SETATTR => Operation::SetAttr { arg: data.fetch()? },
-                fuse_opcode::FUSE_READLINK => Operation::ReadLink,
-                fuse_opcode::FUSE_SYMLINK => Operation::SymLink {
-                    name: data.fetch_str()?,
-                    link: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_MKNOD => Operation::MkNod {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_MKDIR => Operation::MkDir {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_UNLINK => Operation::Unlink {
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_RMDIR => Operation::RmDir {
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_RENAME => Operation::Rename {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                    newname: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_LINK => Operation::Link {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_OPEN => Operation::Open { arg: data.fetch()? },
-                fuse_opcode::FUSE_READ => Operation::Read { arg: data.fetch()? },
-                fuse_opcode::FUSE_WRITE => Operation::Write {
-                    arg: data.fetch()?,
-                    data: data.fetch_all(),
-                },
-                fuse_opcode::FUSE_STATFS => Operation::StatFs,
-                fuse_opcode::FUSE_RELEASE => Operation::Release { arg: data.fetch()? },
-                fuse_opcode::FUSE_FSYNC => Operation::FSync { arg: data.fetch()? },
-                fuse_opcode::FUSE_SETXATTR => Operation::SetXAttr {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                    value: data.fetch_all(),
-                },
-                fuse_opcode::FUSE_GETXATTR => Operation::GetXAttr {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_LISTXATTR => Operation::ListXAttr { arg: data.fetch()? },
-                fuse_opcode::FUSE_REMOVEXATTR => Operation::RemoveXAttr {
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_FLUSH => Operation::Flush { arg: data.fetch()? },
-                fuse_opcode::FUSE_INIT => Operation::Init { arg: data.fetch()? },
-                fuse_opcode::FUSE_OPENDIR => Operation::OpenDir { arg: data.fetch()? },
-                fuse_opcode::FUSE_READDIR => Operation::ReadDir { arg: data.fetch()? },
-                fuse_opcode::FUSE_RELEASEDIR => Operation::ReleaseDir { arg: data.fetch()? },
-                fuse_opcode::FUSE_FSYNCDIR => Operation::FSyncDir { arg: data.fetch()? },
-                fuse_opcode::FUSE_GETLK => Operation::GetLk { arg: data.fetch()? },
-                fuse_opcode::FUSE_SETLK => Operation::SetLk { arg: data.fetch()? },
-                fuse_opcode::FUSE_SETLKW => Operation::SetLkW { arg: data.fetch()? },
-                fuse_opcode::FUSE_ACCESS => Operation::Access { arg: data.fetch()? },
-                fuse_opcode::FUSE_CREATE => Operation::Create {
-                    arg: data.fetch()?,
-                    name: data.fetch_str()?,
-                },
-                fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: data.fetch()? },
-                fuse_opcode::FUSE_BMAP => Operation::BMap { arg: data.fetch()? },
-                fuse_opcode::FUSE_DESTROY => Operation::Destroy,
-
-                #[cfg(target_os = "macos")]
-                fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
-                    name: data.fetch_str()?,
-                },
-                #[cfg(target_os = "macos")]
-                fuse_opcode::FUSE_GETXTIMES => Operation::GetXTimes,
-                #[cfg(target_os = "macos")]
-                fuse_opcode::FUSE_EXCHANGE => Operation::Exchange {
-                    arg: data.fetch()?,
-                    oldname: data.fetch_str()?,
-                    newname: data.fetch_str()?,
-                },
-            })
+    /// Returns true if requests with the given opcode can be parsed. Some operations of newer
+    /// ABI versions aren't supported yet.
+    fn supported(opcode: &fuse_opcode) -> bool {
+        #[allow(unreachable_patterns)]
+        match opcode {
+            #[cfg(feature = "abi-7-11")]
+            fuse_opcode::FUSE_IOCTL | fuse_opcode::FUSE_POLL => false,
+            #[cfg(feature = "abi-7-15")]
+            fuse_opcode::FUSE_NOTIFY_REPLY => false,
+            #[cfg(feature = "abi-7-19")]
+            fuse_opcode::FUSE_FALLOCATE => false,
+            #[cfg(feature = "abi-7-12")]
+            fuse_opcode::CUSE_INIT => false,
+            _ => true,
         }
     }
+
+    /// Parse the arguments of an operation. The given amount of write data is missing at the
+    /// end of the arguments, since it was left in a pipe.
+    fn parse(
+        opcode: &fuse_opcode,
+        data: &mut ArgumentIterator<'a>,
+        spliced: usize,
+    ) -> Option<Self> {
+        Some(match opcode {
+            fuse_opcode::FUSE_LOOKUP => Operation::Lookup {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_FORGET => Operation::Forget { arg: data.fetch()? },
+            fuse_opcode::FUSE_GETATTR => Operation::GetAttr,
+            fuse_opcode::FUSE_SETATTR => Operation::SetAttr { arg: data.fetch()? },
+            fuse_opcode::FUSE_READLINK => Operation::ReadLink,
+            fuse_opcode::FUSE_SYMLINK => Operation::SymLink {
+                name: data.fetch_str()?,
+                link: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_MKNOD => Operation::MkNod {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_MKDIR => Operation::MkDir {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_UNLINK => Operation::Unlink {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_RMDIR => Operation::RmDir {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_RENAME => Operation::Rename {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+                newname: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_LINK => Operation::Link {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_OPEN => Operation::Open { arg: data.fetch()? },
+            fuse_opcode::FUSE_READ => Operation::Read { arg: data.fetch()? },
+            fuse_opcode::FUSE_WRITE => {
+                // Write data must match the size given in the argument
+                let arg: &FuseWriteIn = data.fetch()?;
+                let bytes = data.fetch_all();
+                if bytes.len() + spliced != arg.size as usize {
+                    return None;
+                }
+                Operation::Write { arg, data: bytes }
+            }
+            fuse_opcode::FUSE_STATFS => Operation::StatFs,
+            fuse_opcode::FUSE_RELEASE => Operation::Release { arg: data.fetch()? },
+            fuse_opcode::FUSE_FSYNC => Operation::FSync { arg: data.fetch()? },
+            fuse_opcode::FUSE_SETXATTR => {
+                // Value must match the size given in the argument
+                let arg: &FuseSetxattrIn = data.fetch()?;
+                let name = data.fetch_str()?;
+                let value = data.fetch_all();
+                if value.len() != arg.size as usize {
+                    return None;
+                }
+                Operation::SetXAttr { arg, name, value }
+            }
+            fuse_opcode::FUSE_GETXATTR => Operation::GetXAttr {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_LISTXATTR => Operation::ListXAttr { arg: data.fetch()? },
+            fuse_opcode::FUSE_REMOVEXATTR => Operation::RemoveXAttr {
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_FLUSH => Operation::Flush { arg: data.fetch()? },
+            fuse_opcode::FUSE_INIT => Operation::Init { arg: data.fetch()? },
+            fuse_opcode::FUSE_OPENDIR => Operation::OpenDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_READDIR => Operation::ReadDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_RELEASEDIR => Operation::ReleaseDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_FSYNCDIR => Operation::FSyncDir { arg: data.fetch()? },
+            fuse_opcode::FUSE_GETLK => Operation::GetLk { arg: data.fetch()? },
+            fuse_opcode::FUSE_SETLK => Operation::SetLk { arg: data.fetch()? },
+            fuse_opcode::FUSE_SETLKW => Operation::SetLkW { arg: data.fetch()? },
+            fuse_opcode::FUSE_ACCESS => Operation::Access { arg: data.fetch()? },
+            fuse_opcode::FUSE_CREATE => Operation::Create {
+                arg: data.fetch()?,
+                name: data.fetch_str()?,
+            },
+            fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: data.fetch()? },
+            fuse_opcode::FUSE_BMAP => Operation::BMap { arg: data.fetch()? },
+            fuse_opcode::FUSE_DESTROY => Operation::Destroy,
+            #[cfg(feature = "abi-7-16")]
+            fuse_opcode::FUSE_BATCH_FORGET => {
+                let arg: &fuse_batch_forget_in = data.fetch()?;
+                Operation::BatchForget {
+                    arg,
+                    nodes: data.fetch_slice(arg.count as usize)?,
+                }
+            }
+
+            #[cfg(target_os = "macos")]
+            fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
+                name: data.fetch_str()?,
+            },
+            #[cfg(target_os = "macos")]
+            fuse_opcode::FUSE_GETXTIMES => Operation::GetXTimes,
+            #[cfg(target_os = "macos")]
+            fuse_opcode::FUSE_EXCHANGE => Operation::Exchange {
+                arg: data.fetch()?,
+                oldname: data.fetch_str()?,
+                newname: data.fetch_str()?,
+            },
+
+            // Operations of newer ABI versions that aren't supported yet
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        })
+    }
 }
 
 /// Low-level request of a filesystem operation the kernel driver wants to perform.
@@ -360,27 +412,86 @@ impl fmt::Display for Request<'_> {
     }
 }
 
+/// Returns the header of a request without parsing its arguments, or `None` if the data is too
+/// short. Used for replying to requests that failed to parse.
+pub fn peek_header(data: &[u8]) -> Option<FuseInHeader> {
+    FuseInHeader::read_from_prefix(data)
+}
+
+/// Returns true if the kernel driver waits for a reply to requests with the given opcode
+pub fn expects_reply(opcode: u32) -> bool {
+    #[cfg(feature = "abi-7-15")]
+    if opcode == fuse_opcode::FUSE_NOTIFY_REPLY as u32 {
+        return false;
+    }
+    #[cfg(feature = "abi-7-16")]
+    if opcode == fuse_opcode::FUSE_BATCH_FORGET as u32 {
+        return false;
+    }
+    opcode != fuse_opcode::FUSE_FORGET as u32 && opcode != fuse_opcode::FUSE_INTERRUPT as u32
+}
+
+/// Returns true if the kernel driver accepts a reply to requests with the given opcode. It
+/// doesn't wait for a reply to INTERRUPT, but accepts an error (ENOSYS if interrupts aren't
+/// supported, EAGAIN if the interrupted request isn't known yet).
+pub fn accepts_reply(opcode: u32) -> bool {
+    expects_reply(opcode) || opcode == fuse_opcode::FUSE_INTERRUPT as u32
+}
+
 impl<'a> TryFrom<&'a [u8]> for Request<'a> {
     type Error = RequestError;
 
     fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Request::parse(data, 0)
+    }
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request whose data is complete except for the given amount of write data at
+    /// its end, which was left in a pipe when receiving it (see `Channel::receive_spliced`).
+    /// The write data is empty in the parsed operation then.
+    pub(crate) fn parse(data: &'a [u8], spliced: usize) -> Result<Self, RequestError> {
         // Parse a raw packet as sent by the kernel driver into typed data. Every request always
         // begins with a `fuse_in_header` struct followed by arguments depending on the opcode.
+        // Arguments are parsed in place, which needs the data to be aligned like the kernel
+        // driver's buffers. Arguments following the header are aligned if the header is.
+        if data.as_ptr().align_offset(mem::align_of::<FuseInHeader>()) != 0 {
+            return Err(RequestError::Unaligned);
+        }
         let data_len = data.len();
-        let mut data = ArgumentIterator::new(data);
+        let mut args = ArgumentIterator::new(data);
         // Parse header
-        let header: &FuseInHeader =
-            unsafe { data.fetch() }.ok_or_else(|| RequestError::ShortReadHeader(data.len()))?;
-        // Parse/check opcode
-        let opcode = fuse_opcode::try_from(header.opcode)
-            .map_err(|_: InvalidOpcodeError| RequestError::UnknownOperation(header.opcode))?;
-        // Check data size
-        if data_len < header.len as usize {
-            return Err(RequestError::ShortRead(data_len, header.len as usize));
+        let header: &FuseInHeader = args
+            .fetch()
+            .ok_or_else(|| RequestError::ShortReadHeader(args.len()))?;
+        // Check data size. Arguments must not extend beyond the length given in the header.
+        let len = header.len as usize;
+        if len < mem::size_of::<FuseInHeader>() {
+            return Err(RequestError::ShortReadHeader(len));
+        }
+        if data_len + spliced < len {
+            return Err(RequestError::ShortRead(data_len + spliced, len));
         }
-        // Parse/check operation arguments
-        let operation =
-            Operation::parse(&opcode, &mut data).ok_or(RequestError::InsufficientData)?;
+        // Only the data of write requests is left in a pipe
+        if spliced > 0
+            && (header.opcode != fuse_opcode::FUSE_WRITE as u32
+                || len < mem::size_of::<FuseInHeader>() + spliced)
+        {
+            return Err(RequestError::InsufficientData);
+        }
+        // Parse/check operation arguments. Arguments of unknown operations are kept unparsed.
+        let data = &data[mem::size_of::<FuseInHeader>()..len - spliced];
+        let operation = match fuse_opcode::try_from(header.opcode)
+            .ok()
+            .filter(Operation::supported)
+        {
+            Some(opcode) => Operation::parse(&opcode, &mut ArgumentIterator::new(data), spliced)
+                .ok_or(RequestError::InsufficientData)?,
+            None => Operation::Unknown {
+                opcode: header.opcode,
+                data,
+            },
+        };
         Ok(Self { header, operation })
     }
 }
@@ -420,6 +531,18 @@ impl Request<'_> {
         self.header.pid
     }
 
+    /// Returns true if the kernel driver waits for a reply to this request.
+    #[inline]
+    pub fn expects_reply(&self) -> bool {
+        expects_reply(self.header.opcode)
+    }
+
+    /// Returns true if the kernel driver accepts a reply to this request.
+    #[inline]
+    pub fn accepts_reply(&self) -> bool {
+        accepts_reply(self.header.opcode)
+    }
+
     /// Returns the filesystem operation (and its arguments) of this request.
     #[inline]
     pub fn operation(&self) -> &Operation<'_> {
@@ -431,8 +554,12 @@ impl Request<'_> {
 mod tests {
     use super::*;
 
+    /// Request data needs to be aligned like the kernel driver's buffers to be parsed in place.
+    #[repr(C, align(8))]
+    struct AlignedData<T>(T);
+
     #[cfg(target_endian = "big")]
-    const INIT_REQUEST: [u8; 56] = [
+    const INIT_REQUEST: AlignedData<[u8; 56]> = AlignedData([
         0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x1a, // len, opcode
         0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
@@ -440,10 +567,10 @@ mod tests {
         0xc0, 0xde, 0xba, 0x5e, 0x00, 0x00, 0x00, 0x00, // pid, padding
         0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x08, // major, minor
         0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // max_readahead, flags
-    ];
+    ]);
 
     #[cfg(target_endian = "little")]
-    const INIT_REQUEST: [u8; 56] = [
+    const INIT_REQUEST: AlignedData<[u8; 56]> = AlignedData([
         0x38, 0x00, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, // len, opcode
         0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
         0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
@@ -451,10 +578,10 @@ mod tests {
         0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
         0x07, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // major, minor
         0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // max_readahead, flags
-    ];
+    ]);
 
-    #[cfg(target_endian = "big")]
-    const MKNOD_REQUEST: [u8; 56] = [
+    #[cfg(all(target_endian = "big", not(feature = "abi-7-12")))]
+    const MKNOD_REQUEST: AlignedData<[u8; 56]> = AlignedData([
         0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x08, // len, opcode
         0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
         0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
@@ -462,10 +589,10 @@ mod tests {
         0xc0, 0xde, 0xba, 0x5e, 0x00, 0x00, 0x00, 0x00, // pid, padding
         0x00, 0x00, 0x01, 0xa4, 0x00, 0x00, 0x00, 0x00, // mode, rdev
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
-    ];
+    ]);
 
-    #[cfg(target_endian = "little")]
-    const MKNOD_REQUEST: [u8; 56] = [
+    #[cfg(all(target_endian = "little", not(feature = "abi-7-12")))]
+    const MKNOD_REQUEST: AlignedData<[u8; 56]> = AlignedData([
         0x38, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
         0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
         0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
@@ -473,11 +600,35 @@ mod tests {
         0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
         0xa4, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mode, rdev
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
-    ];
+    ]);
+
+    #[cfg(all(target_endian = "big", feature = "abi-7-12"))]
+    const MKNOD_REQUEST: AlignedData<[u8; 64]> = AlignedData([
+        0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x08, // len, opcode
+        0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
+        0xc0, 0x01, 0xd0, 0x0d, 0xc0, 0x01, 0xca, 0xfe, // uid, gid
+        0xc0, 0xde, 0xba, 0x5e, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x00, 0x00, 0x01, 0xa4, 0x00, 0x00, 0x00, 0x00, // mode, rdev
+        0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x00, // umask, padding
+        0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
+    ]);
+
+    #[cfg(all(target_endian = "little", feature = "abi-7-12"))]
+    const MKNOD_REQUEST: AlignedData<[u8; 64]> = AlignedData([
+        0x40, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0xa4, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mode, rdev
+        0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // umask, padding
+        0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
+    ]);
 
     #[test]
     fn short_read_header() {
-        match Request::try_from(&INIT_REQUEST[..20]) {
+        match Request::try_from(&INIT_REQUEST.0[..20]) {
             Err(RequestError::ShortReadHeader(20)) => (),
             _ => panic!("Unexpected request parsing result"),
         }
@@ -485,7 +636,7 @@ mod tests {
 
     #[test]
     fn short_read() {
-        match Request::try_from(&INIT_REQUEST[..48]) {
+        match Request::try_from(&INIT_REQUEST.0[..48]) {
             Err(RequestError::ShortRead(48, 56)) => (),
             _ => panic!("Unexpected request parsing result"),
         }
@@ -493,7 +644,7 @@ mod tests {
 
     #[test]
     fn init() {
-        let req = Request::try_from(&INIT_REQUEST[..]).unwrap();
+        let req = Request::try_from(&INIT_REQUEST.0[..]).unwrap();
         assert_eq!(req.header.len, 56);
         assert_eq!(req.header.opcode, 26);
         assert_eq!(req.unique(), 0xdead_beef_baad_f00d);
@@ -513,8 +664,8 @@ mod tests {
 
     #[test]
     fn mknod() {
-        let req = Request::try_from(&MKNOD_REQUEST[..]).unwrap();
-        assert_eq!(req.header.len, 56);
+        let req = Request::try_from(&MKNOD_REQUEST.0[..]).unwrap();
+        assert_eq!(req.header.len as usize, MKNOD_REQUEST.0.len());
         assert_eq!(req.header.opcode, 8);
         assert_eq!(req.unique(), 0xdead_beef_baad_f00d);
         assert_eq!(req.nodeid(), 0x1122_3344_5566_7788);
@@ -524,9 +675,53 @@ mod tests {
         match req.operation() {
             Operation::MkNod { arg, name } => {
                 assert_eq!(arg.mode, 0o644);
+                #[cfg(feature = "abi-7-12")]
+                assert_eq!(arg.umask, 0o022);
                 assert_eq!(*name, "foo.txt");
             }
             _ => panic!("Unexpected request operation"),
         }
     }
+
+    #[test]
+    fn unknown_operation() {
+        let mut data = AlignedData(INIT_REQUEST.0);
+        data.0[4..8].copy_from_slice(&0x63u32.to_ne_bytes());
+        let req = Request::try_from(&data.0[..]).unwrap();
+        assert!(req.expects_reply());
+        match req.operation() {
+            Operation::Unknown { opcode, data } => {
+                assert_eq!(*opcode, 0x63);
+                assert_eq!(data.len(), 16);
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    #[test]
+    fn invalid_length() {
+        // Length smaller than the header
+        let mut data = AlignedData(INIT_REQUEST.0);
+        data.0[..4].copy_from_slice(&32u32.to_ne_bytes());
+        match Request::try_from(&data.0[..]) {
+            Err(RequestError::ShortReadHeader(32)) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+        // Arguments beyond the length in the header are ignored
+        data.0[..4].copy_from_slice(&48u32.to_ne_bytes());
+        match Request::try_from(&data.0[..]) {
+            Err(RequestError::InsufficientData) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+    }
+
+    #[test]
+    fn unaligned() {
+        let mut data = AlignedData([0; 64]);
+        data.0[1..57].copy_from_slice(&INIT_REQUEST.0);
+        match Request::try_from(&data.0[1..57]) {
+            Err(RequestError::Unaligned) => (),
+            _ => panic!("Unexpected request parsing result"),
+        }
+    }
 }
@@ -0,0 +1,478 @@
+//! Low-level filesystem operation response.
+//!
+//! A response is the reply to a request that is sent back to the kernel driver. It's encoded as
+//! a `fuse_out_header` followed by data depending on the operation of the request.
+
+use fuse_abi::*;
+use libc::c_int;
+use std::{cmp, error, fmt, io, mem};
+
+use super::request::{Operation, Request};
+
+/// Error that may occur while decoding a response to a request.
+#[derive(Debug)]
+pub enum ResponseError {
+    /// Not enough data for the header (short read).
+    ShortReadHeader(usize),
+    /// The length in the header doesn't match the data size.
+    InvalidLength(usize, usize),
+    /// The response belongs to a different request.
+    UniqueMismatch(u64, u64),
+    /// The request doesn't get a reply.
+    UnexpectedReply,
+    /// The error code is out of range.
+    InvalidError(i32),
+    /// The data size doesn't match the response to the operation.
+    InvalidSize(usize, usize),
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::ShortReadHeader(len) => write!(
+                f,
+                "Short read of FUSE reply header ({} < {})",
+                len,
+                mem::size_of::<FuseOutHeader>()
+            ),
+            ResponseError::InvalidLength(len, total) => {
+                write!(f, "FUSE reply length mismatch ({} != {})", len, total)
+            }
+            ResponseError::UniqueMismatch(unique, expected) => {
+                write!(f, "Reply to request {} instead of {}", unique, expected)
+            }
+            ResponseError::UnexpectedReply => write!(f, "Reply to a request without reply"),
+            ResponseError::InvalidError(error) => write!(f, "Invalid error code ({})", error),
+            ResponseError::InvalidSize(len, expected) => {
+                write!(f, "Invalid reply data size ({} != {})", len, expected)
+            }
+        }
+    }
+}
+
+impl error::Error for ResponseError {}
+
+/// Response to a filesystem operation, with one variant per kind of reply data.
+#[derive(Debug)]
+pub enum Response<'a> {
+    /// Failed operation with the given error code
+    Error(c_int),
+    /// Successful operation without data
+    Empty,
+    /// Raw data (read, readlink, readdir and xattr values or lists)
+    Data(&'a [u8]),
+    Init(FuseInitOut),
+    Entry(FuseEntryOut),
+    Attr(FuseAttrOut),
+    #[cfg(target_os = "macos")]
+    XTimes(fuse_getxtimes_out),
+    Open(FuseOpenOut),
+    Write(FuseWriteOut),
+    Statfs(FuseStatfsOut),
+    Create(FuseEntryOut, FuseOpenOut),
+    Lock(FuseLkOut),
+    Bmap(FuseBmapOut),
+    /// Size of an xattr value or list (for requests with size 0)
+    XattrSize(FuseGetxattrOut),
+}
+
+/// Returns the header of a response with the given data size
+pub(crate) fn out_header(unique: u64, error: c_int, len: usize) -> FuseOutHeader {
+    FuseOutHeader {
+        len: (mem::size_of::<FuseOutHeader>() + len) as u32,
+        error: -error,
+        unique,
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Returns the error code (0 on success)
+    pub fn error(&self) -> c_int {
+        match self {
+            Response::Error(err) => *err,
+            _ => 0,
+        }
+    }
+
+    /// Returns the encoded data following the header (up to two parts)
+    fn parts(&self) -> [&[u8]; 2] {
+        match self {
+            Response::Error(_) | Response::Empty => [&[], &[]],
+            Response::Data(data) => [data, &[]],
+            Response::Init(out) => [&out.as_bytes()[..init_out_size(out.minor)], &[]],
+            Response::Entry(out) => [out.as_bytes(), &[]],
+            Response::Attr(out) => [out.as_bytes(), &[]],
+            #[cfg(target_os = "macos")]
+            Response::XTimes(out) => [out.as_bytes(), &[]],
+            Response::Open(out) => [out.as_bytes(), &[]],
+            Response::Write(out) => [out.as_bytes(), &[]],
+            Response::Statfs(out) => [out.as_bytes(), &[]],
+            // The entry is directly followed by the open reply
+            Response::Create(entry, open) => [entry.as_bytes(), open.as_bytes()],
+            Response::Lock(out) => [out.as_bytes(), &[]],
+            Response::Bmap(out) => [out.as_bytes(), &[]],
+            Response::XattrSize(out) => [out.as_bytes(), &[]],
+        }
+    }
+
+    /// Encode the response to the request with the given unique id and pass it to the given
+    /// function as a list of byte slices (to be sent with a single vectored write). The header
+    /// comes first, empty parts are left out.
+    pub fn with_iovec<R, F: FnOnce(&[&[u8]]) -> R>(&self, unique: u64, f: F) -> R {
+        let parts = self.parts();
+        let len = parts.iter().map(|part| part.len()).sum();
+        let header = out_header(unique, self.error(), len);
+        let mut iovec: [&[u8]; 3] = [header.as_bytes(), &[], &[]];
+        let mut count = 1;
+        for part in parts.iter().filter(|part| !part.is_empty()) {
+            iovec[count] = part;
+            count += 1;
+        }
+        f(&iovec[..count])
+    }
+
+    /// Decode a reply packet to the given request. The kind of reply data is determined by the
+    /// operation of the request.
+    pub fn decode(packet: &'a [u8], request: &Request<'_>) -> Result<Response<'a>, ResponseError> {
+        let header = FuseOutHeader::read_from_prefix(packet)
+            .ok_or(ResponseError::ShortReadHeader(packet.len()))?;
+        if header.len as usize != packet.len() {
+            return Err(ResponseError::InvalidLength(
+                packet.len(),
+                header.len as usize,
+            ));
+        }
+        if header.unique != request.unique() {
+            return Err(ResponseError::UniqueMismatch(
+                header.unique,
+                request.unique(),
+            ));
+        }
+        if !request.accepts_reply() {
+            return Err(ResponseError::UnexpectedReply);
+        }
+        let data = &packet[mem::size_of::<FuseOutHeader>()..];
+        // Like the kernel driver, only accept errno values (excluding internal ones)
+        if header.error > 0 || header.error <= -512 {
+            return Err(ResponseError::InvalidError(header.error));
+        }
+        if header.error < 0 {
+            return match data.len() {
+                0 => Ok(Response::Error(-header.error)),
+                len => Err(ResponseError::InvalidSize(len, 0)),
+            };
+        }
+        Ok(match request.operation() {
+            Operation::Init { .. } => Response::Init(decode_init_out(data)?),
+            Operation::Lookup { .. }
+            | Operation::SymLink { .. }
+            | Operation::MkNod { .. }
+            | Operation::MkDir { .. }
+            | Operation::Link { .. } => Response::Entry(decode_out(data)?),
+            Operation::GetAttr | Operation::SetAttr { .. } => Response::Attr(decode_out(data)?),
+            #[cfg(target_os = "macos")]
+            Operation::GetXTimes => Response::XTimes(decode_out(data)?),
+            Operation::Open { .. } | Operation::OpenDir { .. } => Response::Open(decode_out(data)?),
+            Operation::Write { .. } => Response::Write(decode_out(data)?),
+            Operation::StatFs => Response::Statfs(decode_out(data)?),
+            Operation::Create { .. } => {
+                let split = mem::size_of::<FuseEntryOut>().min(data.len());
+                let expected = mem::size_of::<FuseEntryOut>() + mem::size_of::<FuseOpenOut>();
+                let (entry, open) = data.split_at(split);
+                match (decode_out(entry), decode_out(open)) {
+                    (Ok(entry), Ok(open)) => Response::Create(entry, open),
+                    _ => return Err(ResponseError::InvalidSize(data.len(), expected)),
+                }
+            }
+            Operation::GetLk { .. } => Response::Lock(decode_out(data)?),
+            Operation::BMap { .. } => Response::Bmap(decode_out(data)?),
+            Operation::GetXAttr { arg, .. } | Operation::ListXAttr { arg } if arg.size == 0 => {
+                Response::XattrSize(decode_out(data)?)
+            }
+            Operation::ReadLink
+            | Operation::Read { .. }
+            | Operation::ReadDir { .. }
+            | Operation::GetXAttr { .. }
+            | Operation::ListXAttr { .. } => Response::Data(data),
+            _ => match data.len() {
+                0 => Response::Empty,
+                len => return Err(ResponseError::InvalidSize(len, 0)),
+            },
+        })
+    }
+}
+
+/// Size of the reply to INIT with the given minor version. Kernels before ABI 7.23 expect the
+/// shorter reply of ABI 7.22.
+fn init_out_size(minor: u32) -> usize {
+    match minor {
+        #[cfg(feature = "abi-7-23")]
+        0..=22 => consts::FUSE_COMPAT_22_INIT_OUT_SIZE,
+        _ => mem::size_of::<FuseInitOut>(),
+    }
+}
+
+/// Decode the reply data of INIT, which is shorter than `FuseInitOut` for older minor versions
+/// (see `init_out_size`)
+fn decode_init_out(data: &[u8]) -> Result<FuseInitOut, ResponseError> {
+    let mut bytes = [0; mem::size_of::<FuseInitOut>()];
+    let len = cmp::min(data.len(), bytes.len());
+    bytes[..len].copy_from_slice(&data[..len]);
+    let out = FuseInitOut::read_from_prefix(&bytes).unwrap();
+    match init_out_size(out.minor) {
+        size if size == data.len() => Ok(out),
+        size => Err(ResponseError::InvalidSize(data.len(), size)),
+    }
+}
+
+/// Decode reply data that consists of exactly the given ABI structure
+fn decode_out<T: FromBytes>(data: &[u8]) -> Result<T, ResponseError> {
+    match data.len() == mem::size_of::<T>() {
+        true => Ok(T::read_from_prefix(data).unwrap()),
+        false => Err(ResponseError::InvalidSize(data.len(), mem::size_of::<T>())),
+    }
+}
+
+impl fmt::Display for Response<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Error(err) => write!(f, "error: {}", io::Error::from_raw_os_error(*err)),
+            Response::Empty => write!(f, "ok"),
+            Response::Data(data) => write!(f, "{} bytes", data.len()),
+            Response::Init(out) => write!(
+                f,
+                "INIT ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
+                out.major, out.minor, out.flags, out.max_readahead, out.max_write
+            ),
+            Response::Entry(out) => write!(
+                f,
+                "entry ino {:#018x}, generation {}, {}",
+                out.nodeid,
+                out.generation,
+                DisplayAttr(&out.attr)
+            ),
+            Response::Attr(out) => write!(f, "attr {}", DisplayAttr(&out.attr)),
+            #[cfg(target_os = "macos")]
+            Response::XTimes(out) => {
+                write!(f, "xtimes bkuptime {}, crtime {}", out.bkuptime, out.crtime)
+            }
+            Response::Open(out) => write!(f, "open fh {}, flags {:#x}", out.fh, out.open_flags),
+            Response::Write(out) => write!(f, "written {}", out.size),
+            Response::Statfs(out) => write!(
+                f,
+                "statfs blocks {}, bfree {}, files {}, ffree {}, bsize {}",
+                out.st.blocks, out.st.bfree, out.st.files, out.st.ffree, out.st.bsize
+            ),
+            Response::Create(entry, open) => write!(
+                f,
+                "created ino {:#018x}, generation {}, {}, fh {}, flags {:#x}",
+                entry.nodeid,
+                entry.generation,
+                DisplayAttr(&entry.attr),
+                open.fh,
+                open.open_flags
+            ),
+            Response::Lock(out) => write!(
+                f,
+                "lock type {}, range {}-{}, pid {}",
+                out.lk.typ, out.lk.start, out.lk.end, out.lk.pid
+            ),
+            Response::Bmap(out) => write!(f, "block {}", out.block),
+            Response::XattrSize(out) => write!(f, "size {}", out.size),
+        }
+    }
+}
+
+/// Short description of attributes
+struct DisplayAttr<'a>(&'a FuseAttr);
+
+impl fmt::Display for DisplayAttr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let attr = self.0;
+        write!(
+            f,
+            "mode {:#o}, size {}, nlink {}, uid {}, gid {}",
+            attr.mode, attr.size, attr.nlink, attr.uid, attr.gid
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    /// Request data needs to be aligned like the kernel driver's buffers to be parsed in place.
+    #[repr(C, align(8))]
+    struct AlignedData<T>(T);
+
+    /// Build a request with the given opcode and argument
+    fn request(opcode: fuse_opcode, arg: &[u8]) -> AlignedData<[u8; 128]> {
+        let header = FuseInHeader {
+            len: (mem::size_of::<FuseInHeader>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique: 0xdeadbeef,
+            nodeid: 1,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            padding: 0,
+        };
+        let mut data = AlignedData([0; 128]);
+        data.0[..40].copy_from_slice(header.as_bytes());
+        data.0[40..40 + arg.len()].copy_from_slice(arg);
+        data
+    }
+
+    /// Encode a response to a single packet
+    fn encode(response: &Response<'_>) -> Vec<u8> {
+        response.with_iovec(0xdeadbeef, |iovec| iovec.concat())
+    }
+
+    #[test]
+    fn encode_iovec() {
+        let iovec = Response::Error(libc::ENOENT).with_iovec(1, |iovec| iovec.len());
+        assert_eq!(iovec, 1);
+        let iovec = Response::Data(b"").with_iovec(1, |iovec| iovec.len());
+        assert_eq!(iovec, 1);
+        let open = FuseOpenOut {
+            fh: 1,
+            open_flags: 0,
+            padding: 0,
+        };
+        let response = Response::Create(FuseEntryOut::new_zeroed(), open);
+        let sizes: Vec<_> = response.with_iovec(1, |iovec| iovec.iter().map(|v| v.len()).collect());
+        assert_eq!(sizes, [16, mem::size_of::<FuseEntryOut>(), 16]);
+    }
+
+    #[test]
+    fn error() {
+        let data = request(fuse_opcode::FUSE_GETATTR, &[]);
+        let req = Request::try_from(&data.0[..]).unwrap();
+        let packet = encode(&Response::Error(libc::ENOENT));
+        assert_eq!(
+            packet,
+            [0x10, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff, 0xef, 0xbe, 0xad, 0xde, 0, 0, 0, 0]
+        );
+        match Response::decode(&packet, &req) {
+            Ok(Response::Error(libc::ENOENT)) => (),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = request(
+            fuse_opcode::FUSE_WRITE,
+            FuseWriteIn::new_zeroed().as_bytes(),
+        );
+        let req = Request::try_from(&data.0[..]).unwrap();
+        let packet = encode(&Response::Write(FuseWriteOut {
+            size: 42,
+            padding: 0,
+        }));
+        match Response::decode(&packet, &req) {
+            Ok(Response::Write(out)) => assert_eq!(out.size, 42),
+            res => panic!("Unexpected {:?}", res),
+        }
+
+        let open = FuseOpenOut {
+            fh: 7,
+            open_flags: 1,
+            padding: 0,
+        };
+        let mut arg = FuseCreateIn::new_zeroed().as_bytes().to_vec();
+        arg.extend_from_slice(b"x\0");
+        let data = request(fuse_opcode::FUSE_CREATE, &arg);
+        let req = Request::try_from(&data.0[..]).unwrap();
+        let packet = encode(&Response::Create(FuseEntryOut::new_zeroed(), open));
+        match Response::decode(&packet, &req) {
+            Ok(Response::Create(_, open)) => assert_eq!((open.fh, open.open_flags), (7, 1)),
+            res => panic!("Unexpected {:?}", res),
+        }
+        assert!(Response::decode(&packet[..packet.len() - 1], &req).is_err());
+    }
+
+    #[test]
+    fn init() {
+        let arg = FuseInitIn {
+            major: 7,
+            minor: 22,
+            max_readahead: 0,
+            flags: 0,
+        };
+        let data = request(fuse_opcode::FUSE_INIT, arg.as_bytes());
+        let req = Request::try_from(&data.0[..]).unwrap();
+        // Replies before ABI 7.23 have the size of ABI 7.22
+        for minor in [22, FUSE_KERNEL_MINOR_VERSION] {
+            let mut out = FuseInitOut::new_zeroed();
+            out.major = 7;
+            out.minor = minor;
+            out.max_write = 4096;
+            let packet = encode(&Response::Init(out));
+            let expected = match minor {
+                #[cfg(feature = "abi-7-23")]
+                22 => 24,
+                _ => mem::size_of::<FuseInitOut>(),
+            };
+            assert_eq!(packet.len(), 16 + expected);
+            match Response::decode(&packet, &req) {
+                Ok(Response::Init(out)) => assert_eq!((out.minor, out.max_write), (minor, 4096)),
+                res => panic!("Unexpected {:?}", res),
+            }
+            assert!(Response::decode(&packet[..packet.len() - 4], &req).is_err());
+        }
+    }
+
+    #[test]
+    fn xattr_size() {
+        let mut arg = FuseGetxattrIn::new_zeroed();
+        let data = request(fuse_opcode::FUSE_LISTXATTR, arg.as_bytes());
+        let req = Request::try_from(&data.0[..]).unwrap();
+        let packet = encode(&Response::XattrSize(FuseGetxattrOut {
+            size: 8,
+            padding: 0,
+        }));
+        match Response::decode(&packet, &req) {
+            Ok(Response::XattrSize(out)) => assert_eq!(out.size, 8),
+            res => panic!("Unexpected {:?}", res),
+        }
+        arg.size = 16;
+        let data = request(fuse_opcode::FUSE_LISTXATTR, arg.as_bytes());
+        let req = Request::try_from(&data.0[..]).unwrap();
+        match Response::decode(&packet, &req) {
+            Ok(Response::Data(data)) => assert_eq!(data.len(), 8),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let data = request(fuse_opcode::FUSE_GETATTR, &[]);
+        let req = Request::try_from(&data.0[..]).unwrap();
+        let packet = encode(&Response::Empty);
+        assert!(matches!(
+            Response::decode(&packet, &req),
+            Err(ResponseError::InvalidSize(0, _))
+        ));
+        assert!(matches!(
+            Response::decode(&packet[..8], &req),
+            Err(ResponseError::ShortReadHeader(8))
+        ));
+        let packet = Response::Empty.with_iovec(1, |iovec| iovec.concat());
+        assert!(matches!(
+            Response::decode(&packet, &req),
+            Err(ResponseError::UniqueMismatch(1, 0xdeadbeef))
+        ));
+        let data = request(
+            fuse_opcode::FUSE_FORGET,
+            FuseForgetIn { nlookup: 1 }.as_bytes(),
+        );
+        let req = Request::try_from(&data.0[..]).unwrap();
+        let packet = encode(&Response::Empty);
+        assert!(matches!(
+            Response::decode(&packet, &req),
+            Err(ResponseError::UnexpectedReply)
+        ));
+    }
+}
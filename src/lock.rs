@@ -0,0 +1,433 @@
+//! File lock manager
+//!
+//! The kernel only sends lock requests to the filesystem if remote locking is enabled (see
+//! `Session::set_locks`), otherwise files are locked locally on each host. `LockManager`
+//! implements the semantics of POSIX byte-range locks (fcntl(2)) and BSD flock(2) locks for
+//! filesystems that need to handle lock requests themselves. Requests that wait for a lock
+//! (SETLKW) keep their reply until the lock is granted or the request is interrupted, so the
+//! session loop is never blocked.
+
+use libc::{c_int, EAGAIN, EDEADLK, EINTR, EINVAL};
+use std::collections::{HashMap, HashSet};
+
+use crate::{LockRequest, LockType, ReleaseFlags, ReplyEmpty, ReplyLock, RequestContext};
+
+/// A granted lock
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Lock {
+    owner: u64,
+    start: u64,
+    /// End of the range (inclusive)
+    end: u64,
+    typ: LockType,
+    pid: u32,
+    flock: bool,
+}
+
+impl Lock {
+    /// Returns true if the lock overlaps the given range
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Returns true if the lock prevents the other lock from being granted. POSIX locks and
+    /// flock locks don't interact, and locks of the same owner never conflict.
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.flock == other.flock
+            && self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == LockType::WRITE || other.typ == LockType::WRITE)
+    }
+
+    /// Returns true if the lock belongs to the given owner and lock kind
+    fn owned_by(&self, owner: u64, flock: bool) -> bool {
+        self.owner == owner && self.flock == flock
+    }
+}
+
+impl From<&LockRequest> for Lock {
+    fn from(lock: &LockRequest) -> Lock {
+        Lock {
+            owner: lock.lock_owner,
+            start: lock.start,
+            end: lock.end,
+            typ: lock.typ,
+            pid: lock.pid,
+            flock: lock.flock,
+        }
+    }
+}
+
+/// A setlk request that waits for a lock
+#[derive(Debug)]
+struct Waiter {
+    unique: u64,
+    ino: u64,
+    lock: Lock,
+    reply: ReplyEmpty,
+}
+
+/// In-memory manager of the file locks of all inodes
+#[derive(Debug, Default)]
+pub struct LockManager {
+    locks: HashMap<u64, Vec<Lock>>,
+    waiters: Vec<Waiter>,
+}
+
+impl LockManager {
+    /// Create a new lock manager without any locks
+    pub fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    /// Returns the number of requests that wait for a lock
+    pub fn waiting(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// Reply to a getlk request with the first lock that conflicts with the requested one, or
+    /// with the requested range and an unlock type if the lock could be granted.
+    pub fn getlk(&self, ino: u64, lock: &LockRequest, reply: ReplyLock) {
+        let lock = Lock::from(lock);
+        if let Err(err) = check(&lock) {
+            return reply.error(err);
+        }
+        match self.conflicts(ino, lock).next() {
+            Some(other) => reply.locked(other.start, other.end, other.typ, other.pid),
+            None => reply.locked(lock.start, lock.end, LockType::UNLOCK, 0),
+        }
+    }
+
+    /// Handle a setlk request: acquire, change or release a lock. A conflicting lock fails with
+    /// EAGAIN, unless `sleep` is set (SETLKW). In that case the request waits until the lock
+    /// can be granted, is interrupted (see `interrupt`), or fails with EDEADLK if the owners of
+    /// the conflicting POSIX locks wait for a lock of the requesting owner.
+    pub fn setlk(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        lock: &LockRequest,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let lock = Lock::from(lock);
+        if let Err(err) = check(&lock) {
+            return reply.error(err);
+        }
+        if lock.typ == LockType::UNLOCK {
+            self.unlock(ino, lock.owner, lock.flock, lock.start, lock.end);
+        } else if self.conflicts(ino, lock).next().is_some() {
+            if !sleep {
+                reply.error(EAGAIN);
+            } else if !lock.flock && self.deadlock(ino, &lock) {
+                reply.error(EDEADLK);
+            } else {
+                self.waiters.push(Waiter {
+                    unique: req.unique(),
+                    ino,
+                    lock,
+                    reply,
+                });
+            }
+            return;
+        } else {
+            self.grant(ino, lock);
+        }
+        reply.ok();
+        // Unlocking or downgrading a lock may allow waiting requests to continue
+        self.wake(ino);
+    }
+
+    /// Cancel the waiting setlk request with the given unique id, which is replied with EINTR.
+    /// Returns false if no request with this id is waiting (e.g. because the lock was granted
+    /// in the meantime, or because the request wasn't dispatched yet if requests are
+    /// dispatched on several threads, in which case the interrupt should fail with EAGAIN).
+    pub fn interrupt(&mut self, unique: u64) -> bool {
+        match self.waiters.iter().position(|w| w.unique == unique) {
+            Some(i) => {
+                self.waiters.remove(i).reply.error(EINTR);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all POSIX locks of the given owner (on flush, i.e. when the owner closes a file
+    /// descriptor of the inode)
+    pub fn flush(&mut self, ino: u64, lock_owner: u64) {
+        self.unlock(ino, lock_owner, false, 0, u64::MAX);
+        self.wake(ino);
+    }
+
+    /// Remove the locks of the given owner on release as requested by the release flags:
+    /// POSIX locks for FLUSH and flock locks for FLOCK_UNLOCK.
+    pub fn release(&mut self, ino: u64, lock_owner: u64, flags: ReleaseFlags) {
+        if flags.contains(ReleaseFlags::FLUSH) {
+            self.unlock(ino, lock_owner, false, 0, u64::MAX);
+        }
+        if flags.contains(ReleaseFlags::FLOCK_UNLOCK) {
+            self.unlock(ino, lock_owner, true, 0, u64::MAX);
+        }
+        self.wake(ino);
+    }
+
+    /// Returns the locks of the inode that conflict with the given lock
+    fn conflicts(&self, ino: u64, lock: Lock) -> impl Iterator<Item = &Lock> {
+        self.locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(move |other| other.conflicts(&lock))
+    }
+
+    /// Returns true if the owners of the locks that conflict with the given lock wait
+    /// (directly or through other owners) for a lock of the given lock's owner
+    fn deadlock(&self, ino: u64, lock: &Lock) -> bool {
+        let mut blockers: Vec<u64> = self.conflicts(ino, *lock).map(|l| l.owner).collect();
+        let mut seen = HashSet::new();
+        while let Some(owner) = blockers.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+            if !seen.insert(owner) {
+                continue;
+            }
+            for waiter in self
+                .waiters
+                .iter()
+                .filter(|w| w.lock.owned_by(owner, false))
+            {
+                blockers.extend(self.conflicts(waiter.ino, waiter.lock).map(|l| l.owner));
+            }
+        }
+        false
+    }
+
+    /// Add a lock that doesn't conflict. Replaces the owner's locks in the range, and merges
+    /// it with the owner's adjacent or overlapping locks of the same type.
+    fn grant(&mut self, ino: u64, mut lock: Lock) {
+        self.unlock(ino, lock.owner, lock.flock, lock.start, lock.end);
+        let locks = self.locks.entry(ino).or_default();
+        locks.retain(|other| {
+            let adjacent = other.overlaps(lock.start.saturating_sub(1), lock.end.saturating_add(1));
+            if other.owned_by(lock.owner, lock.flock) && other.typ == lock.typ && adjacent {
+                lock.start = lock.start.min(other.start);
+                lock.end = lock.end.max(other.end);
+                false
+            } else {
+                true
+            }
+        });
+        locks.push(lock);
+    }
+
+    /// Remove the given range from the owner's locks. Locks that extend beyond the range are
+    /// split.
+    fn unlock(&mut self, ino: u64, owner: u64, flock: bool, start: u64, end: u64) {
+        let Some(locks) = self.locks.get_mut(&ino) else {
+            return;
+        };
+        let mut rest = Vec::new();
+        locks.retain(|lock| {
+            if !lock.owned_by(owner, flock) || !lock.overlaps(start, end) {
+                return true;
+            }
+            if lock.start < start {
+                rest.push(Lock {
+                    end: start - 1,
+                    ..*lock
+                });
+            }
+            if lock.end > end {
+                rest.push(Lock {
+                    start: end + 1,
+                    ..*lock
+                });
+            }
+            false
+        });
+        locks.extend(rest);
+        if locks.is_empty() {
+            self.locks.remove(&ino);
+        }
+    }
+
+    /// Grant the locks of waiting requests of the inode that don't conflict anymore (in the
+    /// order the requests arrived)
+    fn wake(&mut self, ino: u64) {
+        let mut i = 0;
+        while i < self.waiters.len() {
+            let waiter = &self.waiters[i];
+            if waiter.ino == ino && self.conflicts(ino, waiter.lock).next().is_none() {
+                let waiter = self.waiters.remove(i);
+                self.grant(ino, waiter.lock);
+                waiter.reply.ok();
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Check that a lock request is valid
+fn check(lock: &Lock) -> Result<(), c_int> {
+    let valid_type = [LockType::READ, LockType::WRITE, LockType::UNLOCK].contains(&lock.typ);
+    if !valid_type || lock.start > lock.end {
+        return Err(EINVAL);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::LockManager;
+    use crate::testing::recv_reply;
+    use crate::{LockRequest, LockType, ReleaseFlags, Reply, RequestContext};
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn request(owner: u64, start: u64, end: u64, typ: LockType) -> LockRequest {
+        LockRequest {
+            fh: 0,
+            lock_owner: owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+            flock: false,
+        }
+    }
+
+    fn context(unique: u64) -> RequestContext {
+        RequestContext::new(unique, 0, 0, 0)
+    }
+
+    /// Returns the error of the next reply
+    fn error(rx: &Receiver<Vec<u8>>) -> i32 {
+        recv_reply(rx).error
+    }
+
+    /// Returns start, end, type and pid of the next getlk reply
+    fn locked(rx: &Receiver<Vec<u8>>) -> (u64, u64, LockType, u32) {
+        let data = recv_reply(rx).data;
+        (
+            u64::from_ne_bytes(data[0..8].try_into().unwrap()),
+            u64::from_ne_bytes(data[8..16].try_into().unwrap()),
+            LockType::from_raw(u32::from_ne_bytes(data[16..20].try_into().unwrap())),
+            u32::from_ne_bytes(data[20..24].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut locks = LockManager::new();
+        let write = request(1, 0, 99, LockType::WRITE);
+        locks.setlk(&context(1), 2, &write, false, Reply::new(1, tx.clone()));
+        assert_eq!(error(&rx), 0);
+        // Unlocking the middle splits the lock
+        let unlock = request(1, 40, 59, LockType::UNLOCK);
+        locks.setlk(&context(2), 2, &unlock, false, Reply::new(2, tx.clone()));
+        assert_eq!(error(&rx), 0);
+        let test = request(2, 50, 50, LockType::WRITE);
+        locks.getlk(2, &test, Reply::new(3, tx.clone()));
+        assert_eq!(locked(&rx), (50, 50, LockType::UNLOCK, 0));
+        let test = request(2, 60, u64::MAX, LockType::READ);
+        locks.getlk(2, &test, Reply::new(4, tx.clone()));
+        assert_eq!(locked(&rx), (60, 99, LockType::WRITE, 1));
+        // Locking the gap again merges the parts into one lock
+        let write = request(1, 40, 59, LockType::WRITE);
+        locks.setlk(&context(5), 2, &write, false, Reply::new(5, tx.clone()));
+        assert_eq!(error(&rx), 0);
+        let test = request(2, 0, u64::MAX, LockType::READ);
+        locks.getlk(2, &test, Reply::new(6, tx));
+        assert_eq!(locked(&rx), (0, 99, LockType::WRITE, 1));
+    }
+
+    #[test]
+    fn conflicts() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut locks = LockManager::new();
+        for owner in 1..=2 {
+            let read = request(owner, 0, 9, LockType::READ);
+            locks.setlk(
+                &context(owner),
+                2,
+                &read,
+                false,
+                Reply::new(owner, tx.clone()),
+            );
+            assert_eq!(error(&rx), 0);
+        }
+        let write = request(3, 5, 5, LockType::WRITE);
+        locks.setlk(&context(3), 2, &write, false, Reply::new(3, tx.clone()));
+        assert_eq!(error(&rx), libc::EAGAIN);
+        // Other inodes and flock locks don't conflict with POSIX locks
+        locks.setlk(&context(4), 3, &write, false, Reply::new(4, tx.clone()));
+        assert_eq!(error(&rx), 0);
+        let flock = LockRequest {
+            flock: true,
+            ..write
+        };
+        locks.setlk(&context(5), 2, &flock, false, Reply::new(5, tx.clone()));
+        assert_eq!(error(&rx), 0);
+        // flock locks are removed on release
+        let flock = LockRequest {
+            lock_owner: 4,
+            ..flock
+        };
+        locks.setlk(&context(6), 2, &flock, false, Reply::new(6, tx.clone()));
+        assert_eq!(error(&rx), libc::EAGAIN);
+        locks.release(2, 3, ReleaseFlags::FLOCK_UNLOCK);
+        locks.setlk(&context(7), 2, &flock, false, Reply::new(7, tx));
+        assert_eq!(error(&rx), 0);
+    }
+
+    #[test]
+    fn wait_and_interrupt() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut locks = LockManager::new();
+        let write = request(1, 0, 9, LockType::WRITE);
+        locks.setlk(&context(1), 2, &write, false, Reply::new(1, tx.clone()));
+        assert_eq!(error(&rx), 0);
+        for owner in 2..=3 {
+            let write = request(owner, 0, 9, LockType::WRITE);
+            locks.setlk(
+                &context(owner),
+                2,
+                &write,
+                true,
+                Reply::new(owner, tx.clone()),
+            );
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(locks.waiting(), 2);
+        // The first waiting request is interrupted, the second gets the lock on flush
+        assert!(locks.interrupt(2));
+        assert_eq!(error(&rx), libc::EINTR);
+        locks.flush(2, 1);
+        assert_eq!(error(&rx), 0);
+        assert_eq!(locks.waiting(), 0);
+        assert!(!locks.interrupt(3));
+        let test = request(1, 0, 0, LockType::READ);
+        locks.getlk(2, &test, Reply::new(4, tx));
+        assert_eq!(locked(&rx), (0, 9, LockType::WRITE, 3));
+    }
+
+    #[test]
+    fn deadlock() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut locks = LockManager::new();
+        let a = request(1, 0, 9, LockType::WRITE);
+        let b = request(2, 10, 19, LockType::WRITE);
+        locks.setlk(&context(1), 2, &a, false, Reply::new(1, tx.clone()));
+        locks.setlk(&context(2), 2, &b, false, Reply::new(2, tx.clone()));
+        assert_eq!((error(&rx), error(&rx)), (0, 0));
+        // Owner 1 waits for owner 2, so owner 2 can't wait for owner 1
+        let wait = request(1, 10, 19, LockType::WRITE);
+        locks.setlk(&context(3), 2, &wait, true, Reply::new(3, tx.clone()));
+        let wait = request(2, 0, 9, LockType::WRITE);
+        locks.setlk(&context(4), 2, &wait, true, Reply::new(4, tx));
+        assert_eq!(error(&rx), libc::EDEADLK);
+        assert_eq!(locks.waiting(), 1);
+    }
+}
@@ -1,248 +1,359 @@
-use std::collections::HashMap;
+//! In-memory filesystem
+//!
+//! `MemoryFS` keeps files, directories and symbolic links in memory. Inodes are freed when
+//! they're neither linked nor referenced by the kernel or an open handle anymore, so unlinked
+//! files can still be used while they're open. Permissions are checked against the uid and gid
+//! of the request (supplementary groups are not considered).
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 
-use crate::{FileAttr, FileType, Filesystem};
-use libc::{c_int, ENOENT};
+use crate::dir::DirSnapshot;
+use crate::fallible::Errno;
+use crate::handle::HandleTable;
+use crate::inode::InodeTable;
+use crate::lock::LockManager;
+use crate::reply::mode_from_kind_and_perm;
+use crate::{
+    FileAttr, FileType, Filesystem, FopenFlags, LockRequest, OpenFlags, ReleaseFlags, ReplyAttr,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLock, ReplyOpen,
+    ReplyStatfs, ReplyWrite, RequestContext, SetAttr, Statfs, FUSE_ROOT_ID,
+};
+use libc::{
+    c_int, EACCES, EBADF, EEXIST, EINVAL, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY,
+    EPERM, R_OK, W_OK, X_OK,
+};
 use log::debug;
 
 const BLOCK_SIZE: u32 = 4096;
 const FRSIZE: u32 = BLOCK_SIZE;
+const NAME_MAX: usize = 255;
+const TTL: Duration = Duration::from_secs(1);
+
+/// Contents of an inode
+enum Contents {
+    File(Vec<u8>),
+    Dir(Dir),
+    Symlink(PathBuf),
+    /// Device, fifo or socket
+    Node,
+}
 
-/// A simple in-memory filesystem
-pub struct MemoryFS {
-    max_size: u64,
-    inodes_num: u64,
-    inodes: HashMap<u64, (String, FileAttr)>, // ino -> FileAttr
-    // name_inode: HashMap<String, u64>, //  name -> ino
-    data: HashMap<u64, Vec<u8>>, // ino -> data
-    // Use a HashMap to represent parent and children relationships
-    // parent_ino -> Vec<child_ino>
-    parent_children: HashMap<u64, Vec<u64>>,
+/// Directory with its parent and entries (without `.` and `..`)
+struct Dir {
+    parent: u64,
+    entries: BTreeMap<OsString, u64>,
 }
 
-impl Filesystem for MemoryFS {
-    fn init(&mut self) -> Result<(), c_int> {
-        let root_file = FileAttr {
-            ino: 1,
-            kind: FileType::Directory,
-            ..Default::default()
-        };
+/// Inode with attributes and contents
+struct Inode {
+    attr: FileAttr,
+    contents: Contents,
+    /// Number of open handles
+    open: u64,
+}
 
-        self.inodes.insert(1, (".".to_string(), root_file));
+impl Inode {
+    /// Returns the directory of a directory inode
+    fn dir(&self) -> Result<&Dir, c_int> {
+        match &self.contents {
+            Contents::Dir(dir) => Ok(dir),
+            _ => Err(ENOTDIR),
+        }
+    }
 
-        let parent_file = FileAttr {
-            ino: 2,
-            kind: FileType::Directory,
-            ..Default::default()
+    /// Returns the directory of a directory inode for modification
+    fn dir_mut(&mut self) -> Result<&mut Dir, c_int> {
+        match &mut self.contents {
+            Contents::Dir(dir) => Ok(dir),
+            _ => Err(ENOTDIR),
+        }
+    }
+
+    /// Checks that the user of the request has the given access (`R_OK`, `W_OK` and `X_OK`)
+    fn check_access(&self, req: &RequestContext, mask: c_int) -> Result<(), c_int> {
+        let attr = &self.attr;
+        let bits = if req.uid() == 0 {
+            // Root may read and write anything, and execute if any execute bit is set
+            match attr.kind == FileType::Directory || attr.perm & 0o111 != 0 {
+                true => 0o7,
+                false => 0o6,
+            }
+        } else if req.uid() == attr.uid {
+            attr.perm >> 6
+        } else if req.gid() == attr.gid {
+            attr.perm >> 3
+        } else {
+            attr.perm
         };
+        match mask & !(bits as c_int) & 0o7 {
+            0 => Ok(()),
+            _ => Err(EACCES),
+        }
+    }
 
-        self.inodes.insert(2, ("..".to_string(), parent_file));
-        self.parent_children.insert(1, vec![]);
+    /// Checks that the user of the request owns the inode (or is root)
+    fn check_owner(&self, req: &RequestContext) -> Result<(), c_int> {
+        match req.uid() == 0 || req.uid() == self.attr.uid {
+            true => Ok(()),
+            false => Err(EPERM),
+        }
+    }
+
+    /// Update modification and change time (after changing the contents)
+    fn modified(&mut self) {
+        let now = SystemTime::now();
+        self.attr.mtime = now;
+        self.attr.ctime = now;
+    }
+}
+
+/// Open file or directory
+struct OpenFile {
+    ino: u64,
+    flags: OpenFlags,
+    /// Entries of an open directory at the time it was opened
+    dir: Option<DirSnapshot>,
+}
+
+/// A simple in-memory filesystem
+pub struct MemoryFS {
+    max_size: u64,
+    /// Number of bytes used by file contents
+    used: u64,
+    inodes: InodeTable<Inode>,
+    handles: HandleTable<OpenFile>,
+    locks: LockManager,
+}
 
-        self.inodes_num = 2;
+impl Filesystem for MemoryFS {
+    fn init(&mut self, req: &RequestContext) -> Result<(), c_int> {
+        // The root directory belongs to the user that mounted the filesystem
+        if let Some(root) = self.inodes.get_mut(FUSE_ROOT_ID) {
+            root.attr.uid = req.uid();
+            root.attr.gid = req.gid();
+        }
         Ok(())
     }
 
-    /*
-        pub blocks: u64,  // Total blocks (in units of frsize)
-        pub bfree: u64,   // Free blocks
-        pub bavail: u64,  // Free blocks for unprivileged users
-        pub files: u64,   // Total inodes
-        pub ffree: u64,   // Free inodes
-        pub bsize: u32,   // Filesystem block size
-        pub namelen: u32, // Maximum filename length
-        pub frsize: u32,  // Fundamental file system block size
-    */
-    fn statfs(&mut self, _ino: u64, reply: crate::ReplyStatfs) {
+    fn statfs(&mut self, _req: &RequestContext, _ino: u64, reply: ReplyStatfs) {
         let blocks = self.max_size / FRSIZE as u64;
-        let bfree = blocks;
-        let bavail = blocks;
-        let files = self.inodes_num;
-
-        reply.statfs(blocks, bfree, bavail, files, 1000, BLOCK_SIZE, 255, FRSIZE)
+        let bfree = (self.max_size - self.used) / FRSIZE as u64;
+        reply.statfs(&Statfs {
+            blocks,
+            bfree,
+            bavail: bfree,
+            files: self.inodes.len() as u64,
+            ffree: 1000,
+            bsize: BLOCK_SIZE,
+            namelen: NAME_MAX as u32,
+            frsize: FRSIZE,
+        })
     }
 
-    fn getattr(&mut self, ino: u64, reply: crate::ReplyAttr) {
-        if let Some((_, file_attr)) = self.inodes.get(&ino) {
-            reply.attr(&Duration::new(1, 0), file_attr);
-        } else {
-            reply.error(ENOENT);
+    fn lookup(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let res = self.inode(parent).and_then(|dir| {
+            dir.check_access(req, X_OK)?;
+            dir.dir()?.entries.get(name).copied().ok_or(ENOENT)
+        });
+        match res {
+            Ok(ino) => self.reply_entry(ino, reply),
+            Err(err) => reply.error(err),
         }
     }
 
-    fn opendir(&mut self, _ino: u64, _flags: u32, reply: crate::ReplyOpen) {
-        reply.opened(0, 0);
+    fn forget(&mut self, _req: &RequestContext, ino: u64, nlookup: u64) {
+        if self.inodes.forget(ino, nlookup) {
+            self.collect(ino);
+        }
     }
 
-    fn readdir(&mut self, ino: u64, _fh: u64, offset: i64, mut reply: crate::ReplyDirectory) {
-        if let Some(children) = self.parent_children.get(&ino) {
-            for (i, ino) in children.iter().enumerate().skip(offset as usize) {
-                if let Some((name, file_attr)) = self.inodes.get(ino) {
-                    reply.add(*ino, (i + 1) as i64, file_attr.kind, name);
-                } else {
-                    reply.error(ENOENT);
-                    return;
-                }
-            }
+    fn getattr(&mut self, _req: &RequestContext, ino: u64, reply: ReplyAttr) {
+        match self.inode(ino) {
+            Ok(inode) => reply.attr(&TTL, &inode.attr),
+            Err(err) => reply.error(err),
         }
-
-        reply.ok();
     }
 
-    fn releasedir(&mut self, _ino: u64, _fh: u64, _flags: u32, reply: crate::ReplyEmpty) {
-        reply.ok();
+    fn setattr(&mut self, req: &RequestContext, ino: u64, attr: &SetAttr, reply: ReplyAttr) {
+        match self.setattr(req, ino, attr) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
     }
 
-    fn rmdir(&mut self, parent: u64, name: &std::ffi::OsStr, reply: crate::ReplyEmpty) {
-        if let Some(name_str) = name.to_str() {
-            self.get_node_by_name(parent, name_str);
-            self.inodes.remove(&parent);
-            self.parent_children
-                .get_mut(&parent)
-                .unwrap()
-                .retain(|x| *x != parent);
+    fn readlink(&mut self, _req: &RequestContext, ino: u64, reply: ReplyData) {
+        match self.inode(ino).map(|inode| &inode.contents) {
+            Ok(Contents::Symlink(target)) => reply.data(target.as_os_str().as_bytes()),
+            Ok(_) => reply.error(EINVAL),
+            Err(err) => reply.error(err),
+        }
+    }
 
-            reply.ok();
+    fn mknod(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        // Regular files are also created if the mode has no file type
+        let kinds = [
+            FileType::RegularFile,
+            FileType::NamedPipe,
+            FileType::CharDevice,
+            FileType::BlockDevice,
+            FileType::Socket,
+        ];
+        let fmt = match mode & 0o170000 {
+            0 => mode_from_kind_and_perm(FileType::RegularFile, 0),
+            fmt => fmt,
+        };
+        let Some(kind) = kinds
+            .into_iter()
+            .find(|&kind| fmt == mode_from_kind_and_perm(kind, 0))
+        else {
+            reply.error(EINVAL);
             return;
+        };
+        let contents = match kind {
+            FileType::RegularFile => Contents::File(Vec::new()),
+            _ => Contents::Node,
+        };
+        let res = self.make(req, parent, name, kind, mode, contents);
+        match res.and_then(|ino| self.inode_mut(ino)) {
+            Ok(inode) => {
+                inode.attr.rdev = rdev;
+                let ino = inode.attr.ino;
+                self.reply_entry(ino, reply);
+            }
+            Err(err) => reply.error(err),
         }
-
-        reply.error(ENOENT);
     }
 
-    fn lookup(&mut self, parent: u64, name: &std::ffi::OsStr, reply: crate::ReplyEntry) {
-        match name
-            .to_str()
-            .and_then(|name_str| self.get_node_by_name(parent, name_str))
-        {
-            Some(ino) => {
-                if let Some((_, file_attr)) = self.inodes.get(&ino) {
-                    reply.entry(&Duration::new(1, 0), file_attr, 0);
-                } else {
-                    reply.error(ENOENT);
-                }
-            }
-            None => {
-                reply.error(ENOENT);
-            }
+    fn mkdir(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        reply: ReplyEntry,
+    ) {
+        let dir = Dir {
+            parent,
+            entries: BTreeMap::new(),
+        };
+        let kind = FileType::Directory;
+        match self.make(req, parent, name, kind, mode, Contents::Dir(dir)) {
+            Ok(ino) => self.reply_entry(ino, reply),
+            Err(err) => reply.error(err),
         }
     }
 
-    fn open(&mut self, _ino: u64, _flags: u32, reply: crate::ReplyOpen) {
-        reply.opened(0, 0);
+    fn unlink(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove(req, parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
-    fn read(&mut self, ino: u64, _fh: u64, offset: i64, _size: u32, reply: crate::ReplyData) {
-        if let Some(data) = self.data.get(&ino) {
-            reply.data(&data[offset as usize..]);
-        } else {
-            reply.error(ENOENT);
+    fn rmdir(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove(req, parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
         }
     }
 
-    fn release(
+    fn symlink(
         &mut self,
-        _ino: u64,
-        _fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
-        _flush: bool,
-        reply: crate::ReplyEmpty,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
     ) {
-        reply.ok();
+        let contents = Contents::Symlink(link.to_path_buf());
+        match self.make(req, parent, name, FileType::Symlink, 0o777, contents) {
+            Ok(ino) => self.reply_entry(ino, reply),
+            Err(err) => reply.error(err),
+        }
     }
 
-    // Create a new file
-    fn create(
+    fn rename(
         &mut self,
+        req: &RequestContext,
         parent: u64,
-        name: &std::ffi::OsStr,
-        _mode: u32,
-        _flags: u32,
-        reply: crate::ReplyCreate,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
     ) {
-        if !self.inodes.contains_key(&parent) {
-            reply.error(ENOENT);
-            return;
+        match self.rename(req, parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
         }
-
-        let ino = self.inodes_num + 1;
-        let file_attr = FileAttr {
-            ino,
-            kind: FileType::RegularFile,
-            perm: 0o755,
-            ..Default::default()
-        };
-
-        self.inodes
-            .insert(ino, (name.to_str().unwrap().to_string(), file_attr));
-
-        self.inodes_num += 1;
-        self.data.insert(ino, Vec::new());
-
-        self.parent_children.get_mut(&parent).unwrap().push(ino);
-        reply.created(&Duration::new(1, 0), &file_attr, 0, 0, 0);
     }
 
-    // create a directory
-    fn mkdir(&mut self, parent: u64, name: &std::ffi::OsStr, _mode: u32, reply: crate::ReplyEntry) {
-        let ino = self.inodes_num + 1;
-        let file_attr = FileAttr {
-            ino,
-            kind: FileType::Directory,
-            perm: 0o755,
-            ..Default::default()
-        };
-
-        self.inodes
-            .insert(ino, (name.to_str().unwrap().to_string(), file_attr));
-        self.inodes_num += 1;
-        self.data.insert(ino, Vec::new());
-        self.parent_children.insert(ino, vec![]);
+    fn link(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.link(req, ino, newparent, newname) {
+            Ok(()) => self.reply_entry(ino, reply),
+            Err(err) => reply.error(err),
+        }
+    }
 
-        self.parent_children.get_mut(&parent).unwrap().push(ino);
-        reply.entry(&Duration::new(1, 0), &file_attr, 0);
+    fn open(&mut self, req: &RequestContext, ino: u64, flags: OpenFlags, reply: ReplyOpen) {
+        match self.open(req, ino, flags, false) {
+            Ok(file) => self.handles.reply_opened(file, FopenFlags::empty(), reply),
+            Err(err) => reply.error(err),
+        }
     }
 
-    fn setattr(
+    fn read(
         &mut self,
+        _req: &RequestContext,
         ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<SystemTime>,
-        mtime: Option<SystemTime>,
-        _fh: Option<u64>,
-        crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        flags: Option<u32>,
-        reply: crate::ReplyAttr,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
     ) {
-        let Some((_, file_attr)) = self.inodes.get_mut(&ino) else {
-            reply.error(ENOENT);
+        if let Err(err) = self.check_handle(ino, fh, false) {
+            reply.error(err);
             return;
-        };
-
-        mode.inspect(|&mode| file_attr.perm = mode as u16);
-        uid.inspect(|&uid| file_attr.uid = uid);
-        gid.inspect(|&gid| file_attr.gid = gid);
-        size.inspect(|&size| file_attr.size = size);
-        atime.inspect(|&atime| file_attr.atime = atime);
-        mtime.inspect(|&mtime| file_attr.mtime = mtime);
-        crtime.inspect(|&crtime| file_attr.crtime = crtime);
-        flags.inspect(|&flags| file_attr.flags = flags);
-        reply.attr(&Duration::new(1, 0), file_attr);
+        }
+        match self.inode(ino).map(|inode| &inode.contents) {
+            Ok(Contents::File(data)) => {
+                let start = (offset.max(0) as usize).min(data.len());
+                let end = start.saturating_add(size as usize).min(data.len());
+                reply.data(&data[start..end]);
+            }
+            Ok(Contents::Dir(_)) => reply.error(EISDIR),
+            Ok(_) => reply.error(EINVAL),
+            Err(err) => reply.error(err),
+        }
     }
 
     fn write(
         &mut self,
+        _req: &RequestContext,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
-        reply: crate::ReplyWrite,
+        reply: ReplyWrite,
     ) {
         debug!(
             "write ino: {}, offset: {}, size: {}",
@@ -250,83 +361,575 @@ impl Filesystem for MemoryFS {
             offset,
             data.len()
         );
-
-        if let Some(file_data) = self.data.get_mut(&ino) {
-            if offset as usize > file_data.len() {
-                reply.error(ENOENT);
-                return;
-            }
-            let end = offset as usize + data.len();
-            if end > file_data.len() {
-                file_data.resize(end, 0);
-            }
-            file_data[offset as usize..end].copy_from_slice(data);
-            if let Some((_, file_attr)) = self.inodes.get_mut(&ino) {
-                file_attr.size += data.len() as u64;
-            }
-            reply.written(data.len() as u32);
-        } else {
+        if let Err(err) = self.check_handle(ino, fh, true) {
+            reply.error(err);
+            return;
+        }
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+        let end = offset as u64 + data.len() as u64;
+        if let Err(err) = self.resize(ino, end, false) {
+            reply.error(err);
+            return;
+        }
+        let Ok(inode) = self.inode_mut(ino) else {
             reply.error(ENOENT);
+            return;
+        };
+        if let Contents::File(file_data) = &mut inode.contents {
+            file_data[offset as usize..end as usize].copy_from_slice(data);
+        }
+        inode.modified();
+        reply.written(data.len() as u32);
+    }
+
+    fn flush(
+        &mut self,
+        _req: &RequestContext,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.locks.flush(ino, lock_owner);
+        reply.ok();
+    }
+
+    fn release(
+        &mut self,
+        _req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        lock_owner: u64,
+        release_flags: ReleaseFlags,
+        reply: ReplyEmpty,
+    ) {
+        self.locks.release(ino, lock_owner, release_flags);
+        self.release(fh, reply);
+    }
+
+    fn opendir(&mut self, req: &RequestContext, ino: u64, flags: OpenFlags, reply: ReplyOpen) {
+        match self.open(req, ino, flags, true) {
+            Ok(file) => self.handles.reply_opened(file, FopenFlags::empty(), reply),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        // Serve the listing from the snapshot taken at opendir, so that offsets stay valid
+        // if the directory changes in between
+        match self.handles.get(fh) {
+            Ok(file) if file.ino == ino => match &file.dir {
+                Some(dir) => dir.reply(offset, reply),
+                None => reply.error(ENOTDIR),
+            },
+            Ok(_) => reply.error(EBADF),
+            Err(err) => reply.error(err.code()),
         }
     }
 
-    fn unlink(&mut self, parent: u64, name: &std::ffi::OsStr, reply: crate::ReplyEmpty) {
-        match name
-            .to_str()
-            .and_then(|name_str| self.get_node_by_name(parent, name_str))
+    fn releasedir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.release(fh, reply);
+    }
+
+    fn getlk(&mut self, _req: &RequestContext, ino: u64, lock: &LockRequest, reply: ReplyLock) {
+        self.locks.getlk(ino, lock, reply);
+    }
+
+    fn setlk(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        lock: &LockRequest,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.locks.setlk(req, ino, lock, sleep, reply);
+    }
+
+    fn access(&mut self, req: &RequestContext, ino: u64, mask: u32, reply: ReplyEmpty) {
+        match self
+            .inode(ino)
+            .and_then(|inode| inode.check_access(req, mask as c_int))
         {
-            Some(ino) => {
-                self.inodes.remove(&ino);
-                self.data.remove(&ino);
-                self.parent_children
-                    .get_mut(&parent)
-                    .unwrap()
-                    .retain(|x| *x != ino);
-                reply.ok();
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: OpenFlags,
+        reply: ReplyCreate,
+    ) {
+        let kind = FileType::RegularFile;
+        let contents = Contents::File(Vec::new());
+        let res = self
+            .make(req, parent, name, kind, mode, contents)
+            .and_then(|ino| self.inode_mut(ino));
+        // The new file is opened without checking permissions, like open(2) with O_CREAT
+        let attr = match res {
+            Ok(inode) => {
+                inode.open += 1;
+                inode.attr
             }
-            None => {
-                reply.error(ENOENT);
+            Err(err) => {
+                reply.error(err);
+                return;
             }
-        }
+        };
+        let ino = attr.ino;
+        let fh = self.handles.insert(OpenFile {
+            ino,
+            flags,
+            dir: None,
+        });
+        self.inodes
+            .reply_created(reply, &TTL, &attr, fh, FopenFlags::empty());
     }
 
-    fn flush(&mut self, _ino: u64, _fh: u64, _lock_owner: u64, reply: crate::ReplyEmpty) {
-        reply.ok();
+    fn interrupt(&mut self, _req: &RequestContext, unique: u64) -> Result<(), c_int> {
+        // Requests are dispatched in order on a single thread, so a request that isn't waiting
+        // for a lock was replied already
+        self.locks.interrupt(unique);
+        Ok(())
     }
 }
 
 /// Create a new in-memory filesystem
 pub fn new(max_size: u64) -> MemoryFS {
-    MemoryFS {
-        max_size,
-        inodes_num: 0,
-        inodes: HashMap::new(),
-        data: HashMap::new(),
-        parent_children: HashMap::new(),
-    }
+    MemoryFS::new(max_size)
 }
 
 impl MemoryFS {
+    /// Create a new in-memory filesystem that stores up to max_size bytes of file contents
     pub fn new(max_size: u64) -> MemoryFS {
+        let root = Inode {
+            attr: FileAttr {
+                ino: FUSE_ROOT_ID,
+                kind: FileType::Directory,
+                perm: 0o755,
+                nlink: 2,
+                ..Default::default()
+            },
+            contents: Contents::Dir(Dir {
+                parent: FUSE_ROOT_ID,
+                entries: BTreeMap::new(),
+            }),
+            open: 0,
+        };
         MemoryFS {
             max_size,
-            inodes_num: 0,
-            inodes: HashMap::new(),
-            data: HashMap::new(),
-            parent_children: HashMap::new(),
+            used: 0,
+            inodes: InodeTable::new(root),
+            handles: HandleTable::new(),
+            locks: LockManager::new(),
         }
     }
 
+    /// Returns the number of lookup references the kernel holds on an inode
+    pub fn lookup_count(&self, ino: u64) -> u64 {
+        self.inodes.lookup_count(ino)
+    }
+
+    /// Returns the inode of the entry with the given name in the given directory
     pub fn get_node_by_name(&self, parent: u64, name: &str) -> Option<u64> {
-        if let Some(children) = self.parent_children.get(&parent) {
-            for child in children {
-                if let Some((child_name, _)) = self.inodes.get(child) {
-                    if child_name == name {
-                        return Some(*child);
+        let dir = self.inode(parent).ok()?.dir().ok()?;
+        dir.entries.get(OsStr::new(name)).copied()
+    }
+
+    /// Returns an inode
+    fn inode(&self, ino: u64) -> Result<&Inode, c_int> {
+        self.inodes.get(ino).ok_or(ENOENT)
+    }
+
+    /// Returns an inode for modification
+    fn inode_mut(&mut self, ino: u64) -> Result<&mut Inode, c_int> {
+        self.inodes.get_mut(ino).ok_or(ENOENT)
+    }
+
+    /// Reply with the entry of an inode and count the lookup
+    fn reply_entry(&mut self, ino: u64, reply: ReplyEntry) {
+        match self.inode(ino).map(|inode| inode.attr) {
+            Ok(attr) => self.inodes.reply_entry(reply, &TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Free an inode if it isn't linked, referenced by the kernel or open anymore
+    fn collect(&mut self, ino: u64) {
+        let unused = self.inodes.get(ino).is_some_and(|inode| {
+            inode.attr.nlink == 0 && inode.open == 0 && self.inodes.lookup_count(ino) == 0
+        });
+        if unused {
+            if let Some(Inode {
+                contents: Contents::File(data),
+                ..
+            }) = self.inodes.remove(ino)
+            {
+                self.used -= data.len() as u64;
+            }
+        }
+    }
+
+    /// Checks that an entry can be added to or removed from a directory
+    fn check_dir_write(&self, req: &RequestContext, parent: u64) -> Result<(), c_int> {
+        let dir = self.inode(parent)?;
+        dir.dir()?;
+        dir.check_access(req, W_OK | X_OK)
+    }
+
+    /// Create an inode and link it into the given directory. Returns the new inode number.
+    fn make(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        kind: FileType,
+        mode: u32,
+        contents: Contents,
+    ) -> Result<u64, c_int> {
+        self.check_dir_write(req, parent)?;
+        if name.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        if self.inode(parent)?.dir()?.entries.contains_key(name) {
+            return Err(EEXIST);
+        }
+        let size = match &contents {
+            Contents::Symlink(target) => target.as_os_str().len() as u64,
+            _ => 0,
+        };
+        let now = SystemTime::now();
+        let mut attr = FileAttr {
+            ino: 0,
+            size,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm: (mode & !req.umask().unwrap_or(0) & 0o7777) as u16,
+            nlink: 1,
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            flags: 0,
+        };
+        if kind == FileType::Directory {
+            attr.nlink = 2;
+        }
+        let inode = Inode {
+            attr,
+            contents,
+            open: 0,
+        };
+        let (ino, _) = self.inodes.insert(inode);
+        self.inode_mut(ino)?.attr.ino = ino;
+        let dir = self.inode_mut(parent)?;
+        dir.dir_mut()?.entries.insert(name.to_os_string(), ino);
+        dir.modified();
+        if kind == FileType::Directory {
+            dir.attr.nlink += 1;
+        }
+        Ok(ino)
+    }
+
+    /// Remove an entry from a directory (unlink or rmdir)
+    fn remove(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        rmdir: bool,
+    ) -> Result<(), c_int> {
+        self.check_dir_write(req, parent)?;
+        let ino = *self.inode(parent)?.dir()?.entries.get(name).ok_or(ENOENT)?;
+        let inode = self.inode(ino)?;
+        match (&inode.contents, rmdir) {
+            (Contents::Dir(dir), true) if !dir.entries.is_empty() => return Err(ENOTEMPTY),
+            (Contents::Dir(_), false) => return Err(EISDIR),
+            (Contents::Dir(_), true) => {}
+            (_, true) => return Err(ENOTDIR),
+            (_, false) => {}
+        }
+        self.unlink_entry(parent, name)?;
+        self.collect(ino);
+        Ok(())
+    }
+
+    /// Remove an entry from a directory and update link counts and times
+    fn unlink_entry(&mut self, parent: u64, name: &OsStr) -> Result<u64, c_int> {
+        let dir = self.inode_mut(parent)?;
+        let ino = dir.dir_mut()?.entries.remove(name).ok_or(ENOENT)?;
+        dir.modified();
+        let inode = self.inode_mut(ino)?;
+        inode.attr.ctime = SystemTime::now();
+        if inode.attr.kind == FileType::Directory {
+            // A removed directory loses its `.` entry, and the parent loses `..`
+            inode.attr.nlink = 0;
+            self.inode_mut(parent)?.attr.nlink -= 1;
+        } else {
+            inode.attr.nlink -= 1;
+        }
+        Ok(ino)
+    }
+
+    /// Move an entry, replacing the target if it exists
+    fn rename(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        self.check_dir_write(req, parent)?;
+        self.check_dir_write(req, newparent)?;
+        if newname.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        let ino = *self.inode(parent)?.dir()?.entries.get(name).ok_or(ENOENT)?;
+        let is_dir = self.inode(ino)?.attr.kind == FileType::Directory;
+        if let Some(&target) = self.inode(newparent)?.dir()?.entries.get(newname) {
+            // Renaming a file to another link of itself does nothing
+            if target == ino {
+                return Ok(());
+            }
+            match (&self.inode(target)?.contents, is_dir) {
+                (Contents::Dir(dir), true) if !dir.entries.is_empty() => return Err(ENOTEMPTY),
+                (Contents::Dir(_), false) => return Err(EISDIR),
+                (Contents::Dir(_), true) => {}
+                (_, true) => return Err(ENOTDIR),
+                (_, false) => {}
+            }
+        }
+        if is_dir {
+            // A directory can't be moved into itself or one of its subdirectories
+            let mut ancestor = newparent;
+            while ancestor != FUSE_ROOT_ID {
+                if ancestor == ino {
+                    return Err(EINVAL);
+                }
+                ancestor = self.inode(ancestor)?.dir()?.parent;
+            }
+        }
+        if let Ok(target) = self.unlink_entry(newparent, newname) {
+            self.collect(target);
+        }
+        let dir = self.inode_mut(parent)?;
+        dir.dir_mut()?.entries.remove(name);
+        dir.modified();
+        if is_dir {
+            dir.attr.nlink -= 1;
+        }
+        let dir = self.inode_mut(newparent)?;
+        dir.dir_mut()?.entries.insert(newname.to_os_string(), ino);
+        dir.modified();
+        if is_dir {
+            dir.attr.nlink += 1;
+        }
+        let inode = self.inode_mut(ino)?;
+        inode.attr.ctime = SystemTime::now();
+        if let Contents::Dir(moved) = &mut inode.contents {
+            moved.parent = newparent;
+        }
+        Ok(())
+    }
+
+    /// Add a hard link to a file
+    fn link(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        if self.inode(ino)?.attr.kind == FileType::Directory {
+            return Err(EPERM);
+        }
+        self.check_dir_write(req, newparent)?;
+        if newname.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        let dir = self.inode_mut(newparent)?;
+        let entries = &mut dir.dir_mut()?.entries;
+        if entries.contains_key(newname) {
+            return Err(EEXIST);
+        }
+        entries.insert(newname.to_os_string(), ino);
+        dir.modified();
+        let inode = self.inode_mut(ino)?;
+        inode.attr.nlink += 1;
+        inode.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+    /// Change attributes of an inode
+    fn setattr(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        attr: &SetAttr,
+    ) -> Result<FileAttr, c_int> {
+        let inode = self.inode(ino)?;
+        if attr.mode.is_some() {
+            inode.check_owner(req)?;
+        }
+        // Only root can give files away, owners can change the group to their own group
+        if attr.uid.is_some_and(|uid| uid != inode.attr.uid) && req.uid() != 0 {
+            return Err(EPERM);
+        }
+        if attr.gid.is_some_and(|gid| gid != inode.attr.gid) {
+            inode.check_owner(req)?;
+            if req.uid() != 0 && attr.gid != Some(req.gid()) {
+                return Err(EPERM);
+            }
+        }
+        if attr.atime.is_some() || attr.mtime.is_some() {
+            inode
+                .check_owner(req)
+                .or_else(|_| inode.check_access(req, W_OK))?;
+        }
+        if let Some(size) = attr.size {
+            match inode.contents {
+                Contents::File(_) => {}
+                Contents::Dir(_) => return Err(EISDIR),
+                _ => return Err(EINVAL),
+            }
+            if attr.fh.is_none() {
+                inode.check_access(req, W_OK)?;
+            }
+            self.resize(ino, size, true)?;
+            self.inode_mut(ino)?.modified();
+        }
+
+        let inode = self.inode_mut(ino)?;
+        let file_attr = &mut inode.attr;
+        // Only the permission bits of the mode can be changed, not the file type
+        attr.mode
+            .inspect(|&mode| file_attr.perm = (mode & 0o7777) as u16);
+        attr.uid.inspect(|&uid| file_attr.uid = uid);
+        attr.gid.inspect(|&gid| file_attr.gid = gid);
+        attr.atime.inspect(|&atime| file_attr.atime = atime);
+        attr.mtime.inspect(|&mtime| file_attr.mtime = mtime);
+        file_attr.ctime = attr.ctime.unwrap_or_else(SystemTime::now);
+        attr.crtime.inspect(|&crtime| file_attr.crtime = crtime);
+        attr.flags.inspect(|&flags| file_attr.flags = flags);
+        if attr.kill_suidgid() {
+            file_attr.perm &= !0o6000;
+        }
+        Ok(*file_attr)
+    }
+
+    /// Resize the contents of a file, zero-filling new bytes. Files only shrink if truncate is
+    /// set. Fails with ENOSPC if the filesystem is full.
+    fn resize(&mut self, ino: u64, size: u64, truncate: bool) -> Result<(), c_int> {
+        let used = self.used;
+        let max_size = self.max_size;
+        let inode = self.inode_mut(ino)?;
+        let Contents::File(data) = &mut inode.contents else {
+            return Err(EINVAL);
+        };
+        let old = data.len() as u64;
+        if size < old && !truncate || size == old {
+            return Ok(());
+        }
+        if size > old && used + (size - old) > max_size {
+            return Err(ENOSPC);
+        }
+        data.resize(size as usize, 0);
+        inode.attr.size = size;
+        inode.attr.blocks = size.div_ceil(512);
+        self.used = used + size - old;
+        Ok(())
+    }
+
+    /// Open a file or directory and return the state of the new handle
+    fn open(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        flags: OpenFlags,
+        dir: bool,
+    ) -> Result<OpenFile, c_int> {
+        let inode = self.inode(ino)?;
+        let snapshot = match (&inode.contents, dir) {
+            (Contents::Dir(entries), true) => {
+                let mut snapshot = DirSnapshot::new(ino, entries.parent);
+                for (name, &child) in &entries.entries {
+                    if let Some(child) = self.inodes.get(child) {
+                        snapshot.add(child.attr.ino, child.attr.kind, name);
                     }
                 }
+                Some(snapshot)
+            }
+            (Contents::Dir(_), false) if flags.is_writable() => return Err(EISDIR),
+            (_, true) => return Err(ENOTDIR),
+            _ => None,
+        };
+        let mut mask = 0;
+        if flags.is_readable() {
+            mask |= R_OK;
+        }
+        if flags.is_writable() {
+            mask |= W_OK;
+        }
+        inode.check_access(req, mask)?;
+        self.inode_mut(ino)?.open += 1;
+        Ok(OpenFile {
+            ino,
+            flags,
+            dir: snapshot,
+        })
+    }
+
+    /// Release a file or directory handle
+    fn release(&mut self, fh: u64, reply: ReplyEmpty) {
+        match self.handles.remove(fh) {
+            Ok(file) => {
+                if let Ok(inode) = self.inode_mut(file.ino) {
+                    inode.open -= 1;
+                }
+                self.collect(file.ino);
+                reply.ok();
             }
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    /// Checks that the given handle belongs to the given inode and allows reading or writing
+    fn check_handle(&self, ino: u64, fh: u64, write: bool) -> Result<(), c_int> {
+        let file = self.handles.get(fh).map_err(Errno::code)?;
+        let allowed = match write {
+            true => file.flags.is_writable(),
+            false => file.flags.is_readable(),
+        };
+        match file.ino == ino && allowed {
+            true => Ok(()),
+            false => Err(EBADF),
         }
-        None
     }
 }
@@ -0,0 +1,1322 @@
+//! Path-based filesystem interface
+//!
+//! `PathFilesystem` is a high-level alternative to the `Filesystem` trait (similar to the
+//! high-level API of libfuse). Its methods get the path of a file relative to the mountpoint
+//! instead of an inode number. The `PathAdapter` runs it in a session by keeping a table of
+//! inodes and their paths. It takes care of lookup counts and `forget`, keeps paths up to date
+//! on renames and hides files that are unlinked while they are still open. Directories are
+//! listed once at opendir, so offsets stay valid while the directory changes.
+
+use libc::c_int;
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::dir::DirSnapshot;
+use crate::fallible::{
+    reply_data, reply_empty, reply_open, reply_xattr, Errno, Lock, Open, OpenDir,
+};
+use crate::handle::HandleTable;
+use crate::{
+    FileAttr, FileType, Filesystem, LockRequest, OpenFlags, ReleaseFlags, ReplyAttr, ReplyBmap,
+    ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLock, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, RequestContext, SetAttr, Statfs, XattrFlags, FUSE_ROOT_ID,
+};
+
+/// Inode number of directory entries that haven't been looked up yet (same as libfuse)
+const UNKNOWN_INO: u64 = 0xffff_ffff;
+
+/// Number of names that are tried when hiding an unlinked file that is still open
+const HIDE_ATTEMPTS: u32 = 10;
+
+/// Entry of a directory listing (result of readdir operations)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    /// Name of the entry
+    pub name: OsString,
+    /// Kind of file
+    pub kind: FileType,
+}
+
+/// Path-based filesystem trait.
+///
+/// Paths are absolute, starting at the root of the filesystem (`/`). Methods return their
+/// result or an `Errno`. Inode numbers in returned attributes are ignored, the adapter assigns
+/// its own. See `Filesystem` for documentation of the operations. Reasonable default
+/// implementations are provided, most of them fail with ENOSYS. Use `PathAdapter` to run a
+/// filesystem implementing this trait in a session.
+pub trait PathFilesystem {
+    /// Initialize filesystem.
+    fn init(&mut self, _req: &RequestContext) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Clean up filesystem.
+    fn destroy(&mut self) {}
+
+    /// Get file attributes. Also used to look up directory entries.
+    fn getattr(&mut self, _req: &RequestContext, _path: &Path) -> Result<FileAttr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set file attributes. Returns the changed attributes.
+    fn setattr(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _attr: &SetAttr,
+    ) -> Result<FileAttr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read symbolic link.
+    fn readlink(&mut self, _req: &RequestContext, _path: &Path) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create file node.
+    fn mknod(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _mode: u32,
+        _rdev: u32,
+    ) -> Result<FileAttr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a directory.
+    fn mkdir(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _mode: u32,
+    ) -> Result<FileAttr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a file.
+    fn unlink(&mut self, _req: &RequestContext, _path: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a directory.
+    fn rmdir(&mut self, _req: &RequestContext, _path: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a symbolic link.
+    fn symlink(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _link: &Path,
+    ) -> Result<FileAttr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a file. An existing file at the new path is replaced.
+    fn rename(&mut self, _req: &RequestContext, _from: &Path, _to: &Path) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a hard link.
+    fn link(&mut self, _req: &RequestContext, _from: &Path, _to: &Path) -> Result<FileAttr, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a file.
+    fn open(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _flags: OpenFlags,
+    ) -> Result<Open, Errno> {
+        Ok(Open::default())
+    }
+
+    /// Read data.
+    fn read(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _offset: i64,
+        _size: u32,
+    ) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Write data. Returns the number of bytes written.
+    fn write(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _flags: u32,
+    ) -> Result<u32, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Flush method.
+    fn flush(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _lock_owner: u64,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open file.
+    fn release(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _release_flags: ReleaseFlags,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize file contents.
+    fn fsync(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Open a directory.
+    fn opendir(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _flags: OpenFlags,
+    ) -> Result<Open, Errno> {
+        Ok(Open::default())
+    }
+
+    /// Read directory. Called at opendir with the handle it returned. Returns all entries of
+    /// the directory, the adapter keeps them until releasedir and takes care of sending them
+    /// in chunks.
+    fn readdir(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+    ) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Release an open directory. Directories that were removed while open are released with
+    /// the path they were opened with.
+    fn releasedir(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _flags: u32,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Synchronize directory contents.
+    fn fsyncdir(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _fh: u64,
+        _datasync: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get file system statistics.
+    fn statfs(&mut self, _req: &RequestContext, _path: &Path) -> Result<Statfs, Errno> {
+        Ok(Statfs {
+            bsize: 512,
+            namelen: 255,
+            ..Statfs::default()
+        })
+    }
+
+    /// Set an extended attribute.
+    fn setxattr(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: XattrFlags,
+        _position: u32,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get the value of an extended attribute.
+    fn getxattr(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _name: &OsStr,
+    ) -> Result<Vec<u8>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// List the names of extended attributes.
+    fn listxattr(&mut self, _req: &RequestContext, _path: &Path) -> Result<Vec<OsString>, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    fn removexattr(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _name: &OsStr,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    fn access(&mut self, _req: &RequestContext, _path: &Path, _mask: u32) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create and open a file.
+    fn create(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _mode: u32,
+        _flags: OpenFlags,
+    ) -> Result<(FileAttr, Open), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Test for a POSIX file lock.
+    fn getlk(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _lock: &LockRequest,
+    ) -> Result<Lock, Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Acquire, modify or release a POSIX file lock.
+    fn setlk(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _lock: &LockRequest,
+        _sleep: bool,
+    ) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Map block index within file to block index within device.
+    fn bmap(
+        &mut self,
+        _req: &RequestContext,
+        _path: &Path,
+        _blocksize: u32,
+        _idx: u64,
+    ) -> Result<u64, Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+/// Inode known to the kernel
+#[derive(Debug)]
+struct Node {
+    /// Inode number of the parent directory
+    parent: u64,
+    /// Name in the parent directory
+    name: OsString,
+    /// False if the node was removed from its parent directory
+    linked: bool,
+    /// Number of lookups the kernel didn't forget yet
+    nlookup: u64,
+    /// Number of open file handles
+    open: u64,
+    /// True if the node was renamed to a hidden name because it was unlinked while open
+    hidden: bool,
+}
+
+/// Directory opened through the adapter
+#[derive(Debug)]
+struct PathDir {
+    /// Handle and entries of the open directory
+    dir: OpenDir,
+    /// Path of the directory at opendir, for releasing it after it was removed
+    path: PathBuf,
+}
+
+/// Adapter that runs a `PathFilesystem` as a `Filesystem` by translating inode numbers to
+/// paths
+#[derive(Debug)]
+pub struct PathAdapter<FS> {
+    filesystem: FS,
+    ttl: Duration,
+    nodes: HashMap<u64, Node>,
+    names: HashMap<(u64, OsString), u64>,
+    next_ino: u64,
+    next_hidden: u64,
+    dirs: HandleTable<PathDir>,
+}
+
+impl<FS: PathFilesystem> PathAdapter<FS> {
+    /// Create a new adapter for the given filesystem
+    pub fn new(filesystem: FS) -> PathAdapter<FS> {
+        let root = Node {
+            parent: FUSE_ROOT_ID,
+            name: OsString::new(),
+            linked: true,
+            nlookup: 1,
+            open: 0,
+            hidden: false,
+        };
+        PathAdapter {
+            filesystem,
+            ttl: Duration::from_secs(1),
+            nodes: HashMap::from([(FUSE_ROOT_ID, root)]),
+            names: HashMap::new(),
+            next_ino: FUSE_ROOT_ID + 1,
+            next_hidden: 0,
+            dirs: HandleTable::new(),
+        }
+    }
+
+    /// Returns the time the kernel may cache entries and attributes
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Set the time the kernel may cache entries and attributes (1 second by default)
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Returns a reference to the wrapped filesystem
+    pub fn filesystem(&self) -> &FS {
+        &self.filesystem
+    }
+
+    /// Returns a mutable reference to the wrapped filesystem
+    pub fn filesystem_mut(&mut self) -> &mut FS {
+        &mut self.filesystem
+    }
+
+    /// Returns the wrapped filesystem
+    pub fn into_inner(self) -> FS {
+        self.filesystem
+    }
+
+    /// Returns the path of the given inode
+    fn path(&self, ino: u64) -> Result<PathBuf, Errno> {
+        let mut names = Vec::new();
+        let mut ino = ino;
+        while ino != FUSE_ROOT_ID {
+            let node = self.nodes.get(&ino).ok_or(Errno::ENOENT)?;
+            if !node.linked {
+                return Err(Errno::ENOENT);
+            }
+            names.push(&node.name);
+            ino = node.parent;
+        }
+        let mut path = PathBuf::from("/");
+        path.extend(names.iter().rev());
+        Ok(path)
+    }
+
+    /// Returns the path of the given entry of a directory
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, Errno> {
+        Ok(self.path(parent)?.join(name))
+    }
+
+    /// Returns the inode of the given directory entry if it is known
+    fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.names.get(&(parent, name.to_os_string())).copied()
+    }
+
+    /// Remember a looked up directory entry and set the inode number of its attributes
+    fn remember(&mut self, parent: u64, name: &OsStr, attr: &mut FileAttr) {
+        let ino = match self.child(parent, name) {
+            Some(ino) => ino,
+            None => {
+                let ino = self.next_ino;
+                self.next_ino += 1;
+                let node = Node {
+                    parent,
+                    name: name.to_os_string(),
+                    linked: true,
+                    nlookup: 0,
+                    open: 0,
+                    hidden: false,
+                };
+                self.nodes.insert(ino, node);
+                self.names.insert((parent, name.to_os_string()), ino);
+                ino
+            }
+        };
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.nlookup += 1;
+        }
+        attr.ino = ino;
+    }
+
+    /// Reply with an entry for the given result and remember it
+    fn reply_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        res: Result<FileAttr, Errno>,
+        reply: ReplyEntry,
+    ) {
+        match res {
+            Ok(mut attr) => {
+                self.remember(parent, name, &mut attr);
+                reply.entry(&self.ttl, &attr, 0);
+            }
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    /// Reply with the given attributes of the given inode
+    fn reply_attr(&self, ino: u64, res: Result<FileAttr, Errno>, reply: ReplyAttr) {
+        match res {
+            Ok(mut attr) => {
+                attr.ino = ino;
+                reply.attr(&self.ttl, &attr);
+            }
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    /// Remove a node from its parent directory. The node is kept as long as the kernel knows
+    /// about it, but has no path anymore.
+    fn detach(&mut self, ino: u64) {
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            if node.linked {
+                node.linked = false;
+                self.names.remove(&(node.parent, node.name.clone()));
+            }
+        }
+        self.release_node(ino);
+    }
+
+    /// Drop a node if the kernel doesn't reference it anymore
+    fn release_node(&mut self, ino: u64) {
+        if ino == FUSE_ROOT_ID {
+            return;
+        }
+        let unused = match self.nodes.get(&ino) {
+            Some(node) => node.nlookup == 0 && node.open == 0,
+            None => false,
+        };
+        if unused {
+            if let Some(node) = self.nodes.remove(&ino) {
+                if node.linked {
+                    self.names.remove(&(node.parent, node.name));
+                }
+            }
+        }
+    }
+
+    /// Rename an open file that is about to be unlinked or replaced to a hidden name in the
+    /// same directory, so that it stays accessible for its open file handles. The hidden file
+    /// is removed when the last file handle is released.
+    fn hide(&mut self, req: &RequestContext, ino: u64) -> Result<(), Errno> {
+        let (parent, path) = match self.nodes.get(&ino) {
+            Some(node) => (node.parent, self.path(ino)?),
+            None => return Err(Errno::ENOENT),
+        };
+        for _ in 0..HIDE_ATTEMPTS {
+            self.next_hidden += 1;
+            let name = OsString::from(format!(".fuse_hidden{:08x}{:08x}", ino, self.next_hidden));
+            if self.child(parent, &name).is_some() {
+                continue;
+            }
+            let hidden_path = self.child_path(parent, &name)?;
+            if self.filesystem.getattr(req, &hidden_path).is_ok() {
+                continue;
+            }
+            self.filesystem.rename(req, &path, &hidden_path)?;
+            let node = self.nodes.get_mut(&ino).unwrap();
+            self.names.remove(&(parent, node.name.clone()));
+            self.names.insert((parent, name.clone()), ino);
+            node.name = name;
+            node.hidden = true;
+            return Ok(());
+        }
+        Err(Errno::EBUSY)
+    }
+
+    /// Remove a hidden file after its last file handle was released
+    fn remove_hidden(&mut self, req: &RequestContext, ino: u64) {
+        let hidden = match self.nodes.get(&ino) {
+            Some(node) => node.hidden && node.open == 0,
+            None => false,
+        };
+        if hidden {
+            if let Ok(path) = self.path(ino) {
+                if let Err(err) = self.filesystem.unlink(req, &path) {
+                    warn!("Failed to remove hidden file {:?}: {}", path, err);
+                }
+            }
+            self.detach(ino);
+        }
+    }
+
+    /// Returns the inode of the given directory entry if it is open and needs to be hidden
+    /// before it is unlinked or replaced
+    fn open_child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.child(parent, name)
+            .filter(|ino| self.nodes.get(ino).is_some_and(|node| node.open > 0))
+    }
+}
+
+impl<FS: PathFilesystem> Filesystem for PathAdapter<FS> {
+    fn init(&mut self, req: &RequestContext) -> Result<(), c_int> {
+        self.filesystem.init(req).map_err(Errno::code)
+    }
+
+    fn destroy(&mut self) {
+        self.filesystem.destroy();
+    }
+
+    fn lookup(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let res = self
+            .child_path(parent, name)
+            .and_then(|path| self.filesystem.getattr(req, &path));
+        self.reply_entry(parent, name, res, reply);
+    }
+
+    fn forget(&mut self, _req: &RequestContext, ino: u64, nlookup: u64) {
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.nlookup = node.nlookup.saturating_sub(nlookup);
+        }
+        self.release_node(ino);
+    }
+
+    fn getattr(&mut self, req: &RequestContext, ino: u64, reply: ReplyAttr) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.getattr(req, &path));
+        self.reply_attr(ino, res, reply);
+    }
+
+    fn setattr(&mut self, req: &RequestContext, ino: u64, attr: &SetAttr, reply: ReplyAttr) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.setattr(req, &path, attr));
+        self.reply_attr(ino, res, reply);
+    }
+
+    fn readlink(&mut self, req: &RequestContext, ino: u64, reply: ReplyData) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.readlink(req, &path));
+        reply_data(res, reply);
+    }
+
+    fn mknod(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let res = self
+            .child_path(parent, name)
+            .and_then(|path| self.filesystem.mknod(req, &path, mode, rdev));
+        self.reply_entry(parent, name, res, reply);
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        reply: ReplyEntry,
+    ) {
+        let res = self
+            .child_path(parent, name)
+            .and_then(|path| self.filesystem.mkdir(req, &path, mode));
+        self.reply_entry(parent, name, res, reply);
+    }
+
+    fn unlink(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let res = match self.open_child(parent, name) {
+            // Keep open files around until they are released
+            Some(ino) => self.hide(req, ino),
+            None => self.child_path(parent, name).and_then(|path| {
+                self.filesystem.unlink(req, &path)?;
+                if let Some(ino) = self.child(parent, name) {
+                    self.detach(ino);
+                }
+                Ok(())
+            }),
+        };
+        reply_empty(res, reply);
+    }
+
+    fn rmdir(&mut self, req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let res = self.child_path(parent, name).and_then(|path| {
+            self.filesystem.rmdir(req, &path)?;
+            if let Some(ino) = self.child(parent, name) {
+                self.detach(ino);
+            }
+            Ok(())
+        });
+        reply_empty(res, reply);
+    }
+
+    fn symlink(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let res = self
+            .child_path(parent, name)
+            .and_then(|path| self.filesystem.symlink(req, &path, link));
+        self.reply_entry(parent, name, res, reply);
+    }
+
+    fn rename(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let res = (|| {
+            let from = self.child_path(parent, name)?;
+            let to = self.child_path(newparent, newname)?;
+            // An open file that gets replaced is kept around until it is released
+            let source = self.child(parent, name);
+            if let Some(target) = self.open_child(newparent, newname) {
+                if Some(target) != source {
+                    self.hide(req, target)?;
+                }
+            }
+            self.filesystem.rename(req, &from, &to)?;
+            if let Some(target) = self.child(newparent, newname) {
+                if Some(target) != source {
+                    self.detach(target);
+                }
+            }
+            if let Some(ino) = source {
+                self.names.remove(&(parent, name.to_os_string()));
+                self.names.insert((newparent, newname.to_os_string()), ino);
+                if let Some(node) = self.nodes.get_mut(&ino) {
+                    node.parent = newparent;
+                    node.name = newname.to_os_string();
+                }
+            }
+            Ok(())
+        })();
+        reply_empty(res, reply);
+    }
+
+    fn link(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        // Every path gets its own inode, so the new link is a new node
+        let res = self.path(ino).and_then(|from| {
+            let to = self.child_path(newparent, newname)?;
+            self.filesystem.link(req, &from, &to)
+        });
+        self.reply_entry(newparent, newname, res, reply);
+    }
+
+    fn open(&mut self, req: &RequestContext, ino: u64, flags: OpenFlags, reply: ReplyOpen) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.open(req, &path, flags));
+        if res.is_ok() {
+            if let Some(node) = self.nodes.get_mut(&ino) {
+                node.open += 1;
+            }
+        }
+        reply_open(res, reply);
+    }
+
+    fn read(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.read(req, &path, fh, offset, size));
+        reply_data(res, reply);
+    }
+
+    fn write(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.write(req, &path, fh, offset, data, flags));
+        match res {
+            Ok(size) => reply.written(size),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.flush(req, &path, fh, lock_owner));
+        reply_empty(res, reply);
+    }
+
+    fn release(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        release_flags: ReleaseFlags,
+        reply: ReplyEmpty,
+    ) {
+        let res = self.path(ino).and_then(|path| {
+            self.filesystem
+                .release(req, &path, fh, flags, lock_owner, release_flags)
+        });
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.open = node.open.saturating_sub(1);
+        }
+        self.remove_hidden(req, ino);
+        self.release_node(ino);
+        reply_empty(res, reply);
+    }
+
+    fn fsync(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.fsync(req, &path, fh, datasync));
+        reply_empty(res, reply);
+    }
+
+    fn opendir(&mut self, req: &RequestContext, ino: u64, flags: OpenFlags, reply: ReplyOpen) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(err) => return reply.error(err.code()),
+        };
+        let open = match self.filesystem.opendir(req, &path, flags) {
+            Ok(open) => open,
+            Err(err) => return reply.error(err.code()),
+        };
+        // List the directory right away, so that readdir offsets are indices into a listing
+        // that doesn't change
+        let listing = match self.filesystem.readdir(req, &path, open.fh) {
+            Ok(listing) => listing,
+            Err(err) => {
+                let _ = self
+                    .filesystem
+                    .releasedir(req, &path, open.fh, flags.bits());
+                return reply.error(err.code());
+            }
+        };
+        let mut entries = DirSnapshot::empty();
+        for entry in listing {
+            let entry_ino = match entry.name.as_bytes() {
+                b"." => ino,
+                b".." => self.nodes.get(&ino).map_or(UNKNOWN_INO, |node| node.parent),
+                _ => self.child(ino, &entry.name).unwrap_or(UNKNOWN_INO),
+            };
+            entries.add(entry_ino, entry.kind, entry.name);
+        }
+        let dir = OpenDir {
+            fh: open.fh,
+            entries,
+        };
+        self.dirs
+            .reply_opened(PathDir { dir, path }, open.flags, reply);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &RequestContext,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: ReplyDirectory,
+    ) {
+        match self.dirs.get(fh) {
+            Ok(open) => open.dir.entries.reply(offset, reply),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // A directory that was removed while open has no path anymore, but still needs to be
+        // released with the path it was opened with
+        let res = self.dirs.remove(fh).and_then(|open| {
+            let path = self.path(ino).unwrap_or_else(|_| open.path.clone());
+            self.filesystem.releasedir(req, &path, open.dir.fh, flags)
+        });
+        reply_empty(res, reply);
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        fh: u64,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        let res = self.dirs.get(fh).and_then(|open| {
+            let path = self.path(ino)?;
+            self.filesystem.fsyncdir(req, &path, open.dir.fh, datasync)
+        });
+        reply_empty(res, reply);
+    }
+
+    fn statfs(&mut self, req: &RequestContext, ino: u64, reply: ReplyStatfs) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.statfs(req, &path));
+        match res {
+            Ok(stats) => reply.statfs(&stats),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: XattrFlags,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let res = self.path(ino).and_then(|path| {
+            self.filesystem
+                .setxattr(req, &path, name, value, flags, position)
+        });
+        reply_empty(res, reply);
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.getxattr(req, &path, name));
+        reply_xattr(res, size, reply);
+    }
+
+    fn listxattr(&mut self, req: &RequestContext, ino: u64, size: u32, reply: ReplyXattr) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.listxattr(req, &path));
+        match res {
+            Ok(names) => reply.list(size, names),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn removexattr(&mut self, req: &RequestContext, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.removexattr(req, &path, name));
+        reply_empty(res, reply);
+    }
+
+    fn access(&mut self, req: &RequestContext, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.access(req, &path, mask));
+        reply_empty(res, reply);
+    }
+
+    fn create(
+        &mut self,
+        req: &RequestContext,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: OpenFlags,
+        reply: ReplyCreate,
+    ) {
+        let res = self
+            .child_path(parent, name)
+            .and_then(|path| self.filesystem.create(req, &path, mode, flags));
+        match res {
+            Ok((mut attr, open)) => {
+                self.remember(parent, name, &mut attr);
+                if let Some(node) = self.nodes.get_mut(&attr.ino) {
+                    node.open += 1;
+                }
+                reply.created(&self.ttl, &attr, 0, open.fh, open.flags);
+            }
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn getlk(&mut self, req: &RequestContext, ino: u64, lock: &LockRequest, reply: ReplyLock) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.getlk(req, &path, lock));
+        match res {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        req: &RequestContext,
+        ino: u64,
+        lock: &LockRequest,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.setlk(req, &path, lock, sleep));
+        reply_empty(res, reply);
+    }
+
+    fn bmap(&mut self, req: &RequestContext, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let res = self
+            .path(ino)
+            .and_then(|path| self.filesystem.bmap(req, &path, blocksize, idx));
+        match res {
+            Ok(block) => reply.bmap(block),
+            Err(err) => reply.error(err.code()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DirEntry, PathAdapter, PathFilesystem};
+    use crate::fallible::{Errno, Open};
+    use crate::reply::ReplyDirectory;
+    use crate::testing::{dir_entries, recv_reply};
+    use crate::{
+        FileAttr, FileType, Filesystem, OpenFlags, ReleaseFlags, Reply, RequestContext,
+        FUSE_ROOT_ID,
+    };
+    use std::collections::HashSet;
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    /// Filesystem with a flat set of files in the root directory
+    #[derive(Default)]
+    struct TestFS {
+        files: HashSet<PathBuf>,
+        opened: Vec<PathBuf>,
+        released: Vec<PathBuf>,
+    }
+
+    impl PathFilesystem for TestFS {
+        fn getattr(&mut self, _req: &RequestContext, path: &Path) -> Result<FileAttr, Errno> {
+            match path == Path::new("/") || self.files.contains(path) {
+                true => Ok(FileAttr::default()),
+                false => Err(Errno::ENOENT),
+            }
+        }
+
+        fn unlink(&mut self, _req: &RequestContext, path: &Path) -> Result<(), Errno> {
+            match self.files.remove(path) {
+                true => Ok(()),
+                false => Err(Errno::ENOENT),
+            }
+        }
+
+        fn rmdir(&mut self, req: &RequestContext, path: &Path) -> Result<(), Errno> {
+            self.unlink(req, path)
+        }
+
+        fn rename(&mut self, _req: &RequestContext, from: &Path, to: &Path) -> Result<(), Errno> {
+            match self.files.remove(from) {
+                true => {
+                    self.files.insert(to.to_path_buf());
+                    Ok(())
+                }
+                false => Err(Errno::ENOENT),
+            }
+        }
+
+        fn open(
+            &mut self,
+            _req: &RequestContext,
+            path: &Path,
+            _flags: OpenFlags,
+        ) -> Result<Open, Errno> {
+            self.opened.push(path.to_path_buf());
+            Ok(Open::default())
+        }
+
+        fn readdir(
+            &mut self,
+            _req: &RequestContext,
+            _path: &Path,
+            _fh: u64,
+        ) -> Result<Vec<DirEntry>, Errno> {
+            let mut names: Vec<_> = self
+                .files
+                .iter()
+                .filter_map(|file| file.file_name())
+                .collect();
+            names.sort();
+            let entries = names.into_iter().map(|name| DirEntry {
+                name: name.to_os_string(),
+                kind: FileType::RegularFile,
+            });
+            Ok(entries.collect())
+        }
+
+        fn releasedir(
+            &mut self,
+            _req: &RequestContext,
+            path: &Path,
+            _fh: u64,
+            _flags: u32,
+        ) -> Result<(), Errno> {
+            self.released.push(path.to_path_buf());
+            Ok(())
+        }
+    }
+
+    /// Returns the error and the inode number (if any) of the next reply
+    fn parse_reply(rx: &Receiver<Vec<u8>>) -> (i32, u64) {
+        let reply = recv_reply(rx);
+        match reply.data.get(0..8) {
+            Some(ino) => (reply.error, u64::from_ne_bytes(ino.try_into().unwrap())),
+            None => (reply.error, 0),
+        }
+    }
+
+    fn setup() -> (PathAdapter<TestFS>, Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+        let mut fs = TestFS::default();
+        fs.files.insert(PathBuf::from("/a"));
+        fs.files.insert(PathBuf::from("/b"));
+        let (tx, rx) = channel::<Vec<u8>>();
+        (PathAdapter::new(fs), tx, rx)
+    }
+
+    #[test]
+    fn lookup_and_forget() {
+        let (mut fs, tx, rx) = setup();
+        let req = RequestContext::new(1, 0, 0, 0);
+        fs.lookup(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(1, tx.clone()),
+        );
+        let (error, ino) = parse_reply(&rx);
+        assert_eq!(error, 0);
+        assert_ne!(ino, FUSE_ROOT_ID);
+        // Looking up the same name again returns the same inode
+        fs.lookup(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(2, tx.clone()),
+        );
+        assert_eq!(parse_reply(&rx), (0, ino));
+        assert_eq!(fs.nodes[&ino].nlookup, 2);
+        fs.lookup(&req, FUSE_ROOT_ID, OsStr::new("c"), Reply::new(3, tx));
+        assert_eq!(parse_reply(&rx).0, libc::ENOENT);
+        fs.forget(&req, ino, 1);
+        assert!(fs.nodes.contains_key(&ino));
+        fs.forget(&req, ino, 1);
+        assert!(!fs.nodes.contains_key(&ino));
+        assert!(fs.names.is_empty());
+    }
+
+    #[test]
+    fn rename() {
+        let (mut fs, tx, rx) = setup();
+        let req = RequestContext::new(1, 0, 0, 0);
+        fs.lookup(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(1, tx.clone()),
+        );
+        let (_, ino) = parse_reply(&rx);
+        let (root, a, c) = (FUSE_ROOT_ID, OsStr::new("a"), OsStr::new("c"));
+        fs.rename(&req, root, a, root, c, Reply::new(2, tx.clone()));
+        assert_eq!(parse_reply(&rx).0, 0);
+        assert_eq!(fs.path(ino), Ok(PathBuf::from("/c")));
+        fs.open(&req, ino, OpenFlags::empty(), Reply::new(3, tx));
+        assert_eq!(parse_reply(&rx).0, 0);
+        assert_eq!(fs.filesystem().opened, [PathBuf::from("/c")]);
+    }
+
+    #[test]
+    fn unlink_open_file() {
+        let (mut fs, tx, rx) = setup();
+        let req = RequestContext::new(1, 0, 0, 0);
+        fs.lookup(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(1, tx.clone()),
+        );
+        let (_, ino) = parse_reply(&rx);
+        fs.open(&req, ino, OpenFlags::empty(), Reply::new(2, tx.clone()));
+        assert_eq!(parse_reply(&rx).0, 0);
+        // The open file is renamed to a hidden name instead of being removed
+        fs.unlink(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(3, tx.clone()),
+        );
+        assert_eq!(parse_reply(&rx).0, 0);
+        let hidden = fs.path(ino).unwrap();
+        assert!(hidden.to_str().unwrap().starts_with("/.fuse_hidden"));
+        assert!(fs.filesystem().files.contains(&hidden));
+        assert!(!fs.filesystem().files.contains(Path::new("/a")));
+        // Releasing the last handle removes the hidden file
+        fs.release(&req, ino, 0, 0, 0, ReleaseFlags::empty(), Reply::new(4, tx));
+        assert_eq!(parse_reply(&rx).0, 0);
+        assert!(!fs.filesystem().files.contains(&hidden));
+        assert_eq!(fs.path(ino), Err(Errno::ENOENT));
+        fs.forget(&req, ino, 1);
+        assert!(!fs.nodes.contains_key(&ino));
+    }
+
+    #[test]
+    fn readdir_snapshot() {
+        let (mut fs, tx, rx) = setup();
+        let req = RequestContext::new(1, 0, 0, 0);
+        fs.opendir(
+            &req,
+            FUSE_ROOT_ID,
+            OpenFlags::empty(),
+            Reply::new(1, tx.clone()),
+        );
+        let (error, fh) = parse_reply(&rx);
+        assert_eq!(error, 0);
+        // Only the first entry fits (32 bytes)
+        fs.readdir(
+            &req,
+            FUSE_ROOT_ID,
+            fh,
+            0,
+            ReplyDirectory::new(2, tx.clone(), 32),
+        );
+        assert_eq!(dir_entries(&recv_reply(&rx)), [(1, "a".to_string())]);
+        // Removing the first file doesn't skip the second one
+        fs.filesystem_mut().files.remove(Path::new("/a"));
+        fs.readdir(
+            &req,
+            FUSE_ROOT_ID,
+            fh,
+            1,
+            ReplyDirectory::new(3, tx.clone(), 4096),
+        );
+        assert_eq!(dir_entries(&recv_reply(&rx)), [(2, "b".to_string())]);
+        fs.releasedir(&req, FUSE_ROOT_ID, fh, 0, Reply::new(4, tx.clone()));
+        assert_eq!(parse_reply(&rx).0, 0);
+        fs.readdir(&req, FUSE_ROOT_ID, fh, 0, ReplyDirectory::new(5, tx, 4096));
+        assert_eq!(parse_reply(&rx).0, libc::EBADF);
+    }
+
+    #[test]
+    fn releasedir_after_rmdir() {
+        let (mut fs, tx, rx) = setup();
+        let req = RequestContext::new(1, 0, 0, 0);
+        fs.lookup(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(1, tx.clone()),
+        );
+        let (_, ino) = parse_reply(&rx);
+        fs.opendir(&req, ino, OpenFlags::empty(), Reply::new(2, tx.clone()));
+        let (error, fh) = parse_reply(&rx);
+        assert_eq!(error, 0);
+        fs.rmdir(
+            &req,
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            Reply::new(3, tx.clone()),
+        );
+        assert_eq!(parse_reply(&rx).0, 0);
+        assert_eq!(fs.path(ino), Err(Errno::ENOENT));
+        // The removed directory is still released with the path it was opened with
+        fs.releasedir(&req, ino, fh, 0, Reply::new(4, tx));
+        assert_eq!(parse_reply(&rx).0, 0);
+        assert_eq!(fs.filesystem().released, [PathBuf::from("/a")]);
+        assert!(fs.dirs.is_empty());
+    }
+}
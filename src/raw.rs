@@ -0,0 +1,78 @@
+//! Raw request handling
+//!
+//! A `RawHandler` gets every low-level request of a session (see `ll::Request`) together with
+//! a reply, instead of having it dispatched to the fixed set of `Filesystem` methods. This
+//! allows handling operations directly, e.g. for proxies that forward requests, multiplexers
+//! of several filesystems or experimental opcodes. Raw handlers are run by a `RawSession`.
+//! A `Session` is the raw handler that dispatches requests to its filesystem.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::buffer::{Buffer, WriteData};
+use crate::{ll, RawReply};
+
+/// Handler of low-level requests
+pub trait RawHandler {
+    /// Handle a request from the kernel driver. Requests that expect a reply must be replied
+    /// exactly once, either right away or later (e.g. from another thread). The reply sends
+    /// an I/O error if it's dropped without replying. INTERRUPT doesn't expect a reply, so its
+    /// reply is either ignored (see `RawReply::ignore`, or just dropped) or sends ENOSYS if
+    /// interrupts aren't supported or EAGAIN if the interrupted request isn't known yet.
+    /// Nothing is interpreted by the session, so the handler needs to negotiate the protocol
+    /// version at INIT itself, and reply to unknown operations (typically with ENOSYS).
+    fn handle(&mut self, request: &RawRequest<'_>, reply: RawReply);
+}
+
+/// Low-level request passed to a raw handler. Derefs to the parsed `ll::Request`.
+#[derive(Debug)]
+pub struct RawRequest<'a> {
+    /// Buffer the request was received in
+    buffer: &'a Arc<Buffer>,
+    /// Parsed request
+    request: ll::Request<'a>,
+}
+
+impl<'a> RawRequest<'a> {
+    /// Create a raw request for the given request parsed from the given buffer
+    pub(crate) fn new(buffer: &'a Arc<Buffer>, request: ll::Request<'a>) -> RawRequest<'a> {
+        RawRequest { buffer, request }
+    }
+
+    /// Returns the raw data of the request, including the header
+    pub fn data(&self) -> &[u8] {
+        self.buffer
+    }
+
+    /// Returns the given data of the request (usually the data of a write operation) as
+    /// `WriteData`, which can be kept after the handler returned without copying it. Panics if
+    /// the data isn't part of this request.
+    pub fn write_data(&self, data: &[u8]) -> WriteData {
+        WriteData::new(self.buffer, data)
+    }
+
+    /// Returns the data of a write request that was left in a pipe when receiving it (see
+    /// `Channel::receive_spliced`), or `None` if the request was received completely
+    pub(crate) fn spliced_data(&self) -> Option<WriteData> {
+        #[cfg(target_os = "linux")]
+        if let Some(spliced) = self.buffer.spliced() {
+            return Some(WriteData::spliced(spliced));
+        }
+        None
+    }
+}
+
+impl<'a> Deref for RawRequest<'a> {
+    type Target = ll::Request<'a>;
+
+    fn deref(&self) -> &ll::Request<'a> {
+        &self.request
+    }
+}
+
+impl fmt::Display for RawRequest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.request, f)
+    }
+}
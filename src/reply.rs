@@ -9,24 +9,48 @@
 #[cfg(target_os = "macos")]
 use fuse_abi::fuse_getxtimes_out;
 use fuse_abi::{
-    FuseAttr, FuseAttrOut, FuseBmapOut, FuseDirent, FuseEntryOut, FuseFileLock, FuseGetxattrOut,
-    FuseKstatfs, FuseLkOut, FuseOpenOut, FuseOutHeader, FuseStatfsOut, FuseWriteOut,
+    AsBytes, FuseAttr, FuseAttrOut, FuseBmapOut, FuseDirent, FuseEntryOut, FuseFileLock,
+    FuseGetxattrOut, FuseKstatfs, FuseLkOut, FuseOpenOut, FuseStatfsOut, FuseWriteOut,
 };
-use libc::{c_int, EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
+use libc::{c_int, EIO, ERANGE, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
 use log::warn;
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
-use std::{fmt, mem, ptr, slice};
+use std::{cmp, fmt, fs, io, mem};
+use tokio::sync::Notify;
 
-use crate::{FileAttr, FileType};
+use crate::channel::ChannelSender;
+use crate::ll::{self, Response};
+use crate::{FileAttr, FileType, FopenFlags, LockType, Statfs};
+
+/// Size of the chunks that reply data is read in if it can't be spliced from a file
+const FALLBACK_CHUNK_SIZE: usize = 128 * 1024;
 
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Send data followed by the given amount of data read from a file at the given offset,
+    /// without copying the file data through userspace if possible. Nothing must have been sent
+    /// if an error is returned, so that the caller can fall back to sending the data itself.
+    fn send_fd(
+        &self,
+        _data: &[&[u8]],
+        _fd: BorrowedFd<'_>,
+        _offset: u64,
+        _len: usize,
+    ) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
 
 impl fmt::Debug for Box<dyn ReplySender> {
@@ -35,22 +59,137 @@ impl fmt::Debug for Box<dyn ReplySender> {
     }
 }
 
+/// Counter of replies that were created but not sent yet
+#[derive(Debug, Default)]
+pub struct PendingReplies {
+    count: AtomicUsize,
+    notify: Notify,
+}
+
+impl PendingReplies {
+    /// Returns the number of pending replies
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Wait until there are no pending replies
+    pub async fn wait(&self) {
+        loop {
+            // Created before checking the count to not miss a notification in between
+            let notified = self.notify.notified();
+            if self.count() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Reply sender that counts as pending reply until it's dropped (i.e. the reply was sent)
+#[derive(Debug)]
+pub struct TrackedSender<S> {
+    sender: S,
+    pending: Arc<PendingReplies>,
+}
+
+impl<S> TrackedSender<S> {
+    /// Create a new sender that is tracked by the given counter
+    pub fn new(sender: S, pending: &Arc<PendingReplies>) -> TrackedSender<S> {
+        pending.count.fetch_add(1, Ordering::SeqCst);
+        TrackedSender {
+            sender,
+            pending: Arc::clone(pending),
+        }
+    }
+}
+
+impl<S> Drop for TrackedSender<S> {
+    fn drop(&mut self) {
+        if self.pending.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.pending.notify.notify_waiters();
+        }
+    }
+}
+
+impl<S: ReplySender> ReplySender for TrackedSender<S> {
+    fn send(&self, data: &[&[u8]]) {
+        self.sender.send(data);
+    }
+
+    fn send_fd(
+        &self,
+        data: &[&[u8]],
+        fd: BorrowedFd<'_>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<()> {
+        self.sender.send_fd(data, fd, offset, len)
+    }
+}
+
+/// Sender of a reply. The (tracked) channel sender of sessions is kept inline, so that
+/// creating a reply doesn't allocate. Other senders (e.g. for testing) are boxed. The sender
+/// type of a session converts into its variant (see the `From` impls).
+#[derive(Debug)]
+pub enum AnySender {
+    Channel(TrackedSender<ChannelSender>),
+    Boxed(Box<dyn ReplySender>),
+}
+
+impl AnySender {
+    /// Box the given sender
+    pub(crate) fn boxed<S: ReplySender>(sender: S) -> AnySender {
+        AnySender::Boxed(Box::new(sender))
+    }
+}
+
+impl From<TrackedSender<ChannelSender>> for AnySender {
+    fn from(sender: TrackedSender<ChannelSender>) -> AnySender {
+        AnySender::Channel(sender)
+    }
+}
+
+impl ReplySender for AnySender {
+    fn send(&self, data: &[&[u8]]) {
+        match self {
+            AnySender::Channel(sender) => sender.send(data),
+            AnySender::Boxed(sender) => sender.send(data),
+        }
+    }
+
+    fn send_fd(
+        &self,
+        data: &[&[u8]],
+        fd: BorrowedFd<'_>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<()> {
+        match self {
+            AnySender::Channel(sender) => sender.send_fd(data, fd, offset, len),
+            AnySender::Boxed(sender) => sender.send_fd(data, fd, offset, len),
+        }
+    }
+}
+
 /// Generic reply trait
 pub trait Reply {
     /// Create a new reply for the given request
     fn new<S: ReplySender>(unique: u64, sender: S) -> Self;
 }
 
-/// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
-fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
-    let len = mem::size_of::<T>();
-    match len {
-        0 => f(&[]),
-        len => {
-            let p = data as *const T as *const u8;
-            let bytes = unsafe { slice::from_raw_parts(p, len) };
-            f(&[bytes])
-        }
+/// Typed reply that wraps a raw reply. Sessions create replies from raw replies, which keeps
+/// their sender as is.
+pub(crate) trait FromRaw {
+    /// Type of the reply data
+    type Data;
+
+    /// Wrap the given raw reply
+    fn from_raw(reply: ReplyRaw<Self::Data>) -> Self;
+}
+
+impl<R: FromRaw> Reply for R {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> R {
+        R::from_raw(ReplyRaw::with_sender(unique, AnySender::boxed(sender)))
     }
 }
 
@@ -63,7 +202,7 @@ fn time_from_system_time(system_time: &SystemTime) -> Result<(u64, u32), SystemT
 // But others like macOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
 #[allow(trivial_numeric_casts)]
 /// Returns the mode for a given file kind and permission
-fn mode_from_kind_and_perm(kind: FileType, perm: u16) -> u32 {
+pub(crate) fn mode_from_kind_and_perm(kind: FileType, perm: u16) -> u32 {
     (match kind {
         FileType::NamedPipe => S_IFIFO,
         FileType::CharDevice => S_IFCHR,
@@ -102,6 +241,10 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> FuseAttr {
         gid: attr.gid,
         rdev: attr.rdev,
         flags: attr.flags,
+        #[cfg(feature = "abi-7-9")]
+        blksize: 0,
+        #[cfg(feature = "abi-7-9")]
+        padding: 0,
     }
 }
 
@@ -128,6 +271,10 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> FuseAttr {
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        #[cfg(feature = "abi-7-9")]
+        blksize: 0,
+        #[cfg(feature = "abi-7-9")]
+        padding: 0,
     }
 }
 
@@ -138,53 +285,86 @@ fn fuse_attr_from_attr(attr: &FileAttr) -> FuseAttr {
 pub struct ReplyRaw<T> {
     /// Unique id of the request to reply to
     unique: u64,
-    /// Closure to call for sending the reply
-    sender: Option<Box<dyn ReplySender>>,
+    /// Sender for sending the reply
+    sender: Option<AnySender>,
     /// Marker for being able to have T on this struct (which enforces
     /// reply types to send the correct type of data)
     marker: PhantomData<T>,
 }
 
-impl<T> Reply for ReplyRaw<T> {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyRaw<T> {
-        let sender = Box::new(sender);
+impl<T> FromRaw for ReplyRaw<T> {
+    type Data = T;
+
+    fn from_raw(reply: ReplyRaw<T>) -> ReplyRaw<T> {
+        reply
+    }
+}
+
+impl<T> ReplyRaw<T> {
+    /// Create a reply that is sent with the given sender
+    pub(crate) fn with_sender(unique: u64, sender: AnySender) -> ReplyRaw<T> {
         ReplyRaw {
             unique,
             sender: Some(sender),
             marker: PhantomData,
         }
     }
-}
 
-impl<T> ReplyRaw<T> {
-    /// Reply to a request with the given error code and data. Must be called
-    /// only once (the `ok` and `error` methods ensure this by consuming `self`)
-    fn send(&mut self, err: c_int, bytes: &[&[u8]]) {
+    /// Reply to a request with the given response. Must be called only once (the `ok` and
+    /// `error` methods ensure this by consuming `self`)
+    pub(crate) fn send(&mut self, response: Response<'_>) {
         assert!(self.sender.is_some());
-        let len = bytes.iter().fold(0, |l, b| l + b.len());
-        let header = FuseOutHeader {
-            len: (mem::size_of::<FuseOutHeader>() + len) as u32,
-            error: -err,
-            unique: self.unique,
-        };
-        as_bytes(&header, |headerbytes| {
-            let sender = self.sender.take().unwrap();
-            let mut sendbytes = headerbytes.to_vec();
-            sendbytes.extend(bytes);
-            sender.send(&sendbytes);
-        });
+        let sender = self.sender.take().unwrap();
+        response.with_iovec(self.unique, |iovec| sender.send(iovec));
+    }
+
+    /// Reply to a request with up to the given amount of data read from a file at the given
+    /// offset. The data is spliced to the kernel driver if the sender supports it, otherwise
+    /// it's read into memory and sent normally. Must be called only once.
+    fn send_fd(&mut self, fd: BorrowedFd<'_>, offset: u64, len: usize) {
+        assert!(self.sender.is_some());
+        // The announced length must match the data that can be spliced, so don't try to send
+        // more than there is left in a regular file
+        let mut len = len;
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } == 0
+            && stat.st_mode & libc::S_IFMT == S_IFREG
+        {
+            let left = (stat.st_size as u64).saturating_sub(offset);
+            len = cmp::min(len as u64, left) as usize;
+        }
+        let header = ll::out_header(self.unique, 0, len);
+        let sender = self.sender.as_ref().unwrap();
+        match sender.send_fd(&[header.as_bytes()], fd, offset, len) {
+            Ok(()) => drop(self.sender.take()),
+            Err(_) => self.send_fd_fallback(fd, offset, len),
+        }
     }
 
-    /// Reply to a request with the given type
-    pub fn ok(mut self, data: &T) {
-        as_bytes(data, |bytes| {
-            self.send(0, bytes);
-        })
+    /// Read up to the given amount of data from a file at the given offset and send it. The
+    /// data is read in chunks, so that no more memory is allocated than there is data.
+    fn send_fd_fallback(&mut self, fd: BorrowedFd<'_>, offset: u64, len: usize) {
+        let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd.as_raw_fd()) });
+        let mut data = Vec::new();
+        while data.len() < len {
+            let pos = data.len();
+            data.resize(pos + cmp::min(len - pos, FALLBACK_CHUNK_SIZE), 0);
+            match file.read_at(&mut data[pos..], offset + pos as u64) {
+                Ok(0) => {
+                    data.truncate(pos);
+                    break;
+                }
+                Ok(n) => data.truncate(pos + n),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => data.truncate(pos),
+                Err(err) => return self.send(Response::Error(err.raw_os_error().unwrap_or(EIO))),
+            }
+        }
+        self.send(Response::Data(&data));
     }
 
     /// Reply to a request with the given error code
     pub fn error(mut self, err: c_int) {
-        self.send(err, &[]);
+        self.send(Response::Error(err));
     }
 }
 
@@ -195,7 +375,90 @@ impl<T> Drop for ReplyRaw<T> {
                 "Reply not sent for operation {}, replying with I/O error",
                 self.unique
             );
-            self.send(EIO, &[]);
+            self.send(Response::Error(EIO));
+        }
+    }
+}
+
+///
+/// Reply to a raw request (see `RawHandler`)
+///
+/// Sends a low-level response. Like the typed replies, it must be used exactly once and
+/// replies with an I/O error if it's dropped without replying. Requests that don't expect a
+/// reply (like FORGET) get a reply that doesn't send anything. INTERRUPT doesn't expect a reply
+/// either, but gets a reply that can send an error (see `Filesystem::interrupt`). Dropping it
+/// doesn't send anything.
+#[derive(Debug)]
+pub struct RawReply {
+    /// Unique id of the request to reply to
+    unique: u64,
+    /// Reply to send, if the request accepts one
+    reply: Option<ReplyRaw<()>>,
+    /// True if the kernel driver waits for the reply
+    expected: bool,
+}
+
+impl RawReply {
+    /// Create a new reply for the given request, which isn't sent if there's no sender. If the
+    /// reply isn't expected, it's not sent when dropped.
+    pub(crate) fn new(unique: u64, sender: Option<AnySender>, expected: bool) -> RawReply {
+        RawReply {
+            unique,
+            reply: sender.map(|sender| ReplyRaw::with_sender(unique, sender)),
+            expected,
+        }
+    }
+
+    /// Returns the unique id of the request to reply to
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Returns true if the kernel driver waits for a reply to the request. INTERRUPT doesn't
+    /// expect a reply, but can be answered with ENOSYS (interrupts aren't supported) or EAGAIN
+    /// (the interrupted request isn't known yet).
+    pub fn expected(&self) -> bool {
+        self.expected
+    }
+
+    /// Reply to the request with the given response
+    pub fn send(mut self, response: Response<'_>) {
+        match self.reply.take() {
+            Some(mut reply) => reply.send(response),
+            None => warn!(
+                "Not replying to operation {}, which doesn't expect a reply",
+                self.unique
+            ),
+        }
+    }
+
+    /// Reply to the request with the given error code
+    pub fn error(self, err: c_int) {
+        self.send(Response::Error(err));
+    }
+
+    /// Don't reply to the request. Only valid for requests the kernel driver doesn't wait for
+    /// a reply to (like INTERRUPT).
+    pub fn ignore(mut self) {
+        if let Some(mut reply) = self.reply.take() {
+            drop(reply.sender.take());
+        }
+    }
+
+    /// Convert into a typed reply for passing it to a filesystem method. Panics if the
+    /// request doesn't expect a reply.
+    pub(crate) fn into_reply<T: FromRaw>(mut self) -> T {
+        let mut reply = self.reply.take().expect("Operation doesn't expect a reply");
+        let sender = reply.sender.take().unwrap();
+        T::from_raw(ReplyRaw::with_sender(self.unique, sender))
+    }
+}
+
+impl Drop for RawReply {
+    fn drop(&mut self) {
+        // Replies to requests the kernel driver doesn't wait for are only sent explicitly
+        if let (false, Some(reply)) = (self.expected, &mut self.reply) {
+            drop(reply.sender.take());
         }
     }
 }
@@ -208,18 +471,18 @@ pub struct ReplyEmpty {
     reply: ReplyRaw<()>,
 }
 
-impl Reply for ReplyEmpty {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEmpty {
-        ReplyEmpty {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyEmpty {
+    type Data = ();
+
+    fn from_raw(reply: ReplyRaw<()>) -> ReplyEmpty {
+        ReplyEmpty { reply }
     }
 }
 
 impl ReplyEmpty {
     /// Reply to a request with nothing
     pub fn ok(mut self) {
-        self.reply.send(0, &[]);
+        self.reply.send(Response::Empty);
     }
 
     /// Reply to a request with the given error code
@@ -236,18 +499,25 @@ pub struct ReplyData {
     reply: ReplyRaw<()>,
 }
 
-impl Reply for ReplyData {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyData {
-        ReplyData {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyData {
+    type Data = ();
+
+    fn from_raw(reply: ReplyRaw<()>) -> ReplyData {
+        ReplyData { reply }
     }
 }
 
 impl ReplyData {
     /// Reply to a request with the given data
     pub fn data(mut self, data: &[u8]) {
-        self.reply.send(0, &[data]);
+        self.reply.send(Response::Data(data));
+    }
+
+    /// Reply to a request with up to the given amount of data read from a file at the given
+    /// offset. If splice is enabled, the data is moved from the file to the kernel driver
+    /// without being copied through userspace. Less data is sent if the end of file is reached.
+    pub fn data_fd<F: AsFd>(mut self, file: &F, offset: u64, len: u32) {
+        self.reply.send_fd(file.as_fd(), offset, len as usize);
     }
 
     /// Reply to a request with the given error code
@@ -264,18 +534,18 @@ pub struct ReplyEntry {
     reply: ReplyRaw<FuseEntryOut>,
 }
 
-impl Reply for ReplyEntry {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyEntry {
-        ReplyEntry {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyEntry {
+    type Data = FuseEntryOut;
+
+    fn from_raw(reply: ReplyRaw<FuseEntryOut>) -> ReplyEntry {
+        ReplyEntry { reply }
     }
 }
 
 impl ReplyEntry {
     /// Reply to a request with the given entry
-    pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
-        self.reply.ok(&FuseEntryOut {
+    pub fn entry(mut self, ttl: &Duration, attr: &FileAttr, generation: u64) {
+        self.reply.send(Response::Entry(FuseEntryOut {
             nodeid: attr.ino,
             generation,
             entry_valid: ttl.as_secs(),
@@ -283,7 +553,7 @@ impl ReplyEntry {
             entry_valid_nsec: ttl.subsec_nanos(),
             attr_valid_nsec: ttl.subsec_nanos(),
             attr: fuse_attr_from_attr(attr),
-        });
+        }));
     }
 
     /// Reply to a request with the given error code
@@ -300,23 +570,23 @@ pub struct ReplyAttr {
     reply: ReplyRaw<FuseAttrOut>,
 }
 
-impl Reply for ReplyAttr {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyAttr {
-        ReplyAttr {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyAttr {
+    type Data = FuseAttrOut;
+
+    fn from_raw(reply: ReplyRaw<FuseAttrOut>) -> ReplyAttr {
+        ReplyAttr { reply }
     }
 }
 
 impl ReplyAttr {
     /// Reply to a request with the given attribute
-    pub fn attr(self, ttl: &Duration, attr: &FileAttr) {
-        self.reply.ok(&FuseAttrOut {
+    pub fn attr(mut self, ttl: &Duration, attr: &FileAttr) {
+        self.reply.send(Response::Attr(FuseAttrOut {
             attr_valid: ttl.as_secs(),
             attr_valid_nsec: ttl.subsec_nanos(),
             dummy: 0,
             attr: fuse_attr_from_attr(attr),
-        });
+        }));
     }
 
     /// Reply to a request with the given error code
@@ -335,27 +605,27 @@ pub struct ReplyXTimes {
 }
 
 #[cfg(target_os = "macos")]
-impl Reply for ReplyXTimes {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyXTimes {
-        ReplyXTimes {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyXTimes {
+    type Data = fuse_getxtimes_out;
+
+    fn from_raw(reply: ReplyRaw<fuse_getxtimes_out>) -> ReplyXTimes {
+        ReplyXTimes { reply }
     }
 }
 
 #[cfg(target_os = "macos")]
 impl ReplyXTimes {
     /// Reply to a request with the given xtimes
-    pub fn xtimes(self, bkuptime: SystemTime, crtime: SystemTime) {
+    pub fn xtimes(mut self, bkuptime: SystemTime, crtime: SystemTime) {
         // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
         let (bkuptime_secs, bkuptime_nanos) = time_from_system_time(&bkuptime).unwrap();
         let (crtime_secs, crtime_nanos) = time_from_system_time(&crtime).unwrap();
-        self.reply.ok(&fuse_getxtimes_out {
+        self.reply.send(Response::XTimes(fuse_getxtimes_out {
             bkuptime: bkuptime_secs,
             crtime: crtime_secs,
             bkuptimensec: bkuptime_nanos,
             crtimensec: crtime_nanos,
-        });
+        }));
     }
 
     /// Reply to a request with the given error code
@@ -372,22 +642,22 @@ pub struct ReplyOpen {
     reply: ReplyRaw<FuseOpenOut>,
 }
 
-impl Reply for ReplyOpen {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyOpen {
-        ReplyOpen {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyOpen {
+    type Data = FuseOpenOut;
+
+    fn from_raw(reply: ReplyRaw<FuseOpenOut>) -> ReplyOpen {
+        ReplyOpen { reply }
     }
 }
 
 impl ReplyOpen {
     /// Reply to a request with the given open result
-    pub fn opened(self, fh: u64, flags: u32) {
-        self.reply.ok(&FuseOpenOut {
+    pub fn opened(mut self, fh: u64, flags: FopenFlags) {
+        self.reply.send(Response::Open(FuseOpenOut {
             fh,
-            open_flags: flags,
+            open_flags: flags.bits(),
             padding: 0,
-        });
+        }));
     }
 
     /// Reply to a request with the given error code
@@ -404,18 +674,19 @@ pub struct ReplyWrite {
     reply: ReplyRaw<FuseWriteOut>,
 }
 
-impl Reply for ReplyWrite {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyWrite {
-        ReplyWrite {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyWrite {
+    type Data = FuseWriteOut;
+
+    fn from_raw(reply: ReplyRaw<FuseWriteOut>) -> ReplyWrite {
+        ReplyWrite { reply }
     }
 }
 
 impl ReplyWrite {
     /// Reply to a request with the given open result
-    pub fn written(self, size: u32) {
-        self.reply.ok(&FuseWriteOut { size, padding: 0 });
+    pub fn written(mut self, size: u32) {
+        self.reply
+            .send(Response::Write(FuseWriteOut { size, padding: 0 }));
     }
 
     /// Reply to a request with the given error code
@@ -432,42 +703,31 @@ pub struct ReplyStatfs {
     reply: ReplyRaw<FuseStatfsOut>,
 }
 
-impl Reply for ReplyStatfs {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyStatfs {
-        ReplyStatfs {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyStatfs {
+    type Data = FuseStatfsOut;
+
+    fn from_raw(reply: ReplyRaw<FuseStatfsOut>) -> ReplyStatfs {
+        ReplyStatfs { reply }
     }
 }
 
 impl ReplyStatfs {
     /// Reply to a request with the given open result
-    #[allow(clippy::too_many_arguments)]
-    pub fn statfs(
-        self,
-        blocks: u64,
-        bfree: u64,
-        bavail: u64,
-        files: u64,
-        ffree: u64,
-        bsize: u32,
-        namelen: u32,
-        frsize: u32,
-    ) {
-        self.reply.ok(&FuseStatfsOut {
+    pub fn statfs(mut self, stats: &Statfs) {
+        self.reply.send(Response::Statfs(FuseStatfsOut {
             st: FuseKstatfs {
-                blocks,
-                bfree,
-                bavail,
-                files,
-                ffree,
-                bsize,
-                namelen,
-                frsize,
+                blocks: stats.blocks,
+                bfree: stats.bfree,
+                bavail: stats.bavail,
+                files: stats.files,
+                ffree: stats.ffree,
+                bsize: stats.bsize,
+                namelen: stats.namelen,
+                frsize: stats.frsize,
                 padding: 0,
                 spare: [0; 6],
             },
-        });
+        }));
     }
 
     /// Reply to a request with the given error code
@@ -484,33 +744,39 @@ pub struct ReplyCreate {
     reply: ReplyRaw<(FuseEntryOut, FuseOpenOut)>,
 }
 
-impl Reply for ReplyCreate {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyCreate {
-        ReplyCreate {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyCreate {
+    type Data = (FuseEntryOut, FuseOpenOut);
+
+    fn from_raw(reply: ReplyRaw<(FuseEntryOut, FuseOpenOut)>) -> ReplyCreate {
+        ReplyCreate { reply }
     }
 }
 
 impl ReplyCreate {
     /// Reply to a request with the given entry
-    pub fn created(self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
-        self.reply.ok(&(
-            FuseEntryOut {
-                nodeid: attr.ino,
-                generation,
-                entry_valid: ttl.as_secs(),
-                attr_valid: ttl.as_secs(),
-                entry_valid_nsec: ttl.subsec_nanos(),
-                attr_valid_nsec: ttl.subsec_nanos(),
-                attr: fuse_attr_from_attr(attr),
-            },
-            FuseOpenOut {
-                fh,
-                open_flags: flags,
-                padding: 0,
-            },
-        ));
+    pub fn created(
+        mut self,
+        ttl: &Duration,
+        attr: &FileAttr,
+        generation: u64,
+        fh: u64,
+        flags: FopenFlags,
+    ) {
+        let entry = FuseEntryOut {
+            nodeid: attr.ino,
+            generation,
+            entry_valid: ttl.as_secs(),
+            attr_valid: ttl.as_secs(),
+            entry_valid_nsec: ttl.subsec_nanos(),
+            attr_valid_nsec: ttl.subsec_nanos(),
+            attr: fuse_attr_from_attr(attr),
+        };
+        let open = FuseOpenOut {
+            fh,
+            open_flags: flags.bits(),
+            padding: 0,
+        };
+        self.reply.send(Response::Create(entry, open));
     }
 
     /// Reply to a request with the given error code
@@ -527,25 +793,25 @@ pub struct ReplyLock {
     reply: ReplyRaw<FuseLkOut>,
 }
 
-impl Reply for ReplyLock {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyLock {
-        ReplyLock {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyLock {
+    type Data = FuseLkOut;
+
+    fn from_raw(reply: ReplyRaw<FuseLkOut>) -> ReplyLock {
+        ReplyLock { reply }
     }
 }
 
 impl ReplyLock {
     /// Reply to a request with the given open result
-    pub fn locked(self, start: u64, end: u64, typ: u32, pid: u32) {
-        self.reply.ok(&FuseLkOut {
+    pub fn locked(mut self, start: u64, end: u64, typ: LockType, pid: u32) {
+        self.reply.send(Response::Lock(FuseLkOut {
             lk: FuseFileLock {
                 start,
                 end,
-                typ,
+                typ: typ.raw(),
                 pid,
             },
-        });
+        }));
     }
 
     /// Reply to a request with the given error code
@@ -562,18 +828,18 @@ pub struct ReplyBmap {
     reply: ReplyRaw<FuseBmapOut>,
 }
 
-impl Reply for ReplyBmap {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyBmap {
-        ReplyBmap {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyBmap {
+    type Data = FuseBmapOut;
+
+    fn from_raw(reply: ReplyRaw<FuseBmapOut>) -> ReplyBmap {
+        ReplyBmap { reply }
     }
 }
 
 impl ReplyBmap {
     /// Reply to a request with the given open result
-    pub fn bmap(self, block: u64) {
-        self.reply.ok(&FuseBmapOut { block });
+    pub fn bmap(mut self, block: u64) {
+        self.reply.send(Response::Bmap(FuseBmapOut { block }));
     }
 
     /// Reply to a request with the given error code
@@ -591,46 +857,78 @@ pub struct ReplyDirectory {
     data: Vec<u8>,
 }
 
+/// Maximum length of names in directory entries (FUSE_NAME_MAX in the kernel)
+const FUSE_NAME_MAX: usize = 1024;
+
 impl ReplyDirectory {
-    /// Creates a new ReplyDirectory with a specified buffer size.
+    /// Creates a new ReplyDirectory with a specified buffer size. The size should be the size
+    /// the kernel requested in the READDIR request, entries that don't fit are not added.
     pub fn new<S: ReplySender>(unique: u64, sender: S, size: usize) -> ReplyDirectory {
+        ReplyDirectory::from_raw(Reply::new(unique, sender), size)
+    }
+
+    /// Wrap the given raw reply, with a buffer of the given size
+    pub(crate) fn from_raw(reply: ReplyRaw<()>, size: usize) -> ReplyDirectory {
         ReplyDirectory {
-            reply: Reply::new(unique, sender),
+            reply,
             data: Vec::with_capacity(size),
         }
     }
 
     /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
     /// A transparent offset value can be provided for each entry. The kernel uses these
-    /// value to request the next entries in further readdir calls
+    /// value to request the next entries in further readdir calls. Entries with invalid
+    /// names (empty, too long, or containing '/' or NUL) are skipped.
     pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
         let name = name.as_ref().as_bytes();
+        if name.is_empty()
+            || name.len() > FUSE_NAME_MAX
+            || name.iter().any(|&c| c == 0 || c == b'/')
+        {
+            warn!(
+                "Skipping directory entry with invalid name {:?}",
+                OsStr::from_bytes(name)
+            );
+            return false;
+        }
         let entlen = mem::size_of::<FuseDirent>() + name.len();
         let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
-        let padlen = entsize - entlen;
         if self.data.len() + entsize > self.data.capacity() {
             return true;
         }
-        unsafe {
-            let p = self.data.as_mut_ptr().add(self.data.len());
-            let pdirent: *mut FuseDirent = mem::transmute(p);
-            (*pdirent).ino = ino;
-            (*pdirent).off = offset as u64;
-            (*pdirent).namelen = name.len() as u32;
-            (*pdirent).typ = mode_from_kind_and_perm(kind, 0) >> 12;
-            let p = p.add(mem::size_of_val(&*pdirent));
-            ptr::copy_nonoverlapping(name.as_ptr(), p, name.len());
-            let p = p.add(name.len());
-            ptr::write_bytes(p, 0u8, padlen);
-            let newlen = self.data.len() + entsize;
-            self.data.set_len(newlen);
-        }
+        // Entries are 64bit aligned, as the kernel expects them
+        debug_assert_eq!(self.data.len() % mem::size_of::<u64>(), 0);
+        self.data.extend_from_slice(&ino.to_ne_bytes());
+        self.data.extend_from_slice(&(offset as u64).to_ne_bytes());
+        self.data
+            .extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        self.data
+            .extend_from_slice(&(mode_from_kind_and_perm(kind, 0) >> 12).to_ne_bytes());
+        self.data.extend_from_slice(name);
+        self.data.resize(self.data.len() + entsize - entlen, 0);
         false
     }
 
+    /// Add the given entries following the given offset and send the reply. The offset of
+    /// every entry is its index in the iterator plus one, so that the kernel continues with
+    /// the next entry. As many entries as fit into the buffer are sent.
+    pub fn fill<I, T>(mut self, offset: i64, entries: I)
+    where
+        I: IntoIterator<Item = (u64, FileType, T)>,
+        T: AsRef<OsStr>,
+    {
+        let start = usize::try_from(offset).unwrap_or(0);
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(start) {
+            if self.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        self.ok();
+    }
+
     /// Reply to a request with the filled directory buffer
     pub fn ok(mut self) {
-        self.reply.send(0, &[&self.data]);
+        self.reply.send(Response::Data(&self.data));
     }
 
     /// Reply to a request with the given error code
@@ -647,23 +945,52 @@ pub struct ReplyXattr {
     reply: ReplyRaw<FuseGetxattrOut>,
 }
 
-impl Reply for ReplyXattr {
-    fn new<S: ReplySender>(unique: u64, sender: S) -> ReplyXattr {
-        ReplyXattr {
-            reply: Reply::new(unique, sender),
-        }
+impl FromRaw for ReplyXattr {
+    type Data = FuseGetxattrOut;
+
+    fn from_raw(reply: ReplyRaw<FuseGetxattrOut>) -> ReplyXattr {
+        ReplyXattr { reply }
     }
 }
 
 impl ReplyXattr {
     /// Reply to a request with the size of the xattr.
-    pub fn size(self, size: u32) {
-        self.reply.ok(&FuseGetxattrOut { size, padding: 0 });
+    pub fn size(mut self, size: u32) {
+        self.reply
+            .send(Response::XattrSize(FuseGetxattrOut { size, padding: 0 }));
     }
 
     /// Reply to a request with the data in the xattr.
     pub fn data(mut self, data: &[u8]) {
-        self.reply.send(0, &[data]);
+        self.reply.send(Response::Data(data));
+    }
+
+    /// Reply to a getxattr request with the given value. Follows the size probing protocol:
+    /// If the requested size is 0, only the size of the value is sent. If the value doesn't
+    /// fit into the requested size, ERANGE is sent. Otherwise the value is sent.
+    pub fn value(self, size: u32, value: &[u8]) {
+        if size == 0 {
+            self.size(value.len() as u32);
+        } else if value.len() > size as usize {
+            self.error(ERANGE);
+        } else {
+            self.data(value);
+        }
+    }
+
+    /// Reply to a listxattr request with the given names. The names are sent as a list of
+    /// NUL-terminated strings, following the same size probing protocol as `value`.
+    pub fn list<I, T>(self, size: u32, names: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<OsStr>,
+    {
+        let mut list = Vec::new();
+        for name in names {
+            list.extend_from_slice(name.as_ref().as_bytes());
+            list.push(0);
+        }
+        self.value(size, &list);
     }
 
     /// Reply to a request with the given error code.
@@ -677,10 +1004,13 @@ mod test {
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
     use super::{
-        as_bytes, Reply, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+        Reply, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
         ReplyEntry, ReplyLock, ReplyOpen, ReplyRaw, ReplyStatfs, ReplyWrite, ReplyXattr,
     };
-    use crate::{FileAttr, FileType};
+    use crate::ll::Response;
+    use crate::testing::recv_reply;
+    use crate::{FileAttr, FileType, FopenFlags, LockType, Statfs};
+    use fuse_abi::AsBytes;
     use std::sync::mpsc::{channel, Sender};
     use std::thread;
     use std::time::{Duration, UNIX_EPOCH};
@@ -693,52 +1023,7 @@ mod test {
         c: u16,
     }
 
-    #[test]
-    fn serialize_empty() {
-        let data = ();
-        as_bytes(&data, |bytes| {
-            assert!(bytes.is_empty());
-        });
-    }
-
-    #[test]
-    fn serialize_slice() {
-        let data: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
-        as_bytes(&data, |bytes| {
-            assert_eq!(bytes, [[0x12, 0x34, 0x56, 0x78]]);
-        });
-    }
-
-    #[test]
-    fn serialize_struct() {
-        let data = Data {
-            a: 0x12,
-            b: 0x34,
-            c: 0x5678,
-        };
-        as_bytes(&data, |bytes| {
-            assert_eq!(bytes, [[0x12, 0x34, 0x78, 0x56]]);
-        });
-    }
-
-    #[test]
-    fn serialize_tuple() {
-        let data = (
-            Data {
-                a: 0x12,
-                b: 0x34,
-                c: 0x5678,
-            },
-            Data {
-                a: 0x9a,
-                b: 0xbc,
-                c: 0xdef0,
-            },
-        );
-        as_bytes(&data, |bytes| {
-            assert_eq!(bytes, [[0x12, 0x34, 0x78, 0x56, 0x9a, 0xbc, 0xf0, 0xde]]);
-        });
-    }
+    unsafe impl AsBytes for Data {}
 
     struct AssertSender {
         expected: Vec<Vec<u8>>,
@@ -750,6 +1035,16 @@ mod test {
         }
     }
 
+    /// Adjust an expected reply whose given slice ends with file attributes to the ABI in
+    /// use. ABI 7.9 added blksize and padding to the attributes.
+    fn with_attr_abi(mut expected: Vec<Vec<u8>>, slice: usize) -> Vec<Vec<u8>> {
+        if cfg!(feature = "abi-7-9") {
+            expected[0][0] += 8;
+            expected[slice].extend_from_slice(&[0; 8]);
+        }
+        expected
+    }
+
     #[test]
     fn reply_raw() {
         let data = Data {
@@ -766,8 +1061,8 @@ mod test {
                 vec![0x12, 0x34, 0x78, 0x56],
             ],
         };
-        let reply: ReplyRaw<Data> = Reply::new(0xdeadbeef, sender);
-        reply.ok(&data);
+        let mut reply: ReplyRaw<Data> = Reply::new(0xdeadbeef, sender);
+        reply.send(Response::Data(data.as_bytes()));
     }
 
     #[test]
@@ -810,49 +1105,114 @@ mod test {
     }
 
     #[test]
-    fn reply_entry() {
+    fn reply_data_fd() {
+        // Without splice support, data is read from the file (up to the end of file)
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![
-                        0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0x99, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            } else {
+            expected: vec![
                 vec![
+                    0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                b"23456789".to_vec(),
+            ],
+        };
+        let path = std::env::temp_dir().join(format!("fuse-reply-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.data_fd(&file, 2, 100);
+    }
+
+    /// Sender that only supports splicing and checks the header and the length of the data
+    struct SpliceSender {
+        expected: Vec<u8>,
+        len: usize,
+    }
+
+    impl super::ReplySender for SpliceSender {
+        fn send(&self, _data: &[&[u8]]) {
+            panic!("Reply data wasn't spliced");
+        }
+
+        fn send_fd(
+            &self,
+            data: &[&[u8]],
+            _fd: std::os::unix::io::BorrowedFd<'_>,
+            _offset: u64,
+            len: usize,
+        ) -> std::io::Result<()> {
+            assert_eq!(data, [&self.expected[..]]);
+            assert_eq!(len, self.len);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reply_data_fd_at_eof() {
+        // Data up to the end of file is still spliced
+        let sender = SpliceSender {
+            expected: vec![
+                0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00,
+                0x00, 0x00,
+            ],
+            len: 8,
+        };
+        let path = std::env::temp_dir().join(format!("fuse-reply-eof-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let reply: ReplyData = Reply::new(0xdeadbeef, sender);
+        reply.data_fd(&file, 2, u32::MAX);
+    }
+
+    #[test]
+    fn reply_entry() {
+        let sender = AssertSender {
+            expected: with_attr_abi(
+                if cfg!(target_os = "macos") {
                     vec![
-                        0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
+                        vec![
+                            0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0x99, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                } else {
                     vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            },
+                        vec![
+                            0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                },
+                1,
+            ),
         };
         let reply: ReplyEntry = Reply::new(0xdeadbeef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
@@ -879,43 +1239,46 @@ mod test {
     #[test]
     fn reply_attr() {
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![
-                        0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0x99, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            } else {
-                vec![
+            expected: with_attr_abi(
+                if cfg!(target_os = "macos") {
                     vec![
-                        0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
+                        vec![
+                            0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0x99, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                } else {
                     vec![
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            },
+                        vec![
+                            0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                },
+                1,
+            ),
         };
         let reply: ReplyAttr = Reply::new(0xdeadbeef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
@@ -974,7 +1337,7 @@ mod test {
             ],
         };
         let reply: ReplyOpen = Reply::new(0xdeadbeef, sender);
-        reply.opened(0x1122, 0x33);
+        reply.opened(0x1122, FopenFlags::from_bits_retain(0x33));
     }
 
     #[test]
@@ -1012,56 +1375,73 @@ mod test {
             ],
         };
         let reply: ReplyStatfs = Reply::new(0xdeadbeef, sender);
-        reply.statfs(0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88);
+        reply.statfs(&Statfs {
+            blocks: 0x11,
+            bfree: 0x22,
+            bavail: 0x33,
+            files: 0x44,
+            ffree: 0x55,
+            bsize: 0x66,
+            namelen: 0x77,
+            frsize: 0x88,
+        });
     }
 
     #[test]
     fn reply_create() {
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
+            expected: with_attr_abi(
+                if cfg!(target_os = "macos") {
                     vec![
-                        0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
+                        vec![
+                            0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0x99, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                } else {
                     vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0x99, 0x00, 0x00, 0x00, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            } else {
-                vec![
-                    vec![
-                        0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            },
+                        vec![
+                            0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                },
+                1,
+            ),
         };
         let reply: ReplyCreate = Reply::new(0xdeadbeef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
@@ -1082,7 +1462,7 @@ mod test {
             rdev: 0x88,
             flags: 0x99,
         };
-        reply.created(&ttl, &attr, 0xaa, 0xbb, 0xcc);
+        reply.created(&ttl, &attr, 0xaa, 0xbb, FopenFlags::from_bits_retain(0xcc));
     }
 
     #[test]
@@ -1100,7 +1480,7 @@ mod test {
             ],
         };
         let reply: ReplyLock = Reply::new(0xdeadbeef, sender);
-        reply.locked(0x11, 0x22, 0x33, 0x44);
+        reply.locked(0x11, 0x22, LockType::from_raw(0x33), 0x44);
     }
 
     #[test]
@@ -1147,6 +1527,39 @@ mod test {
         }
     }
 
+    #[test]
+    fn reply_directory_limits() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let mut reply = ReplyDirectory::new(0xdeadbeef, tx, 64);
+        // Invalid names are skipped
+        assert!(!reply.add(1, 1, FileType::RegularFile, ""));
+        assert!(!reply.add(1, 1, FileType::RegularFile, "a/b"));
+        assert!(!reply.add(1, 1, FileType::RegularFile, "a\0b"));
+        assert!(!reply.add(1, 1, FileType::RegularFile, "x".repeat(1025)));
+        // Two entries of 32 bytes fit, the third doesn't
+        assert!(!reply.add(1, 1, FileType::RegularFile, "a"));
+        assert!(!reply.add(2, 2, FileType::RegularFile, "b"));
+        assert!(reply.add(3, 3, FileType::RegularFile, "c"));
+        reply.ok();
+        assert_eq!(recv_reply(&rx).data.len(), 64);
+    }
+
+    #[test]
+    fn reply_directory_fill() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let entries = [
+            (1, FileType::Directory, "."),
+            (1, FileType::Directory, ".."),
+            (2, FileType::RegularFile, "a"),
+        ];
+        ReplyDirectory::new(0xdeadbeef, tx, 4096).fill(1, entries);
+        let data = recv_reply(&rx).data;
+        assert_eq!(data.len(), 64);
+        // Offsets continue at the index of the following entry
+        assert_eq!(i64::from_ne_bytes(data[8..16].try_into().unwrap()), 2);
+        assert_eq!(i64::from_ne_bytes(data[40..48].try_into().unwrap()), 3);
+    }
+
     #[test]
     fn reply_xattr_size() {
         let sender = AssertSender {
@@ -1174,7 +1587,29 @@ mod test {
             ],
         };
         let reply = ReplyXattr::new(0xdeadbeef, sender);
-        reply.data(&vec![0x11, 0x22, 0x33, 0x44]);
+        reply.data(&[0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    fn reply_xattr_value() {
+        let (tx, rx) = channel::<Vec<u8>>();
+        // Size probe
+        ReplyXattr::new(1, tx.clone()).value(0, b"value");
+        let data = recv_reply(&rx).data;
+        assert_eq!(u32::from_ne_bytes(data[0..4].try_into().unwrap()), 5);
+        // Buffer too small
+        ReplyXattr::new(2, tx.clone()).value(4, b"value");
+        assert_eq!(recv_reply(&rx).error, libc::ERANGE);
+        ReplyXattr::new(3, tx.clone()).value(5, b"value");
+        assert_eq!(recv_reply(&rx).data, b"value");
+        // Names are NUL-terminated
+        ReplyXattr::new(4, tx.clone()).list(0, ["user.a", "user.b"]);
+        let data = recv_reply(&rx).data;
+        assert_eq!(u32::from_ne_bytes(data[0..4].try_into().unwrap()), 14);
+        ReplyXattr::new(5, tx.clone()).list(13, ["user.a", "user.b"]);
+        assert_eq!(recv_reply(&rx).error, libc::ERANGE);
+        ReplyXattr::new(6, tx).list(100, ["user.a", "user.b"]);
+        assert_eq!(recv_reply(&rx).data, b"user.a\0user.b\0");
     }
 
     #[test]
@@ -9,14 +9,22 @@ use fuse_abi::consts::*;
 use fuse_abi::*;
 use libc::{EIO, ENOSYS, EPROTO};
 use log::{debug, error, warn};
-use std::convert::TryFrom;
+#[cfg(target_os = "linux")]
+use std::fs;
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::{cmp, io};
 
+use crate::buffer::Buffer;
 use crate::channel::ChannelSender;
-use crate::reply::{Reply, ReplyDirectory, ReplyEmpty, ReplyRaw};
-use crate::session::{Session, MAX_WRITE_SIZE};
-use crate::{ll, Filesystem};
+use crate::reply::{AnySender, PendingReplies, ReplyDirectory, TrackedSender};
+use crate::session::Session;
+#[cfg(feature = "abi-7-16")]
+use crate::Forget;
+use crate::{
+    ll, Filesystem, LockRequest, OpenFlags, RawHandler, RawReply, RawRequest, ReleaseFlags,
+    SetAttr, XattrFlags,
+};
 
 /// We generally support async reads
 #[cfg(not(target_os = "macos"))]
@@ -31,38 +39,117 @@ const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAM
 
 /// Request data structure
 #[derive(Debug)]
-pub struct Request<'a> {
+pub struct Request<'a, S = ChannelSender> {
     /// Channel sender for sending the reply
-    ch: ChannelSender,
-    /// Parsed request
-    request: ll::Request<'a>,
+    ch: S,
+    /// Counter of replies that weren't sent yet
+    pending: &'a Arc<PendingReplies>,
+    /// Parsed request and the buffer it was received in
+    request: RawRequest<'a>,
 }
 
-impl<'a> Request<'a> {
-    /// Create a new request from the given data
-    pub fn new(ch: ChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
-        let request = match ll::Request::try_from(data) {
+impl<'a, S: Clone> Request<'a, S>
+where
+    TrackedSender<S>: Into<AnySender>,
+{
+    /// Create a new request from the data in the given buffer. Replies are sent with the given
+    /// sender (the channel to the kernel driver, or a fake sender for testing). Returns `None`
+    /// if the request can't be parsed. It's replied with an I/O error in that case if the
+    /// kernel waits for a reply.
+    pub(crate) fn new(
+        ch: S,
+        buffer: &'a Arc<Buffer>,
+        pending: &'a Arc<PendingReplies>,
+    ) -> Option<Request<'a, S>> {
+        let request = match ll::Request::parse(buffer, buffer.spliced_len()) {
             Ok(request) => request,
             Err(err) => {
-                // FIXME: Reply with ENOSYS?
-                error!("{}", err);
+                match ll::peek_header(buffer) {
+                    Some(header) => {
+                        warn!("Failed to parse FUSE({}): {}", header.unique, err);
+                        if ll::expects_reply(header.opcode) {
+                            let sender = TrackedSender::new(ch, pending).into();
+                            RawReply::new(header.unique, Some(sender), true).error(EIO);
+                        }
+                    }
+                    // Requests without a header can't be replied
+                    None => error!("{}", err),
+                }
                 return None;
             }
         };
 
-        Some(Self { ch, request })
+        Some(Self {
+            ch,
+            pending,
+            request: RawRequest::new(buffer, request),
+        })
     }
 
-    /// Dispatch request to the given filesystem.
-    /// This calls the appropriate filesystem operation method for the
-    /// request and sends back the returned reply to the kernel
-    pub fn dispatch<FS: Filesystem>(&self, se: &mut Session<FS>) {
+    /// Dispatch request to the given raw handler (e.g. a session that calls the appropriate
+    /// filesystem operation method for the request)
+    pub(crate) fn dispatch<H: RawHandler>(&self, handler: &mut H) {
         debug!("{}", self.request);
+        let sender = self
+            .request
+            .accepts_reply()
+            .then(|| TrackedSender::new(self.ch.clone(), self.pending).into());
+        let reply = RawReply::new(self.request.unique(), sender, self.request.expects_reply());
+        handler.handle(&self.request, reply);
+    }
+}
+
+impl<S> Request<'_, S> {
+    /// Returns the unique identifier of this request
+    #[inline]
+    #[allow(dead_code)]
+    pub fn unique(&self) -> u64 {
+        self.request.unique()
+    }
+
+    /// Returns the uid of this request
+    #[inline]
+    #[allow(dead_code)]
+    pub fn uid(&self) -> u32 {
+        self.request.uid()
+    }
+
+    /// Returns the gid of this request
+    #[inline]
+    #[allow(dead_code)]
+    pub fn gid(&self) -> u32 {
+        self.request.gid()
+    }
+
+    /// Returns the pid of this request
+    #[inline]
+    #[allow(dead_code)]
+    pub fn pid(&self) -> u32 {
+        self.request.pid()
+    }
+}
+
+/// A session dispatches raw requests to the methods of its filesystem
+impl<FS: Filesystem> RawHandler for Session<FS> {
+    fn handle(&mut self, request: &RawRequest<'_>, reply: RawReply) {
+        let req = RequestContext::new(
+            request.unique(),
+            request.uid(),
+            request.gid(),
+            request.pid(),
+        );
+
+        match request.operation() {
+            // Unknown operations are unsupported, no matter if initialized or not
+            ll::Operation::Unknown { .. } => {
+                warn!("Unsupported FUSE operation: {}", request);
+                if reply.expected() {
+                    reply.error(ENOSYS);
+                }
+            }
 
-        match self.request.operation() {
             // Filesystem initialization
             ll::Operation::Init { arg } => {
-                let reply: ReplyRaw<FuseInitOut> = self.reply();
                 // We don't support ABI versions before 7.6
                 if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
                     error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
@@ -70,258 +157,267 @@ impl<'a> Request<'a> {
                     return;
                 }
                 // Remember ABI version supported by kernel
-                se.proto_major = arg.major;
-                se.proto_minor = arg.minor;
+                self.proto_major = arg.major;
+                self.proto_minor = arg.minor;
                 // Call filesystem init method and give it a chance to return an error
-                let res = se.filesystem.init();
+                let res = self.filesystem.init(&req);
                 if let Err(err) = res {
                     reply.error(err);
                     return;
                 }
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
-                // supports only lower major versions, we replied with an error above.
+                // supports only lower major versions, we replied with an error above. A
+                // kernel with the same major version gets the lower of both minor versions,
+                // so that the reply has the size it expects (see `ll::Response::Init`).
+                let minor = match arg.major {
+                    FUSE_KERNEL_VERSION => cmp::min(arg.minor, FUSE_KERNEL_MINOR_VERSION),
+                    _ => FUSE_KERNEL_MINOR_VERSION,
+                };
+                let flags = arg.flags & (INIT_FLAGS | self.extra_init_flags()); // use features given in INIT_FLAGS and reported as capable
+                self.limit_max_write(flags);
                 let init = FuseInitOut {
                     major: FUSE_KERNEL_VERSION,
-                    minor: FUSE_KERNEL_MINOR_VERSION,
+                    minor,
                     max_readahead: arg.max_readahead, // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS, // use features given in INIT_FLAGS and reported as capable
+                    flags,
+                    #[cfg(not(feature = "abi-7-13"))]
                     unused: 0,
-                    max_write: MAX_WRITE_SIZE as u32, // use a max write size that fits into the session's buffer
+                    #[cfg(feature = "abi-7-13")]
+                    max_background: 0, // use the kernel's default
+                    #[cfg(feature = "abi-7-13")]
+                    congestion_threshold: 0, // use the kernel's default
+                    max_write: self.max_write() as u32, // use a max write size that fits into the session's buffers
+                    #[cfg(feature = "abi-7-23")]
+                    time_gran: 0, // use the kernel's default
+                    #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
+                    unused: [0; 9],
+                    #[cfg(feature = "abi-7-28")]
+                    max_pages: self.max_pages(),
+                    #[cfg(feature = "abi-7-28")]
+                    padding: 0,
+                    #[cfg(feature = "abi-7-28")]
+                    unused: [0; 8],
                 };
                 debug!(
                     "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
                     init.major, init.minor, init.flags, init.max_readahead, init.max_write
                 );
-                se.initialized = true;
-                reply.ok(&init);
+                self.initialized = true;
+                reply.send(ll::Response::Init(init));
             }
             // Any operation is invalid before initialization
-            _ if !se.initialized => {
-                warn!("Ignoring FUSE operation before init: {}", self.request);
-                self.reply::<ReplyEmpty>().error(EIO);
+            _ if !self.initialized => {
+                warn!("Ignoring FUSE operation before init: {}", request);
+                match request.expects_reply() {
+                    true => reply.error(EIO),
+                    false => reply.ignore(),
+                }
             }
             // Filesystem destroyed
             ll::Operation::Destroy => {
-                se.filesystem.destroy();
-                se.destroyed = true;
-                self.reply::<ReplyEmpty>().ok();
+                self.filesystem.destroy();
+                self.destroyed = true;
+                reply.send(ll::Response::Empty);
             }
             // Any operation is invalid after destroy
-            _ if se.destroyed => {
-                warn!("Ignoring FUSE operation after destroy: {}", self.request);
-                self.reply::<ReplyEmpty>().error(EIO);
+            _ if self.destroyed => {
+                warn!("Ignoring FUSE operation after destroy: {}", request);
+                match request.expects_reply() {
+                    true => reply.error(EIO),
+                    false => reply.ignore(),
+                }
             }
 
-            ll::Operation::Interrupt { .. } => {
-                // TODO: handle FUSE_INTERRUPT
-                self.reply::<ReplyEmpty>().error(ENOSYS);
+            ll::Operation::Interrupt { arg } => {
+                // The interrupt itself is only replied if it failed
+                match self.filesystem.interrupt(&req, arg.unique) {
+                    Ok(()) => reply.ignore(),
+                    Err(err) => reply.error(err),
+                }
             }
 
             ll::Operation::Lookup { name } => {
-                se.filesystem
-                    .lookup(self.request.nodeid(), name, self.reply());
+                self.filesystem
+                    .lookup(&req, request.nodeid(), name, reply.into_reply());
             }
             ll::Operation::Forget { arg } => {
-                se.filesystem.forget(self.request.nodeid(), arg.nlookup); // no reply
+                self.filesystem.forget(&req, request.nodeid(), arg.nlookup); // no reply
+            }
+            #[cfg(feature = "abi-7-16")]
+            ll::Operation::BatchForget { nodes, .. } => {
+                let nodes: Vec<Forget> = nodes.iter().map(Forget::from).collect();
+                self.filesystem.batch_forget(&req, &nodes); // no reply
             }
             ll::Operation::GetAttr => {
-                se.filesystem.getattr(self.request.nodeid(), self.reply());
+                self.filesystem
+                    .getattr(&req, request.nodeid(), reply.into_reply());
             }
             ll::Operation::SetAttr { arg } => {
-                let mode = match arg.valid & FATTR_MODE {
-                    0 => None,
-                    _ => Some(arg.mode),
-                };
-                let uid = match arg.valid & FATTR_UID {
-                    0 => None,
-                    _ => Some(arg.uid),
-                };
-                let gid = match arg.valid & FATTR_GID {
-                    0 => None,
-                    _ => Some(arg.gid),
-                };
-                let size = match arg.valid & FATTR_SIZE {
-                    0 => None,
-                    _ => Some(arg.size),
-                };
-                let atime = match arg.valid & FATTR_ATIME {
-                    0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.atime, arg.atimensec)),
-                };
-                let mtime = match arg.valid & FATTR_MTIME {
-                    0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.mtime, arg.mtimensec)),
-                };
-                let fh = match arg.valid & FATTR_FH {
-                    0 => None,
-                    _ => Some(arg.fh),
-                };
-                #[cfg(target_os = "macos")]
-                #[inline]
-                fn get_macos_setattr(
-                    arg: &FuseSetattrIn,
-                ) -> (
-                    Option<SystemTime>,
-                    Option<SystemTime>,
-                    Option<SystemTime>,
-                    Option<u32>,
-                ) {
-                    let crtime = match arg.valid & FATTR_CRTIME {
-                        0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
-                    };
-                    let chgtime = match arg.valid & FATTR_CHGTIME {
-                        0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
-                    };
-                    let bkuptime = match arg.valid & FATTR_BKUPTIME {
-                        0 => None,
-                        _ => Some(UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
-                    };
-                    let flags = match arg.valid & FATTR_FLAGS {
-                        0 => None,
-                        _ => Some(arg.flags),
-                    };
-                    (crtime, chgtime, bkuptime, flags)
-                }
-                #[cfg(not(target_os = "macos"))]
-                #[inline]
-                fn get_macos_setattr(
-                    _arg: &FuseSetattrIn,
-                ) -> (
-                    Option<SystemTime>,
-                    Option<SystemTime>,
-                    Option<SystemTime>,
-                    Option<u32>,
-                ) {
-                    (None, None, None, None)
-                }
-                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(
-                    self.request.nodeid(),
-                    mode,
-                    uid,
-                    gid,
-                    size,
-                    atime,
-                    mtime,
-                    fh,
-                    crtime,
-                    chgtime,
-                    bkuptime,
-                    flags,
-                    self.reply(),
+                self.filesystem.setattr(
+                    &req,
+                    request.nodeid(),
+                    &SetAttr::from(*arg),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::ReadLink => {
-                se.filesystem.readlink(self.request.nodeid(), self.reply());
+                self.filesystem
+                    .readlink(&req, request.nodeid(), reply.into_reply());
             }
             ll::Operation::MkNod { arg, name } => {
-                se.filesystem.mknod(
-                    self.request.nodeid(),
+                // The kernel tells the umask of the process since ABI 7.12
+                #[cfg(feature = "abi-7-12")]
+                let req = req.with_umask(arg.umask);
+                self.filesystem.mknod(
+                    &req,
+                    request.nodeid(),
                     name,
                     arg.mode,
                     arg.rdev,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::MkDir { arg, name } => {
-                se.filesystem
-                    .mkdir(self.request.nodeid(), name, arg.mode, self.reply());
+                // The kernel tells the umask of the process since ABI 7.12
+                #[cfg(feature = "abi-7-12")]
+                let req = req.with_umask(arg.umask);
+                self.filesystem
+                    .mkdir(&req, request.nodeid(), name, arg.mode, reply.into_reply());
             }
             ll::Operation::Unlink { name } => {
-                se.filesystem
-                    .unlink(self.request.nodeid(), name, self.reply());
+                self.filesystem
+                    .unlink(&req, request.nodeid(), name, reply.into_reply());
             }
             ll::Operation::RmDir { name } => {
-                se.filesystem
-                    .rmdir(self.request.nodeid(), name, self.reply());
+                self.filesystem
+                    .rmdir(&req, request.nodeid(), name, reply.into_reply());
             }
             ll::Operation::SymLink { name, link } => {
-                se.filesystem
-                    .symlink(self.request.nodeid(), name, Path::new(link), self.reply());
+                self.filesystem.symlink(
+                    &req,
+                    request.nodeid(),
+                    name,
+                    Path::new(link),
+                    reply.into_reply(),
+                );
             }
             ll::Operation::Rename { arg, name, newname } => {
-                se.filesystem.rename(
-                    self.request.nodeid(),
+                self.filesystem.rename(
+                    &req,
+                    request.nodeid(),
                     name,
                     arg.newdir,
                     newname,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::Link { arg, name } => {
-                se.filesystem
-                    .link(arg.oldnodeid, self.request.nodeid(), name, self.reply());
+                self.filesystem.link(
+                    &req,
+                    arg.oldnodeid,
+                    request.nodeid(),
+                    name,
+                    reply.into_reply(),
+                );
             }
             ll::Operation::Open { arg } => {
-                se.filesystem
-                    .open(self.request.nodeid(), arg.flags, self.reply());
+                self.filesystem.open(
+                    &req,
+                    request.nodeid(),
+                    OpenFlags::from_bits_retain(arg.flags),
+                    reply.into_reply(),
+                );
             }
             ll::Operation::Read { arg } => {
-                se.filesystem.read(
-                    self.request.nodeid(),
+                self.filesystem.read(
+                    &req,
+                    request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
                     arg.size,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::Write { arg, data } => {
+                let data = request
+                    .spliced_data()
+                    .unwrap_or_else(|| request.write_data(data));
                 assert!(data.len() == arg.size as usize);
-                se.filesystem.write(
-                    self.request.nodeid(),
+                self.filesystem.write_buf(
+                    &req,
+                    request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
                     data,
                     arg.write_flags,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::Flush { arg } => {
-                se.filesystem
-                    .flush(self.request.nodeid(), arg.fh, arg.lock_owner, self.reply());
+                self.filesystem.flush(
+                    &req,
+                    request.nodeid(),
+                    arg.fh,
+                    arg.lock_owner,
+                    reply.into_reply(),
+                );
             }
             ll::Operation::Release { arg } => {
-                let flush = !matches!(arg.release_flags & FUSE_RELEASE_FLUSH, 0);
-                se.filesystem.release(
-                    self.request.nodeid(),
+                self.filesystem.release(
+                    &req,
+                    request.nodeid(),
                     arg.fh,
                     arg.flags,
                     arg.lock_owner,
-                    flush,
-                    self.reply(),
+                    ReleaseFlags::from_bits_retain(arg.release_flags),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::FSync { arg } => {
                 let datasync = !matches!(arg.fsync_flags & 1, 0);
-                se.filesystem
-                    .fsync(self.request.nodeid(), arg.fh, datasync, self.reply());
+                self.filesystem
+                    .fsync(&req, request.nodeid(), arg.fh, datasync, reply.into_reply());
             }
             ll::Operation::OpenDir { arg } => {
-                se.filesystem
-                    .opendir(self.request.nodeid(), arg.flags, self.reply());
+                self.filesystem.opendir(
+                    &req,
+                    request.nodeid(),
+                    OpenFlags::from_bits_retain(arg.flags),
+                    reply.into_reply(),
+                );
             }
             ll::Operation::ReadDir { arg } => {
-                se.filesystem.readdir(
-                    self.request.nodeid(),
+                self.filesystem.readdir(
+                    &req,
+                    request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
-                    ReplyDirectory::new(self.request.unique(), self.ch, arg.size as usize),
+                    ReplyDirectory::from_raw(reply.into_reply(), arg.size as usize),
                 );
             }
             ll::Operation::ReleaseDir { arg } => {
-                se.filesystem
-                    .releasedir(self.request.nodeid(), arg.fh, arg.flags, self.reply());
+                self.filesystem.releasedir(
+                    &req,
+                    request.nodeid(),
+                    arg.fh,
+                    arg.flags,
+                    reply.into_reply(),
+                );
             }
             ll::Operation::FSyncDir { arg } => {
                 let datasync = !matches!(arg.fsync_flags & 1, 0);
-                se.filesystem
-                    .fsyncdir(self.request.nodeid(), arg.fh, datasync, self.reply());
+                self.filesystem.fsyncdir(
+                    &req,
+                    request.nodeid(),
+                    arg.fh,
+                    datasync,
+                    reply.into_reply(),
+                );
             }
             ll::Operation::StatFs => {
-                se.filesystem.statfs(self.request.nodeid(), self.reply());
+                self.filesystem
+                    .statfs(&req, request.nodeid(), reply.into_reply());
             }
             ll::Operation::SetXAttr { arg, name, value } => {
                 assert!(value.len() == arg.size as usize);
@@ -335,94 +431,94 @@ impl<'a> Request<'a> {
                 fn get_position(_arg: &FuseSetxattrIn) -> u32 {
                     0
                 }
-                se.filesystem.setxattr(
-                    self.request.nodeid(),
+                self.filesystem.setxattr(
+                    &req,
+                    request.nodeid(),
                     name,
                     value,
-                    arg.flags,
+                    XattrFlags::from_bits_retain(arg.flags),
                     get_position(arg),
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::GetXAttr { arg, name } => {
-                se.filesystem
-                    .getxattr(self.request.nodeid(), name, arg.size, self.reply());
+                self.filesystem.getxattr(
+                    &req,
+                    request.nodeid(),
+                    name,
+                    arg.size,
+                    reply.into_reply(),
+                );
             }
             ll::Operation::ListXAttr { arg } => {
-                se.filesystem
-                    .listxattr(self.request.nodeid(), arg.size, self.reply());
+                self.filesystem
+                    .listxattr(&req, request.nodeid(), arg.size, reply.into_reply());
             }
             ll::Operation::RemoveXAttr { name } => {
-                se.filesystem
-                    .removexattr(self.request.nodeid(), name, self.reply());
+                self.filesystem
+                    .removexattr(&req, request.nodeid(), name, reply.into_reply());
             }
             ll::Operation::Access { arg } => {
-                se.filesystem
-                    .access(self.request.nodeid(), arg.mask, self.reply());
+                self.filesystem
+                    .access(&req, request.nodeid(), arg.mask, reply.into_reply());
             }
             ll::Operation::Create { arg, name } => {
-                se.filesystem.create(
-                    self.request.nodeid(),
+                // The kernel tells the umask of the process since ABI 7.12
+                #[cfg(feature = "abi-7-12")]
+                let req = req.with_umask(arg.umask);
+                self.filesystem.create(
+                    &req,
+                    request.nodeid(),
                     name,
                     arg.mode,
-                    arg.flags,
-                    self.reply(),
+                    OpenFlags::from_bits_retain(arg.flags),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::GetLk { arg } => {
-                se.filesystem.getlk(
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.owner,
-                    arg.lk.start,
-                    arg.lk.end,
-                    arg.lk.typ,
-                    arg.lk.pid,
-                    self.reply(),
+                self.filesystem.getlk(
+                    &req,
+                    request.nodeid(),
+                    &LockRequest::from(*arg),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::SetLk { arg } => {
-                se.filesystem.setlk(
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.owner,
-                    arg.lk.start,
-                    arg.lk.end,
-                    arg.lk.typ,
-                    arg.lk.pid,
+                self.filesystem.setlk(
+                    &req,
+                    request.nodeid(),
+                    &LockRequest::from(*arg),
                     false,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::SetLkW { arg } => {
-                se.filesystem.setlk(
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.owner,
-                    arg.lk.start,
-                    arg.lk.end,
-                    arg.lk.typ,
-                    arg.lk.pid,
+                self.filesystem.setlk(
+                    &req,
+                    request.nodeid(),
+                    &LockRequest::from(*arg),
                     true,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
             ll::Operation::BMap { arg } => {
-                se.filesystem.bmap(
-                    self.request.nodeid(),
+                self.filesystem.bmap(
+                    &req,
+                    request.nodeid(),
                     arg.blocksize,
                     arg.block,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
 
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName { name } => {
-                se.filesystem.setvolname(name, self.reply());
+                self.filesystem.setvolname(&req, name, reply.into_reply());
             }
             #[cfg(target_os = "macos")]
             ll::Operation::GetXTimes => {
-                se.filesystem.getxtimes(self.request.nodeid(), self.reply());
+                self.filesystem
+                    .getxtimes(&req, request.nodeid(), reply.into_reply());
             }
             #[cfg(target_os = "macos")]
             ll::Operation::Exchange {
@@ -430,49 +526,136 @@ impl<'a> Request<'a> {
                 oldname,
                 newname,
             } => {
-                se.filesystem.exchange(
+                self.filesystem.exchange(
+                    &req,
                     arg.olddir,
                     &oldname,
                     arg.newdir,
                     &newname,
                     arg.options,
-                    self.reply(),
+                    reply.into_reply(),
                 );
             }
         }
     }
+}
+
+/// Information about the process that caused a request. Passed to every filesystem method to
+/// allow e.g. permission checks or setting the owner of new files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RequestContext {
+    unique: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    umask: Option<u32>,
+}
 
-    /// Create a reply object for this request that can be passed to the filesystem
-    /// implementation and makes sure that a request is replied exactly once
-    fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+impl RequestContext {
+    /// Create a new request context (useful for calling filesystem methods directly)
+    pub fn new(unique: u64, uid: u32, gid: u32, pid: u32) -> RequestContext {
+        RequestContext {
+            unique,
+            uid,
+            gid,
+            pid,
+            umask: None,
+        }
     }
 
-    /// Returns the unique identifier of this request
+    /// Returns the same context with the given umask
+    pub fn with_umask(self, umask: u32) -> RequestContext {
+        RequestContext {
+            umask: Some(umask),
+            ..self
+        }
+    }
+
+    /// Returns the unique identifier of the request
     #[inline]
-    #[allow(dead_code)]
     pub fn unique(&self) -> u64 {
-        self.request.unique()
+        self.unique
     }
 
-    /// Returns the uid of this request
+    /// Returns the uid of the process that caused the request
     #[inline]
-    #[allow(dead_code)]
     pub fn uid(&self) -> u32 {
-        self.request.uid()
+        self.uid
     }
 
-    /// Returns the gid of this request
+    /// Returns the gid of the process that caused the request
     #[inline]
-    #[allow(dead_code)]
     pub fn gid(&self) -> u32 {
-        self.request.gid()
+        self.gid
     }
 
-    /// Returns the pid of this request
+    /// Returns the pid (actually the thread id) of the process that caused the request
     #[inline]
-    #[allow(dead_code)]
     pub fn pid(&self) -> u32 {
-        self.request.pid()
+        self.pid
+    }
+
+    /// Returns the umask of the process that caused the request. Only available for mknod,
+    /// mkdir and create operations with ABI 7.12 or later.
+    #[inline]
+    pub fn umask(&self) -> Option<u32> {
+        self.umask
+    }
+
+    /// Returns the supplementary groups of the process that caused the request. They're not
+    /// part of the request and need to be looked up, which may fail if the process already
+    /// exited. Only supported on Linux.
+    pub fn groups(&self) -> io::Result<Vec<u32>> {
+        #[cfg(target_os = "linux")]
+        {
+            let path = format!("/proc/{0}/task/{0}/status", self.pid);
+            parse_groups(&fs::read_to_string(path)?)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no groups in status"))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Parse the supplementary groups from a process status file (Linux)
+#[cfg(target_os = "linux")]
+fn parse_groups(status: &str) -> Option<Vec<u32>> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))?;
+    line.split_whitespace()
+        .map(|gid| gid.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::RequestContext;
+
+    #[test]
+    fn request_context() {
+        let req = RequestContext::new(0x1234, 1000, 100, 42);
+        assert_eq!(req.unique(), 0x1234);
+        assert_eq!(req.uid(), 1000);
+        assert_eq!(req.gid(), 100);
+        assert_eq!(req.pid(), 42);
+        assert_eq!(req.umask(), None);
+        assert_eq!(req.with_umask(0o022).umask(), Some(0o022));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_groups() {
+        let status = "Name:\tcat\nGid:\t100\t100\t100\t100\nGroups:\t4 24 100 \nNgid:\t0\n";
+        assert_eq!(super::parse_groups(status), Some(vec![4, 24, 100]));
+        assert_eq!(super::parse_groups("Groups:\t\n"), Some(vec![]));
+        assert_eq!(super::parse_groups("Name:\tcat\n"), None);
+        // Groups of the current process
+        let pid = unsafe { libc::getpid() } as u32;
+        let req = RequestContext::new(0, 0, 0, pid);
+        let mut groups = vec![0; 256];
+        let n = unsafe { libc::getgroups(groups.len() as i32, groups.as_mut_ptr()) };
+        groups.truncate(n as usize);
+        assert_eq!(req.groups().unwrap(), groups);
     }
 }
@@ -3,35 +3,92 @@
 //! A session runs a filesystem implementation while it is being mounted to a specific mount
 //! point. A session begins by mounting the filesystem and ends by unmounting it. While the
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
-//! for filesystem operations under its mount point.
+//! for filesystem operations under its mount point. A raw session runs a raw handler instead
+//! of a filesystem (see `RawHandler`).
 
+use fuse_abi::consts::FUSE_MIN_READ_BUFFER;
 use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::info;
+use log::{info, warn};
 use std::ffi::OsStr;
-use std::io;
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{cmp, io, thread};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
 use tokio::sync::mpsc;
 
-use crate::channel::Channel;
+use crate::buffer::{Buffer, BufferPool};
+use crate::channel::{self, Channel, ChannelSender};
+use crate::reply::PendingReplies;
 use crate::request::Request;
-use crate::Filesystem;
+use crate::trace::Recorder;
+use crate::{Filesystem, RawHandler};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on macOS
 /// and 128k on other systems.
 pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
-/// Size of the buffer for reading a request from the kernel. Since the kernel may send
-/// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+/// The largest max write size INIT can grant. Linux doesn't send writes larger than 32 pages
+/// (128k) unless max_pages is negotiated, which needs ABI 7.28.
+#[cfg(any(target_os = "macos", feature = "abi-7-28"))]
+const INIT_MAX_WRITE_SIZE: usize = MAX_WRITE_SIZE;
+#[cfg(not(any(target_os = "macos", feature = "abi-7-28")))]
+const INIT_MAX_WRITE_SIZE: usize = 128 * 1024;
+
+/// The default max size of write requests
+#[cfg(target_os = "macos")]
+const DEFAULT_MAX_WRITE_SIZE: usize = MAX_WRITE_SIZE;
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_MAX_WRITE_SIZE: usize = 128 * 1024;
+
+/// Max number of pages Linux sends in a request unless max_pages is negotiated
+const DEFAULT_MAX_PAGES_PER_REQ: usize = 32;
+
+/// Time to wait for outstanding replies when shutting down a session with `run_with_signal`
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the size of memory pages
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Returns true if the given negotiated INIT flags include max_pages
+#[cfg(feature = "abi-7-28")]
+fn max_pages(flags: u32) -> bool {
+    flags & fuse_abi::consts::FUSE_MAX_PAGES != 0
+}
+
+/// Returns true if the given negotiated INIT flags include max_pages (requires ABI 7.28)
+#[cfg(not(feature = "abi-7-28"))]
+fn max_pages(_flags: u32) -> bool {
+    false
+}
+
+/// Max size of an extended attribute value (XATTR_SIZE_MAX on Linux)
+const XATTR_SIZE_MAX: usize = 64 * 1024;
+
+/// Size of a buffer for reading a request from the kernel with the given max write size.
+/// Since the kernel may send up to max write bytes in a write request, we use that value plus
+/// some extra space for the headers. Other requests aren't limited by the max write size, the
+/// largest of them is a SETXATTR with a value of up to XATTR_SIZE_MAX bytes.
+fn buffer_size(max_write: usize) -> usize {
+    cmp::max(
+        FUSE_MIN_READ_BUFFER,
+        cmp::max(max_write, XATTR_SIZE_MAX) + 4096,
+    )
+}
 
 /// The session data structure
 #[derive(Debug)]
 pub struct Session<FS: Filesystem> {
     /// Filesystem operation implementations
     pub filesystem: FS,
-    /// Communication channel to the kernel driver
-    ch: Channel,
+    /// Connection to the kernel driver
+    conn: Connection,
     /// FUSE protocol major version
     pub proto_major: u32,
     /// FUSE protocol minor version
@@ -40,27 +97,127 @@ pub struct Session<FS: Filesystem> {
     pub initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// True if lock requests should be sent to the filesystem instead of locking locally
+    locks: bool,
 }
 
 impl<FS: Filesystem> Session<FS> {
-    /// Create a new session by mounting the given filesystem to the given mountpoint
+    /// Create a new session by mounting the given filesystem to the given mountpoint.
+    /// Following the libfuse convention, a mountpoint of the form `/dev/fd/N` refers to an
+    /// already mounted FUSE device file descriptor N, which is adopted instead of mounting
+    /// (see `Session::from_fd`). Mount options are ignored in that case.
     pub fn new(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<Session<FS>> {
-        info!("Mounting {}", mountpoint.display());
+        Ok(Session::with_connection(
+            filesystem,
+            Connection::mount(mountpoint, options)?,
+        ))
+    }
+
+    /// Create a new session for an already opened and mounted FUSE device file descriptor
+    /// (e.g. passed in by a container runtime or a privileged mount helper). The session
+    /// takes ownership of the file descriptor, but doesn't mount or unmount anything. The
+    /// usual INIT handshake still takes place when the session is run.
+    pub fn from_fd(filesystem: FS, fd: OwnedFd) -> Session<FS> {
+        Session::with_connection(filesystem, Connection::from_fd(fd))
+    }
 
-        let ch = Channel::new(mountpoint, options)?;
-        Ok(Session {
+    /// Create a new session that communicates over the given connection
+    fn with_connection(filesystem: FS, conn: Connection) -> Session<FS> {
+        Session {
             filesystem,
-            ch,
+            conn,
             proto_major: 0,
             proto_minor: 0,
             initialized: false,
             destroyed: false,
-        })
+            locks: false,
+        }
     }
 
     /// Return path of the mounted filesystem
     pub fn mountpoint(&self) -> &Path {
-        self.ch.mountpoint()
+        self.conn.ch.mountpoint()
+    }
+
+    /// Returns the max size of write requests
+    pub fn max_write(&self) -> usize {
+        self.conn.max_write
+    }
+
+    /// Set the max size of write requests the kernel may send (128k by default on Linux, 16M on
+    /// macOS). Buffers for receiving requests are sized accordingly. The size is clamped to what
+    /// INIT can grant, i.e. 4k..16M, but only 4k..128k on Linux without the abi-7-28 feature.
+    /// Linux sends writes larger than 32 pages only if max_pages is negotiated. If the kernel
+    /// doesn't support it, the max write size and the buffers shrink at INIT. Must be set
+    /// before running the session.
+    pub fn set_max_write(&mut self, max_write: usize) {
+        self.conn.set_max_write(max_write);
+    }
+
+    /// Limit the max size of write requests to what the kernel sends with the given negotiated
+    /// INIT flags. Linux sends writes of up to 32 pages, unless max_pages is negotiated
+    /// (FUSE_MAX_PAGES, ABI 7.28). Buffers for receiving requests shrink accordingly.
+    pub(crate) fn limit_max_write(&mut self, flags: u32) {
+        let max_write = DEFAULT_MAX_PAGES_PER_REQ * page_size();
+        if cfg!(target_os = "linux") && !max_pages(flags) && self.max_write() > max_write {
+            self.set_max_write(max_write);
+        }
+    }
+
+    /// Returns the max number of pages per request to report at INIT, so that the kernel may
+    /// send write requests of the max write size
+    #[cfg(feature = "abi-7-28")]
+    pub(crate) fn max_pages(&self) -> u16 {
+        cmp::min(self.max_write().div_ceil(page_size()), u16::MAX as usize) as u16
+    }
+
+    /// Enable or disable splice(2) for moving data between the kernel driver and files without
+    /// copying it through userspace. The data of write requests is left in the pipe it was
+    /// received in, so that the filesystem can splice it to a file (see `WriteData::write_to`),
+    /// and reply data can be spliced from a file (see `ReplyData::data_fd`). Must be set before
+    /// running the session. If splicing isn't possible for some request or reply, the data is
+    /// read and sent normally instead.
+    #[cfg(target_os = "linux")]
+    pub fn set_splice(&mut self, splice: bool) {
+        self.conn.ch.set_splice(splice);
+    }
+
+    /// Enable or disable remote locking. If enabled, the kernel sends POSIX lock requests
+    /// (and flock requests since ABI 7.17) to the filesystem's `getlk` and `setlk` methods
+    /// (see `lock::LockManager`), otherwise it only locks files locally. Must be set before
+    /// running the session.
+    pub fn set_locks(&mut self, locks: bool) {
+        self.locks = locks;
+    }
+
+    /// Record all requests and replies of the session with the given recorder (see
+    /// `trace::Recorder`), or stop recording. Should be set before running the session, so
+    /// that the trace starts with the INIT request. Replies can't be spliced while recording.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.conn.ch.set_recorder(recorder);
+    }
+
+    /// Additional INIT flags to report as supported, depending on the session's settings
+    pub(crate) fn extra_init_flags(&self) -> u32 {
+        let mut flags = 0;
+        #[cfg(all(target_os = "linux", feature = "abi-7-14"))]
+        if self.conn.ch.splice() {
+            use fuse_abi::consts::{FUSE_SPLICE_MOVE, FUSE_SPLICE_READ, FUSE_SPLICE_WRITE};
+            flags |= FUSE_SPLICE_READ | FUSE_SPLICE_WRITE | FUSE_SPLICE_MOVE;
+        }
+        // The kernel uses the max number of pages only if max_pages is reported
+        #[cfg(feature = "abi-7-28")]
+        {
+            flags |= fuse_abi::consts::FUSE_MAX_PAGES;
+        }
+        if self.locks {
+            flags |= fuse_abi::consts::FUSE_POSIX_LOCKS;
+            #[cfg(feature = "abi-7-17")]
+            {
+                flags |= fuse_abi::consts::FUSE_FLOCK_LOCKS;
+            }
+        }
+        flags
     }
 
     /// Run the session loop that receives kernel requests and dispatches them to method
@@ -68,76 +225,867 @@ impl<FS: Filesystem> Session<FS> {
     /// having multiple buffers (which take up much memory), but the filesystem methods
     /// may run concurrent by spawning threads.
     pub fn run(&mut self) -> io::Result<()> {
-        // Buffer for receiving requests from the kernel. Only one is allocated and
-        // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-        loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(self.ch.sender(), &buffer) {
-                    // Dispatch request
-                    Some(req) => req.dispatch(self),
-                    // Quit loop on illegal request
-                    None => break,
-                },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
-                },
+        run(self)
+    }
+
+    /// Run the session loop with the given number of worker threads. Every worker reads
+    /// requests from its own clone of the channel to the kernel driver (see
+    /// `Channel::try_clone`) and replies go out on the channel a request came from. INIT is
+    /// handled by the calling thread. After that, every worker dispatches requests to its own
+    /// clone of the filesystem, so requests are handled concurrently. Clones of the filesystem
+    /// therefore need to share their state (e.g. behind an `Arc` with locks). Returns after the
+    /// filesystem was unmounted and all workers finished.
+    pub fn run_multithreaded(&mut self, workers: usize) -> io::Result<()>
+    where
+        FS: Clone + Send,
+    {
+        run_multithreaded(self, workers)
+    }
+
+    /// Run the session loop until the given future completes (e.g. a signal or cancellation
+    /// token) or the filesystem is unmounted. Then stop accepting new requests and shut down
+    /// gracefully: wait up to the given timeout for replies still owed by the filesystem (e.g.
+    /// by its worker threads), call the filesystem's destroy method and unmount (lazily if the
+    /// filesystem is busy). Returns a summary of the shutdown.
+    pub async fn run_until<F: Future>(
+        &mut self,
+        shutdown: F,
+        timeout: Duration,
+    ) -> io::Result<ShutdownSummary> {
+        run_until(self, shutdown, timeout).await
+    }
+
+    /// Run the session loop until a message is received on the given channel (or the channel
+    /// is closed) and shut down gracefully (see `run_until`).
+    pub async fn run_with_signal(&mut self, mut rx: mpsc::Receiver<()>) -> io::Result<()> {
+        let summary = self.run_until(rx.recv(), DEFAULT_SHUTDOWN_TIMEOUT).await?;
+        info!("Session shut down: {:?}", summary);
+        Ok(())
+    }
+}
+
+impl<FS: Filesystem + Clone> Fork for Session<FS> {
+    fn forkable(&self) -> bool {
+        // Clones are made after init, so that they get the state the filesystem set up there
+        self.initialized
+    }
+
+    fn fork(&self, conn: Connection) -> Session<FS> {
+        Session {
+            filesystem: self.filesystem.clone(),
+            conn,
+            proto_major: self.proto_major,
+            proto_minor: self.proto_minor,
+            initialized: self.initialized,
+            destroyed: self.destroyed,
+            locks: self.locks,
+        }
+    }
+
+    fn join(&mut self, worker: Session<FS>) {
+        self.destroyed |= worker.destroyed;
+    }
+}
+
+impl<FS: Filesystem> Dispatch for Session<FS> {
+    type Handler = Self;
+    const SPLICE_WRITE: bool = true;
+
+    fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    fn handler(&mut self) -> &mut Self {
+        self
+    }
+
+    fn destroy(&mut self) -> bool {
+        // The kernel doesn't send a destroy operation unless it's a block device based
+        // filesystem, so the filesystem may not be destroyed yet
+        if !self.initialized || self.destroyed {
+            return false;
+        }
+        self.filesystem.destroy();
+        self.destroyed = true;
+        true
+    }
+}
+
+/// A session that runs a raw handler instead of a filesystem. It mounts, receives requests
+/// and shuts down like a `Session`, but passes every request to the handler without
+/// interpreting it (including INIT and DESTROY).
+#[derive(Debug)]
+pub struct RawSession<H: RawHandler> {
+    /// Handler of raw requests
+    pub handler: H,
+    /// Connection to the kernel driver
+    conn: Connection,
+}
+
+impl<H: RawHandler> RawSession<H> {
+    /// Create a new raw session by mounting to the given mountpoint (see `Session::new`)
+    pub fn new(handler: H, mountpoint: &Path, options: &[&OsStr]) -> io::Result<RawSession<H>> {
+        Ok(RawSession {
+            handler,
+            conn: Connection::mount(mountpoint, options)?,
+        })
+    }
+
+    /// Create a new raw session for an already opened and mounted FUSE device file
+    /// descriptor (see `Session::from_fd`)
+    pub fn from_fd(handler: H, fd: OwnedFd) -> RawSession<H> {
+        RawSession {
+            handler,
+            conn: Connection::from_fd(fd),
+        }
+    }
+
+    /// Return path of the mount point
+    pub fn mountpoint(&self) -> &Path {
+        self.conn.ch.mountpoint()
+    }
+
+    /// Returns the max size of write requests that fit into the buffers for receiving requests
+    pub fn max_write(&self) -> usize {
+        self.conn.max_write
+    }
+
+    /// Size the buffers for receiving requests for write requests of the given max size
+    /// (clamped like `Session::set_max_write`). The handler must not report a larger max write
+    /// size to the kernel at INIT. Must be set before running the session.
+    pub fn set_max_write(&mut self, max_write: usize) {
+        self.conn.set_max_write(max_write);
+    }
+
+    /// Enable or disable splice(2) for sending reply data from files (see
+    /// `Session::set_splice`). Requests are always received completely, since the handler gets
+    /// their raw data. The handler needs to report the splice flags to the kernel at INIT
+    /// itself.
+    #[cfg(target_os = "linux")]
+    pub fn set_splice(&mut self, splice: bool) {
+        self.conn.ch.set_splice(splice);
+    }
+
+    /// Record all requests and replies of the session with the given recorder, or stop
+    /// recording (see `Session::set_recorder`)
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.conn.ch.set_recorder(recorder);
+    }
+
+    /// Run the session loop that receives kernel requests and passes them to the handler
+    /// until the kernel driver stops sending requests (see `Session::run`)
+    pub fn run(&mut self) -> io::Result<()> {
+        run(self)
+    }
+
+    /// Run the session loop with the given number of worker threads (see
+    /// `Session::run_multithreaded`). Every worker passes requests to its own clone of the
+    /// handler, which is made before INIT is received. Clones therefore need to share the
+    /// state they negotiate at INIT.
+    pub fn run_multithreaded(&mut self, workers: usize) -> io::Result<()>
+    where
+        H: Clone + Send,
+    {
+        run_multithreaded(self, workers)
+    }
+
+    /// Run the session loop until the given future completes or the kernel driver stops
+    /// sending requests, then shut down gracefully (see `Session::run_until`). The handler
+    /// isn't told about the shutdown, so the summary never reports it as destroyed.
+    pub async fn run_until<F: Future>(
+        &mut self,
+        shutdown: F,
+        timeout: Duration,
+    ) -> io::Result<ShutdownSummary> {
+        run_until(self, shutdown, timeout).await
+    }
+}
+
+impl<H: RawHandler + Clone> Fork for RawSession<H> {
+    fn forkable(&self) -> bool {
+        true
+    }
+
+    fn fork(&self, conn: Connection) -> RawSession<H> {
+        RawSession {
+            handler: self.handler.clone(),
+            conn,
+        }
+    }
+
+    fn join(&mut self, _worker: RawSession<H>) {}
+}
+
+impl<H: RawHandler> Dispatch for RawSession<H> {
+    type Handler = H;
+    const SPLICE_WRITE: bool = false;
+
+    fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    fn destroy(&mut self) -> bool {
+        false
+    }
+}
+
+/// Summary of a session shutdown (see `Session::run_until`)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ShutdownSummary {
+    /// True if the shutdown was requested, false if the filesystem was unmounted externally
+    pub requested: bool,
+    /// Number of replies that were outstanding when the session stopped accepting requests
+    pub pending_replies: usize,
+    /// Number of replies that were still outstanding when the timeout expired
+    pub abandoned_replies: usize,
+    /// True if the filesystem's destroy method was called during shutdown
+    pub destroyed: bool,
+    /// True if the filesystem was unmounted during shutdown
+    pub unmounted: bool,
+    /// True if the filesystem was busy and had to be unmounted lazily
+    pub lazy_unmount: bool,
+}
+
+/// Connection to the kernel driver and buffers for receiving requests, as used by sessions
+#[derive(Debug)]
+struct Connection {
+    /// Communication channel to the kernel driver
+    ch: Channel,
+    /// Max size of write requests the buffers are sized for
+    max_write: usize,
+    /// Pool of buffers for receiving requests
+    pool: BufferPool,
+    /// Counter of replies that weren't sent yet
+    pending: Arc<PendingReplies>,
+    /// True if this is the connection of a worker thread, which shares the mount of the
+    /// session's connection
+    worker: bool,
+}
+
+impl Connection {
+    /// Mount to the given mountpoint, or adopt the FUSE device if it's a `/dev/fd/N` path
+    fn mount(mountpoint: &Path, options: &[&OsStr]) -> io::Result<Connection> {
+        let ch = match channel::parse_dev_fd(mountpoint) {
+            Some(fd) => {
+                info!("Using FUSE device {}", mountpoint.display());
+                Channel::from_dev_fd(fd)?
             }
+            None => {
+                info!("Mounting {}", mountpoint.display());
+                Channel::new(mountpoint, options)?
+            }
+        };
+        Ok(Connection::new(ch))
+    }
+
+    /// Adopt an already opened and mounted FUSE device file descriptor
+    fn from_fd(fd: OwnedFd) -> Connection {
+        let ch = unsafe { Channel::from_raw_fd(fd.into_raw_fd()) };
+        info!("Using FUSE device {}", ch.mountpoint().display());
+        Connection::new(ch)
+    }
+
+    /// Create a new connection that communicates over the given channel
+    fn new(ch: Channel) -> Connection {
+        Connection {
+            ch,
+            max_write: DEFAULT_MAX_WRITE_SIZE,
+            pool: BufferPool::new(buffer_size(DEFAULT_MAX_WRITE_SIZE)),
+            pending: Arc::default(),
+            worker: false,
         }
-        Ok(())
     }
 
-    pub async fn run_with_signal(&mut self, mut rx: mpsc::Receiver<()>) -> io::Result<()> {
-        // Buffer for receiving requests from the kernel. Only one is allocated and
-        // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-        loop {
-            tokio::select! {
-                    _ = rx.recv() => {
-                        // Received signal to exit
-                        break;
+    /// Create a connection for a worker thread with a clone of the channel (see
+    /// `Channel::try_clone`). The buffers and the counter of pending replies are shared.
+    fn fork(&self) -> io::Result<Connection> {
+        Ok(Connection {
+            ch: self.ch.try_clone()?,
+            max_write: self.max_write,
+            pool: self.pool.clone(),
+            pending: Arc::clone(&self.pending),
+            worker: true,
+        })
+    }
+
+    /// Set the max size of write requests and size the buffers accordingly
+    fn set_max_write(&mut self, max_write: usize) {
+        self.max_write = max_write.clamp(4096, INIT_MAX_WRITE_SIZE);
+        self.pool.set_size(buffer_size(self.max_write));
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.worker {
+            info!("Unmounted {}", self.ch.mountpoint().display());
+        }
+    }
+}
+
+/// A session with a connection whose requests are dispatched to a raw handler
+trait Dispatch {
+    /// Handler that requests are dispatched to
+    type Handler: RawHandler;
+
+    /// True if the handler takes write requests whose data was left in a pipe (see
+    /// `Channel::receive_spliced`)
+    const SPLICE_WRITE: bool;
+
+    /// Returns the connection to the kernel driver
+    fn connection(&mut self) -> &mut Connection;
+
+    /// Returns the handler that requests are dispatched to
+    fn handler(&mut self) -> &mut Self::Handler;
+
+    /// Destroy the handler when shutting down, if needed. Returns true if it was destroyed.
+    fn destroy(&mut self) -> bool;
+}
+
+/// A session that can dispatch requests on multiple worker threads
+trait Fork: Dispatch + Sized {
+    /// Returns true if workers can take over dispatching, false if the calling thread needs to
+    /// dispatch requests until then (e.g. until INIT is done)
+    fn forkable(&self) -> bool;
+
+    /// Create a session for a worker thread that dispatches requests received on the given
+    /// connection to a clone of the handler
+    fn fork(&self, conn: Connection) -> Self;
+
+    /// Take over the state of a worker's session after the worker finished
+    fn join(&mut self, worker: Self);
+}
+
+/// Run the session loop that receives kernel requests and dispatches them
+fn run<S: Dispatch>(se: &mut S) -> io::Result<()> {
+    run_while(se, |_| true).map(|_| ())
+}
+
+/// Run the session loop as long as the given condition holds for the session. Returns false
+/// if the filesystem was unmounted before.
+fn run_while<S: Dispatch>(se: &mut S, cond: impl Fn(&S) -> bool) -> io::Result<bool> {
+    let pool = se.connection().pool.clone();
+    let pending = Arc::clone(&se.connection().pending);
+    while cond(se) {
+        let conn = se.connection();
+        // Buffers for receiving requests are taken from the pool. A buffer goes back to
+        // the pool after dispatching, unless the filesystem keeps write data from it.
+        let mut buffer = pool.get();
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read
+        let res = match S::SPLICE_WRITE {
+            true => conn.ch.receive_spliced(&mut buffer),
+            false => conn.ch.receive(&mut buffer),
+        };
+        match res {
+            // Dispatch request
+            Ok(()) => dispatch(conn.ch.sender(), buffer, &pool, &pending, se.handler()),
+            Err(err) => match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => continue,
+                // Interrupted system call, retry
+                Some(EINTR) => continue,
+                // Explicitly try again
+                Some(EAGAIN) => continue,
+                // Filesystem was unmounted, quit the loop
+                Some(ENODEV) => return Ok(false),
+                // Unhandled error
+                _ => return Err(err),
+            },
+        }
+    }
+    Ok(true)
+}
+
+/// Run the session loop with the given number of worker threads. Every worker runs the
+/// session loop on its own session (see `Fork`), replies are sent to the channel a request
+/// came from.
+fn run_multithreaded<S: Fork + Send>(se: &mut S, workers: usize) -> io::Result<()> {
+    if !run_while(se, |se| !se.forkable())? {
+        return Ok(());
+    }
+    let conns = (0..workers.max(1))
+        .map(|_| se.connection().fork())
+        .collect::<io::Result<Vec<_>>>()?;
+    info!("Running session with {} workers", conns.len());
+    let workers: Vec<S> = conns.into_iter().map(|conn| se.fork(conn)).collect();
+    let results: Vec<_> = thread::scope(|scope| {
+        let threads: Vec<_> = workers
+            .into_iter()
+            .map(|mut worker| scope.spawn(move || run(&mut worker).map(|()| worker)))
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("Session worker panicked")))
+            })
+            .collect()
+    });
+    for result in results {
+        se.join(result?);
+    }
+    Ok(())
+}
+
+/// Run the session loop until the given future completes and shut down gracefully
+async fn run_until<S: Dispatch, F: Future>(
+    se: &mut S,
+    shutdown: F,
+    timeout: Duration,
+) -> io::Result<ShutdownSummary> {
+    let pool = se.connection().pool.clone();
+    let pending = Arc::clone(&se.connection().pending);
+    // The channel outlives the registration, which is dropped before shutting down
+    let fd = unsafe {
+        AsyncFd::register_with_interest(se.connection().ch.as_raw_fd(), Interest::READABLE)?
+    };
+    tokio::pin!(shutdown);
+    let requested = loop {
+        tokio::select! {
+            _ = &mut shutdown => break true,
+            guard = fd.readable() => {
+                let mut guard = guard?;
+                let conn = se.connection();
+                let mut buffer = pool.get();
+                let res = match S::SPLICE_WRITE {
+                    true => conn.ch.async_receive_spliced(&mut buffer).await,
+                    false => conn.ch.async_receive(&mut buffer).await,
+                };
+                match res {
+                    // Dispatch request
+                    Ok(()) => dispatch(conn.ch.sender(), buffer, &pool, &pending, se.handler()),
+                    Err(err) => match err.raw_os_error() {
+                        // Operation interrupted. According to FUSE, this is safe to retry
+                        Some(ENOENT) => continue,
+                        // Interrupted system call, retry
+                        Some(EINTR) => continue,
+                        // Nothing to read, wait until the device is readable again
+                        Some(EAGAIN) => guard.clear_ready(),
+                        // Filesystem was unmounted, quit the loop
+                        Some(ENODEV) => break false,
+                        // Unhandled error
+                        _ => return Err(err),
                     },
-                receive_result =  self.ch.async_receive(&mut buffer) => {
-                    match receive_result {
-                        Ok(()) => match Request::new(self.ch.sender(), &buffer) {
-                            // Dispatch request
-                            Some(req) => req.dispatch(self),
-                            // Quit loop on illegal request
-                            None => break,
-                        },
-                        Err(err) => match err.raw_os_error() {
-                            // Operation interrupted. According to FUSE, this is safe to retry
-                            Some(ENOENT) => continue,
-                            // Interrupted system call, retry
-                            Some(EINTR) => continue,
-                            // Explicitly try again
-                            Some(EAGAIN) => continue,
-                            // Filesystem was unmounted, quit the loop
-                            Some(ENODEV) => break,
-                            // Unhandled error
-                            _ => return Err(err),
-                        },
-                    }
                 }
             }
         }
-        Ok(())
+    };
+    drop(fd);
+    shutdown_session(se, requested, timeout).await
+}
+
+/// Shut down the session after the session loop stopped
+async fn shutdown_session<S: Dispatch>(
+    se: &mut S,
+    requested: bool,
+    timeout: Duration,
+) -> io::Result<ShutdownSummary> {
+    let pending = Arc::clone(&se.connection().pending);
+    let pending_replies = pending.count();
+    if pending_replies > 0 {
+        info!("Waiting for {} outstanding replies", pending_replies);
     }
+    let abandoned_replies = match tokio::time::timeout(timeout, pending.wait()).await {
+        Ok(()) => 0,
+        Err(_) => pending.count(),
+    };
+    if abandoned_replies > 0 {
+        warn!(
+            "Shutting down with {} outstanding replies",
+            abandoned_replies
+        );
+    }
+    let destroyed = se.destroy();
+    // If the filesystem was unmounted externally, there's nothing left to unmount
+    let ch = &mut se.connection().ch;
+    let (unmounted, lazy_unmount) = match requested && ch.is_mounted() {
+        true => (true, ch.unmount()?),
+        false => (false, false),
+    };
+    Ok(ShutdownSummary {
+        requested,
+        pending_replies,
+        abandoned_replies,
+        destroyed,
+        unmounted,
+        lazy_unmount,
+    })
 }
 
-impl<FS: Filesystem> Drop for Session<FS> {
-    fn drop(&mut self) {
-        info!("Unmounted {}", self.mountpoint().display());
+/// Dispatch the request received in the given buffer to the given handler. Illegal requests
+/// are replied with an error if possible. The buffer is shared using a recycled allocation of
+/// the pool, so that requests can be dispatched (and simple ones replied) without allocating.
+fn dispatch<H: RawHandler>(
+    sender: ChannelSender,
+    buffer: Buffer,
+    pool: &BufferPool,
+    pending: &Arc<PendingReplies>,
+    handler: &mut H,
+) {
+    let buffer = pool.share(buffer);
+    if let Some(req) = Request::new(sender, &buffer, pending) {
+        req.dispatch(handler);
+    }
+    pool.recycle(buffer);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        dispatch, RawSession, Session, DEFAULT_MAX_WRITE_SIZE, INIT_MAX_WRITE_SIZE, MAX_WRITE_SIZE,
+        XATTR_SIZE_MAX,
+    };
+    use crate::ll::Response;
+    use crate::testing::{packet, Device};
+    use crate::{
+        FileAttr, FileType, Filesystem, RawHandler, RawReply, RawRequest, ReplyAttr, ReplyStatfs,
+        RequestContext,
+    };
+    use fuse_abi::{fuse_opcode, AsBytes, FuseAttrOut, FuseForgetIn, FuseInterruptIn};
+    use libc::EIO;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+    use std::{cmp, mem, thread};
+
+    thread_local! {
+        /// Number of allocations of the current thread, if counting
+        static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Allocator that counts the allocations of threads that enabled counting. Note that a
+    /// global allocator is used by the whole test binary, not only by the tests of this
+    /// module. Threads that don't count allocations just use the system allocator.
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get().map(|n| n + 1)));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Attributes of an empty directory
+    fn dir_attr(ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    /// Filesystem that replies to getattr with an empty directory
+    struct AttrFS;
+
+    impl Filesystem for AttrFS {
+        fn getattr(&mut self, _req: &RequestContext, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &dir_attr(ino));
+        }
+    }
+
+    /// Filesystem whose clones reply to getattr only while two getattr calls run at the same
+    /// time (with EIO if that doesn't happen within a few seconds)
+    #[derive(Clone, Default)]
+    struct ConcurrentFS {
+        calls: Arc<(Mutex<usize>, Condvar)>,
+    }
+
+    impl Filesystem for ConcurrentFS {
+        fn getattr(&mut self, _req: &RequestContext, ino: u64, reply: ReplyAttr) {
+            let (calls, cond) = &*self.calls;
+            let mut calls = calls.lock().unwrap();
+            *calls += 1;
+            cond.notify_all();
+            let (_calls, timeout) = cond
+                .wait_timeout_while(calls, Duration::from_secs(5), |calls| *calls < 2)
+                .unwrap();
+            match timeout.timed_out() {
+                true => reply.error(EIO),
+                false => reply.attr(&Duration::from_secs(1), &dir_attr(ino)),
+            }
+        }
+    }
+
+    /// Filesystem that keeps statfs replies (and never sends them)
+    #[derive(Default)]
+    struct PendingFS {
+        replies: Vec<ReplyStatfs>,
+        destroyed: bool,
+    }
+
+    impl Filesystem for PendingFS {
+        fn statfs(&mut self, _req: &RequestContext, _ino: u64, reply: ReplyStatfs) {
+            self.replies.push(reply);
+        }
+
+        fn destroy(&mut self) {
+            self.destroyed = true;
+        }
+    }
+
+    /// Filesystem that writes data to a file and keeps it
+    #[cfg(target_os = "linux")]
+    struct FileFS {
+        file: std::fs::File,
+        data: Vec<crate::WriteData>,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Filesystem for FileFS {
+        fn write_buf(
+            &mut self,
+            _req: &RequestContext,
+            _ino: u64,
+            _fh: u64,
+            offset: i64,
+            data: crate::WriteData,
+            _flags: u32,
+            reply: crate::ReplyWrite,
+        ) {
+            use std::os::unix::io::AsFd;
+            data.write_to(self.file.as_fd(), offset as u64).unwrap();
+            reply.written(data.len() as u32);
+            self.data.push(data);
+        }
+    }
+
+    /// Raw handler that replies to every request with its opcode
+    #[derive(Default)]
+    struct OpcodeHandler {
+        requests: Vec<String>,
+    }
+
+    impl RawHandler for OpcodeHandler {
+        fn handle(&mut self, request: &RawRequest<'_>, reply: RawReply) {
+            self.requests.push(request.to_string());
+            if reply.expected() {
+                reply.send(Response::Data(&request.data()[4..8]));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_pending_replies() {
+        let (device, fd) = Device::new();
+        device.init(0);
+        device.send(&packet(fuse_opcode::FUSE_STATFS as u32, 2, 1, &[]));
+
+        let mut se = Session::from_fd(PendingFS::default(), fd);
+        let shutdown = tokio::time::sleep(Duration::from_millis(100));
+        let summary = se
+            .run_until(shutdown, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert!(summary.requested);
+        assert_eq!(summary.pending_replies, 1);
+        assert_eq!(summary.abandoned_replies, 1);
+        assert!(summary.destroyed);
+        assert!(se.filesystem.destroyed);
+        assert!(!summary.unmounted);
+        assert_eq!(se.filesystem.replies.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn raw_session() {
+        let (device, fd) = Device::new();
+        device.init(0);
+        let interrupt = FuseInterruptIn { unique: 1 };
+        device.send(&packet(
+            fuse_opcode::FUSE_INTERRUPT as u32,
+            2,
+            0,
+            &[interrupt.as_bytes()],
+        ));
+        device.send(&packet(99, 3, 1, &[&[0; 16]]));
+        let forget = FuseForgetIn { nlookup: 1 };
+        device.send(&packet(
+            fuse_opcode::FUSE_FORGET as u32,
+            4,
+            1,
+            &[forget.as_bytes()],
+        ));
+
+        let mut se = RawSession::from_fd(OpcodeHandler::default(), fd);
+        let shutdown = tokio::time::sleep(Duration::from_millis(100));
+        let summary = se
+            .run_until(shutdown, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(summary.pending_replies, 0);
+        assert!(!summary.destroyed);
+        assert_eq!(se.handler.requests.len(), 4);
+        assert!(se.handler.requests[2].ends_with("UNKNOWN opcode 99, 16 bytes"));
+        // Requests are passed as is and only replied if they expect a reply. The dropped reply
+        // to the interrupt isn't sent.
+        for (unique, opcode) in [(1, 26u32), (3, 99)] {
+            let reply = device.receive();
+            assert_eq!((reply.unique, reply.error), (unique, 0));
+            assert_eq!(reply.data, opcode.to_ne_bytes());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn spliced_write() {
+        use fuse_abi::{FromBytes, FuseWriteIn};
+
+        let (device, fd) = Device::new();
+        device.init(0);
+        let mut arg = FuseWriteIn::new_zeroed();
+        arg.offset = 2;
+        arg.size = 6;
+        device.send(&packet(
+            fuse_opcode::FUSE_WRITE as u32,
+            2,
+            1,
+            &[arg.as_bytes(), b"foobar"],
+        ));
+
+        let path = std::env::temp_dir().join(format!("fuse-write-{}", std::process::id()));
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let data = Vec::new();
+        let mut se = Session::from_fd(FileFS { file, data }, fd);
+        se.set_splice(true);
+        let shutdown = tokio::time::sleep(Duration::from_millis(100));
+        se.run_until(shutdown, Duration::from_millis(10))
+            .await
+            .unwrap();
+        // Splicing from the fake device works like from the FUSE device
+        assert!(se.conn.ch.splice());
+        device.receive();
+        let reply = device.receive();
+        assert_eq!((reply.unique, reply.error), (2, 0));
+        assert_eq!(reply.data[..4], 6u32.to_ne_bytes());
+        // The data was written to the file and is still available afterwards
+        let mut written = [0; 8];
+        std::os::unix::fs::FileExt::read_exact_at(&se.filesystem.file, &mut written, 0).unwrap();
+        assert_eq!(&written, b"\0\0foobar");
+        assert_eq!(&*se.filesystem.data[0], b"foobar");
+    }
+
+    #[test]
+    fn multithreaded_session() {
+        let (device, fd) = Device::new();
+        device.init(0);
+        for unique in 2..4 {
+            device.send(&packet(fuse_opcode::FUSE_GETATTR as u32, unique, 1, &[]));
+        }
+
+        let mut se = Session::from_fd(ConcurrentFS::default(), fd);
+        thread::scope(|scope| {
+            let session = scope.spawn(|| se.run_multithreaded(2));
+            let mut replies: Vec<_> = (0..3).map(|_| device.receive()).collect();
+            replies.sort_by_key(|reply| reply.unique);
+            // Both getattr requests are dispatched to clones of the filesystem at the same time
+            for (reply, unique) in replies.iter().zip(1..) {
+                assert_eq!((reply.unique, reply.error), (unique, 0));
+            }
+            // Closing the device ends all workers, like unmounting
+            drop(device);
+            session.join().unwrap().unwrap();
+        });
+        assert!(se.initialized);
+        assert_eq!(*se.filesystem.calls.0.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn dispatch_without_allocations() {
+        let (device, fd) = Device::new();
+        device.init(0);
+        for unique in 2..5 {
+            device.send(&packet(fuse_opcode::FUSE_GETATTR as u32, unique, 1, &[]));
+        }
+
+        // Receive and dispatch requests like the session loop, counting allocations
+        let mut se = Session::from_fd(AttrFS, fd);
+        let pool = se.conn.pool.clone();
+        let pending = Arc::clone(&se.conn.pending);
+        let allocations: Vec<_> = (0..4)
+            .map(|_| {
+                ALLOCATIONS.with(|count| count.set(Some(0)));
+                let mut buffer = pool.get();
+                se.conn.ch.receive(&mut buffer).unwrap();
+                let sender = se.conn.ch.sender();
+                dispatch(sender, buffer, &pool, &pending, &mut se);
+                ALLOCATIONS.with(|count| count.take()).unwrap()
+            })
+            .collect();
+        // Once buffers are pooled, getattr requests are replied without allocating
+        assert_eq!(allocations[2..], [0, 0]);
+        for unique in 1..5 {
+            let reply = device.receive();
+            assert_eq!((reply.unique, reply.error), (unique, 0));
+            if unique > 1 {
+                assert_eq!(reply.data.len(), mem::size_of::<FuseAttrOut>());
+            }
+        }
+    }
+
+    #[test]
+    fn max_write_is_clamped() {
+        let (_device, fd) = Device::new();
+        let mut se = Session::from_fd(AttrFS, fd);
+        assert_eq!(se.max_write(), DEFAULT_MAX_WRITE_SIZE);
+        se.set_max_write(1024);
+        assert_eq!(se.max_write(), 4096);
+        // Buffers still fit the largest SETXATTR
+        assert_eq!(se.conn.pool.size(), XATTR_SIZE_MAX + 4096);
+        se.set_max_write(64 * 1024);
+        assert_eq!(se.max_write(), 64 * 1024);
+        se.set_max_write(MAX_WRITE_SIZE + 1);
+        assert_eq!(se.max_write(), INIT_MAX_WRITE_SIZE);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn max_write_negotiation() {
+        let (_device, fd) = Device::new();
+        let mut se = Session::from_fd(AttrFS, fd);
+        se.set_max_write(1024 * 1024);
+        #[cfg(feature = "abi-7-28")]
+        {
+            use fuse_abi::consts::FUSE_MAX_PAGES;
+            assert_ne!(se.extra_init_flags() & FUSE_MAX_PAGES, 0);
+            se.limit_max_write(FUSE_MAX_PAGES);
+            assert_eq!(se.max_write(), 1024 * 1024);
+            assert_eq!(se.max_pages() as usize, 1024 * 1024 / super::page_size());
+        }
+        // Without max_pages, the kernel sends writes of up to 32 pages
+        se.limit_max_write(0);
+        let max_write = cmp::min(se.max_write(), 32 * super::page_size());
+        assert_eq!(se.max_write(), max_write);
+        assert_eq!(se.conn.pool.size(), super::buffer_size(max_write));
     }
 }
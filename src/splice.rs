@@ -0,0 +1,338 @@
+//! Zero-copy data transfer
+//!
+//! Helpers to move data between file descriptors through a pipe with splice(2), so that the
+//! data doesn't need to be copied through userspace (Linux only). Reply data can be sent from
+//! a file to the kernel driver this way without ever being read by the filesystem. Likewise,
+//! the data of write requests can be left in the pipe it was received in and be spliced to a
+//! file by the filesystem.
+
+use libc::{self, c_int, c_void, loff_t, size_t};
+use std::cell::RefCell;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::sync::{Mutex, OnceLock};
+use std::{fmt, io, ptr};
+
+use crate::buffer::write_all_at;
+
+/// Page size assumed when estimating the pipe capacity needed for some data. Data in a pipe is
+/// stored in page sized buffers and unaligned data may occupy an additional buffer.
+const PAGE_SIZE: usize = 4096;
+
+thread_local! {
+    /// Pipe for sending replies and writing spliced data, one per thread to allow sending
+    /// concurrently
+    static REPLY_PIPE: RefCell<Option<Pipe>> = const { RefCell::new(None) };
+}
+
+/// A pipe that data can be spliced into and out of
+#[derive(Debug)]
+pub struct Pipe {
+    read: c_int,
+    write: c_int,
+    size: usize,
+}
+
+impl Pipe {
+    /// Create a new pipe
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        // The pipe is non-blocking to fail instead of deadlocking if it runs full
+        let rc = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pipe = Pipe {
+            read: fds[0],
+            write: fds[1],
+            size: 0,
+        };
+        let size = unsafe { libc::fcntl(pipe.write, libc::F_GETPIPE_SZ) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        pipe.size = size as usize;
+        Ok(pipe)
+    }
+
+    /// Make sure the pipe can hold the given amount of data (plus some extra space for
+    /// unaligned data). Returns false if the pipe can't be grown that much, e.g. because the
+    /// size exceeds /proc/sys/fs/pipe-max-size.
+    pub fn reserve(&mut self, len: usize) -> bool {
+        let size = len + 2 * PAGE_SIZE;
+        if self.size >= size {
+            return true;
+        }
+        let rc = unsafe { libc::fcntl(self.write, libc::F_SETPIPE_SZ, size as c_int) };
+        if rc < 0 {
+            return false;
+        }
+        self.size = rc as usize;
+        self.size >= size
+    }
+
+    /// Move up to the given amount of data from the given file descriptor into the pipe. If an
+    /// offset is given, data is read from that offset and the offset is updated.
+    pub fn splice_from(
+        &self,
+        fd: c_int,
+        offset: Option<&mut loff_t>,
+        len: usize,
+    ) -> io::Result<usize> {
+        let offset = offset.map_or(ptr::null_mut(), |off| off as *mut loff_t);
+        let rc = unsafe {
+            libc::splice(
+                fd,
+                offset,
+                self.write,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc as usize)
+        }
+    }
+
+    /// Move up to the given amount of data from the pipe to the given file descriptor. If an
+    /// offset is given, data is written at that offset and the offset is updated.
+    fn splice_to(&self, fd: c_int, offset: Option<&mut loff_t>, len: usize) -> io::Result<usize> {
+        let offset = offset.map_or(ptr::null_mut(), |off| off as *mut loff_t);
+        let rc = unsafe {
+            libc::splice(
+                self.read,
+                ptr::null_mut(),
+                fd,
+                offset,
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc as usize)
+        }
+    }
+
+    /// Duplicate up to the given amount of data from this pipe into the given pipe without
+    /// consuming it
+    fn tee_to(&self, pipe: &Pipe, len: usize) -> io::Result<usize> {
+        let rc = unsafe { libc::tee(self.read, pipe.write, len, 0) };
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc as usize)
+        }
+    }
+
+    /// Write all given data into the pipe
+    fn write_all(&self, data: &[&[u8]]) -> io::Result<()> {
+        for bytes in data {
+            let rc =
+                unsafe { libc::write(self.write, bytes.as_ptr() as *const c_void, bytes.len()) };
+            if rc < 0 {
+                return Err(io::Error::last_os_error());
+            } else if rc as usize != bytes.len() {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the given amount of data from the pipe and append it to the given buffer
+    pub fn read_exact(&self, buffer: &mut Vec<u8>, len: usize) -> io::Result<()> {
+        let end = buffer.len() + len;
+        assert!(end <= buffer.capacity());
+        while buffer.len() < end {
+            let rc = unsafe {
+                libc::read(
+                    self.read,
+                    buffer.as_mut_ptr().add(buffer.len()) as *mut c_void,
+                    (end - buffer.len()) as size_t,
+                )
+            };
+            match rc {
+                rc if rc < 0 => return Err(io::Error::last_os_error()),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                rc => unsafe { buffer.set_len(buffer.len() + rc as usize) },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// Send the given data followed by the given amount of data read from a file at the given
+/// offset to the given file descriptor with a single write. The file data is spliced through a
+/// pipe and never copied to userspace. The kernel driver takes a message either completely or
+/// not at all, so nothing has been sent if an error is returned.
+pub fn send(
+    fd: c_int,
+    data: &[&[u8]],
+    file: BorrowedFd<'_>,
+    offset: u64,
+    len: usize,
+) -> io::Result<()> {
+    REPLY_PIPE.with(|pipe| {
+        let mut pipe = pipe.borrow_mut();
+        if pipe.is_none() {
+            *pipe = Some(Pipe::new()?);
+        }
+        let total = data.iter().map(|d| d.len()).sum::<usize>() + len;
+        let res = send_through(pipe.as_mut().unwrap(), fd, data, file, offset, len, total);
+        if res.is_err() {
+            // Some data may be left in the pipe, use a fresh one next time
+            *pipe = None;
+        }
+        res
+    })
+}
+
+/// Fill the given pipe with a complete message and splice it to the given file descriptor
+fn send_through(
+    pipe: &mut Pipe,
+    fd: c_int,
+    data: &[&[u8]],
+    file: BorrowedFd<'_>,
+    offset: u64,
+    len: usize,
+    total: usize,
+) -> io::Result<()> {
+    if !pipe.reserve(total) {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    pipe.write_all(data)?;
+    let mut offset = offset as loff_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        match pipe.splice_from(file.as_raw_fd(), Some(&mut offset), remaining)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => remaining -= n,
+        }
+    }
+    // The kernel driver expects a whole message with a single write
+    match pipe.splice_to(fd, None, total)? {
+        n if n == total => Ok(()),
+        _ => Err(io::ErrorKind::WriteZero.into()),
+    }
+}
+
+/// Data left in the pipe it was received in (e.g. the data of a write request). The data can
+/// be spliced to files any number of times, or be read into memory once it's needed there.
+pub struct SplicedData {
+    /// Pipe holding the data. Nothing else may be in it.
+    pipe: Mutex<Pipe>,
+    /// Size of the data
+    len: usize,
+    /// The data, once it was read from the pipe
+    bytes: OnceLock<Vec<u8>>,
+}
+
+impl SplicedData {
+    /// Take over the given pipe which holds exactly the given amount of data
+    pub fn new(pipe: Pipe, len: usize) -> SplicedData {
+        SplicedData {
+            pipe: Mutex::new(pipe),
+            len,
+            bytes: OnceLock::new(),
+        }
+    }
+
+    /// Returns the size of the data
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the data, reading it from the pipe if this wasn't done before. Panics if the
+    /// pipe can't be read, which doesn't happen since it's known to hold the data.
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes.get_or_init(|| {
+            let pipe = self.pipe.lock().unwrap();
+            let mut bytes = Vec::with_capacity(self.len);
+            pipe.read_exact(&mut bytes, self.len)
+                .expect("Failed to read spliced data");
+            bytes
+        })
+    }
+
+    /// Write the data to the given file at the given offset. As long as the data wasn't read
+    /// into memory, a copy of it is spliced to the file. Otherwise (or if the file doesn't
+    /// support splicing) it's written normally.
+    pub fn write_to(&self, file: BorrowedFd<'_>, offset: u64) -> io::Result<()> {
+        let mut written = 0;
+        if self.bytes.get().is_none() {
+            let pipe = self.pipe.lock().unwrap();
+            // The data may have been read while waiting for the lock
+            if self.bytes.get().is_none() {
+                written = REPLY_PIPE.with(|copy| {
+                    let mut copy = copy.borrow_mut();
+                    if copy.is_none() {
+                        *copy = Some(Pipe::new()?);
+                    }
+                    let res = self.splice_copy(&pipe, copy.as_mut().unwrap(), file, offset);
+                    if !matches!(res, Ok(len) if len == self.len) {
+                        // Some data may be left in the pipe, use a fresh one next time
+                        *copy = None;
+                    }
+                    res
+                })?;
+            }
+        }
+        if written < self.len {
+            write_all_at(file, &self.bytes()[written..], offset + written as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Splice a copy of the data in the given pipe to the given file at the given offset
+    /// through the given (empty) pipe. Returns the amount of data written, which is less than
+    /// the size of the data if the rest needs to be written normally.
+    fn splice_copy(
+        &self,
+        pipe: &Pipe,
+        copy: &mut Pipe,
+        file: BorrowedFd<'_>,
+        offset: u64,
+    ) -> io::Result<usize> {
+        if !copy.reserve(self.len) {
+            return Ok(0);
+        }
+        // A copy of the data is spliced, since the data itself is gone from the pipe after
+        // splicing it and may be needed again. Teeing doesn't consume the data, so a short
+        // copy can't be completed.
+        if pipe.tee_to(copy, self.len)? != self.len {
+            return Ok(0);
+        }
+        let mut offset = offset as loff_t;
+        let mut written = 0;
+        while written < self.len {
+            match copy.splice_to(file.as_raw_fd(), Some(&mut offset), self.len - written) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                // Files that don't support splicing are written normally
+                Err(err) if written == 0 && err.raw_os_error() == Some(libc::EINVAL) => {
+                    return Ok(0);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl fmt::Debug for SplicedData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SplicedData({} bytes)", self.len)
+    }
+}
@@ -0,0 +1,383 @@
+//! POSIX-like client
+//!
+//! `LoopbackClient` resolves paths with lookups and keeps count of them like the kernel's inode
+//! cache does, until the lookups are forgotten with `drop_caches`. Opened files are flushed and
+//! released on close. Symbolic links in paths are not followed.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path};
+
+use super::Driver;
+use crate::fallible::{DirEntry, Entry, Errno};
+use crate::reply::mode_from_kind_and_perm;
+use crate::{FileAttr, FileType, Filesystem, OpenFlags, SetAttr, FUSE_ROOT_ID};
+
+/// Lock owner of all files opened by a client (the kernel uses one per process)
+const LOCK_OWNER: u64 = 1;
+
+/// File descriptor of a file opened with a `LoopbackClient`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Fd(u64);
+
+/// Open file of a client
+#[derive(Debug)]
+struct OpenFile {
+    ino: u64,
+    fh: u64,
+}
+
+/// Client that runs file operations by path against a filesystem in-process
+#[derive(Debug)]
+pub struct LoopbackClient<FS: Filesystem> {
+    driver: Driver<FS>,
+    /// Number of lookups of each inode that weren't forgotten yet
+    lookups: HashMap<u64, u64>,
+    files: HashMap<Fd, OpenFile>,
+    next_fd: u64,
+}
+
+impl<FS: Filesystem> LoopbackClient<FS> {
+    /// Create a client for the given filesystem and initialize the filesystem
+    pub fn new(filesystem: FS) -> Result<LoopbackClient<FS>, Errno> {
+        let mut driver = Driver::new(filesystem);
+        driver.init()?;
+        Ok(LoopbackClient {
+            driver,
+            lookups: HashMap::new(),
+            files: HashMap::new(),
+            next_fd: 3,
+        })
+    }
+
+    /// Returns the driver that sends the requests
+    pub fn driver(&self) -> &Driver<FS> {
+        &self.driver
+    }
+
+    /// Returns the driver for sending requests directly
+    pub fn driver_mut(&mut self) -> &mut Driver<FS> {
+        &mut self.driver
+    }
+
+    /// Returns the filesystem
+    pub fn filesystem(&self) -> &FS {
+        self.driver.filesystem()
+    }
+
+    /// Returns the filesystem for modification
+    pub fn filesystem_mut(&mut self) -> &mut FS {
+        self.driver.filesystem_mut()
+    }
+
+    /// Returns the number of lookups of an inode that weren't forgotten yet
+    pub fn lookup_count(&self, ino: u64) -> u64 {
+        self.lookups.get(&ino).copied().unwrap_or(0)
+    }
+
+    /// Forget all lookups, except of inodes of open files (like dropping the kernel's caches)
+    pub fn drop_caches(&mut self) {
+        let open: Vec<u64> = self.files.values().map(|file| file.ino).collect();
+        let lookups: Vec<(u64, u64)> = self
+            .lookups
+            .iter()
+            .filter(|(ino, _)| !open.contains(ino))
+            .map(|(&ino, &nlookup)| (ino, nlookup))
+            .collect();
+        for (ino, nlookup) in lookups {
+            self.driver.forget(ino, nlookup);
+            self.lookups.remove(&ino);
+        }
+    }
+
+    /// Returns the attributes of a file
+    pub fn stat<P: AsRef<Path>>(&mut self, path: P) -> Result<FileAttr, Errno> {
+        let ino = self.resolve(path.as_ref())?;
+        Ok(self.driver.getattr(ino)?.attr)
+    }
+
+    /// Change attributes of a file (chmod, chown, truncate, utimes)
+    pub fn setattr<P: AsRef<Path>>(&mut self, path: P, attr: &SetAttr) -> Result<FileAttr, Errno> {
+        let ino = self.resolve(path.as_ref())?;
+        Ok(self.driver.setattr(ino, attr)?.attr)
+    }
+
+    /// Open a file. With `OpenFlags::CREAT`, the file is created with the given permissions if
+    /// it doesn't exist (with `OpenFlags::EXCL`, it must not exist). With `OpenFlags::TRUNC`,
+    /// the file is truncated after opening it.
+    pub fn open<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Fd, Errno> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        let entry = match self.lookup(parent, name) {
+            Ok(_) if flags.contains(OpenFlags::CREAT | OpenFlags::EXCL) => {
+                return Err(Errno::EEXIST)
+            }
+            Ok(entry) => entry,
+            Err(Errno::ENOENT) if flags.contains(OpenFlags::CREAT) => {
+                return self.create(parent, name, mode, flags)
+            }
+            Err(err) => return Err(err),
+        };
+        if entry.attr.kind == FileType::Directory && flags.is_writable() {
+            return Err(Errno::EISDIR);
+        }
+        // Like the kernel, truncate with a separate setattr after opening
+        let ino = entry.attr.ino;
+        let fd = self.open_ino(ino, flags)?;
+        if flags.contains(OpenFlags::TRUNC) && flags.is_writable() {
+            let attr = SetAttr {
+                size: Some(0),
+                fh: Some(self.files[&fd].fh),
+                ..SetAttr::default()
+            };
+            if let Err(err) = self.driver.setattr(ino, &attr) {
+                let _ = self.close(fd);
+                return Err(err);
+            }
+        }
+        Ok(fd)
+    }
+
+    /// Read up to size bytes at the given offset of an open file
+    pub fn read(&mut self, fd: Fd, offset: i64, size: u32) -> Result<Vec<u8>, Errno> {
+        let file = self.files.get(&fd).ok_or(Errno::EBADF)?;
+        self.driver.read(file.ino, file.fh, offset, size)
+    }
+
+    /// Write data at the given offset of an open file. Returns the number of bytes written.
+    pub fn write(&mut self, fd: Fd, offset: i64, data: &[u8]) -> Result<usize, Errno> {
+        let file = self.files.get(&fd).ok_or(Errno::EBADF)?;
+        Ok(self.driver.write(file.ino, file.fh, offset, data)? as usize)
+    }
+
+    /// Close an open file. The file is flushed and released, errors of the flush are returned.
+    pub fn close(&mut self, fd: Fd) -> Result<(), Errno> {
+        let file = self.files.remove(&fd).ok_or(Errno::EBADF)?;
+        let res = match self.driver.flush(file.ino, file.fh, LOCK_OWNER) {
+            // Filesystems don't need to implement flush
+            Err(Errno::ENOSYS) => Ok(()),
+            res => res,
+        };
+        // Errors of release are not returned to close
+        let _ = self.driver.release(file.ino, file.fh);
+        res
+    }
+
+    /// List the entries of a directory
+    pub fn readdir<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<DirEntry>, Errno> {
+        let ino = self.resolve(path.as_ref())?;
+        let open = self.driver.opendir(ino)?;
+        let res = self.driver.readdir_all(ino, open.fh);
+        let _ = self.driver.releasedir(ino, open.fh);
+        res
+    }
+
+    /// Create a directory
+    pub fn mkdir<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<FileAttr, Errno> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        let entry = self.driver.mkdir(parent, name, mode)?;
+        Ok(self.count(entry).attr)
+    }
+
+    /// Create a symbolic link at the given path that points to the given target
+    pub fn symlink<P: AsRef<Path>, T: AsRef<Path>>(
+        &mut self,
+        target: T,
+        path: P,
+    ) -> Result<FileAttr, Errno> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        let entry = self.driver.symlink(parent, name, target)?;
+        Ok(self.count(entry).attr)
+    }
+
+    /// Returns the target of a symbolic link
+    pub fn readlink<P: AsRef<Path>>(&mut self, path: P) -> Result<std::ffi::OsString, Errno> {
+        let ino = self.resolve(path.as_ref())?;
+        self.driver.readlink(ino)
+    }
+
+    /// Create a hard link at the given path to an existing file
+    pub fn link<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        existing: P,
+        path: Q,
+    ) -> Result<FileAttr, Errno> {
+        let ino = self.resolve(existing.as_ref())?;
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        let entry = self.driver.link(ino, parent, name)?;
+        Ok(self.count(entry).attr)
+    }
+
+    /// Remove a file
+    pub fn unlink<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Errno> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        // The kernel looks up the entry before removing it
+        self.lookup(parent, name)?;
+        self.driver.unlink(parent, name)
+    }
+
+    /// Remove an empty directory
+    pub fn rmdir<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Errno> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        self.lookup(parent, name)?;
+        self.driver.rmdir(parent, name)
+    }
+
+    /// Rename a file, replacing the target if it exists
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<(), Errno> {
+        let (parent, name) = self.resolve_parent(from.as_ref())?;
+        let (newparent, newname) = self.resolve_parent(to.as_ref())?;
+        // The kernel looks up the source and the target (which may not exist) before renaming
+        self.lookup(parent, name)?;
+        match self.lookup(newparent, newname) {
+            Ok(_) | Err(Errno::ENOENT) => {}
+            Err(err) => return Err(err),
+        }
+        self.driver.rename(parent, name, newparent, newname)
+    }
+
+    /// Look up a directory entry and count the lookup
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<Entry, Errno> {
+        let entry = self.driver.lookup(parent, name)?;
+        Ok(self.count(entry))
+    }
+
+    /// Count the lookup of an entry returned by the filesystem
+    fn count(&mut self, entry: Entry) -> Entry {
+        *self.lookups.entry(entry.attr.ino).or_default() += 1;
+        entry
+    }
+
+    /// Returns the inode of a path, looking up every component
+    fn resolve(&mut self, path: &Path) -> Result<u64, Errno> {
+        // Stack of directories to go back to on ".."
+        let mut dirs = vec![FUSE_ROOT_ID];
+        let mut kind = FileType::Directory;
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    if kind != FileType::Directory {
+                        return Err(Errno::ENOTDIR);
+                    }
+                    let entry = self.lookup(*dirs.last().unwrap(), name)?;
+                    dirs.push(entry.attr.ino);
+                    kind = entry.attr.kind;
+                }
+                Component::ParentDir if dirs.len() > 1 => {
+                    dirs.pop();
+                }
+                _ => {}
+            }
+        }
+        Ok(*dirs.last().unwrap())
+    }
+
+    /// Returns the inode of the parent directory and the file name of a path
+    fn resolve_parent<'a>(&mut self, path: &'a Path) -> Result<(u64, &'a OsStr), Errno> {
+        let name = path.file_name().ok_or(Errno::EINVAL)?;
+        let parent = self.resolve(path.parent().unwrap_or(Path::new("/")))?;
+        Ok((parent, name))
+    }
+
+    /// Create and open a file. Falls back to mknod and open if create isn't implemented.
+    fn create(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: OpenFlags,
+    ) -> Result<Fd, Errno> {
+        let mode = mode_from_kind_and_perm(FileType::RegularFile, (mode & 0o7777) as u16);
+        match self.driver.create(parent, name, mode, flags) {
+            Ok(created) => {
+                let ino = self.count(created.entry).attr.ino;
+                Ok(self.insert(ino, created.open.fh))
+            }
+            Err(Errno::ENOSYS) => {
+                let entry = self.driver.mknod(parent, name, mode, 0)?;
+                let ino = self.count(entry).attr.ino;
+                self.open_ino(ino, flags)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Open an inode. The kernel doesn't pass the flags for creating and truncating.
+    fn open_ino(&mut self, ino: u64, flags: OpenFlags) -> Result<Fd, Errno> {
+        let strip = OpenFlags::CREAT | OpenFlags::EXCL | OpenFlags::TRUNC;
+        let flags = OpenFlags::from_bits_retain(flags.bits() & !strip.bits());
+        let open = self.driver.open(ino, flags)?;
+        Ok(self.insert(ino, open.fh))
+    }
+
+    /// Add an open file and return its descriptor
+    fn insert(&mut self, ino: u64, fh: u64) -> Fd {
+        let fd = Fd(self.next_fd);
+        self.next_fd += 1;
+        self.files.insert(fd, OpenFile { ino, fh });
+        fd
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LoopbackClient;
+    use crate::fallible::Errno;
+    use crate::memory::MemoryFS;
+    use crate::{FileType, OpenFlags};
+
+    fn client() -> LoopbackClient<MemoryFS> {
+        LoopbackClient::new(MemoryFS::new(1 << 20)).unwrap()
+    }
+
+    #[test]
+    fn create_write_read() {
+        let mut client = client();
+        let flags = OpenFlags::CREAT | OpenFlags::RDWR;
+        let fd = client.open("/hello.txt", flags, 0o644).unwrap();
+        assert_eq!(client.write(fd, 0, b"Hello World!\n").unwrap(), 13);
+        assert_eq!(client.read(fd, 6, 100).unwrap(), b"World!\n");
+        client.close(fd).unwrap();
+        assert_eq!(client.close(fd), Err(Errno::EBADF));
+        let attr = client.stat("/hello.txt").unwrap();
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.size, 13);
+        let exclusive = flags | OpenFlags::EXCL;
+        assert_eq!(
+            client.open("/hello.txt", exclusive, 0o644),
+            Err(Errno::EEXIST)
+        );
+        let names: Vec<_> = client
+            .readdir("/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(names.iter().any(|name| name == "hello.txt"));
+        assert_eq!(client.driver().pending_replies(), 0);
+    }
+
+    #[test]
+    fn lookup_counts() {
+        let mut client = client();
+        let dir = client.mkdir("/dir", 0o755).unwrap();
+        let fd = client.open("/dir/file", OpenFlags::CREAT, 0o644).unwrap();
+        let file = client.stat("/dir/file").unwrap();
+        // mkdir, create and every lookup of a path component count
+        assert_eq!(client.lookup_count(dir.ino), 3);
+        assert_eq!(client.lookup_count(file.ino), 2);
+        assert_eq!(client.stat("/dir/file/x"), Err(Errno::ENOTDIR));
+        assert_eq!(client.rename("/dir/missing", "/x"), Err(Errno::ENOENT));
+        // Lookups of open files are kept
+        client.drop_caches();
+        assert_eq!(client.lookup_count(dir.ino), 0);
+        assert_eq!(client.lookup_count(file.ino), 3);
+        client.close(fd).unwrap();
+        client.drop_caches();
+        assert_eq!(client.lookup_count(file.ino), 0);
+    }
+}
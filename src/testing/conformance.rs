@@ -0,0 +1,912 @@
+//! POSIX conformance suite
+//!
+//! A suite of checks in the spirit of pjdfstest that runs against any `Filesystem` in-process.
+//! It covers namespace operations, permissions, timestamps, link counts, truncation and readdir
+//! consistency. Every case runs on a new filesystem through a `LoopbackClient`, as root unless
+//! the case switches users. Filesystems that leave permission checks to the kernel (mounted with
+//! `default_permissions`) can skip the `permissions` cases.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::LoopbackClient;
+use crate::fallible::Errno;
+use crate::{FileAttr, FileType, Filesystem, OpenFlags, SetAttr, FUSE_ROOT_ID};
+
+/// Check a condition in a case
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(Failure(format!("{} (line {})", format!($($arg)+), line!())));
+        }
+    };
+}
+
+/// Check that two values are equal in a case
+macro_rules! ensure_eq {
+    ($left:expr, $right:expr) => {{
+        let (left, right) = (&$left, &$right);
+        if left != right {
+            return Err(Failure(format!(
+                "{} == {}: {:?} != {:?} (line {})",
+                stringify!($left),
+                stringify!($right),
+                left,
+                right,
+                line!()
+            )));
+        }
+    }};
+}
+
+/// Reason a case failed
+struct Failure(String);
+
+impl From<Errno> for Failure {
+    fn from(err: Errno) -> Failure {
+        Failure(format!("unexpected error: {:?}", err))
+    }
+}
+
+type Outcome = Result<(), Failure>;
+
+/// Case of the suite, called with a client of a new filesystem
+type Case<FS> = fn(&mut LoopbackClient<FS>) -> Outcome;
+
+/// Returns all cases with their names
+fn cases<FS: Filesystem>() -> Vec<(&'static str, Case<FS>)> {
+    vec![
+        ("namespace::create", namespace_create),
+        ("namespace::exists", namespace_exists),
+        ("namespace::mkdir_rmdir", namespace_mkdir_rmdir),
+        ("namespace::rmdir_errors", namespace_rmdir_errors),
+        ("namespace::unlink", namespace_unlink),
+        ("namespace::unlink_open", namespace_unlink_open),
+        ("namespace::rename", namespace_rename),
+        ("namespace::rename_replace", namespace_rename_replace),
+        ("namespace::rename_dir", namespace_rename_dir),
+        ("namespace::rename_errors", namespace_rename_errors),
+        ("namespace::symlink", namespace_symlink),
+        ("permissions::owner", permissions_owner),
+        ("permissions::access", permissions_access),
+        ("permissions::open", permissions_open),
+        ("permissions::chmod_chown", permissions_chmod_chown),
+        ("permissions::dir_write", permissions_dir_write),
+        ("timestamps::create", timestamps_create),
+        ("timestamps::write", timestamps_write),
+        ("timestamps::setattr", timestamps_setattr),
+        ("timestamps::chmod", timestamps_chmod),
+        ("timestamps::dir", timestamps_dir),
+        ("nlink::file", nlink_file),
+        ("nlink::dir", nlink_dir),
+        ("nlink::rename", nlink_rename),
+        ("truncate::shrink_extend", truncate_shrink_extend),
+        ("truncate::open_trunc", truncate_open_trunc),
+        ("truncate::overwrite", truncate_overwrite),
+        ("truncate::hole", truncate_hole),
+        ("truncate::read_bounds", truncate_read_bounds),
+        ("readdir::dots", readdir_dots),
+        ("readdir::entries", readdir_entries),
+        ("readdir::changes", readdir_changes),
+        ("readdir::offsets", readdir_offsets),
+    ]
+}
+
+/// Conformance suite for filesystems created by a function
+///
+/// ```no_run
+/// use fuse::memory::MemoryFS;
+/// use fuse::testing::conformance::Suite;
+///
+/// Suite::new(|| MemoryFS::new(1 << 20)).run().assert_passed();
+/// ```
+pub struct Suite<F> {
+    new: F,
+    skip: Vec<String>,
+}
+
+impl<FS: Filesystem, F: FnMut() -> FS> Suite<F> {
+    /// Create a suite that runs every case on a new filesystem returned by the given function
+    pub fn new(new: F) -> Suite<F> {
+        Suite {
+            new,
+            skip: Vec::new(),
+        }
+    }
+
+    /// Skip the cases whose names start with the given prefix (e.g. `permissions` or
+    /// `namespace::symlink`)
+    pub fn skip(mut self, prefix: &str) -> Suite<F> {
+        self.skip.push(prefix.to_string());
+        self
+    }
+
+    /// Run all cases that aren't skipped. Panics of the filesystem fail the case.
+    pub fn run(&mut self) -> Report {
+        let mut report = Report::default();
+        for (name, case) in cases::<FS>() {
+            if self
+                .skip
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_str()))
+            {
+                report.skipped.push(name);
+                continue;
+            }
+            let res = match LoopbackClient::new((self.new)()) {
+                Ok(mut client) => panic::catch_unwind(AssertUnwindSafe(|| case(&mut client)))
+                    .unwrap_or_else(|_| Err(Failure("panicked".to_string()))),
+                Err(err) => Err(Failure(format!("init failed: {:?}", err))),
+            };
+            match res {
+                Ok(()) => report.passed.push(name),
+                Err(Failure(message)) => report.failed.push((name, message)),
+            }
+        }
+        report
+    }
+}
+
+/// Results of a suite run
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Report {
+    /// Names of the cases that passed
+    pub passed: Vec<&'static str>,
+    /// Names of the cases that were skipped
+    pub skipped: Vec<&'static str>,
+    /// Names of the cases that failed and why
+    pub failed: Vec<(&'static str, String)>,
+}
+
+impl Report {
+    /// Returns true if no case failed
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Panics with the failed cases if any case failed
+    pub fn assert_passed(&self) {
+        assert!(self.is_ok(), "{}", self);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.passed.len(),
+            self.failed.len(),
+            self.skipped.len()
+        )?;
+        for (name, message) in &self.failed {
+            write!(f, "\n{}: {}", name, message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Users that cases switch to
+const ROOT: (u32, u32) = (0, 0);
+const OWNER: (u32, u32) = (1000, 1000);
+const GROUP: (u32, u32) = (1001, 1000);
+const OTHER: (u32, u32) = (1002, 1002);
+
+/// Time well in the past, for checking that operations update timestamps
+fn past() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+}
+
+/// Returns a time shortly before now, for checking that new timestamps are current (allows
+/// for filesystems that store times with a resolution of a second)
+fn recently() -> SystemTime {
+    SystemTime::now() - Duration::from_secs(2)
+}
+
+fn set_user<FS: Filesystem>(c: &mut LoopbackClient<FS>, (uid, gid): (u32, u32)) {
+    c.driver_mut().set_user(uid, gid);
+}
+
+/// Create a file with the given contents and return its attributes
+fn create<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+    data: &[u8],
+) -> Result<FileAttr, Failure> {
+    let flags = OpenFlags::CREAT | OpenFlags::EXCL | OpenFlags::WRONLY;
+    let fd = c.open(path.as_ref(), flags, 0o644)?;
+    if !data.is_empty() {
+        let written = c.write(fd, 0, data)?;
+        ensure_eq!(written, data.len());
+    }
+    c.close(fd)?;
+    Ok(c.stat(path)?)
+}
+
+/// Returns the contents of a file
+fn contents<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+) -> Result<Vec<u8>, Failure> {
+    let fd = c.open(path, OpenFlags::empty(), 0)?;
+    let mut data = Vec::new();
+    loop {
+        let chunk = c.read(fd, data.len() as i64, 4096)?;
+        if chunk.is_empty() {
+            break;
+        }
+        data.extend(chunk);
+    }
+    c.close(fd)?;
+    Ok(data)
+}
+
+/// Returns the names in a directory (without `.` and `..`)
+fn names<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+) -> Result<Vec<OsString>, Failure> {
+    Ok(c.readdir(path)?
+        .into_iter()
+        .map(|entry| entry.name)
+        .filter(|name| name != "." && name != "..")
+        .collect())
+}
+
+fn setattr<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+    attr: SetAttr,
+) -> Result<FileAttr, Errno> {
+    c.setattr(path, &attr)
+}
+
+fn chmod<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+    mode: u32,
+) -> Result<FileAttr, Errno> {
+    let attr = SetAttr {
+        mode: Some(mode),
+        ..SetAttr::default()
+    };
+    setattr(c, path, attr)
+}
+
+fn chown<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+    (uid, gid): (u32, u32),
+) -> Result<FileAttr, Errno> {
+    let attr = SetAttr {
+        uid: Some(uid),
+        gid: Some(gid),
+        ..SetAttr::default()
+    };
+    setattr(c, path, attr)
+}
+
+fn truncate<FS: Filesystem, P: AsRef<Path>>(
+    c: &mut LoopbackClient<FS>,
+    path: P,
+    size: u64,
+) -> Result<FileAttr, Errno> {
+    let attr = SetAttr {
+        size: Some(size),
+        ..SetAttr::default()
+    };
+    setattr(c, path, attr)
+}
+
+/// Set access and modification time to a time in the past
+fn age<FS: Filesystem, P: AsRef<Path>>(c: &mut LoopbackClient<FS>, path: P) -> Outcome {
+    let attr = SetAttr {
+        atime: Some(past()),
+        mtime: Some(past()),
+        ..SetAttr::default()
+    };
+    setattr(c, path, attr)?;
+    Ok(())
+}
+
+fn namespace_create<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let attr = create(c, "/file", b"")?;
+    ensure_eq!(attr.kind, FileType::RegularFile);
+    ensure_eq!(attr.size, 0);
+    ensure_eq!(c.stat("/file")?.ino, attr.ino);
+    ensure_eq!(c.stat("/missing").map(|_| ()), Err(Errno::ENOENT));
+    ensure_eq!(c.stat("/file/child").map(|_| ()), Err(Errno::ENOTDIR));
+    let fd = c.open("/missing", OpenFlags::empty(), 0).map(|_| ());
+    ensure_eq!(fd, Err(Errno::ENOENT));
+    let root = c.stat("/")?;
+    ensure_eq!(root.ino, FUSE_ROOT_ID);
+    ensure_eq!(root.kind, FileType::Directory);
+    Ok(())
+}
+
+fn namespace_exists<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    c.mkdir("/dir", 0o755)?;
+    let exclusive = OpenFlags::CREAT | OpenFlags::EXCL | OpenFlags::WRONLY;
+    ensure_eq!(
+        c.open("/file", exclusive, 0o644).map(|_| ()),
+        Err(Errno::EEXIST)
+    );
+    ensure_eq!(c.mkdir("/file", 0o755).map(|_| ()), Err(Errno::EEXIST));
+    ensure_eq!(c.mkdir("/dir", 0o755).map(|_| ()), Err(Errno::EEXIST));
+    ensure_eq!(c.symlink("x", "/dir").map(|_| ()), Err(Errno::EEXIST));
+    ensure_eq!(c.link("/file", "/dir").map(|_| ()), Err(Errno::EEXIST));
+    // Opening an existing file with O_CREAT (but without O_EXCL) opens it
+    let fd = c.open("/file", OpenFlags::CREAT | OpenFlags::RDWR, 0o644)?;
+    c.close(fd)?;
+    Ok(())
+}
+
+fn namespace_mkdir_rmdir<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let attr = c.mkdir("/dir", 0o755)?;
+    ensure_eq!(attr.kind, FileType::Directory);
+    let sub = c.mkdir("/dir/sub", 0o755)?;
+    ensure_eq!(c.stat("/dir/sub")?.ino, sub.ino);
+    c.rmdir("/dir/sub")?;
+    ensure_eq!(c.stat("/dir/sub").map(|_| ()), Err(Errno::ENOENT));
+    // Removing a directory must not affect its parent
+    ensure_eq!(c.stat("/dir")?.ino, attr.ino);
+    c.rmdir("/dir")?;
+    ensure_eq!(c.stat("/dir").map(|_| ()), Err(Errno::ENOENT));
+    ensure_eq!(c.stat("/")?.kind, FileType::Directory);
+    ensure_eq!(names(c, "/")?, Vec::<OsString>::new());
+    Ok(())
+}
+
+fn namespace_rmdir_errors<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    c.mkdir("/dir", 0o755)?;
+    create(c, "/dir/file", b"")?;
+    ensure_eq!(c.rmdir("/dir"), Err(Errno::ENOTEMPTY));
+    ensure_eq!(c.rmdir("/dir/file"), Err(Errno::ENOTDIR));
+    ensure_eq!(c.rmdir("/missing"), Err(Errno::ENOENT));
+    c.unlink("/dir/file")?;
+    c.rmdir("/dir")?;
+    Ok(())
+}
+
+fn namespace_unlink<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"data")?;
+    c.unlink("/file")?;
+    ensure_eq!(c.stat("/file").map(|_| ()), Err(Errno::ENOENT));
+    ensure_eq!(c.unlink("/file"), Err(Errno::ENOENT));
+    c.mkdir("/dir", 0o755)?;
+    // POSIX allows EPERM, Linux returns EISDIR
+    let res = c.unlink("/dir");
+    ensure!(
+        res == Err(Errno::EISDIR) || res == Err(Errno::EPERM),
+        "unlink of a directory returned {:?}",
+        res
+    );
+    ensure_eq!(c.stat("/dir")?.kind, FileType::Directory);
+    Ok(())
+}
+
+fn namespace_unlink_open<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"data")?;
+    let fd = c.open("/file", OpenFlags::RDWR, 0)?;
+    c.unlink("/file")?;
+    ensure_eq!(c.read(fd, 0, 100)?, b"data");
+    c.write(fd, 4, b"more")?;
+    ensure_eq!(c.read(fd, 0, 100)?, b"datamore");
+    c.close(fd)?;
+    ensure_eq!(c.stat("/file").map(|_| ()), Err(Errno::ENOENT));
+    // The name can be used again
+    create(c, "/file", b"new")?;
+    ensure_eq!(contents(c, "/file")?, b"new");
+    Ok(())
+}
+
+fn namespace_rename<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let attr = create(c, "/a", b"data")?;
+    c.rename("/a", "/b")?;
+    ensure_eq!(c.stat("/a").map(|_| ()), Err(Errno::ENOENT));
+    ensure_eq!(c.stat("/b")?.ino, attr.ino);
+    ensure_eq!(contents(c, "/b")?, b"data");
+    c.mkdir("/dir", 0o755)?;
+    c.rename("/b", "/dir/c")?;
+    ensure_eq!(c.stat("/dir/c")?.ino, attr.ino);
+    ensure_eq!(names(c, "/")?, vec![OsString::from("dir")]);
+    // Renaming to the same name does nothing
+    c.rename("/dir/c", "/dir/c")?;
+    ensure_eq!(c.stat("/dir/c")?.ino, attr.ino);
+    Ok(())
+}
+
+fn namespace_rename_replace<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let a = create(c, "/a", b"new")?;
+    create(c, "/b", b"old")?;
+    c.rename("/a", "/b")?;
+    ensure_eq!(c.stat("/b")?.ino, a.ino);
+    ensure_eq!(contents(c, "/b")?, b"new");
+    ensure_eq!(names(c, "/")?, vec![OsString::from("b")]);
+    let dir = c.mkdir("/d1", 0o755)?;
+    c.mkdir("/d2", 0o755)?;
+    c.rename("/d1", "/d2")?;
+    ensure_eq!(c.stat("/d2")?.ino, dir.ino);
+    ensure_eq!(c.stat("/d1").map(|_| ()), Err(Errno::ENOENT));
+    // Renaming a file to another link of itself does nothing
+    c.link("/b", "/c")?;
+    c.rename("/b", "/c")?;
+    ensure_eq!(c.stat("/b")?.ino, a.ino);
+    ensure_eq!(c.stat("/c")?.ino, a.ino);
+    Ok(())
+}
+
+fn namespace_rename_dir<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    c.mkdir("/a", 0o755)?;
+    c.mkdir("/b", 0o755)?;
+    let file = create(c, "/a/file", b"data")?;
+    let dir = c.mkdir("/a/sub", 0o755)?;
+    c.rename("/a", "/b/moved")?;
+    ensure_eq!(c.stat("/b/moved/file")?.ino, file.ino);
+    ensure_eq!(c.stat("/b/moved/sub")?.ino, dir.ino);
+    ensure_eq!(c.stat("/a").map(|_| ()), Err(Errno::ENOENT));
+    // `..` of the moved directory points to its new parent
+    let b = c.stat("/b")?;
+    let dotdot = c
+        .readdir("/b/moved")?
+        .into_iter()
+        .find(|entry| entry.name == "..");
+    ensure!(dotdot.is_some(), "no .. entry");
+    ensure_eq!(dotdot.unwrap().ino, b.ino);
+    ensure_eq!(c.stat("/b/moved/../moved/file")?.ino, file.ino);
+    Ok(())
+}
+
+fn namespace_rename_errors<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    c.mkdir("/dir", 0o755)?;
+    c.mkdir("/full", 0o755)?;
+    create(c, "/full/file", b"")?;
+    ensure_eq!(c.rename("/missing", "/x"), Err(Errno::ENOENT));
+    ensure_eq!(c.rename("/dir", "/file"), Err(Errno::ENOTDIR));
+    ensure_eq!(c.rename("/file", "/dir"), Err(Errno::EISDIR));
+    ensure_eq!(c.rename("/dir", "/full"), Err(Errno::ENOTEMPTY));
+    c.mkdir("/dir/sub", 0o755)?;
+    ensure_eq!(c.rename("/dir", "/dir/sub/dir"), Err(Errno::EINVAL));
+    // Nothing changed
+    let mut root = names(c, "/")?;
+    root.sort();
+    ensure_eq!(root, vec!["dir", "file", "full"]);
+    ensure_eq!(names(c, "/dir")?, vec!["sub"]);
+    Ok(())
+}
+
+fn namespace_symlink<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let attr = c.symlink("target/file", "/link")?;
+    ensure_eq!(attr.kind, FileType::Symlink);
+    ensure_eq!(c.stat("/link")?.kind, FileType::Symlink);
+    ensure_eq!(c.readlink("/link")?, "target/file");
+    ensure_eq!(c.stat("/link")?.size, "target/file".len() as u64);
+    create(c, "/file", b"")?;
+    ensure_eq!(c.readlink("/file").map(|_| ()), Err(Errno::EINVAL));
+    c.unlink("/link")?;
+    ensure_eq!(c.stat("/link").map(|_| ()), Err(Errno::ENOENT));
+    Ok(())
+}
+
+fn permissions_owner<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    c.mkdir("/home", 0o777)?;
+    chmod(c, "/home", 0o777)?;
+    set_user(c, OWNER);
+    let flags = OpenFlags::CREAT | OpenFlags::EXCL | OpenFlags::WRONLY;
+    let fd = c.open("/home/file", flags, 0o640)?;
+    c.close(fd)?;
+    let file = c.stat("/home/file")?;
+    ensure_eq!((file.uid, file.gid), OWNER);
+    ensure_eq!(file.perm, 0o640);
+    let dir = c.mkdir("/home/dir", 0o750)?;
+    ensure_eq!((dir.uid, dir.gid), OWNER);
+    ensure_eq!(dir.perm, 0o750);
+    let link = c.symlink("file", "/home/link")?;
+    ensure_eq!((link.uid, link.gid), OWNER);
+    Ok(())
+}
+
+fn permissions_access<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    chown(c, "/file", OWNER)?;
+    chmod(c, "/file", 0o640)?;
+    let ino = c.stat("/file")?.ino;
+    let cases = [
+        (OWNER, libc::R_OK | libc::W_OK, Ok(())),
+        (OWNER, libc::X_OK, Err(Errno::EACCES)),
+        (GROUP, libc::R_OK, Ok(())),
+        (GROUP, libc::W_OK, Err(Errno::EACCES)),
+        (OTHER, libc::R_OK, Err(Errno::EACCES)),
+        (OTHER, libc::F_OK, Ok(())),
+        (ROOT, libc::R_OK | libc::W_OK, Ok(())),
+        (ROOT, libc::X_OK, Err(Errno::EACCES)),
+    ];
+    for (user, mask, expected) in cases {
+        set_user(c, user);
+        let res = c.driver_mut().access(ino, mask);
+        ensure!(
+            res == expected,
+            "access({}) as {:?} returned {:?}, expected {:?}",
+            mask,
+            user,
+            res,
+            expected
+        );
+    }
+    Ok(())
+}
+
+fn permissions_open<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"secret")?;
+    chown(c, "/file", OWNER)?;
+    chmod(c, "/file", 0o640)?;
+    set_user(c, OTHER);
+    let res = c.open("/file", OpenFlags::empty(), 0).map(|_| ());
+    ensure_eq!(res, Err(Errno::EACCES));
+    set_user(c, GROUP);
+    let res = c.open("/file", OpenFlags::WRONLY, 0).map(|_| ());
+    ensure_eq!(res, Err(Errno::EACCES));
+    ensure_eq!(contents(c, "/file")?, b"secret");
+    set_user(c, OWNER);
+    let fd = c.open("/file", OpenFlags::RDWR, 0)?;
+    c.close(fd)?;
+    Ok(())
+}
+
+fn permissions_chmod_chown<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    chown(c, "/file", OWNER)?;
+    set_user(c, OTHER);
+    ensure_eq!(chmod(c, "/file", 0o777).map(|_| ()), Err(Errno::EPERM));
+    set_user(c, OWNER);
+    ensure_eq!(chmod(c, "/file", 0o600)?.perm, 0o600);
+    // Only root can give files away
+    ensure_eq!(chown(c, "/file", OTHER).map(|_| ()), Err(Errno::EPERM));
+    set_user(c, ROOT);
+    let attr = chown(c, "/file", OTHER)?;
+    ensure_eq!((attr.uid, attr.gid), OTHER);
+    ensure_eq!(attr.perm, 0o600);
+    Ok(())
+}
+
+fn permissions_dir_write<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    c.mkdir("/dir", 0o755)?;
+    chmod(c, "/dir", 0o755)?;
+    create(c, "/dir/file", b"")?;
+    set_user(c, OWNER);
+    let flags = OpenFlags::CREAT | OpenFlags::WRONLY;
+    let res = c.open("/dir/new", flags, 0o644).map(|_| ());
+    ensure_eq!(res, Err(Errno::EACCES));
+    ensure_eq!(c.mkdir("/dir/new", 0o755).map(|_| ()), Err(Errno::EACCES));
+    ensure_eq!(c.unlink("/dir/file"), Err(Errno::EACCES));
+    ensure_eq!(c.rename("/dir/file", "/dir/new"), Err(Errno::EACCES));
+    set_user(c, ROOT);
+    ensure_eq!(names(c, "/dir")?, vec!["file"]);
+    Ok(())
+}
+
+fn timestamps_create<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let start = recently();
+    let attr = create(c, "/file", b"")?;
+    ensure!(attr.atime >= start, "atime {:?} of new file", attr.atime);
+    ensure!(attr.mtime >= start, "mtime {:?} of new file", attr.mtime);
+    ensure!(attr.ctime >= start, "ctime {:?} of new file", attr.ctime);
+    let dir = c.mkdir("/dir", 0o755)?;
+    ensure!(dir.mtime >= start, "mtime {:?} of new directory", dir.mtime);
+    Ok(())
+}
+
+fn timestamps_write<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"data")?;
+    age(c, "/file")?;
+    let start = recently();
+    let fd = c.open("/file", OpenFlags::WRONLY, 0)?;
+    c.write(fd, 0, b"more")?;
+    c.close(fd)?;
+    let attr = c.stat("/file")?;
+    ensure!(attr.mtime >= start, "mtime {:?} after write", attr.mtime);
+    ensure!(attr.ctime >= start, "ctime {:?} after write", attr.ctime);
+    age(c, "/file")?;
+    let attr = truncate(c, "/file", 1)?;
+    ensure!(attr.mtime >= start, "mtime {:?} after truncate", attr.mtime);
+    Ok(())
+}
+
+fn timestamps_setattr<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    let atime = UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+    let mtime = UNIX_EPOCH + Duration::new(1_100_000_000, 987_654_321);
+    let attr = SetAttr {
+        atime: Some(atime),
+        mtime: Some(mtime),
+        ..SetAttr::default()
+    };
+    let start = recently();
+    let attr = setattr(c, "/file", attr)?;
+    ensure_eq!(attr.atime, atime);
+    ensure_eq!(attr.mtime, mtime);
+    ensure!(attr.ctime >= start, "ctime {:?} after utimes", attr.ctime);
+    let attr = c.stat("/file")?;
+    ensure_eq!(attr.atime, atime);
+    ensure_eq!(attr.mtime, mtime);
+    Ok(())
+}
+
+fn timestamps_chmod<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    age(c, "/file")?;
+    let start = recently();
+    let attr = chmod(c, "/file", 0o600)?;
+    ensure!(attr.ctime >= start, "ctime {:?} after chmod", attr.ctime);
+    ensure_eq!(attr.mtime, past());
+    let attr = c.link("/file", "/link")?;
+    ensure!(attr.ctime >= start, "ctime {:?} after link", attr.ctime);
+    ensure_eq!(attr.mtime, past());
+    Ok(())
+}
+
+fn timestamps_dir<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    c.mkdir("/dir", 0o755)?;
+    let start = recently();
+    let steps: [(&str, Case<FS>); 4] = [
+        ("create", |c| create(c, "/dir/file", b"").map(|_| ())),
+        ("rename", |c| Ok(c.rename("/dir/file", "/dir/other")?)),
+        ("unlink", |c| Ok(c.unlink("/dir/other")?)),
+        ("mkdir", |c| {
+            c.mkdir("/dir/sub", 0o755)
+                .map(|_| ())
+                .map_err(Failure::from)
+        }),
+    ];
+    for (step, f) in steps {
+        age(c, "/dir")?;
+        f(c)?;
+        let attr = c.stat("/dir")?;
+        ensure!(
+            attr.mtime >= start,
+            "directory mtime {:?} after {}",
+            attr.mtime,
+            step
+        );
+        ensure!(
+            attr.ctime >= start,
+            "directory ctime {:?} after {}",
+            attr.ctime,
+            step
+        );
+    }
+    Ok(())
+}
+
+fn nlink_file<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let attr = create(c, "/a", b"data")?;
+    ensure_eq!(attr.nlink, 1);
+    let link = c.link("/a", "/b")?;
+    ensure_eq!(link.ino, attr.ino);
+    ensure_eq!(link.nlink, 2);
+    ensure_eq!(c.stat("/a")?.nlink, 2);
+    c.unlink("/a")?;
+    ensure_eq!(c.stat("/b")?.nlink, 1);
+    ensure_eq!(contents(c, "/b")?, b"data");
+    c.mkdir("/dir", 0o755)?;
+    ensure_eq!(c.link("/dir", "/dir2").map(|_| ()), Err(Errno::EPERM));
+    Ok(())
+}
+
+fn nlink_dir<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let root = c.stat("/")?.nlink;
+    let dir = c.mkdir("/dir", 0o755)?;
+    ensure_eq!(dir.nlink, 2);
+    ensure_eq!(c.stat("/")?.nlink, root + 1);
+    c.mkdir("/dir/sub", 0o755)?;
+    ensure_eq!(c.stat("/dir")?.nlink, 3);
+    // Files don't count
+    create(c, "/dir/file", b"")?;
+    ensure_eq!(c.stat("/dir")?.nlink, 3);
+    c.rmdir("/dir/sub")?;
+    ensure_eq!(c.stat("/dir")?.nlink, 2);
+    c.unlink("/dir/file")?;
+    c.rmdir("/dir")?;
+    ensure_eq!(c.stat("/")?.nlink, root);
+    Ok(())
+}
+
+fn nlink_rename<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    c.mkdir("/a", 0o755)?;
+    c.mkdir("/b", 0o755)?;
+    c.mkdir("/a/sub", 0o755)?;
+    c.rename("/a/sub", "/b/sub")?;
+    ensure_eq!(c.stat("/a")?.nlink, 2);
+    ensure_eq!(c.stat("/b")?.nlink, 3);
+    ensure_eq!(c.stat("/b/sub")?.nlink, 2);
+    // Replacing a file drops a link of it
+    create(c, "/x", b"")?;
+    create(c, "/y", b"")?;
+    c.link("/y", "/z")?;
+    c.rename("/x", "/y")?;
+    ensure_eq!(c.stat("/z")?.nlink, 1);
+    ensure_eq!(c.stat("/y")?.nlink, 1);
+    Ok(())
+}
+
+fn truncate_shrink_extend<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"0123456789")?;
+    ensure_eq!(truncate(c, "/file", 4)?.size, 4);
+    ensure_eq!(c.stat("/file")?.size, 4);
+    ensure_eq!(contents(c, "/file")?, b"0123");
+    ensure_eq!(truncate(c, "/file", 8)?.size, 8);
+    ensure_eq!(contents(c, "/file")?, b"0123\0\0\0\0");
+    ensure_eq!(truncate(c, "/file", 0)?.size, 0);
+    ensure_eq!(contents(c, "/file")?, b"");
+    c.mkdir("/dir", 0o755)?;
+    ensure_eq!(truncate(c, "/dir", 0).map(|_| ()), Err(Errno::EISDIR));
+    Ok(())
+}
+
+fn truncate_open_trunc<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"0123456789")?;
+    // O_TRUNC on a read-only open doesn't truncate
+    let fd = c.open("/file", OpenFlags::TRUNC, 0)?;
+    c.close(fd)?;
+    ensure_eq!(c.stat("/file")?.size, 10);
+    let fd = c.open("/file", OpenFlags::WRONLY | OpenFlags::TRUNC, 0)?;
+    ensure_eq!(c.stat("/file")?.size, 0);
+    c.write(fd, 0, b"ab")?;
+    c.close(fd)?;
+    ensure_eq!(contents(c, "/file")?, b"ab");
+    Ok(())
+}
+
+fn truncate_overwrite<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"0123456789")?;
+    let fd = c.open("/file", OpenFlags::RDWR, 0)?;
+    ensure_eq!(c.write(fd, 2, b"abc")?, 3);
+    ensure_eq!(c.stat("/file")?.size, 10);
+    ensure_eq!(c.write(fd, 8, b"xyz")?, 3);
+    ensure_eq!(c.stat("/file")?.size, 11);
+    c.close(fd)?;
+    ensure_eq!(contents(c, "/file")?, b"01abc567xyz");
+    Ok(())
+}
+
+fn truncate_hole<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"")?;
+    let fd = c.open("/file", OpenFlags::WRONLY, 0)?;
+    c.write(fd, 5, b"x")?;
+    c.close(fd)?;
+    ensure_eq!(c.stat("/file")?.size, 6);
+    ensure_eq!(contents(c, "/file")?, b"\0\0\0\0\0x");
+    Ok(())
+}
+
+fn truncate_read_bounds<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/file", b"0123456789")?;
+    let fd = c.open("/file", OpenFlags::empty(), 0)?;
+    ensure_eq!(c.read(fd, 0, 4)?, b"0123");
+    ensure_eq!(c.read(fd, 8, 4)?, b"89");
+    ensure_eq!(c.read(fd, 10, 4)?, b"");
+    ensure_eq!(c.read(fd, 100, 4)?, b"");
+    // Reading from a file opened write-only fails
+    c.close(fd)?;
+    let fd = c.open("/file", OpenFlags::WRONLY, 0)?;
+    ensure_eq!(c.read(fd, 0, 4), Err(Errno::EBADF));
+    c.close(fd)?;
+    Ok(())
+}
+
+fn readdir_dots<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let dir = c.mkdir("/dir", 0o755)?;
+    let entries = c.readdir("/dir")?;
+    let find = |name: &str| entries.iter().find(|entry| entry.name == name);
+    ensure!(find(".").is_some(), "no . entry");
+    ensure!(find("..").is_some(), "no .. entry");
+    ensure_eq!(find(".").unwrap().ino, dir.ino);
+    ensure_eq!(find("..").unwrap().ino, FUSE_ROOT_ID);
+    ensure_eq!(find(".").unwrap().kind, FileType::Directory);
+    ensure_eq!(entries.len(), 2);
+    Ok(())
+}
+
+fn readdir_entries<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    let mut expected = Vec::new();
+    for i in 0..50 {
+        let path = format!("/file-with-a-longer-name-{}", i);
+        let attr = create(c, &path, b"")?;
+        expected.push((path[1..].to_string(), attr.ino, FileType::RegularFile));
+    }
+    for i in 0..5 {
+        let path = format!("/dir{}", i);
+        let attr = c.mkdir(&path, 0o755)?;
+        expected.push((path[1..].to_string(), attr.ino, FileType::Directory));
+    }
+    let attr = c.symlink("target", "/link")?;
+    expected.push(("link".to_string(), attr.ino, FileType::Symlink));
+    let entries = c.readdir("/")?;
+    let mut seen = HashSet::new();
+    for entry in &entries {
+        ensure!(
+            seen.insert(entry.name.clone()),
+            "{:?} listed twice",
+            entry.name
+        );
+    }
+    for (name, ino, kind) in expected {
+        let entry = entries.iter().find(|entry| entry.name == name.as_str());
+        ensure!(entry.is_some(), "{} not listed", name);
+        let entry = entry.unwrap();
+        ensure_eq!(entry.ino, ino);
+        ensure_eq!(entry.kind, kind);
+    }
+    ensure_eq!(entries.len(), 58);
+    Ok(())
+}
+
+fn readdir_changes<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    create(c, "/a", b"")?;
+    create(c, "/b", b"")?;
+    c.unlink("/a")?;
+    c.rename("/b", "/c")?;
+    c.mkdir("/d", 0o755)?;
+    c.rmdir("/d")?;
+    ensure_eq!(names(c, "/")?, vec!["c"]);
+    Ok(())
+}
+
+fn readdir_offsets<FS: Filesystem>(c: &mut LoopbackClient<FS>) -> Outcome {
+    for i in 0..20 {
+        create(c, format!("/{}", i), b"")?;
+    }
+    let all = c.readdir("/")?;
+    // Read the directory in small batches, continuing at the offset of the last entry
+    let driver = c.driver_mut();
+    let open = driver.opendir(FUSE_ROOT_ID)?;
+    let mut entries: Vec<crate::fallible::DirEntry> = Vec::new();
+    loop {
+        let offset = entries.last().map_or(0, |entry| entry.offset);
+        let batch = driver.readdir(FUSE_ROOT_ID, open.fh, offset, 100)?;
+        if batch.is_empty() {
+            break;
+        }
+        entries.extend(batch);
+    }
+    driver.releasedir(FUSE_ROOT_ID, open.fh)?;
+    let names = |entries: &[crate::fallible::DirEntry]| {
+        entries
+            .iter()
+            .map(|entry| entry.name.clone())
+            .collect::<Vec<_>>()
+    };
+    ensure_eq!(names(&entries), names(&all));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Suite;
+    use crate::memory::MemoryFS;
+
+    #[test]
+    fn memory() {
+        Suite::new(|| MemoryFS::new(1 << 20)).run().assert_passed();
+    }
+
+    #[test]
+    fn skip() {
+        let report = Suite::new(|| MemoryFS::new(1 << 20))
+            .skip("permissions")
+            .skip("timestamps::")
+            .run();
+        assert!(report
+            .skipped
+            .iter()
+            .all(|name| name.starts_with("permissions") || name.starts_with("timestamps")));
+        assert!(report.skipped.contains(&"permissions::access"));
+        assert!(!report.passed.is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+//! Fake FUSE device
+//!
+//! A socket pair takes the place of the FUSE device, so that sessions can be run on a channel
+//! without mounting anything. Like the FUSE device, it preserves message boundaries.
+
+use fuse_abi::{fuse_opcode, AsBytes, FuseInitIn};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use super::{packet, Response};
+
+/// Kernel end of a fake FUSE device
+#[derive(Debug)]
+pub(crate) struct Device {
+    peer: OwnedFd,
+}
+
+// Only the driver's session channel is needed outside of tests
+#[cfg_attr(not(test), allow(dead_code))]
+impl Device {
+    /// Create a fake device. Returns the device and the file descriptor that a session uses
+    /// in place of the FUSE device (see `Session::from_fd`).
+    pub fn new() -> (Device, OwnedFd) {
+        let mut fds = [0; 2];
+        let rc =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(rc, 0, "Failed to create socket pair");
+        let (fd, peer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        (Device { peer }, fd)
+    }
+
+    /// Send a request packet (see `packet`)
+    pub fn send(&self, packet: &[u8]) {
+        let fd = self.peer.as_raw_fd();
+        let rc = unsafe { libc::write(fd, packet.as_ptr() as *const _, packet.len()) };
+        assert_eq!(rc, packet.len() as isize, "Failed to send request");
+    }
+
+    /// Send INIT (unique id 1) for ABI 7.8 with the given flags
+    pub fn init(&self, flags: u32) {
+        let arg = FuseInitIn {
+            major: 7,
+            minor: 8,
+            max_readahead: 4096,
+            flags,
+        };
+        self.send(&packet(
+            fuse_opcode::FUSE_INIT as u32,
+            1,
+            0,
+            &[arg.as_bytes()],
+        ));
+    }
+
+    /// Receive the next reply. Blocks until a reply was sent.
+    pub fn receive(&self) -> Response {
+        let mut data = [0; 4096];
+        let fd = self.peer.as_raw_fd();
+        let len = unsafe { libc::read(fd, data.as_mut_ptr() as *mut _, data.len()) };
+        assert!(len >= 0, "Failed to receive reply");
+        Response::parse(&data[..len as usize])
+    }
+}
@@ -0,0 +1,959 @@
+//! Mock kernel driver
+//!
+//! `Driver` builds request packets the way the kernel sends them, dispatches them to a
+//! filesystem like a running session does, and decodes the replies into typed results.
+
+use fuse_abi::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::device::Device;
+use crate::buffer::{Buffer, BufferPool};
+use crate::fallible::{Attr, Created, DirEntry, Entry, Errno, Open};
+use crate::ll::{self, accepts_reply, expects_reply};
+use crate::reply::{
+    mode_from_kind_and_perm, AnySender, PendingReplies, ReplySender, TrackedSender,
+};
+use crate::request::Request;
+use crate::{FileAttr, FileType, Filesystem, FopenFlags, OpenFlags, Session, SetAttr, Statfs};
+
+/// Max readahead the driver reports at init
+const MAX_READAHEAD: u32 = 128 * 1024;
+
+/// Reply sender that passes replies back to the driver
+#[derive(Clone, Debug)]
+struct DriverSender(Sender<Vec<u8>>);
+
+impl ReplySender for DriverSender {
+    fn send(&self, data: &[&[u8]]) {
+        // The driver may be gone already if the filesystem kept a reply
+        let _ = self.0.send(data.concat());
+    }
+}
+
+impl From<TrackedSender<DriverSender>> for AnySender {
+    fn from(sender: TrackedSender<DriverSender>) -> AnySender {
+        AnySender::boxed(sender)
+    }
+}
+
+/// Reply to a request as sent by the filesystem
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response {
+    /// Unique id of the request
+    pub unique: u64,
+    /// Error code (0 on success)
+    pub error: i32,
+    /// Data following the reply header
+    pub data: Vec<u8>,
+}
+
+impl Response {
+    /// Parse a reply packet. Panics if the packet is malformed.
+    pub(crate) fn parse(packet: &[u8]) -> Response {
+        let header = FuseOutHeader::read_from_prefix(packet)
+            .unwrap_or_else(|| panic!("Reply too short for a header ({} bytes)", packet.len()));
+        assert_eq!(
+            header.len as usize,
+            packet.len(),
+            "Reply length doesn't match the header"
+        );
+        Response {
+            unique: header.unique,
+            error: -header.error,
+            data: packet[mem::size_of::<FuseOutHeader>()..].to_vec(),
+        }
+    }
+
+    /// Returns the data of a successful reply, or the error
+    pub fn result(self) -> Result<Vec<u8>, Errno> {
+        match self.error {
+            0 => Ok(self.data),
+            err => Err(Errno::from(err)),
+        }
+    }
+
+    /// Decode the data of a successful reply as the given ABI structure
+    fn decode<T: FromBytes>(self) -> Result<T, Errno> {
+        Ok(decode(&self.result()?, 0))
+    }
+}
+
+/// Build a request packet with the given opcode, unique id, inode and arguments, like the
+/// kernel sends it for a process of root (with pid 0)
+pub fn packet(opcode: u32, unique: u64, nodeid: u64, args: &[&[u8]]) -> Vec<u8> {
+    let header = FuseInHeader {
+        len: 0,
+        opcode,
+        unique,
+        nodeid,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+        padding: 0,
+    };
+    encode(header, args)
+}
+
+/// Encode a request packet with the given header and arguments. The length in the header is
+/// set to the length of the packet.
+fn encode(mut header: FuseInHeader, args: &[&[u8]]) -> Vec<u8> {
+    let len = mem::size_of::<FuseInHeader>() + args.iter().map(|arg| arg.len()).sum::<usize>();
+    header.len = len as u32;
+    let mut packet = Vec::with_capacity(len);
+    packet.extend_from_slice(header.as_bytes());
+    for arg in args {
+        packet.extend_from_slice(arg);
+    }
+    packet
+}
+
+/// Mock kernel driver that sends requests to a filesystem in-process
+#[derive(Debug)]
+pub struct Driver<FS: Filesystem> {
+    session: Session<FS>,
+    /// Device of the (unused) channel of the session
+    _device: Device,
+    pool: BufferPool,
+    pending: Arc<PendingReplies>,
+    sender: DriverSender,
+    receiver: Receiver<Vec<u8>>,
+    /// Requests that were sent and not replied yet
+    unreplied: HashMap<u64, Arc<Buffer>>,
+    /// Interrupts that were sent and may be replied with an error
+    interrupts: HashSet<u64>,
+    /// Replies that were received but not taken yet
+    replies: HashMap<u64, Response>,
+    next_unique: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl<FS: Filesystem> Driver<FS> {
+    /// Create a new driver for the given filesystem. Call `init` first, like the kernel does.
+    pub fn new(filesystem: FS) -> Driver<FS> {
+        // Requests are dispatched directly, but a session can't exist without a channel
+        let (device, fd) = Device::new();
+        let (sender, receiver) = channel();
+        Driver {
+            session: Session::from_fd(filesystem, fd),
+            _device: device,
+            pool: BufferPool::new(fuse_abi::consts::FUSE_MIN_READ_BUFFER),
+            pending: Arc::default(),
+            sender: DriverSender(sender),
+            receiver,
+            unreplied: HashMap::new(),
+            interrupts: HashSet::new(),
+            replies: HashMap::new(),
+            next_unique: 1,
+            uid: 0,
+            gid: 0,
+            pid: 1,
+        }
+    }
+
+    /// Returns the session the requests are dispatched in
+    pub fn session(&self) -> &Session<FS> {
+        &self.session
+    }
+
+    /// Returns the session for changing settings (e.g. `Session::set_locks`)
+    pub fn session_mut(&mut self) -> &mut Session<FS> {
+        &mut self.session
+    }
+
+    /// Returns the filesystem
+    pub fn filesystem(&self) -> &FS {
+        &self.session.filesystem
+    }
+
+    /// Returns the filesystem for modification
+    pub fn filesystem_mut(&mut self) -> &mut FS {
+        &mut self.session.filesystem
+    }
+
+    /// Set the user and group that following requests are sent for (root by default)
+    pub fn set_user(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    /// Set the pid of the process that following requests are sent for
+    pub fn set_pid(&mut self, pid: u32) {
+        self.pid = pid;
+    }
+
+    /// Returns the number of replies that were created but not sent yet
+    pub fn pending_replies(&self) -> usize {
+        self.pending.count()
+    }
+
+    /// Returns the unique id the next request will be sent with
+    pub fn next_unique(&self) -> u64 {
+        self.next_unique
+    }
+
+    /// Send a request with the given opcode, inode and arguments and return its unique id.
+    /// Replies sent by the filesystem are collected and can be taken with `reply`. Requests
+    /// that can't be parsed are replied with an error, like a session does.
+    pub fn send(&mut self, opcode: u32, nodeid: u64, args: &[&[u8]]) -> u64 {
+        let header = FuseInHeader {
+            len: 0,
+            opcode,
+            unique: self.next_unique,
+            nodeid,
+            uid: self.uid,
+            gid: self.gid,
+            pid: self.pid,
+            padding: 0,
+        };
+        self.send_packet(&encode(header, args))
+    }
+
+    /// Send a raw request packet (e.g. from a recorded trace) as is, including its unique id,
+    /// and return the unique id. Panics if the packet is too short for a request header.
+    pub fn send_packet(&mut self, packet: &[u8]) -> u64 {
+        let header: FuseInHeader = decode(packet, 0);
+        let unique = header.unique;
+        let mut buffer = self.pool.get();
+        buffer.extend_from_slice(packet);
+        self.dispatch(header, buffer);
+        unique
+    }
+
+    /// Dispatch a request packet to the filesystem. The packet is kept until the request is
+    /// replied, for checking the reply.
+    fn dispatch(&mut self, header: FuseInHeader, buffer: Buffer) {
+        self.next_unique = self.next_unique.max(header.unique + 1);
+        let buffer = self.pool.share(buffer);
+        if expects_reply(header.opcode) {
+            self.unreplied.insert(header.unique, buffer.clone());
+        } else if accepts_reply(header.opcode) {
+            self.interrupts.insert(header.unique);
+        }
+        if let Some(req) = Request::new(self.sender.clone(), &buffer, &self.pending) {
+            req.dispatch(&mut self.session);
+        }
+        self.pool.recycle(buffer);
+    }
+
+    /// Take the reply to the request with the given unique id, if it was sent yet. Panics if
+    /// a request was replied more than once.
+    pub fn reply(&mut self, unique: u64) -> Option<Response> {
+        self.poll();
+        self.replies.remove(&unique)
+    }
+
+    /// Collect the replies the filesystem sent so far. Panics if a request was replied more
+    /// than once, or if a reply doesn't belong to a request that expects one (like FORGET), or
+    /// if an INTERRUPT is replied without an error.
+    pub fn poll(&mut self) {
+        while let Ok(packet) = self.receiver.try_recv() {
+            let response = Response::parse(&packet);
+            let unique = response.unique;
+            if self.interrupts.remove(&unique) {
+                assert!(
+                    response.error != 0,
+                    "Interrupt {} replied without error",
+                    unique
+                );
+                self.replies.insert(unique, response);
+                continue;
+            }
+            let Some(request) = self.unreplied.remove(&unique) else {
+                match unique < self.next_unique {
+                    true => panic!(
+                        "Request {} was replied more than once or unexpectedly",
+                        unique
+                    ),
+                    false => panic!("Reply to unknown request {}", unique),
+                }
+            };
+            // Replies must have the right data for the operation (requests that failed to
+            // parse are replied with an error, which the session ensures)
+            if let Ok(request) = ll::Request::try_from(&request[..]) {
+                if let Err(err) = ll::Response::decode(&packet, &request) {
+                    panic!("Malformed reply to {}: {}", request, err);
+                }
+            }
+            self.replies.insert(unique, response);
+        }
+    }
+
+    /// Send a request and return its reply. Panics if the filesystem didn't reply right away.
+    pub fn call(&mut self, opcode: u32, nodeid: u64, args: &[&[u8]]) -> Response {
+        let unique = self.send(opcode, nodeid, args);
+        self.reply(unique)
+            .unwrap_or_else(|| panic!("No reply to request {} (opcode {})", unique, opcode))
+    }
+
+    /// Send a request that is replied without data
+    fn call_empty(
+        &mut self,
+        opcode: fuse_opcode,
+        nodeid: u64,
+        args: &[&[u8]],
+    ) -> Result<(), Errno> {
+        self.call(opcode as u32, nodeid, args).result().map(|_| ())
+    }
+
+    /// Send a request that is replied with an entry
+    fn call_entry(
+        &mut self,
+        opcode: fuse_opcode,
+        nodeid: u64,
+        args: &[&[u8]],
+    ) -> Result<Entry, Errno> {
+        let out: FuseEntryOut = self.call(opcode as u32, nodeid, args).decode()?;
+        Ok(entry(&out))
+    }
+
+    /// Initialize the filesystem (INIT), offering all capabilities. Returns the INIT flags the
+    /// filesystem enabled.
+    pub fn init(&mut self) -> Result<u32, Errno> {
+        let arg = FuseInitIn {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: MAX_READAHEAD,
+            flags: u32::MAX,
+        };
+        let opcode = fuse_opcode::FUSE_INIT as u32;
+        let out: FuseInitOut = self.call(opcode, 0, &[arg.as_bytes()]).decode()?;
+        Ok(out.flags)
+    }
+
+    /// Destroy the filesystem (DESTROY)
+    pub fn destroy(&mut self) -> Result<(), Errno> {
+        self.call_empty(fuse_opcode::FUSE_DESTROY, 0, &[])
+    }
+
+    /// Look up a directory entry by name (LOOKUP)
+    pub fn lookup<T: AsRef<OsStr>>(&mut self, parent: u64, name: T) -> Result<Entry, Errno> {
+        let name = c_string(name.as_ref());
+        self.call_entry(fuse_opcode::FUSE_LOOKUP, parent, &[&name])
+    }
+
+    /// Forget lookups of an inode (FORGET, not replied)
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        let arg = FuseForgetIn { nlookup };
+        self.send(fuse_opcode::FUSE_FORGET as u32, ino, &[arg.as_bytes()]);
+    }
+
+    /// Get file attributes (GETATTR)
+    pub fn getattr(&mut self, ino: u64) -> Result<Attr, Errno> {
+        #[cfg(feature = "abi-7-9")]
+        let arg = fuse_getattr_in::new_zeroed();
+        #[cfg(feature = "abi-7-9")]
+        let args = [arg.as_bytes()];
+        #[cfg(not(feature = "abi-7-9"))]
+        let args: [&[u8]; 0] = [];
+        let out: FuseAttrOut = self
+            .call(fuse_opcode::FUSE_GETATTR as u32, ino, &args)
+            .decode()?;
+        Ok(attr(&out))
+    }
+
+    /// Set file attributes (SETATTR). Attributes that are given are flagged valid.
+    pub fn setattr(&mut self, ino: u64, attr: &SetAttr) -> Result<Attr, Errno> {
+        let arg = setattr_in(attr);
+        let opcode = fuse_opcode::FUSE_SETATTR as u32;
+        let out: FuseAttrOut = self.call(opcode, ino, &[arg.as_bytes()]).decode()?;
+        Ok(self::attr(&out))
+    }
+
+    /// Read the target of a symbolic link (READLINK)
+    pub fn readlink(&mut self, ino: u64) -> Result<OsString, Errno> {
+        let data = self
+            .call(fuse_opcode::FUSE_READLINK as u32, ino, &[])
+            .result()?;
+        Ok(OsString::from_vec(data))
+    }
+
+    /// Create a symbolic link (SYMLINK)
+    pub fn symlink<T: AsRef<OsStr>, U: AsRef<Path>>(
+        &mut self,
+        parent: u64,
+        name: T,
+        link: U,
+    ) -> Result<Entry, Errno> {
+        let name = c_string(name.as_ref());
+        let link = c_string(link.as_ref().as_os_str());
+        self.call_entry(fuse_opcode::FUSE_SYMLINK, parent, &[&name, &link])
+    }
+
+    /// Create a file node (MKNOD)
+    pub fn mknod<T: AsRef<OsStr>>(
+        &mut self,
+        parent: u64,
+        name: T,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<Entry, Errno> {
+        let mut arg = FuseMknodIn::new_zeroed();
+        arg.mode = mode;
+        arg.rdev = rdev;
+        let name = c_string(name.as_ref());
+        self.call_entry(fuse_opcode::FUSE_MKNOD, parent, &[arg.as_bytes(), &name])
+    }
+
+    /// Create a directory (MKDIR)
+    pub fn mkdir<T: AsRef<OsStr>>(
+        &mut self,
+        parent: u64,
+        name: T,
+        mode: u32,
+    ) -> Result<Entry, Errno> {
+        let mut arg = FuseMkdirIn::new_zeroed();
+        arg.mode = mode;
+        let name = c_string(name.as_ref());
+        self.call_entry(fuse_opcode::FUSE_MKDIR, parent, &[arg.as_bytes(), &name])
+    }
+
+    /// Remove a file (UNLINK)
+    pub fn unlink<T: AsRef<OsStr>>(&mut self, parent: u64, name: T) -> Result<(), Errno> {
+        let name = c_string(name.as_ref());
+        self.call_empty(fuse_opcode::FUSE_UNLINK, parent, &[&name])
+    }
+
+    /// Remove a directory (RMDIR)
+    pub fn rmdir<T: AsRef<OsStr>>(&mut self, parent: u64, name: T) -> Result<(), Errno> {
+        let name = c_string(name.as_ref());
+        self.call_empty(fuse_opcode::FUSE_RMDIR, parent, &[&name])
+    }
+
+    /// Rename a file (RENAME)
+    pub fn rename<T: AsRef<OsStr>, U: AsRef<OsStr>>(
+        &mut self,
+        parent: u64,
+        name: T,
+        newparent: u64,
+        newname: U,
+    ) -> Result<(), Errno> {
+        let arg = FuseRenameIn { newdir: newparent };
+        let name = c_string(name.as_ref());
+        let newname = c_string(newname.as_ref());
+        let args = [arg.as_bytes(), &name, &newname];
+        self.call_empty(fuse_opcode::FUSE_RENAME, parent, &args)
+    }
+
+    /// Create a hard link (LINK)
+    pub fn link<T: AsRef<OsStr>>(
+        &mut self,
+        ino: u64,
+        newparent: u64,
+        newname: T,
+    ) -> Result<Entry, Errno> {
+        let arg = FuseLinkIn { oldnodeid: ino };
+        let newname = c_string(newname.as_ref());
+        self.call_entry(
+            fuse_opcode::FUSE_LINK,
+            newparent,
+            &[arg.as_bytes(), &newname],
+        )
+    }
+
+    /// Open a file (OPEN)
+    pub fn open(&mut self, ino: u64, flags: OpenFlags) -> Result<Open, Errno> {
+        self.call_open(fuse_opcode::FUSE_OPEN, ino, flags)
+    }
+
+    /// Read data from an open file (READ)
+    pub fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, Errno> {
+        let arg = read_in(fh, offset, size);
+        self.call(fuse_opcode::FUSE_READ as u32, ino, &[arg.as_bytes()])
+            .result()
+    }
+
+    /// Write data to an open file (WRITE). Returns the number of bytes written.
+    pub fn write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> Result<u32, Errno> {
+        let mut arg = FuseWriteIn::new_zeroed();
+        arg.fh = fh;
+        arg.offset = offset as u64;
+        arg.size = data.len() as u32;
+        let opcode = fuse_opcode::FUSE_WRITE as u32;
+        let out: FuseWriteOut = self.call(opcode, ino, &[arg.as_bytes(), data]).decode()?;
+        Ok(out.size)
+    }
+
+    /// Get filesystem statistics (STATFS)
+    pub fn statfs(&mut self, ino: u64) -> Result<Statfs, Errno> {
+        let out: FuseStatfsOut = self
+            .call(fuse_opcode::FUSE_STATFS as u32, ino, &[])
+            .decode()?;
+        Ok(Statfs {
+            blocks: out.st.blocks,
+            bfree: out.st.bfree,
+            bavail: out.st.bavail,
+            files: out.st.files,
+            ffree: out.st.ffree,
+            bsize: out.st.bsize,
+            namelen: out.st.namelen,
+            frsize: out.st.frsize,
+        })
+    }
+
+    /// Flush an open file (FLUSH, sent on every close)
+    pub fn flush(&mut self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), Errno> {
+        let mut arg = FuseFlushIn::new_zeroed();
+        arg.fh = fh;
+        arg.lock_owner = lock_owner;
+        self.call_empty(fuse_opcode::FUSE_FLUSH, ino, &[arg.as_bytes()])
+    }
+
+    /// Release an open file (RELEASE)
+    pub fn release(&mut self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = release_in(fh);
+        self.call_empty(fuse_opcode::FUSE_RELEASE, ino, &[arg.as_bytes()])
+    }
+
+    /// Synchronize file contents (FSYNC)
+    pub fn fsync(&mut self, ino: u64, fh: u64, datasync: bool) -> Result<(), Errno> {
+        let mut arg = FuseFsyncIn::new_zeroed();
+        arg.fh = fh;
+        arg.fsync_flags = datasync as u32;
+        self.call_empty(fuse_opcode::FUSE_FSYNC, ino, &[arg.as_bytes()])
+    }
+
+    /// Open a directory (OPENDIR)
+    pub fn opendir(&mut self, ino: u64) -> Result<Open, Errno> {
+        self.call_open(fuse_opcode::FUSE_OPENDIR, ino, OpenFlags::empty())
+    }
+
+    /// Read directory entries starting at the given offset (READDIR). As many entries as fit
+    /// into the given size are returned.
+    pub fn readdir(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<DirEntry>, Errno> {
+        let arg = read_in(fh, offset, size);
+        let opcode = fuse_opcode::FUSE_READDIR as u32;
+        let data = self.call(opcode, ino, &[arg.as_bytes()]).result()?;
+        Ok(dir_entries(&data))
+    }
+
+    /// Read all entries of an open directory with as many READDIR requests as needed
+    pub fn readdir_all(&mut self, ino: u64, fh: u64) -> Result<Vec<DirEntry>, Errno> {
+        let mut entries: Vec<DirEntry> = Vec::new();
+        loop {
+            let offset = entries.last().map_or(0, |entry| entry.offset);
+            let batch = self.readdir(ino, fh, offset, 4096)?;
+            if batch.is_empty() {
+                return Ok(entries);
+            }
+            entries.extend(batch);
+        }
+    }
+
+    /// Release an open directory (RELEASEDIR)
+    pub fn releasedir(&mut self, ino: u64, fh: u64) -> Result<(), Errno> {
+        let arg = release_in(fh);
+        self.call_empty(fuse_opcode::FUSE_RELEASEDIR, ino, &[arg.as_bytes()])
+    }
+
+    /// Check file access permissions (ACCESS)
+    pub fn access(&mut self, ino: u64, mask: i32) -> Result<(), Errno> {
+        let arg = FuseAccessIn {
+            mask: mask as u32,
+            padding: 0,
+        };
+        self.call_empty(fuse_opcode::FUSE_ACCESS, ino, &[arg.as_bytes()])
+    }
+
+    /// Create and open a file (CREATE)
+    pub fn create<T: AsRef<OsStr>>(
+        &mut self,
+        parent: u64,
+        name: T,
+        mode: u32,
+        flags: OpenFlags,
+    ) -> Result<Created, Errno> {
+        let mut arg = FuseCreateIn::new_zeroed();
+        arg.flags = flags.bits();
+        arg.mode = mode;
+        let name = c_string(name.as_ref());
+        let opcode = fuse_opcode::FUSE_CREATE as u32;
+        let data = self
+            .call(opcode, parent, &[arg.as_bytes(), &name])
+            .result()?;
+        // The reply consists of an entry followed by the open result
+        let entry_out: FuseEntryOut = decode(&data, 0);
+        let open_out: FuseOpenOut = decode(&data, mem::size_of::<FuseEntryOut>());
+        Ok(Created {
+            entry: entry(&entry_out),
+            open: open(&open_out),
+        })
+    }
+
+    /// Interrupt the request with the given unique id (INTERRUPT). Returns the unique id of
+    /// the interrupt, which is only replied if it failed (e.g. with ENOSYS).
+    pub fn interrupt(&mut self, unique: u64) -> u64 {
+        let arg = FuseInterruptIn { unique };
+        self.send(fuse_opcode::FUSE_INTERRUPT as u32, 0, &[arg.as_bytes()])
+    }
+
+    /// Send an open or opendir request
+    fn call_open(
+        &mut self,
+        opcode: fuse_opcode,
+        ino: u64,
+        flags: OpenFlags,
+    ) -> Result<Open, Errno> {
+        let arg = FuseOpenIn {
+            flags: flags.bits(),
+            unused: 0,
+        };
+        let out: FuseOpenOut = self.call(opcode as u32, ino, &[arg.as_bytes()]).decode()?;
+        Ok(open(&out))
+    }
+}
+
+/// Read an ABI structure at the given offset of reply data. Panics if the data is too short.
+fn decode<T: FromBytes>(data: &[u8], offset: usize) -> T {
+    T::read_from_prefix(data.get(offset..).unwrap_or_default()).unwrap_or_else(|| {
+        panic!(
+            "Reply too short for {} ({} bytes)",
+            std::any::type_name::<T>(),
+            data.len()
+        )
+    })
+}
+
+/// Returns the bytes of a name followed by a NUL byte
+fn c_string(name: &OsStr) -> Vec<u8> {
+    let mut data = name.as_bytes().to_vec();
+    data.push(0);
+    data
+}
+
+/// Returns the arguments of a read or readdir request
+fn read_in(fh: u64, offset: i64, size: u32) -> FuseReadIn {
+    let mut arg = FuseReadIn::new_zeroed();
+    arg.fh = fh;
+    arg.offset = offset as u64;
+    arg.size = size;
+    arg
+}
+
+/// Returns the arguments of a release or releasedir request
+fn release_in(fh: u64) -> FuseReleaseIn {
+    let mut arg = FuseReleaseIn::new_zeroed();
+    arg.fh = fh;
+    arg
+}
+
+/// Returns the arguments of a setattr request
+fn setattr_in(attr: &SetAttr) -> FuseSetattrIn {
+    use crate::AttrValid;
+    let mut arg = FuseSetattrIn::new_zeroed();
+    let mut valid = attr.valid;
+    let mut set = |flag: AttrValid, given: bool| {
+        if given {
+            valid |= flag;
+        }
+    };
+    set(AttrValid::MODE, attr.mode.is_some());
+    set(AttrValid::UID, attr.uid.is_some());
+    set(AttrValid::GID, attr.gid.is_some());
+    set(AttrValid::SIZE, attr.size.is_some());
+    set(AttrValid::ATIME, attr.atime.is_some());
+    set(AttrValid::MTIME, attr.mtime.is_some());
+    set(AttrValid::CTIME, attr.ctime.is_some());
+    set(AttrValid::FH, attr.fh.is_some());
+    arg.valid = valid.bits();
+    arg.mode = attr.mode.unwrap_or(0);
+    arg.uid = attr.uid.unwrap_or(0);
+    arg.gid = attr.gid.unwrap_or(0);
+    arg.size = attr.size.unwrap_or(0);
+    arg.fh = attr.fh.unwrap_or(0);
+    (arg.atime, arg.atimensec) = time_in(attr.atime);
+    (arg.mtime, arg.mtimensec) = time_in(attr.mtime);
+    // The ctime is passed in fields that were unused before ABI 7.23
+    (arg.unused2, arg.unused3) = time_in(attr.ctime);
+    #[cfg(feature = "abi-7-9")]
+    if let Some(lock_owner) = attr.lock_owner {
+        arg.valid |= AttrValid::LOCKOWNER.bits();
+        arg.lock_owner = lock_owner;
+    }
+    arg
+}
+
+/// Returns seconds and nanoseconds of a time since the epoch
+fn time_in(time: Option<SystemTime>) -> (u64, u32) {
+    let duration = time.map_or(Duration::ZERO, |time| {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default()
+    });
+    (duration.as_secs(), duration.subsec_nanos())
+}
+
+/// Returns the time for the given seconds and nanoseconds since the epoch
+fn time_out(secs: u64, nsecs: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::new(secs, nsecs)
+}
+
+/// Returns the file type for the given mode (or of a directory entry type shifted to a mode)
+fn kind_from_mode(mode: u32) -> FileType {
+    const KINDS: [FileType; 7] = [
+        FileType::NamedPipe,
+        FileType::CharDevice,
+        FileType::BlockDevice,
+        FileType::Directory,
+        FileType::RegularFile,
+        FileType::Symlink,
+        FileType::Socket,
+    ];
+    KINDS
+        .into_iter()
+        .find(|&kind| mode & 0o170000 == mode_from_kind_and_perm(kind, 0))
+        .unwrap_or(FileType::RegularFile)
+}
+
+/// Returns a FileAttr from a fuse_attr
+fn file_attr(attr: &FuseAttr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: time_out(attr.atime, attr.atimensec),
+        mtime: time_out(attr.mtime, attr.mtimensec),
+        ctime: time_out(attr.ctime, attr.ctimensec),
+        #[cfg(target_os = "macos")]
+        crtime: time_out(attr.crtime, attr.crtimensec),
+        #[cfg(not(target_os = "macos"))]
+        crtime: UNIX_EPOCH,
+        kind: kind_from_mode(attr.mode),
+        perm: (attr.mode & 0o7777) as u16,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        #[cfg(target_os = "macos")]
+        flags: attr.flags,
+        #[cfg(not(target_os = "macos"))]
+        flags: 0,
+    }
+}
+
+/// Returns an Entry from a fuse_entry_out
+fn entry(out: &FuseEntryOut) -> Entry {
+    Entry {
+        attr: FileAttr {
+            ino: out.nodeid,
+            ..file_attr(&out.attr)
+        },
+        ttl: Duration::new(out.entry_valid, out.entry_valid_nsec),
+        generation: out.generation,
+    }
+}
+
+/// Returns an Attr from a fuse_attr_out
+fn attr(out: &FuseAttrOut) -> Attr {
+    Attr {
+        attr: file_attr(&out.attr),
+        ttl: Duration::new(out.attr_valid, out.attr_valid_nsec),
+    }
+}
+
+/// Returns an Open from a fuse_open_out
+fn open(out: &FuseOpenOut) -> Open {
+    Open {
+        fh: out.fh,
+        flags: FopenFlags::from_bits_retain(out.open_flags),
+    }
+}
+
+/// Parse the entries of a readdir reply. Panics if the data is malformed.
+fn dir_entries(mut data: &[u8]) -> Vec<DirEntry> {
+    let header = mem::size_of::<FuseDirent>();
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let dirent = FuseDirent::read_from_prefix(data).expect("Truncated directory entry");
+        let namelen = dirent.namelen as usize;
+        assert!(
+            data.len() >= header + namelen,
+            "Truncated directory entry name"
+        );
+        entries.push(DirEntry {
+            ino: dirent.ino,
+            offset: dirent.off as i64,
+            kind: kind_from_mode(dirent.typ << 12),
+            name: OsStr::from_bytes(&data[header..header + namelen]).to_os_string(),
+        });
+        // Entries are padded to 8 bytes
+        let len = (header + namelen + 7) & !7;
+        data = &data[len.min(data.len())..];
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::Driver;
+    use crate::dir::DirSnapshot;
+    use crate::fallible::Errno;
+    use crate::{
+        FileAttr, FileType, Filesystem, FopenFlags, OpenFlags, ReplyAttr, ReplyData,
+        ReplyDirectory, ReplyEntry, ReplyOpen, RequestContext, FUSE_ROOT_ID,
+    };
+    use fuse_abi::{fuse_opcode, AsBytes, FromBytes, FuseWriteIn};
+    use std::ffi::OsStr;
+    use std::time::Duration;
+
+    const TTL: Duration = Duration::from_secs(1);
+
+    /// Filesystem with a single file hello.txt
+    struct HelloFS;
+
+    impl HelloFS {
+        fn attr(ino: u64) -> FileAttr {
+            FileAttr {
+                ino,
+                size: 13,
+                kind: if ino == FUSE_ROOT_ID {
+                    FileType::Directory
+                } else {
+                    FileType::RegularFile
+                },
+                perm: 0o644,
+                nlink: 1,
+                ..FileAttr::default()
+            }
+        }
+    }
+
+    impl Filesystem for HelloFS {
+        fn lookup(&mut self, _req: &RequestContext, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            match (parent, name.to_str()) {
+                (FUSE_ROOT_ID, Some("hello.txt")) => reply.entry(&TTL, &HelloFS::attr(2), 7),
+                _ => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn getattr(&mut self, _req: &RequestContext, ino: u64, reply: ReplyAttr) {
+            reply.attr(&TTL, &HelloFS::attr(ino));
+        }
+
+        fn open(&mut self, _req: &RequestContext, _ino: u64, _flags: OpenFlags, reply: ReplyOpen) {
+            reply.opened(42, FopenFlags::KEEP_CACHE);
+        }
+
+        fn read(
+            &mut self,
+            _req: &RequestContext,
+            _ino: u64,
+            _fh: u64,
+            offset: i64,
+            size: u32,
+            reply: ReplyData,
+        ) {
+            let data = &b"Hello World!\n"[offset as usize..];
+            reply.data(&data[..data.len().min(size as usize)]);
+        }
+
+        fn readdir(
+            &mut self,
+            _req: &RequestContext,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            reply: ReplyDirectory,
+        ) {
+            let mut dir = DirSnapshot::new(ino, ino);
+            dir.add(2, FileType::RegularFile, "hello.txt");
+            dir.reply(offset, reply);
+        }
+    }
+
+    #[test]
+    fn init_lookup_open_read() {
+        let mut driver = Driver::new(HelloFS);
+        driver.init().unwrap();
+        let entry = driver.lookup(FUSE_ROOT_ID, "hello.txt").unwrap();
+        assert_eq!(entry.attr.ino, 2);
+        assert_eq!(entry.attr.kind, FileType::RegularFile);
+        assert_eq!(entry.attr.perm, 0o644);
+        assert_eq!(entry.generation, 7);
+        assert_eq!(entry.ttl, TTL);
+        assert_eq!(
+            driver.lookup(FUSE_ROOT_ID, "missing").unwrap_err(),
+            Errno::ENOENT
+        );
+        let open = driver.open(2, OpenFlags::empty()).unwrap();
+        assert_eq!(open.fh, 42);
+        assert_eq!(open.flags, FopenFlags::KEEP_CACHE);
+        assert_eq!(driver.read(2, open.fh, 6, 100).unwrap(), b"World!\n");
+        assert_eq!(driver.getattr(2).unwrap().attr.size, 13);
+        // Unimplemented operations fail with ENOSYS, release succeeds by default
+        assert_eq!(driver.write(2, open.fh, 0, b"x"), Err(Errno::ENOSYS));
+        assert_eq!(driver.release(2, open.fh), Ok(()));
+        assert_eq!(driver.pending_replies(), 0);
+    }
+
+    #[test]
+    fn readdir() {
+        let mut driver = Driver::new(HelloFS);
+        driver.init().unwrap();
+        let entries = driver.readdir_all(FUSE_ROOT_ID, 0).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.clone()).collect();
+        assert_eq!(names, [".", "..", "hello.txt"]);
+        assert_eq!(entries[2].kind, FileType::RegularFile);
+        assert_eq!(entries[2].offset, 3);
+    }
+
+    #[test]
+    fn requests_before_init() {
+        let mut driver = Driver::new(HelloFS);
+        assert_eq!(driver.getattr(FUSE_ROOT_ID).unwrap_err(), Errno::EIO);
+        // Requests without a reply aren't replied with an error either
+        driver.forget(FUSE_ROOT_ID, 1);
+        driver.poll();
+        driver.init().unwrap();
+        assert!(driver.getattr(FUSE_ROOT_ID).is_ok());
+        driver.destroy().unwrap();
+        assert!(driver.session().destroyed);
+        let unique = driver.interrupt(1);
+        assert!(driver.reply(unique).is_none());
+    }
+
+    #[test]
+    fn interrupt_unsupported() {
+        let mut driver = Driver::new(HelloFS);
+        driver.init().unwrap();
+        let unique = driver.interrupt(1);
+        assert_eq!(driver.reply(unique).unwrap().error, libc::ENOSYS);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let mut driver = Driver::new(HelloFS);
+        driver.init().unwrap();
+        // Unknown operations and malformed requests are replied with an error
+        assert_eq!(driver.call(99, FUSE_ROOT_ID, &[]).error, libc::ENOSYS);
+        let lookup = fuse_opcode::FUSE_LOOKUP as u32;
+        assert_eq!(
+            driver.call(lookup, FUSE_ROOT_ID, &[b"foo"]).error,
+            libc::EIO
+        );
+        let write = fuse_opcode::FUSE_WRITE as u32;
+        let arg = FuseWriteIn {
+            size: 2,
+            ..FromBytes::new_zeroed()
+        };
+        let reply = driver.call(write, 2, &[arg.as_bytes(), b"x"]);
+        assert_eq!(reply.error, libc::EIO);
+        // Malformed requests without a reply stay unreplied
+        driver.send(fuse_opcode::FUSE_FORGET as u32, 2, &[]);
+        driver.poll();
+        // The filesystem keeps working
+        assert!(driver.lookup(FUSE_ROOT_ID, "hello.txt").is_ok());
+    }
+}
@@ -0,0 +1,23 @@
+//! Testing filesystems without mounting
+//!
+//! The types in this module take the place of the kernel, so that filesystems can be tested in
+//! plain unit tests, without `/dev/fuse` or the permission to mount anything. `Driver` sends
+//! single requests and decodes their replies, `LoopbackClient` offers file operations by path
+//! and sends the requests the kernel's VFS layer would send for them. `Simulator` sends random
+//! request sequences with adversarial (but valid) orderings and checks invariants on the replies.
+
+mod client;
+pub mod conformance;
+mod device;
+mod driver;
+#[cfg(test)]
+mod replies;
+mod simulator;
+
+pub use client::{Fd, LoopbackClient};
+#[cfg(test)]
+pub(crate) use device::Device;
+pub use driver::{packet, Driver, Response};
+#[cfg(test)]
+pub(crate) use replies::{dir_entries, recv_reply};
+pub use simulator::{Simulator, Violation};
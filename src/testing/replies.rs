@@ -0,0 +1,34 @@
+//! Replies sent through a channel
+//!
+//! Unit tests call filesystem methods and reply helpers directly with replies whose sender is a
+//! `Sender<Vec<u8>>`. Every reply packet is sent to the channel and parsed on the other end.
+
+use std::sync::mpsc::{Receiver, Sender};
+
+use super::Response;
+use crate::reply::ReplySender;
+
+impl ReplySender for Sender<Vec<u8>> {
+    fn send(&self, data: &[&[u8]]) {
+        Sender::send(self, data.concat()).unwrap();
+    }
+}
+
+/// Receive and parse the next reply. Panics if no reply was sent.
+pub(crate) fn recv_reply(rx: &Receiver<Vec<u8>>) -> Response {
+    Response::parse(&rx.recv().unwrap())
+}
+
+/// Returns the offsets and names of the entries in a readdir reply
+pub(crate) fn dir_entries(reply: &Response) -> Vec<(i64, String)> {
+    let mut data = &reply.data[..];
+    let mut entries = Vec::new();
+    while !data.is_empty() {
+        let offset = i64::from_ne_bytes(data[8..16].try_into().unwrap());
+        let namelen = u32::from_ne_bytes(data[16..20].try_into().unwrap()) as usize;
+        let name = String::from_utf8(data[24..24 + namelen].to_vec()).unwrap();
+        entries.push((offset, name));
+        data = &data[(24 + namelen + 7) & !7..];
+    }
+    entries
+}
@@ -0,0 +1,992 @@
+//! Deterministic kernel simulator
+//!
+//! `Simulator` stress tests a filesystem with random sequences of requests that are valid, but
+//! interleaved in ways that are hard to provoke with a real kernel. Replies are processed late
+//! and out of order, so FORGETs are sent while replies with new lookup references are still in
+//! flight. RELEASEs are sent late, in a different order than the files were closed and possibly
+//! after their inode was forgotten. INTERRUPTs are sent for requests that were already replied,
+//! and writes arrive at random offsets through different handles, like writeback of dirty pages.
+//!
+//! Every run is determined by its seed, so a failing seed replays exactly (as long as the
+//! filesystem is deterministic and replies to every request before returning).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::{error, fmt};
+
+use super::Driver;
+use crate::fallible::{Entry, Errno};
+use crate::{FileType, Filesystem, OpenFlags, SetAttr, FUSE_ROOT_ID};
+
+/// Names of directory entries. Few names make requests collide more often.
+const NAMES: [&str; 4] = ["a", "b", "c", "d"];
+/// Max offset of reads and writes
+const MAX_OFFSET: u64 = 8192;
+/// Number of trace lines shown with a violation
+const TRACE_LINES: usize = 20;
+
+/// Deterministic random number generator (SplitMix64)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in 0..n (n must not be 0)
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Returns the index of a random item, or `None` if there are no items
+    fn index(&mut self, len: usize) -> Option<usize> {
+        match len {
+            0 => None,
+            len => Some(self.below(len as u64) as usize),
+        }
+    }
+
+    fn name(&mut self) -> &'static str {
+        NAMES[self.below(NAMES.len() as u64) as usize]
+    }
+}
+
+/// Inode the kernel references
+#[derive(Debug)]
+struct Known {
+    nlookup: u64,
+    kind: FileType,
+    generation: u64,
+    /// Directory entry of the inode in the kernel's dentry cache, if it's still linked there
+    dentry: Option<(u64, OsString)>,
+}
+
+/// Open file or directory as seen by the kernel
+#[derive(Clone, Debug)]
+struct Handle {
+    ino: u64,
+    fh: u64,
+    dir: bool,
+    flags: OpenFlags,
+    /// Offset to continue reading a directory at
+    offset: i64,
+}
+
+/// Contents of a file as written by the simulator
+#[derive(Debug)]
+struct Shadow {
+    generation: u64,
+    data: Vec<u8>,
+}
+
+/// Reply the kernel didn't process yet
+#[derive(Debug)]
+enum InFlight {
+    /// Entry with a new lookup reference
+    Entry {
+        entry: Entry,
+        parent: u64,
+        name: OsString,
+    },
+    /// Entry of a created file with a new open handle
+    Created {
+        entry: Entry,
+        parent: u64,
+        name: OsString,
+        handle: Handle,
+    },
+    /// Opened file or directory
+    Open(Handle),
+    /// Directory entries (the offset to continue at)
+    Readdir { fh: u64, offset: i64 },
+}
+
+impl InFlight {
+    /// Describe the reply for the trace
+    fn describe(&self) -> String {
+        match self {
+            InFlight::Entry {
+                entry,
+                parent,
+                name,
+            } => format!(
+                "entry {} (generation {}) for ({}, {:?})",
+                entry.attr.ino, entry.generation, parent, name
+            ),
+            InFlight::Created {
+                entry,
+                parent,
+                name,
+                handle,
+            } => format!(
+                "created {} (generation {}, fh {}) for ({}, {:?})",
+                entry.attr.ino, entry.generation, handle.fh, parent, name
+            ),
+            InFlight::Open(handle) => format!("opened {} (fh {})", handle.ino, handle.fh),
+            InFlight::Readdir { fh, offset } => format!("readdir fh {} until {}", fh, offset),
+        }
+    }
+
+    /// Returns the inode and directory entry that the reply adds a lookup reference to
+    fn entry(&self) -> Option<(u64, u64)> {
+        match self {
+            InFlight::Entry { entry, parent, .. } | InFlight::Created { entry, parent, .. } => {
+                Some((entry.attr.ino, *parent))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Invariant violation found by the simulator
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Violation {
+    /// Seed of the run. `Simulator::run` with this seed replays the run.
+    pub seed: u64,
+    /// Step of the run that failed (the number of steps for violations found at the end)
+    pub step: usize,
+    /// What went wrong
+    pub message: String,
+    /// Requests sent and replies processed up to the violation
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Seed {} failed at step {}: {}",
+            self.seed, self.step, self.message
+        )?;
+        let skip = self.trace.len().saturating_sub(TRACE_LINES);
+        for line in &self.trace[skip..] {
+            write!(f, "\n  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Violation {}
+
+/// Simulator that runs random request sequences against filesystems created by a function
+///
+/// Besides the checks on replies (exactly one reply per request, valid error numbers, entries
+/// of the expected kind, no inode numbers reused while the kernel references them, read and
+/// written sizes within bounds, file sizes and contents matching the writes), it can check that
+/// the lookup counts of the filesystem balance with the references the kernel holds.
+///
+/// ```no_run
+/// use fuse::memory::MemoryFS;
+/// use fuse::testing::Simulator;
+///
+/// let mut simulator = Simulator::new(|| MemoryFS::new(1 << 20))
+///     .lookup_count(MemoryFS::lookup_count);
+/// if let Err(violation) = simulator.run_seeds(0..100) {
+///     panic!("{}", violation);
+/// }
+/// ```
+pub struct Simulator<FS, F> {
+    new: F,
+    steps: usize,
+    lookup_count: Option<fn(&FS, u64) -> u64>,
+}
+
+impl<FS: Filesystem, F: FnMut() -> FS> Simulator<FS, F> {
+    /// Create a simulator that runs every seed on a new filesystem returned by the given
+    /// function. Runs have 1000 steps by default.
+    pub fn new(new: F) -> Simulator<FS, F> {
+        Simulator {
+            new,
+            steps: 1000,
+            lookup_count: None,
+        }
+    }
+
+    /// Set the number of steps of a run (a step sends a request or processes a reply)
+    pub fn steps(mut self, steps: usize) -> Simulator<FS, F> {
+        self.steps = steps;
+        self
+    }
+
+    /// Check the lookup counts of the filesystem with the given function, which returns the
+    /// number of lookup references the filesystem counted for an inode. They're checked
+    /// whenever the kernel processed all replies, and must be zero after everything was
+    /// forgotten at the end of a run.
+    pub fn lookup_count(mut self, lookup_count: fn(&FS, u64) -> u64) -> Simulator<FS, F> {
+        self.lookup_count = Some(lookup_count);
+        self
+    }
+
+    /// Run the simulation with the given seed
+    pub fn run(&mut self, seed: u64) -> Result<(), Violation> {
+        let mut run = Run::new((self.new)(), seed, self.lookup_count);
+        let violation = |run: &Run<FS>, step, message| Violation {
+            seed,
+            step,
+            message,
+            trace: run.trace.clone(),
+        };
+        if let Err(err) = run.driver.init() {
+            return Err(violation(&run, 0, format!("init failed: {:?}", err)));
+        }
+        for step in 1..=self.steps {
+            run.guarded(|run| run.step())
+                .map_err(|message| violation(&run, step, message))?;
+        }
+        run.guarded(|run| run.finish())
+            .map_err(|message| violation(&run, self.steps, message))
+    }
+
+    /// Run the simulation with each of the given seeds. Stops at the first violation.
+    pub fn run_seeds(&mut self, seeds: Range<u64>) -> Result<(), Violation> {
+        seeds.into_iter().try_for_each(|seed| self.run(seed))
+    }
+}
+
+/// State of a simulation run (the kernel's view of the filesystem)
+struct Run<FS: Filesystem> {
+    driver: Driver<FS>,
+    rng: Rng,
+    lookup_count: Option<fn(&FS, u64) -> u64>,
+    /// Inodes the kernel references (except the root)
+    known: BTreeMap<u64, Known>,
+    /// Inodes the kernel ever got a reference to
+    seen: BTreeSet<u64>,
+    handles: Vec<Handle>,
+    /// Handles that were closed, but not released yet
+    closed: Vec<Handle>,
+    in_flight: Vec<InFlight>,
+    /// Contents of files, if known
+    contents: BTreeMap<u64, Shadow>,
+    trace: Vec<String>,
+}
+
+impl<FS: Filesystem> Run<FS> {
+    fn new(filesystem: FS, seed: u64, lookup_count: Option<fn(&FS, u64) -> u64>) -> Run<FS> {
+        Run {
+            driver: Driver::new(filesystem),
+            rng: Rng(seed),
+            lookup_count,
+            known: BTreeMap::new(),
+            seen: BTreeSet::new(),
+            handles: Vec::new(),
+            closed: Vec::new(),
+            in_flight: Vec::new(),
+            contents: BTreeMap::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    /// Run a function, turning panics (of the filesystem or the driver) into violations
+    fn guarded<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(res) => res,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(format!("panicked: {}", message))
+            }
+        }
+    }
+
+    /// Record a request or event in the trace
+    fn log(&mut self, event: String) {
+        let unique = self.driver.next_unique();
+        self.trace.push(format!("{:>6} {}", unique, event));
+    }
+
+    /// Do a random step
+    fn step(&mut self) -> Result<(), String> {
+        match self.rng.below(100) {
+            0..=24 if !self.in_flight.is_empty() => self.process(),
+            0..=39 => self.lookup(),
+            40..=44 => self.getattr(),
+            45..=49 => self.create(),
+            50..=52 => self.mkdir(),
+            53..=55 => self.remove(false),
+            56..=57 => self.remove(true),
+            58..=60 => self.rename(),
+            61..=65 => self.open(false),
+            66..=67 => self.open(true),
+            68..=71 => self.read(),
+            72..=79 => self.write(),
+            80..=81 => self.truncate(),
+            82..=84 => self.readdir(),
+            85..=88 => self.close(),
+            89..=92 => self.release(),
+            93..=97 => self.forget(),
+            _ => self.interrupt(),
+        }?;
+        self.driver.poll();
+        self.check_lookup_counts()
+    }
+
+    /// Process all replies, close and release all files, forget all inodes and check that
+    /// nothing is left
+    fn finish(&mut self) -> Result<(), String> {
+        while !self.in_flight.is_empty() {
+            self.process()?;
+        }
+        while !self.handles.is_empty() {
+            self.close()?;
+        }
+        while !self.closed.is_empty() {
+            self.release()?;
+        }
+        while !self.known.is_empty() {
+            let before = self.known.len();
+            self.forget()?;
+            if self.known.len() == before {
+                return Err("inodes can't be forgotten (cyclic dentries)".to_string());
+            }
+        }
+        self.driver.poll();
+        if self.driver.pending_replies() > 0 {
+            let pending = self.driver.pending_replies();
+            return Err(format!("{} requests were never replied", pending));
+        }
+        self.check_lookup_counts()
+    }
+
+    /// Returns a random directory the kernel references
+    fn dir(&mut self) -> u64 {
+        let dirs: Vec<u64> = self
+            .known
+            .iter()
+            .filter(|(_, known)| known.kind == FileType::Directory)
+            .map(|(&ino, _)| ino)
+            .chain([FUSE_ROOT_ID])
+            .collect();
+        dirs[self.rng.index(dirs.len()).unwrap()]
+    }
+
+    /// Returns a random regular file the kernel references
+    fn file(&mut self) -> Option<u64> {
+        let files: Vec<u64> = self
+            .known
+            .iter()
+            .filter(|(_, known)| known.kind == FileType::RegularFile)
+            .map(|(&ino, _)| ino)
+            .collect();
+        self.rng.index(files.len()).map(|i| files[i])
+    }
+
+    /// Returns a random open handle that allows reading or writing
+    fn handle(&mut self, write: bool) -> Option<Handle> {
+        let handles: Vec<&Handle> = self
+            .handles
+            .iter()
+            .filter(|handle| !handle.dir)
+            .filter(|handle| match write {
+                true => handle.flags.is_writable(),
+                false => handle.flags.is_readable(),
+            })
+            .collect();
+        self.rng.index(handles.len()).map(|i| handles[i].clone())
+    }
+
+    /// Process a random reply that is in flight
+    fn process(&mut self) -> Result<(), String> {
+        let Some(i) = self.rng.index(self.in_flight.len()) else {
+            return Ok(());
+        };
+        let reply = self.in_flight.swap_remove(i);
+        self.trace
+            .push(format!("       process {}", reply.describe()));
+        match reply {
+            InFlight::Entry {
+                entry,
+                parent,
+                name,
+            } => self.reference(&entry, parent, name)?,
+            InFlight::Created {
+                entry,
+                parent,
+                name,
+                handle,
+            } => {
+                self.reference(&entry, parent, name)?;
+                self.handles.push(handle);
+            }
+            InFlight::Open(handle) => self.handles.push(handle),
+            InFlight::Readdir { fh, offset } => {
+                if let Some(handle) = self.handles.iter_mut().find(|handle| handle.fh == fh) {
+                    handle.offset = offset;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the lookup reference of a processed entry
+    fn reference(&mut self, entry: &Entry, parent: u64, name: OsString) -> Result<(), String> {
+        let ino = entry.attr.ino;
+        // The kernel replaces the dentry of the name (it doesn't know about unlinks by others)
+        for known in self.known.values_mut() {
+            if known.dentry.as_ref() == Some(&(parent, name.clone())) {
+                known.dentry = None;
+            }
+        }
+        self.seen.insert(ino);
+        let known = self.known.entry(ino).or_insert(Known {
+            nlookup: 0,
+            kind: entry.attr.kind,
+            generation: entry.generation,
+            dentry: None,
+        });
+        if known.generation != entry.generation {
+            return Err(format!(
+                "inode {} was reused (generation {} -> {}) while the kernel references it",
+                ino, known.generation, entry.generation
+            ));
+        }
+        if known.kind != entry.attr.kind {
+            return Err(format!(
+                "inode {} changed from {:?} to {:?}",
+                ino, known.kind, entry.attr.kind
+            ));
+        }
+        known.nlookup += 1;
+        known.dentry = Some((parent, name));
+        Ok(())
+    }
+
+    /// Check an entry returned by the filesystem and reset the known contents of reused inodes
+    fn check_entry(&mut self, entry: &Entry, kind: Option<FileType>) -> Result<(), String> {
+        let ino = entry.attr.ino;
+        if ino == 0 || ino == FUSE_ROOT_ID {
+            return Err(format!("entry with invalid inode {}", ino));
+        }
+        if kind.is_some_and(|kind| kind != entry.attr.kind) {
+            return Err(format!(
+                "entry of kind {:?}, expected {:?}",
+                entry.attr.kind,
+                kind.unwrap()
+            ));
+        }
+        if self
+            .contents
+            .get(&ino)
+            .is_some_and(|shadow| shadow.generation != entry.generation)
+        {
+            self.contents.remove(&ino);
+        }
+        if let Some(shadow) = self.contents.get(&ino) {
+            if entry.attr.size != shadow.data.len() as u64 {
+                return Err(format!(
+                    "entry of inode {} with size {}, expected {}",
+                    ino,
+                    entry.attr.size,
+                    shadow.data.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self) -> Result<(), String> {
+        let parent = self.dir();
+        let name = self.rng.name();
+        self.log(format!("lookup({}, {:?})", parent, name));
+        match self.driver.lookup(parent, name) {
+            Ok(entry) => {
+                self.check_entry(&entry, None)?;
+                self.in_flight.push(InFlight::Entry {
+                    entry,
+                    parent,
+                    name: name.into(),
+                });
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    fn getattr(&mut self) -> Result<(), String> {
+        let ino = match self.rng.below(4) {
+            0 => FUSE_ROOT_ID,
+            _ => match self.rng.index(self.known.len()) {
+                Some(i) => *self.known.keys().nth(i).unwrap(),
+                None => FUSE_ROOT_ID,
+            },
+        };
+        self.log(format!("getattr({})", ino));
+        match self.driver.getattr(ino) {
+            Ok(attr) => {
+                if attr.attr.ino != ino {
+                    return Err(format!("attributes of inode {} for {}", attr.attr.ino, ino));
+                }
+                match self.contents.get(&ino) {
+                    Some(shadow) if attr.attr.size != shadow.data.len() as u64 => Err(format!(
+                        "inode {} has size {}, expected {}",
+                        ino,
+                        attr.attr.size,
+                        shadow.data.len()
+                    )),
+                    _ => Ok(()),
+                }
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    fn create(&mut self) -> Result<(), String> {
+        let parent = self.dir();
+        let name = self.rng.name();
+        let flags = OpenFlags::RDWR;
+        self.log(format!("create({}, {:?})", parent, name));
+        match self.driver.create(parent, name, 0o100644, flags) {
+            Ok(created) => {
+                let entry = created.entry;
+                self.check_entry(&entry, Some(FileType::RegularFile))?;
+                let ino = entry.attr.ino;
+                let shadow = Shadow {
+                    generation: entry.generation,
+                    data: Vec::new(),
+                };
+                self.contents.insert(ino, shadow);
+                let handle = Handle {
+                    ino,
+                    fh: created.open.fh,
+                    dir: false,
+                    flags,
+                    offset: 0,
+                };
+                self.in_flight.push(InFlight::Created {
+                    entry,
+                    parent,
+                    name: name.into(),
+                    handle,
+                });
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    fn mkdir(&mut self) -> Result<(), String> {
+        let parent = self.dir();
+        let name = self.rng.name();
+        self.log(format!("mkdir({}, {:?})", parent, name));
+        match self.driver.mkdir(parent, name, 0o755) {
+            Ok(entry) => {
+                self.check_entry(&entry, Some(FileType::Directory))?;
+                self.in_flight.push(InFlight::Entry {
+                    entry,
+                    parent,
+                    name: name.into(),
+                });
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    /// Unlink a file or remove a directory
+    fn remove(&mut self, rmdir: bool) -> Result<(), String> {
+        let parent = self.dir();
+        let name = self.rng.name();
+        let res = match rmdir {
+            true => {
+                self.log(format!("rmdir({}, {:?})", parent, name));
+                self.driver.rmdir(parent, name)
+            }
+            false => {
+                self.log(format!("unlink({}, {:?})", parent, name));
+                self.driver.unlink(parent, name)
+            }
+        };
+        match res {
+            Ok(()) => {
+                self.unlink_dentry(parent, name.into());
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    fn rename(&mut self) -> Result<(), String> {
+        let parent = self.dir();
+        let name = self.rng.name();
+        let newparent = self.dir();
+        let newname = self.rng.name();
+        self.log(format!(
+            "rename({}, {:?}, {}, {:?})",
+            parent, name, newparent, newname
+        ));
+        match self.driver.rename(parent, name, newparent, newname) {
+            Ok(()) => {
+                if (parent, name) != (newparent, newname) {
+                    self.unlink_dentry(newparent, newname.into());
+                    let dentry = Some((parent, OsString::from(name)));
+                    for known in self.known.values_mut() {
+                        if known.dentry == dentry {
+                            known.dentry = Some((newparent, newname.into()));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    /// Drop a dentry from the kernel's cache after its name was removed
+    fn unlink_dentry(&mut self, parent: u64, name: OsString) {
+        let dentry = Some((parent, name));
+        for known in self.known.values_mut() {
+            if known.dentry == dentry {
+                known.dentry = None;
+            }
+        }
+    }
+
+    fn open(&mut self, dir: bool) -> Result<(), String> {
+        let (ino, flags) = match dir {
+            true => (self.dir(), OpenFlags::empty()),
+            false => {
+                let Some(ino) = self.file() else {
+                    return Ok(());
+                };
+                let flags = [OpenFlags::empty(), OpenFlags::WRONLY, OpenFlags::RDWR];
+                (ino, flags[self.rng.below(3) as usize])
+            }
+        };
+        let res = match dir {
+            true => {
+                self.log(format!("opendir({})", ino));
+                self.driver.opendir(ino)
+            }
+            false => {
+                self.log(format!("open({}, {:?})", ino, flags));
+                self.driver.open(ino, flags)
+            }
+        };
+        match res {
+            Ok(open) => {
+                self.in_flight.push(InFlight::Open(Handle {
+                    ino,
+                    fh: open.fh,
+                    dir,
+                    flags,
+                    offset: 0,
+                }));
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    fn read(&mut self) -> Result<(), String> {
+        let Some(handle) = self.handle(false) else {
+            return Ok(());
+        };
+        let offset = self.rng.below(MAX_OFFSET) as i64;
+        let size = 1 + self.rng.below(4096) as u32;
+        self.log(format!(
+            "read({}, {}, {}, {})",
+            handle.ino, handle.fh, offset, size
+        ));
+        match self.driver.read(handle.ino, handle.fh, offset, size) {
+            Ok(data) => {
+                if data.len() > size as usize {
+                    return Err(format!("read {} bytes, asked for {}", data.len(), size));
+                }
+                if let Some(shadow) = self.contents.get(&handle.ino) {
+                    let start = (offset as usize).min(shadow.data.len());
+                    let end = (start + size as usize).min(shadow.data.len());
+                    if data != shadow.data[start..end] {
+                        return Err(format!(
+                            "read {} bytes that weren't written, expected {} bytes",
+                            data.len(),
+                            end - start
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    fn write(&mut self) -> Result<(), String> {
+        let Some(handle) = self.handle(true) else {
+            return Ok(());
+        };
+        let offset = self.rng.below(MAX_OFFSET);
+        let len = 1 + self.rng.below(64) as usize;
+        let data: Vec<u8> = (0..len).map(|_| self.rng.next() as u8).collect();
+        self.log(format!(
+            "write({}, {}, {}, {})",
+            handle.ino, handle.fh, offset, len
+        ));
+        let res = self
+            .driver
+            .write(handle.ino, handle.fh, offset as i64, &data);
+        match res {
+            Ok(written) if written as usize > len => {
+                Err(format!("wrote {} bytes, got {}", written, len))
+            }
+            Ok(written) if written as usize == len => {
+                if let Some(shadow) = self.contents.get_mut(&handle.ino) {
+                    let (start, end) = (offset as usize, offset as usize + len);
+                    if shadow.data.len() < end {
+                        shadow.data.resize(end, 0);
+                    }
+                    shadow.data[start..end].copy_from_slice(&data);
+                }
+                Ok(())
+            }
+            res => {
+                // Short and failed writes leave the contents unknown
+                self.contents.remove(&handle.ino);
+                res.map(|_| ()).or_else(check_errno)
+            }
+        }
+    }
+
+    fn truncate(&mut self) -> Result<(), String> {
+        let Some(ino) = self.file() else {
+            return Ok(());
+        };
+        let size = self.rng.below(MAX_OFFSET);
+        // Truncate through an open handle like ftruncate sometimes
+        let fh = self
+            .handles
+            .iter()
+            .find(|handle| handle.ino == ino && handle.flags.is_writable())
+            .filter(|_| self.rng.below(2) == 0)
+            .map(|handle| handle.fh);
+        let attr = SetAttr {
+            size: Some(size),
+            fh,
+            ..SetAttr::default()
+        };
+        self.log(format!("setattr({}, size {}, fh {:?})", ino, size, fh));
+        match self.driver.setattr(ino, &attr) {
+            Ok(attr) => {
+                if attr.attr.size != size {
+                    return Err(format!(
+                        "truncated to {}, got size {}",
+                        size, attr.attr.size
+                    ));
+                }
+                if let Some(shadow) = self.contents.get_mut(&ino) {
+                    shadow.data.resize(size as usize, 0);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.contents.remove(&ino);
+                check_errno(err)
+            }
+        }
+    }
+
+    fn readdir(&mut self) -> Result<(), String> {
+        let handles: Vec<&Handle> = self.handles.iter().filter(|handle| handle.dir).collect();
+        let Some(i) = self.rng.index(handles.len()) else {
+            return Ok(());
+        };
+        let handle = handles[i].clone();
+        let size = [100, 4096][self.rng.below(2) as usize];
+        self.log(format!(
+            "readdir({}, {}, {}, {})",
+            handle.ino, handle.fh, handle.offset, size
+        ));
+        match self
+            .driver
+            .readdir(handle.ino, handle.fh, handle.offset, size)
+        {
+            Ok(entries) => {
+                let offsets: BTreeSet<i64> = entries.iter().map(|entry| entry.offset).collect();
+                if offsets.len() != entries.len() || offsets.contains(&0) {
+                    return Err(format!("invalid readdir offsets {:?}", offsets));
+                }
+                if offsets.contains(&handle.offset) {
+                    return Err(format!("readdir repeated offset {}", handle.offset));
+                }
+                // Start over at the end of the directory
+                let offset = entries.last().map_or(0, |entry| entry.offset);
+                self.in_flight.push(InFlight::Readdir {
+                    fh: handle.fh,
+                    offset,
+                });
+                Ok(())
+            }
+            Err(err) => check_errno(err),
+        }
+    }
+
+    /// Close a random file (flush now, release later)
+    fn close(&mut self) -> Result<(), String> {
+        let Some(i) = self.rng.index(self.handles.len()) else {
+            return Ok(());
+        };
+        let handle = self.handles.swap_remove(i);
+        let res = match handle.dir {
+            true => Ok(()),
+            false => {
+                self.log(format!("flush({}, {})", handle.ino, handle.fh));
+                self.driver.flush(handle.ino, handle.fh, 1)
+            }
+        };
+        self.closed.push(handle);
+        res.or_else(check_errno)
+    }
+
+    /// Release a random closed file
+    fn release(&mut self) -> Result<(), String> {
+        let Some(i) = self.rng.index(self.closed.len()) else {
+            return Ok(());
+        };
+        let handle = self.closed.swap_remove(i);
+        let res = match handle.dir {
+            true => {
+                self.log(format!("releasedir({}, {})", handle.ino, handle.fh));
+                self.driver.releasedir(handle.ino, handle.fh)
+            }
+            false => {
+                self.log(format!("release({}, {})", handle.ino, handle.fh));
+                self.driver.release(handle.ino, handle.fh)
+            }
+        };
+        res.or_else(check_errno)
+    }
+
+    /// Forget a random inode that the kernel could evict: it's not open (but RELEASEs may be
+    /// pending), and no dentry below it is cached
+    fn forget(&mut self) -> Result<(), String> {
+        let pinned: BTreeSet<u64> = self
+            .handles
+            .iter()
+            .map(|handle| handle.ino)
+            .chain(self.in_flight.iter().filter_map(|reply| match reply {
+                InFlight::Open(handle) => Some(handle.ino),
+                reply => reply.entry().map(|(_, parent)| parent),
+            }))
+            .chain(
+                self.known
+                    .values()
+                    .filter_map(|known| known.dentry.as_ref().map(|(parent, _)| *parent)),
+            )
+            .collect();
+        let evictable: Vec<u64> = self
+            .known
+            .keys()
+            .filter(|ino| !pinned.contains(ino))
+            .copied()
+            .collect();
+        let Some(i) = self.rng.index(evictable.len()) else {
+            return Ok(());
+        };
+        let ino = evictable[i];
+        let known = self.known.remove(&ino).unwrap();
+        self.log(format!("forget({}, {})", ino, known.nlookup));
+        self.driver.forget(ino, known.nlookup);
+        Ok(())
+    }
+
+    /// Interrupt one of the last requests (which were all replied already)
+    fn interrupt(&mut self) -> Result<(), String> {
+        let next = self.driver.next_unique();
+        let unique = next.saturating_sub(1 + self.rng.below(8)).max(1);
+        self.log(format!("interrupt({})", unique));
+        self.driver.interrupt(unique);
+        Ok(())
+    }
+
+    /// Check that the lookup counts of the filesystem match the kernel's references, if the
+    /// kernel processed all replies
+    fn check_lookup_counts(&self) -> Result<(), String> {
+        let Some(lookup_count) = self.lookup_count else {
+            return Ok(());
+        };
+        if !self.in_flight.is_empty() {
+            return Ok(());
+        }
+        for &ino in &self.seen {
+            let expected = self.known.get(&ino).map_or(0, |known| known.nlookup);
+            let count = lookup_count(self.driver.filesystem(), ino);
+            if count != expected {
+                return Err(format!(
+                    "filesystem counts {} lookups of inode {}, the kernel holds {}",
+                    count, ino, expected
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that an error is a valid error number
+fn check_errno(err: Errno) -> Result<(), String> {
+    match err.code() {
+        1..=4095 => Ok(()),
+        code => Err(format!("invalid error number {}", code)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Simulator;
+    use crate::memory::MemoryFS;
+    use crate::{FileAttr, FileType, Filesystem, ReplyEntry, RequestContext};
+    use std::ffi::OsStr;
+    use std::time::Duration;
+
+    /// Filesystem with a single file, which gets a new generation as soon as it's forgotten,
+    /// even if lookup references are still in flight
+    struct EagerFS {
+        generation: u64,
+    }
+
+    impl Filesystem for EagerFS {
+        fn lookup(
+            &mut self,
+            _req: &RequestContext,
+            _parent: u64,
+            _name: &OsStr,
+            reply: ReplyEntry,
+        ) {
+            let attr = FileAttr {
+                ino: 2,
+                kind: FileType::RegularFile,
+                perm: 0o644,
+                nlink: 1,
+                ..FileAttr::default()
+            };
+            reply.entry(&Duration::from_secs(1), &attr, self.generation);
+        }
+
+        fn forget(&mut self, _req: &RequestContext, _ino: u64, _nlookup: u64) {
+            self.generation += 1;
+        }
+    }
+
+    #[test]
+    fn memory() {
+        let mut simulator =
+            Simulator::new(|| MemoryFS::new(1 << 20)).lookup_count(MemoryFS::lookup_count);
+        if let Err(violation) = simulator.run_seeds(0..20) {
+            panic!("{}", violation);
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let mut simulator = Simulator::new(|| EagerFS { generation: 0 }).steps(200);
+        let violation = simulator.run_seeds(0..100).unwrap_err();
+        assert!(violation.message.contains("was reused"));
+        assert_eq!(simulator.run(violation.seed), Err(violation));
+    }
+}
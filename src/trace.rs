@@ -0,0 +1,643 @@
+//! Recording and replaying raw FUSE traffic
+//!
+//! A `Recorder` set on a session (see `Session::set_recorder`) writes every request received
+//! from the kernel driver and every reply sent back to a trace file, together with the time it
+//! was seen. A trace can be read back with `TraceReader`, printed (the `Display` of a `Frame`
+//! decodes requests and names their flags, which is what the `fuse-trace` tool does) or fed
+//! into a filesystem again with `replay` to see whether it still replies the same way.
+//!
+//! A trace file starts with the 8 byte magic `FUSETRC1`, followed by frames of a kind byte
+//! (0 for requests, 1 for replies), the time in nanoseconds since the epoch (u64), the length
+//! of the data (u32) and the raw request or reply packet. Numbers are little endian.
+
+use fuse_abi::{FromBytes, FuseOutHeader};
+use log::warn;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, mem};
+
+use crate::fallible::Errno;
+use crate::session::MAX_WRITE_SIZE;
+use crate::testing::{Driver, Response};
+use crate::{ll, Filesystem};
+
+/// Magic bytes at the start of a trace file (including the format version)
+const MAGIC: &[u8; 8] = b"FUSETRC1";
+
+/// Size of the header of a frame (kind, time and length)
+const FRAME_HEADER_SIZE: usize = 1 + 8 + 4;
+
+/// Max size of the data of a frame. No packet is larger than the largest write request (with
+/// some extra space for the headers, like the buffers for receiving requests).
+const MAX_FRAME_SIZE: usize = MAX_WRITE_SIZE + 4096;
+
+/// Names of open flags (besides the access mode)
+const OPEN_FLAGS: &[(libc::c_int, &str)] = &[
+    (libc::O_CREAT, "O_CREAT"),
+    (libc::O_EXCL, "O_EXCL"),
+    (libc::O_NOCTTY, "O_NOCTTY"),
+    (libc::O_TRUNC, "O_TRUNC"),
+    (libc::O_APPEND, "O_APPEND"),
+    (libc::O_NONBLOCK, "O_NONBLOCK"),
+    (libc::O_SYNC, "O_SYNC"),
+    (libc::O_DSYNC, "O_DSYNC"),
+    (libc::O_DIRECTORY, "O_DIRECTORY"),
+    (libc::O_NOFOLLOW, "O_NOFOLLOW"),
+    (libc::O_CLOEXEC, "O_CLOEXEC"),
+    #[cfg(target_os = "linux")]
+    (libc::O_DIRECT, "O_DIRECT"),
+    #[cfg(target_os = "linux")]
+    (libc::O_LARGEFILE, "O_LARGEFILE"),
+    #[cfg(target_os = "linux")]
+    (libc::O_NOATIME, "O_NOATIME"),
+];
+
+/// Names of INIT flags. Flags are named regardless of the ABI version the crate is built for,
+/// since a trace may come from any kernel. The highest flags differ on macOS.
+const INIT_FLAGS: &[(u32, &str)] = &[
+    (1 << 0, "ASYNC_READ"),
+    (1 << 1, "POSIX_LOCKS"),
+    (1 << 2, "FILE_OPS"),
+    (1 << 3, "ATOMIC_O_TRUNC"),
+    (1 << 4, "EXPORT_SUPPORT"),
+    (1 << 5, "BIG_WRITES"),
+    (1 << 6, "DONT_MASK"),
+    (1 << 7, "SPLICE_WRITE"),
+    (1 << 8, "SPLICE_MOVE"),
+    (1 << 9, "SPLICE_READ"),
+    (1 << 10, "FLOCK_LOCKS"),
+    (1 << 11, "HAS_IOCTL_DIR"),
+    (1 << 12, "AUTO_INVAL_DATA"),
+    (1 << 13, "DO_READDIRPLUS"),
+    (1 << 14, "READDIRPLUS_AUTO"),
+    (1 << 15, "ASYNC_DIO"),
+    (1 << 16, "WRITEBACK_CACHE"),
+    (1 << 17, "NO_OPEN_SUPPORT"),
+    (1 << 18, "PARALLEL_DIROPS"),
+    (1 << 19, "HANDLE_KILLPRIV"),
+    (1 << 20, "POSIX_ACL"),
+    (1 << 21, "ABORT_ERROR"),
+    (1 << 22, "MAX_PAGES"),
+    (1 << 23, "CACHE_SYMLINKS"),
+    (1 << 24, "NO_OPENDIR_SUPPORT"),
+    (1 << 25, "EXPLICIT_INVAL_DATA"),
+    (1 << 26, "MAP_ALIGNMENT"),
+    #[cfg(not(target_os = "macos"))]
+    (1 << 27, "SUBMOUNTS"),
+    #[cfg(not(target_os = "macos"))]
+    (1 << 28, "HANDLE_KILLPRIV_V2"),
+    #[cfg(not(target_os = "macos"))]
+    (1 << 29, "SETXATTR_EXT"),
+    #[cfg(not(target_os = "macos"))]
+    (1 << 30, "INIT_EXT"),
+    #[cfg(not(target_os = "macos"))]
+    (1 << 31, "INIT_RESERVED"),
+    #[cfg(target_os = "macos")]
+    (1 << 27, "ALLOCATE"),
+    #[cfg(target_os = "macos")]
+    (1 << 28, "EXCHANGE_DATA"),
+    #[cfg(target_os = "macos")]
+    (1 << 29, "CASE_INSENSITIVE"),
+    #[cfg(target_os = "macos")]
+    (1 << 30, "VOL_RENAME"),
+    #[cfg(target_os = "macos")]
+    (1 << 31, "XTIMES"),
+];
+
+/// Names of the attributes to set in a SETATTR request
+const SETATTR_VALID: &[(u32, &str)] = &[
+    (1 << 0, "MODE"),
+    (1 << 1, "UID"),
+    (1 << 2, "GID"),
+    (1 << 3, "SIZE"),
+    (1 << 4, "ATIME"),
+    (1 << 5, "MTIME"),
+    (1 << 6, "FH"),
+    (1 << 7, "ATIME_NOW"),
+    (1 << 8, "MTIME_NOW"),
+    (1 << 9, "LOCKOWNER"),
+    (1 << 10, "CTIME"),
+    (1 << 11, "KILL_SUIDGID"),
+    (1 << 28, "CRTIME"),
+    (1 << 29, "CHGTIME"),
+    (1 << 30, "BKUPTIME"),
+    (1 << 31, "FLAGS"),
+];
+
+/// Names of RELEASE flags
+const RELEASE_FLAGS: &[(u32, &str)] = &[(1 << 0, "FLUSH"), (1 << 1, "FLOCK_UNLOCK")];
+
+/// Names of WRITE flags
+const WRITE_FLAGS: &[(u32, &str)] = &[
+    (1 << 0, "CACHE"),
+    (1 << 1, "LOCKOWNER"),
+    (1 << 2, "KILL_SUIDGID"),
+];
+
+/// Kind of a recorded frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameKind {
+    /// Request received from the kernel driver
+    Request,
+    /// Reply sent to the kernel driver
+    Reply,
+}
+
+/// A request or reply packet recorded in a trace
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    /// Whether the packet is a request or a reply
+    pub kind: FrameKind,
+    /// Time the packet was received or sent
+    pub time: SystemTime,
+    /// Raw packet data, starting with the request or reply header
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns the unique id of the request the packet belongs to (None if it's too short)
+    pub fn unique(&self) -> Option<u64> {
+        match self.kind {
+            FrameKind::Request => ll::peek_header(&self.data).map(|header| header.unique),
+            FrameKind::Reply => reply_header(&self.data).map(|header| header.unique),
+        }
+    }
+
+    /// Describe a reply with its data decoded, which needs the request it belongs to. Replies
+    /// that can't be decoded are described like the `Display` of the frame does.
+    pub fn describe_reply(&self, request: &Frame) -> String {
+        if let Ok(request) = ll::Request::try_from(&request.data[..]) {
+            if let Ok(response) = ll::Response::decode(&self.data, &request) {
+                return format!("REPLY({:3}) {}", request.unique(), response);
+            }
+        }
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FrameKind::Request => match ll::Request::try_from(&self.data[..]) {
+                Ok(request) => match describe_flags(request.operation()) {
+                    Some(flags) => write!(f, "{} [{}]", request, flags),
+                    None => write!(f, "{}", request),
+                },
+                Err(err) => match ll::peek_header(&self.data) {
+                    Some(header) => write!(f, "FUSE({:3}) invalid request: {}", header.unique, err),
+                    None => write!(f, "Invalid request: {}", err),
+                },
+            },
+            FrameKind::Reply => match reply_header(&self.data) {
+                Some(header) if header.error != 0 => write!(
+                    f,
+                    "REPLY({:3}) error: {}",
+                    header.unique,
+                    Errno::from(-header.error)
+                ),
+                Some(header) => write!(
+                    f,
+                    "REPLY({:3}) {} bytes",
+                    header.unique,
+                    self.data.len() - mem::size_of::<FuseOutHeader>()
+                ),
+                None => write!(f, "Invalid reply ({} bytes)", self.data.len()),
+            },
+        }
+    }
+}
+
+/// Writes requests and replies of a session to a trace. Clones write to the same trace, so a
+/// recorder can be shared by the workers of a session. Frames are buffered and written when
+/// the recorder is flushed or the last clone is dropped (i.e. when the session ends).
+#[derive(Clone)]
+pub struct Recorder {
+    out: Arc<Mutex<BufWriter<Box<dyn Write + Send>>>>,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recorder")
+    }
+}
+
+impl Recorder {
+    /// Create a recorder that writes to a new trace file at the given path
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Recorder::new(File::create(path)?)
+    }
+
+    /// Create a recorder that writes a trace to the given writer
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<Recorder> {
+        let mut out = BufWriter::new(Box::new(writer) as Box<dyn Write + Send>);
+        out.write_all(MAGIC)?;
+        Ok(Recorder {
+            out: Arc::new(Mutex::new(out)),
+        })
+    }
+
+    /// Write all buffered frames
+    pub fn flush(&self) -> io::Result<()> {
+        self.out
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .flush()
+    }
+
+    /// Record a request received from the kernel driver
+    pub(crate) fn request(&self, data: &[u8]) {
+        self.record(FrameKind::Request, &[data]);
+    }
+
+    /// Record a reply sent to the kernel driver
+    pub(crate) fn reply(&self, data: &[&[u8]]) {
+        self.record(FrameKind::Reply, data);
+    }
+
+    /// Write a frame with the given data. Failing to record must not affect the session, so
+    /// errors are only logged.
+    fn record(&self, kind: FrameKind, data: &[&[u8]]) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let len = data.iter().map(|d| d.len()).sum::<usize>() as u32;
+        let mut header = [0; FRAME_HEADER_SIZE];
+        header[0] = match kind {
+            FrameKind::Request => 0,
+            FrameKind::Reply => 1,
+        };
+        header[1..9].copy_from_slice(&time.to_le_bytes());
+        header[9..].copy_from_slice(&len.to_le_bytes());
+        let mut out = self.out.lock().unwrap_or_else(|err| err.into_inner());
+        let res = out
+            .write_all(&header)
+            .and_then(|()| data.iter().try_for_each(|d| out.write_all(d)));
+        if let Err(err) = res {
+            warn!("Failed to record FUSE {:?}: {}", kind, err);
+        }
+    }
+}
+
+/// Reads the frames of a trace
+#[derive(Debug)]
+pub struct TraceReader<R> {
+    reader: R,
+    /// True after the end of the trace or an error
+    done: bool,
+}
+
+impl TraceReader<BufReader<File>> {
+    /// Open the trace file at the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<TraceReader<BufReader<File>>> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Read a trace from the given reader. Fails if the trace doesn't start with the magic.
+    pub fn new(mut reader: R) -> io::Result<TraceReader<R>> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a FUSE trace",
+            ));
+        }
+        Ok(TraceReader {
+            reader,
+            done: false,
+        })
+    }
+
+    /// Read the next frame, or None at the end of the trace
+    fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        // A trace ends cleanly only between frames
+        if self.reader.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut header[1..])?;
+        let kind = match header[0] {
+            0 => FrameKind::Request,
+            1 => FrameKind::Reply,
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid frame kind {}", kind),
+                ))
+            }
+        };
+        let time = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let len = u32::from_le_bytes(header[9..].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Frame too large ({} bytes)", len),
+            ));
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Frame {
+            kind,
+            time: UNIX_EPOCH + Duration::from_nanos(time),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<io::Result<Frame>> {
+        if self.done {
+            return None;
+        }
+        let res = self.read_frame().transpose();
+        self.done = !matches!(res, Some(Ok(_)));
+        res
+    }
+}
+
+/// A request that was replied differently when replaying a trace
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// Description of the request
+    pub request: String,
+    /// Reply in the trace (None if the request wasn't replied)
+    pub recorded: Option<Response>,
+    /// Reply of the filesystem (None if the request wasn't replied)
+    pub replayed: Option<Response>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: recorded {}, replayed {}",
+            self.request,
+            describe_response(self.recorded.as_ref()),
+            describe_response(self.replayed.as_ref())
+        )?;
+        if let (Some(recorded), Some(replayed)) = (&self.recorded, &self.replayed) {
+            if recorded.error == 0 && replayed.error == 0 {
+                let offset = recorded
+                    .data
+                    .iter()
+                    .zip(&replayed.data)
+                    .position(|(a, b)| a != b)
+                    .unwrap_or_else(|| recorded.data.len().min(replayed.data.len()));
+                write!(f, " (differs at byte {})", offset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Result of replaying a trace
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// Number of requests sent to the filesystem
+    pub requests: usize,
+    /// Number of requests in the trace that were too short for a header and were skipped
+    pub skipped: usize,
+    /// Requests that were replied differently
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// Returns true if all requests were replied like in the trace
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests replayed, {} skipped, {} replied differently",
+            self.requests,
+            self.skipped,
+            self.mismatches.len()
+        )?;
+        for mismatch in &self.mismatches {
+            write!(f, "\n  {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send the requests of a trace to the filesystem of the given driver and compare its replies
+/// to the recorded ones. The trace should start with the INIT request, like a trace recorded
+/// from the start of a session does. Requests are sent as recorded (including their unique
+/// ids), replies are compared after all requests were sent, so a filesystem may reply
+/// asynchronously. Replies containing timestamps (like attributes of newly created files)
+/// only match if the filesystem behaves deterministically.
+pub fn replay<FS, I>(driver: &mut Driver<FS>, frames: I) -> ReplayReport
+where
+    FS: Filesystem,
+    I: IntoIterator<Item = Frame>,
+{
+    let mut report = ReplayReport::default();
+    let mut requests = Vec::new();
+    let mut recorded = std::collections::HashMap::new();
+    for frame in frames {
+        match frame.kind {
+            // Malformed requests are sent as well, since a session replies to them with an
+            // error. Only requests without a header can't be replayed.
+            FrameKind::Request => match frame.unique() {
+                Some(unique) => {
+                    requests.push((unique, frame.to_string()));
+                    driver.send_packet(&frame.data);
+                    report.requests += 1;
+                }
+                None => report.skipped += 1,
+            },
+            FrameKind::Reply => {
+                if reply_header(&frame.data).is_some() {
+                    let response = Response::parse(&frame.data);
+                    recorded.insert(response.unique, response);
+                }
+            }
+        }
+    }
+    for (unique, request) in requests {
+        let recorded = recorded.remove(&unique);
+        let replayed = driver.reply(unique);
+        if recorded != replayed {
+            report.mismatches.push(Mismatch {
+                request,
+                recorded,
+                replayed,
+            });
+        }
+    }
+    report
+}
+
+/// Returns the header of a reply packet, or None if the packet is malformed
+fn reply_header(data: &[u8]) -> Option<FuseOutHeader> {
+    let header = FuseOutHeader::read_from_prefix(data)?;
+    match header.len as usize == data.len() {
+        true => Some(header),
+        false => None,
+    }
+}
+
+/// Describe a reply for a mismatch
+fn describe_response(response: Option<&Response>) -> String {
+    match response {
+        None => "no reply".to_string(),
+        Some(response) if response.error != 0 => format!("error {}", Errno::from(response.error)),
+        Some(response) => format!("{} bytes", response.data.len()),
+    }
+}
+
+/// Returns the names of the flags of an operation, if it has any
+fn describe_flags(operation: &ll::Operation<'_>) -> Option<String> {
+    Some(match operation {
+        ll::Operation::Init { arg } => flag_names(arg.flags, INIT_FLAGS),
+        ll::Operation::Open { arg } | ll::Operation::OpenDir { arg } => open_flags(arg.flags),
+        ll::Operation::Create { arg, .. } => open_flags(arg.flags),
+        ll::Operation::SetAttr { arg } => flag_names(arg.valid, SETATTR_VALID),
+        ll::Operation::Release { arg } | ll::Operation::ReleaseDir { arg } => format!(
+            "{}; {}",
+            open_flags(arg.flags),
+            flag_names(arg.release_flags, RELEASE_FLAGS)
+        ),
+        ll::Operation::Write { arg, .. } => flag_names(arg.write_flags, WRITE_FLAGS),
+        _ => return None,
+    })
+}
+
+/// Returns the names of the given open flags, starting with the access mode
+fn open_flags(flags: u32) -> String {
+    let mode = match flags as libc::c_int & libc::O_ACCMODE {
+        libc::O_RDONLY => "O_RDONLY",
+        libc::O_WRONLY => "O_WRONLY",
+        libc::O_RDWR => "O_RDWR",
+        _ => "O_ACCMODE",
+    };
+    let flags = flags & !(libc::O_ACCMODE as u32);
+    let names: Vec<_> = OPEN_FLAGS
+        .iter()
+        .map(|&(flag, name)| (flag as u32, name))
+        .collect();
+    match flags {
+        0 => mode.to_string(),
+        _ => format!("{}|{}", mode, flag_names(flags, &names)),
+    }
+}
+
+/// Returns the names of the flags set in the given value, joined by `|`. A flag is only named
+/// if all of its bits are set and not taken by a flag before it. Unknown flags are shown as a
+/// hex number.
+fn flag_names(flags: u32, names: &[(u32, &str)]) -> String {
+    let mut parts = Vec::new();
+    let mut rest = flags;
+    for &(flag, name) in names {
+        if flag != 0 && rest & flag == flag {
+            parts.push(name.to_string());
+            rest &= !flag;
+        }
+    }
+    if rest != 0 || parts.is_empty() {
+        parts.push(format!("{:#x}", rest));
+    }
+    parts.join("|")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        flag_names, open_flags, replay, FrameKind, Recorder, TraceReader, INIT_FLAGS, MAGIC,
+        RELEASE_FLAGS,
+    };
+    use crate::memory::MemoryFS;
+    use crate::testing::{packet, Device, Driver};
+    use crate::Session;
+    use fuse_abi::fuse_opcode;
+    use std::time::Duration;
+
+    #[test]
+    fn flags() {
+        assert_eq!(flag_names(0b11, INIT_FLAGS), "ASYNC_READ|POSIX_LOCKS");
+        assert_eq!(flag_names(1 << 22 | 1, INIT_FLAGS), "ASYNC_READ|MAX_PAGES");
+        assert_eq!(flag_names(1 << 5 | 1, RELEASE_FLAGS), "FLUSH|0x20");
+        assert_eq!(flag_names(0, INIT_FLAGS), "0x0");
+        assert_eq!(open_flags(0), "O_RDONLY");
+        let flags = libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC;
+        assert_eq!(open_flags(flags as u32), "O_RDWR|O_CREAT|O_TRUNC");
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("fuse-trace-{}", std::process::id()));
+        let (device, fd) = Device::new();
+        device.init(1);
+        device.send(&packet(fuse_opcode::FUSE_LOOKUP as u32, 2, 1, &[b"foo\0"]));
+        device.send(&packet(fuse_opcode::FUSE_STATFS as u32, 3, 1, &[]));
+
+        let mut se = Session::from_fd(MemoryFS::new(1 << 20), fd);
+        se.set_recorder(Some(Recorder::create(&path).unwrap()));
+        let shutdown = tokio::time::sleep(Duration::from_millis(100));
+        se.run_until(shutdown, Duration::from_millis(10))
+            .await
+            .unwrap();
+        // Dropping the session flushes the recorder
+        drop(se);
+        drop(device);
+
+        let frames = TraceReader::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 6);
+        for (i, frame) in frames.iter().enumerate() {
+            let kind = [FrameKind::Request, FrameKind::Reply][i % 2];
+            assert_eq!(frame.kind, kind);
+            assert_eq!(frame.unique(), Some(i as u64 / 2 + 1));
+        }
+        let text = frames[0].to_string();
+        assert!(
+            text.contains("INIT") && text.ends_with("[ASYNC_READ]"),
+            "{}",
+            text
+        );
+        assert!(frames[2].to_string().contains("LOOKUP name \"foo\""));
+        assert!(frames[3].to_string().starts_with("REPLY(  2) error: "));
+        let text = frames[1].describe_reply(&frames[0]);
+        assert!(text.starts_with("REPLY(  1) INIT ABI 7."), "{}", text);
+        let text = frames[5].describe_reply(&frames[4]);
+        assert!(text.starts_with("REPLY(  3) statfs blocks "), "{}", text);
+
+        // Replaying into the same kind of filesystem gives the same replies
+        let mut driver = Driver::new(MemoryFS::new(1 << 20));
+        let report = replay(&mut driver, frames.clone());
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.requests, 3);
+
+        // A filesystem of a different size replies to statfs differently
+        let mut driver = Driver::new(MemoryFS::new(1 << 21));
+        let report = replay(&mut driver, frames);
+        assert_eq!(report.mismatches.len(), 1, "{}", report);
+        assert!(report.mismatches[0].request.contains("STATFS"));
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut trace = MAGIC.to_vec();
+        trace.extend_from_slice(&[0; 9]);
+        trace.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = TraceReader::new(&trace[..]).unwrap();
+        let err = reader.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}